@@ -0,0 +1,193 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Bouquets: the channel lineups operators define in the BAT.
+//!
+//! Logical channel numbers in a BAT are read with the same descriptors as those of a NIT,
+//! see the `lcn` module; operator specific descriptors such as those of Freesat and Sky
+//! are not decoded.
+
+use std::path::{Path, PathBuf};
+
+use crate::channels_file::{ChannelsFile, ServiceTriplet};
+use crate::lcn::{self, LogicalChannel, TargetRegion};
+use crate::si;
+
+/// A service of a bouquet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BouquetService {
+    pub triplet: ServiceTriplet,
+    /// The service type from the service list descriptor, if listed there.
+    pub service_type: Option<u8>,
+    /// The logical channel the bouquet gives the service.
+    pub channel: Option<LogicalChannel>,
+}
+
+/// A bouquet and its services.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bouquet {
+    pub bouquet_id: u16,
+    pub name: String,
+    pub services: Vec<BouquetService>,
+}
+
+impl Bouquet {
+    /// Build a bouquet from its BAT, choosing channel numbers for a receiver in `region`.
+    /// The services are those in the service list descriptors and those given a logical
+    /// channel.
+    pub fn from_bat(bat: &si::Bat, region: Option<&TargetRegion>) -> Bouquet {
+        let assignments = lcn::bouquet_assignments(bat);
+        let mut triplets = Vec::new();
+        for transport in &bat.transports {
+            for (service_id, service_type) in transport.services() {
+                triplets.push((
+                    ServiceTriplet {
+                        network_id: transport.original_network_id,
+                        transport_id: transport.transport_stream_id,
+                        service_id,
+                    },
+                    Some(service_type),
+                ));
+            }
+        }
+        for assignment in &assignments {
+            let triplet = ServiceTriplet {
+                network_id: assignment.original_network_id,
+                transport_id: assignment.transport_stream_id,
+                service_id: assignment.service_id,
+            };
+            if !triplets.iter().any(|(t, _)| *t == triplet) {
+                triplets.push((triplet, None));
+            }
+        }
+        Bouquet {
+            bouquet_id: bat.bouquet_id,
+            name: bat.bouquet_name.clone(),
+            services: triplets
+                .into_iter()
+                .map(|(triplet, service_type)| BouquetService {
+                    triplet,
+                    service_type,
+                    channel: lcn::logical_channel(&assignments, triplet, region),
+                })
+                .collect(),
+        }
+    }
+
+    /// The bouquet's description of a service, if it is in the bouquet. A triplet
+    /// without an original network id matches any.
+    pub fn service(&self, triplet: ServiceTriplet) -> Option<&BouquetService> {
+        self.services.iter().find(|s| {
+            s.triplet.service_id == triplet.service_id
+                && s.triplet.transport_id == triplet.transport_id
+                && (triplet.network_id == 0 || s.triplet.network_id == triplet.network_id)
+        })
+    }
+
+    /// Return a channels file with only the services of the bouquet, numbered with the
+    /// bouquet's logical channels. Services the bouquet gives no channel lose any number
+    /// from the NIT, so that the file is numbered by the bouquet alone.
+    pub fn apply(&self, file: &ChannelsFile) -> ChannelsFile {
+        let mut entries = Vec::new();
+        for entry in &file.entries {
+            let service = match entry.triplet().and_then(|t| self.service(t)) {
+                Some(service) => service,
+                None => continue,
+            };
+            let mut entry = entry.clone();
+            match service.channel {
+                Some(channel) => lcn::annotate(&mut entry, channel),
+                None => lcn::clear(&mut entry),
+            }
+            entry.set_annotation("BOUQUET", &self.bouquet_id.to_string());
+            entries.push(entry);
+        }
+        ChannelsFile { entries }
+    }
+}
+
+/// The path of the output file for a bouquet: the bouquet id is added to the file stem, so
+/// `dvb-channels.conf` gives `dvb-channels-272.conf` for bouquet 272.
+pub fn output_path(path: &Path, bouquet_id: u16) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, bouquet_id, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, bouquet_id),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::channels_file::ChannelEntry;
+
+    fn entry(name: &str, service_id: u16) -> ChannelEntry {
+        let mut entry = ChannelEntry::new(name);
+        entry.set("SERVICE_ID", &service_id.to_string());
+        entry.set("NETWORK_ID", "2");
+        entry.set("TRANSPORT_ID", "2041");
+        entry
+    }
+
+    #[test]
+    fn bouquet_selects_and_numbers_services() {
+        let bat = si::Bat {
+            bouquet_id: 272,
+            bouquet_name: "Lineup".to_string(),
+            transports: vec![si::NitTransport {
+                transport_stream_id: 2041,
+                original_network_id: 2,
+                descriptors: vec![
+                    0x41, 0x06, 0x18, 0x9e, 0x01, 0x18, 0x9f, 0x02, // service list
+                    0x83, 0x04, 0x18, 0x9e, 0xfc, 0x65, // channel 101
+                ],
+            }],
+            ..si::Bat::default()
+        };
+        let bouquet = Bouquet::from_bat(&bat, None);
+        assert_eq!(bouquet.services.len(), 2);
+        assert_eq!(bouquet.services[1].service_type, Some(2));
+        let mut two = entry("Two", 6303);
+        two.set("VCHANNEL", "7");
+        two.set_annotation("HIDDEN", "1");
+        let file = ChannelsFile {
+            entries: vec![entry("One", 6302), two, entry("Other", 1)],
+        };
+        let selected = bouquet.apply(&file);
+        assert_eq!(
+            selected
+                .entries
+                .iter()
+                .map(|e| (e.name.as_str(), e.get("VCHANNEL")))
+                .collect::<Vec<_>>(),
+            vec![("One", Some("101")), ("Two", None)]
+        );
+        assert_eq!(selected.entries[0].get_annotation("BOUQUET"), Some("272"));
+        assert_eq!(selected.entries[1].get_annotation("HIDDEN"), None);
+        assert_eq!(
+            output_path(Path::new("out/dvb-channels.conf"), 272),
+            Path::new("out/dvb-channels-272.conf")
+        );
+    }
+}
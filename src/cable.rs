@@ -0,0 +1,213 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Scanning a cable network from its home transponder. Cable operators publish a single
+//! frequency; the NIT received there lists every transport stream of the network and
+//! where it is carried.
+
+use crate::channels_file::{ChannelEntry, ChannelsFile};
+use crate::si::Nit;
+
+/// How far apart, in Hz, frequencies may be and still be of the same transponder.
+const FREQUENCY_TOLERANCE: u32 = 100_000;
+
+/// The inner FEC code of the cable delivery system descriptor for no inner FEC.
+const NO_INNER_FEC: u8 = 0x0f;
+
+/// A transponder of a cable network.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CableTransponder {
+    /// The frequency in Hz.
+    pub frequency: u32,
+    /// The symbol rate in symbols per second.
+    pub symbol_rate: u32,
+    /// The modulation as the cable delivery system descriptor codes it, automatic if not
+    /// known.
+    pub modulation: Option<u8>,
+    /// The inner FEC scheme as the cable delivery system descriptor codes it, automatic if
+    /// not known.
+    pub inner_fec: Option<u8>,
+}
+
+impl CableTransponder {
+    /// The transmitter file entry with which libdvbv5 tunes to this transponder.
+    fn entry(&self) -> ChannelEntry {
+        let mut entry = ChannelEntry::new("CHANNEL");
+        entry.set("DELIVERY_SYSTEM", "DVBC/ANNEX_A");
+        entry.set("FREQUENCY", &self.frequency.to_string());
+        entry.set("SYMBOL_RATE", &self.symbol_rate.to_string());
+        entry.set("INNER_FEC", inner_fec_name(self.inner_fec));
+        entry.set("MODULATION", modulation_name(self.modulation));
+        entry.set("INVERSION", "AUTO");
+        entry
+    }
+}
+
+/// Get the descriptor code of a modulation named on the command line, `None` being
+/// automatic detection.
+pub fn parse_modulation(name: &str) -> Result<Option<u8>, String> {
+    match name {
+        "qam16" => Ok(Some(1)),
+        "qam32" => Ok(Some(2)),
+        "qam64" => Ok(Some(3)),
+        "qam128" => Ok(Some(4)),
+        "qam256" => Ok(Some(5)),
+        "auto" => Ok(None),
+        _ => Err(format!("{} is not a cable modulation", name)),
+    }
+}
+
+/// The libdvbv5 name of a modulation given by its descriptor code.
+fn modulation_name(modulation: Option<u8>) -> &'static str {
+    match modulation {
+        Some(1) => "QAM/16",
+        Some(2) => "QAM/32",
+        Some(3) => "QAM/64",
+        Some(4) => "QAM/128",
+        Some(5) => "QAM/256",
+        _ => "QAM/AUTO",
+    }
+}
+
+/// The libdvbv5 name of an inner FEC scheme given by its descriptor code.
+fn inner_fec_name(inner_fec: Option<u8>) -> &'static str {
+    match inner_fec {
+        Some(1) => "1/2",
+        Some(2) => "2/3",
+        Some(3) => "3/4",
+        Some(4) => "5/6",
+        Some(5) => "7/8",
+        Some(6) => "8/9",
+        Some(7) => "3/5",
+        Some(8) => "4/5",
+        Some(9) => "9/10",
+        Some(NO_INNER_FEC) => "NONE",
+        _ => "AUTO",
+    }
+}
+
+/// The transponders of the network `network_id`: the home transponder followed by those
+/// of the cable delivery system descriptors of the network's NIT. A transponder whose
+/// descriptor gives no symbol rate is assumed to have that of the home transponder.
+pub fn network_transponders(
+    home: &CableTransponder,
+    networks: &[Nit],
+    network_id: u16,
+) -> Vec<CableTransponder> {
+    let mut transponders = vec![home.clone()];
+    let deliveries = networks
+        .iter()
+        .filter(|nit| nit.network_id == network_id)
+        .flat_map(|nit| nit.transports.iter())
+        .map(|transport| transport.delivery())
+        .filter(|delivery| delivery.modulation.is_some());
+    for delivery in deliveries {
+        for frequency in &delivery.frequencies {
+            let frequency = frequency * 1000;
+            if transponders
+                .iter()
+                .any(|t| t.frequency.abs_diff(frequency) <= FREQUENCY_TOLERANCE)
+            {
+                continue;
+            }
+            transponders.push(CableTransponder {
+                frequency,
+                symbol_rate: delivery.symbol_rate.unwrap_or(home.symbol_rate),
+                modulation: delivery.modulation,
+                inner_fec: delivery.inner_fec,
+            });
+        }
+    }
+    transponders
+}
+
+/// A transmitter file of transponders, for scanning.
+pub fn transmitter_file(transponders: &[CableTransponder]) -> ChannelsFile {
+    ChannelsFile {
+        entries: transponders.iter().map(CableTransponder::entry).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::si::tests::{make_nit_payload, make_section};
+    use crate::si::{Section, NIT_ACTUAL_TABLE_ID};
+
+    #[test]
+    fn transponders_of_the_network_nit() {
+        // Transport streams at 330 MHz, 6.9 Msymbol/s 256-QAM, and at 338 MHz 64-QAM
+        // without a symbol rate, and one at the home frequency.
+        let cable = |frequency: u8, modulation: u8, symbol_rate: u8| {
+            vec![
+                0x44,
+                0x0b,
+                0x03,
+                frequency,
+                0x00,
+                0x00,
+                0xff,
+                0xf2,
+                modulation,
+                0x00,
+                symbol_rate,
+                0x00,
+                0x0f,
+            ]
+        };
+        let payload = make_nit_payload(
+            "Cable",
+            &[
+                (1, 61441, cable(0x30, 5, 0x69)),
+                (2, 61441, cable(0x38, 3, 0xff)),
+                (3, 61441, cable(0x22, 3, 0x69)),
+            ],
+        );
+        let section =
+            Section::parse(&make_section(NIT_ACTUAL_TABLE_ID, 61441, 0, &payload)).unwrap();
+        let nit = Nit::parse(&[&section]).unwrap();
+        let home = CableTransponder {
+            frequency: 322_000_000,
+            symbol_rate: 6_875_000,
+            modulation: None,
+            inner_fec: None,
+        };
+        assert_eq!(
+            network_transponders(&home, std::slice::from_ref(&nit), 1).len(),
+            1
+        );
+        let transponders = network_transponders(&home, &[nit], 61441);
+        assert_eq!(
+            transponders
+                .iter()
+                .map(|t| (t.frequency, t.symbol_rate, t.modulation))
+                .collect::<Vec<_>>(),
+            vec![
+                (322_000_000, 6_875_000, None),
+                (330_000_000, 6_900_000, Some(5)),
+                (338_000_000, 6_875_000, Some(3)),
+            ]
+        );
+        let file = transmitter_file(&transponders);
+        assert_eq!(file.entries[1].get("MODULATION"), Some("QAM/256"));
+        assert_eq!(file.entries[1].get("INNER_FEC"), Some("NONE"));
+        assert_eq!(file.entries[0].get("MODULATION"), Some("QAM/AUTO"));
+    }
+}
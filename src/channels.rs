@@ -18,9 +18,18 @@
  */
 
 //! Module realising the abstractions needed for this application.
+//!
+//! The scan drives libdvbv5 through `dvbv5_sys` rather than the `dvbv5` wrappers, which
+//! give no access to the satellite settings of a frontend, nor to the entries of a
+//! transmitter file beyond reading them: a scan sets their properties for satellite
+//! tuning, streams and retries, inserts and drops entries, and tells which stored entries
+//! came from which transponder. Using the raw entries is kept to the functions with safety
+//! requirements below and `TransmitterData::scan`.
 
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
 use std::io::{stdout, Write};
+use std::ops::Range;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::thread::sleep;
@@ -32,69 +41,1087 @@ use dvbv5_sys;
 
 use dvbv5;
 
+use crate::bouquet::{self, Bouquet};
+use crate::cable::{self, CableTransponder};
+use crate::channels_file::{ChannelEntry, ChannelsFile, OutputFormat};
+use crate::components;
+use crate::dedup;
+use crate::demux::{Demux, SectionFilter};
+use crate::filter::{NetworkRestriction, ServiceFilter};
+use crate::frontend::{Channel, Frontend};
+use crate::isdbt;
+use crate::lcn::{self, TargetRegion};
+use crate::positioner::{DishPosition, Positioner};
+use crate::psip;
+use crate::retry::{RetryStrategy, Variant};
+use crate::satellite::{self, Conversion, Lnb, LnbPower, SatellitePosition};
+use crate::si;
+use crate::signal::LockStatistics;
+use crate::temporary::TemporaryDirectory;
+use crate::text::{self, Charset};
+use crate::unicable::UserBand;
+
+thread_local! {
+    /// The statistics read by `frontend_check` when it last achieved lock. libdvbv5 gives
+    /// no way of returning data from the check function other than this.
+    static LOCK_STATISTICS: RefCell<Option<LockStatistics>> = const { RefCell::new(None) };
+
+    /// Whether `frontend_check` is to turn the LNB power off once libdvbv5 has tuned.
+    static LNB_POWER_OFF: RefCell<bool> = const { RefCell::new(false) };
+}
+
+/// The time allowed for receiving a complete SDT, before applying the timeout multiplier.
+/// The SDT actual must be repeated at least every two seconds.
+const SDT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The time allowed for receiving a complete NIT, before applying the timeout multiplier.
+/// The NIT must be repeated at least every ten seconds.
+const NIT_TIMEOUT: Duration = Duration::from_secs(11);
+
+/// The time allowed for receiving the PAT or a PMT, before applying the timeout multiplier.
+/// Both should be repeated at least every half second.
+const PSI_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The time allowed for receiving a complete VCT, before applying the timeout multiplier.
+/// The VCT must be repeated at least every 400 ms.
+const VCT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A transponder on which lock was achieved during a scan.
+#[derive(Clone, Debug)]
+pub struct ScannedTransponder {
+    /// The frequency as given in the transmitter file.
+    pub frequency: u32,
+    /// The satellite position the transponder was received from, if one was selected.
+    pub position: Option<SatellitePosition>,
+    /// The PLP or input stream the transport stream was received from, if known.
+    pub stream_id: Option<u32>,
+    /// The variation of the tuning parameters of the transmitter file with which lock was
+    /// achieved, if the parameters as given failed.
+    pub variant: Option<Variant>,
+    pub statistics: Option<LockStatistics>,
+    pub sdt: Option<si::Sdt>,
+    /// The virtual channels of an ATSC transport stream, which has no SDT.
+    pub vct: Option<psip::Vct>,
+    pub pmts: Vec<si::Pmt>,
+    /// The indices of the entries of the channels data stored from the transponder.
+    pub entries: Range<usize>,
+}
+
+/// A transponder tried during a scan.
+#[derive(Debug)]
+pub enum TriedTransponder<'a> {
+    Locked(&'a ScannedTransponder),
+    /// The frequency, as given in the transmitter files, of a transponder on which no lock
+    /// was achieved.
+    Unlocked(u32),
+}
+
+/// Interleave the transponders on which lock was achieved with those on which it was not,
+/// given with the number of transponders locked before them, in the order they were tried.
+fn in_scan_order<'a>(
+    transponders: &'a [ScannedTransponder],
+    unlocked: &[(usize, u32)],
+) -> Vec<TriedTransponder<'a>> {
+    let mut tried = Vec::new();
+    let mut locked = transponders.iter();
+    let mut count = 0;
+    for (before, frequency) in unlocked {
+        tried.extend(
+            locked
+                .by_ref()
+                .take(before - count)
+                .map(TriedTransponder::Locked),
+        );
+        count = *before;
+        tried.push(TriedTransponder::Unlocked(*frequency));
+    }
+    tried.extend(locked.map(TriedTransponder::Locked));
+    tried
+}
+
+/// Read the SDT of the transport stream a frontend is tuned to.
+fn read_sdt(frontend_id: &dvbv5::FrontendId, timeout_multiplier: u32) -> Option<si::Sdt> {
+    let filter = SectionFilter::new(frontend_id, si::SDT_PID, si::SDT_ACTUAL_TABLE_ID).ok()?;
+    let collector = filter.collect(SDT_TIMEOUT * timeout_multiplier);
+    let tables = collector.tables();
+    si::Sdt::parse(tables.first()?).ok()
+}
+
+/// Read the VCT of the ATSC transport stream a frontend is tuned to, the CVCT for cable or
+/// the TVCT.
+fn read_vct(
+    frontend_id: &dvbv5::FrontendId,
+    cable: bool,
+    timeout_multiplier: u32,
+) -> Option<psip::Vct> {
+    let table_id = if cable {
+        psip::CVCT_TABLE_ID
+    } else {
+        psip::TVCT_TABLE_ID
+    };
+    let filter = SectionFilter::new(frontend_id, psip::PSIP_PID, table_id).ok()?;
+    let collector = filter.collect(VCT_TIMEOUT * timeout_multiplier);
+    let tables = collector.tables();
+    psip::Vct::parse(tables.first()?).ok()
+}
+
+/// Read the PMTs of the programs of the transport stream a frontend is tuned to.
+fn read_pmts(frontend_id: &dvbv5::FrontendId, timeout_multiplier: u32) -> Vec<si::Pmt> {
+    let pat = match SectionFilter::new(frontend_id, si::PAT_PID, si::PAT_TABLE_ID) {
+        Ok(filter) => {
+            let collector = filter.collect(PSI_TIMEOUT * timeout_multiplier);
+            match collector.tables().first() {
+                Some(sections) => si::Pat::parse(sections),
+                None => return Vec::new(),
+            }
+        }
+        Err(_) => return Vec::new(),
+    };
+    pat.programs
+        .iter()
+        .filter(|(program_number, _)| *program_number != 0)
+        .filter_map(|&(program_number, pid)| {
+            let filter = SectionFilter::new(frontend_id, pid, si::PMT_TABLE_ID).ok()?;
+            let collector = filter.collect(PSI_TIMEOUT * timeout_multiplier);
+            // Several programs may share a PMT PID.
+            let tables = collector.tables();
+            tables
+                .iter()
+                .flatten()
+                .filter(|section| section.extension == program_number)
+                .find_map(|section| si::Pmt::parse(section).ok())
+        })
+        .collect()
+}
+
+/// Read the NITs carried by the transport stream a frontend is tuned to: that of its own
+/// network, and those of other networks as well if `other_nit` is set.
+fn read_nits(
+    frontend_id: &dvbv5::FrontendId,
+    other_nit: bool,
+    timeout_multiplier: u32,
+) -> Vec<si::Nit> {
+    let mut table_ids = vec![si::NIT_ACTUAL_TABLE_ID];
+    if other_nit {
+        table_ids.push(si::NIT_OTHER_TABLE_ID);
+    }
+    table_ids
+        .into_iter()
+        .filter_map(|table_id| {
+            let filter = SectionFilter::new(frontend_id, si::NIT_PID, table_id).ok()?;
+            let timeout = NIT_TIMEOUT * timeout_multiplier;
+            // There may be the NITs of several other networks, so a whole cycle of them
+            // is read.
+            Some(if table_id == si::NIT_OTHER_TABLE_ID {
+                filter.collect_cycle(timeout)
+            } else {
+                filter.collect(timeout)
+            })
+        })
+        .flat_map(|collector| {
+            collector
+                .tables()
+                .iter()
+                .filter_map(|sections| si::Nit::parse(sections).ok())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Read the BATs carried by the transport stream a frontend is tuned to, a whole cycle of
+/// them as there may be several bouquets.
+fn read_bats(frontend_id: &dvbv5::FrontendId, timeout_multiplier: u32) -> Vec<si::Bat> {
+    match SectionFilter::new(frontend_id, si::SDT_PID, si::BAT_TABLE_ID) {
+        // The BAT must be repeated at least as often as the NIT.
+        Ok(filter) => filter
+            .collect_cycle(NIT_TIMEOUT * timeout_multiplier)
+            .tables()
+            .iter()
+            .filter_map(|sections| si::Bat::parse(sections).ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The processing to apply when writing `ChannelsData`.
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    pub format: OutputFormat,
+    /// Keep only the best received copy of services found on more than one transponder.
+    pub deduplicate: bool,
+    /// The services to write.
+    pub filter: ServiceFilter,
+    /// The region of the receiver, for choosing between region specific channel numbers.
+    pub lcn_region: Option<TargetRegion>,
+    /// Order the services by channel number rather than the order they were found.
+    pub sort_by_lcn: bool,
+    /// The character table to decode names with, whatever the broadcaster selects.
+    pub charset_override: Option<Charset>,
+    /// Keep only the services of this bouquet, numbered as the bouquet numbers them.
+    pub bouquet_id: Option<u16>,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            format: OutputFormat::Dvbv5,
+            deduplicate: false,
+            filter: ServiceFilter::default(),
+            lcn_region: None,
+            sort_by_lcn: false,
+            charset_override: None,
+            bouquet_id: None,
+        }
+    }
+}
+
 /// An opaque structure generated by an execution of `TransmitterFile::scan` for writing
 /// results of a scan to file of channel data on the filestore.
 #[derive(Debug)]
 pub struct ChannelsData {
     file_ptr: dvbv5::FilePtr,
-    frontend_parameters_ptr: dvbv5::FrontendParametersPtr,
+    frontend: Frontend,
+    transponders: Vec<ScannedTransponder>,
+    /// The frequencies, as given in the transmitter files, of the transponders on which no
+    /// lock was achieved, with the number of transponders locked before each.
+    unlocked: Vec<(usize, u32)>,
+    networks: Vec<si::Nit>,
+    bats: Vec<si::Bat>,
+    lnb_power: LnbPower,
 }
 
 impl ChannelsData {
-    /// Create a `ChannelData` instance given a `FilePtr` instance and a `FrontendParametersPtr` instance.
+    /// Create a `ChannelData` instance given a `FilePtr` instance, the `Frontend` used,
+    /// the transponders scanned, those that did not lock, the NITs and BATs received, and
+    /// the power supplied to the LNB.
     fn new(
         file_ptr: dvbv5::FilePtr,
-        frontend_parameters_ptr: dvbv5::FrontendParametersPtr,
+        frontend: Frontend,
+        transponders: Vec<ScannedTransponder>,
+        unlocked: Vec<(usize, u32)>,
+        networks: Vec<si::Nit>,
+        bats: Vec<si::Bat>,
+        lnb_power: LnbPower,
     ) -> ChannelsData {
         ChannelsData {
             file_ptr,
-            frontend_parameters_ptr,
+            frontend,
+            transponders,
+            unlocked,
+            networks,
+            bats,
+            lnb_power,
         }
     }
 
-    /// Write the data in this `ChannelData` instance to a file on the filestore.
-    pub fn write(&self, output_path: &Path) -> bool {
-        if dvbv5::write_file_format(
-            &output_path,
+    /// The transponders tried, whether lock was achieved on them or not, in the order they
+    /// were tried.
+    pub fn tried(&self) -> Vec<TriedTransponder<'_>> {
+        in_scan_order(&self.transponders, &self.unlocked)
+    }
+
+    /// The bouquets received, with channel numbers for a receiver in `region`.
+    pub fn bouquets(&self, region: Option<&TargetRegion>) -> Vec<Bouquet> {
+        self.bats
+            .iter()
+            .map(|bat| Bouquet::from_bat(bat, region))
+            .collect()
+    }
+
+    /// The scanned transponder carrying the entry of the channels data with the given
+    /// index. The frequency of an entry cannot be used to find it as transponders of
+    /// different satellite positions may have the same frequency.
+    fn transponder(&self, index: usize) -> Option<&ScannedTransponder> {
+        self.transponders
+            .iter()
+            .find(|t| t.entries.contains(&index))
+    }
+
+    /// The PMT of the service of a channels file entry, stored from `transponder`.
+    fn pmt<'a>(transponder: &'a ScannedTransponder, entry: &ChannelEntry) -> Option<&'a si::Pmt> {
+        let service_id = entry.triplet()?.service_id;
+        transponder
+            .pmts
+            .iter()
+            .find(|pmt| pmt.program_number == service_id)
+    }
+
+    /// The SDT description of the service of a channels file entry.
+    fn sdt_service(&self, entry: &ChannelEntry) -> Option<&si::SdtService> {
+        let triplet = entry.triplet()?;
+        self.transponders
+            .iter()
+            .filter_map(|t| t.sdt.as_ref())
+            .filter(|sdt| {
+                sdt.transport_stream_id == triplet.transport_id
+                    && (triplet.network_id == 0 || sdt.original_network_id == triplet.network_id)
+            })
+            .flat_map(|sdt| sdt.services.iter())
+            .find(|s| s.service_id == triplet.service_id)
+    }
+
+    /// Annotate each entry of a channels file with the statistics at lock, the satellite
+    /// position and LNB power, the stream id and the tuning variant of the transponder carrying it, with
+    /// the details of the service from the SDT or the ATSC VCT, its ISDB-T layers, its
+    /// components from the PMT, and with its logical channel number for a receiver in the
+    /// region given by `options`.
+    ///
+    /// The names libdvbv5 gives entries are replaced by the SDT names decoded here, as
+    /// libdvbv5 does not handle all the character tables broadcasters use, and for ATSC
+    /// services by the virtual channel names, numbered with the virtual channel numbers.
+    fn annotate(&self, file: &mut ChannelsFile, options: &WriteOptions) {
+        let assignments = self
+            .networks
+            .iter()
+            .flat_map(lcn::assignments)
+            .collect::<Vec<_>>();
+        for (index, entry) in file.entries.iter_mut().enumerate() {
+            let transponder = self.transponder(index);
+            if let Some(statistics) = transponder.and_then(|t| t.statistics) {
+                statistics.annotate(entry);
+            }
+            if let Some(position) = transponder.and_then(|t| t.position.as_ref()) {
+                entry.set_annotation("POSITION", &position.name);
+                entry.set_annotation("DISEQC", &position.to_string());
+            }
+            if self.lnb_power != LnbPower::On && entry.get("LNB").is_some() {
+                entry.set_annotation("LNB_POWER", self.lnb_power.name());
+            }
+            if let Some(stream_id) = transponder.and_then(|t| t.stream_id) {
+                entry.set("STREAM_ID", &stream_id.to_string());
+            }
+            if let Some(variant) = transponder.and_then(|t| t.variant) {
+                entry.set_annotation("TUNING_VARIANT", &variant.to_string());
+            }
+            if let Some(service) = self.sdt_service(entry) {
+                if let Some(service_type) = service.service_type {
+                    entry.set_annotation("SERVICE_TYPE", &service_type.to_string());
+                }
+                let service_name =
+                    text::decode_name(&service.service_name, options.charset_override);
+                if !service_name.is_empty() {
+                    entry.name = service_name;
+                }
+                let provider_name =
+                    text::decode_name(&service.provider_name, options.charset_override);
+                if !provider_name.is_empty() {
+                    entry.set_annotation("PROVIDER", &provider_name);
+                }
+                entry.set_annotation("SCRAMBLED", if service.is_scrambled() { "1" } else { "0" });
+            }
+            if let Some(channel) = transponder
+                .and_then(|t| t.vct.as_ref())
+                .zip(entry.triplet())
+                .and_then(|(vct, t)| vct.channel(t.transport_id, t.service_id))
+            {
+                psip::annotate(entry, channel);
+            }
+            if let Some(triplet) = entry.triplet().filter(|_| isdbt::is_isdbt(entry)) {
+                let one_seg_services = isdbt::partial_reception_services(
+                    &self.networks,
+                    triplet.network_id,
+                    triplet.transport_id,
+                );
+                isdbt::annotate(entry, &one_seg_services);
+            }
+            if let Some(pmt) = transponder.and_then(|t| Self::pmt(t, entry)) {
+                components::annotate(entry, &components::components(pmt));
+            }
+            if let Some(channel) = entry
+                .triplet()
+                .and_then(|t| lcn::logical_channel(&assignments, t, options.lcn_region.as_ref()))
+            {
+                lcn::annotate(entry, channel);
+            }
+        }
+    }
+
+    /// The transport stream id and original network id of each transport stream received
+    /// that the NIT of the network `network_id` does not list: those of another network
+    /// received on a frequency of this one.
+    pub fn foreign_transport_streams(&self, network_id: u16) -> Vec<(u16, u16)> {
+        let network = self.networks.iter().find(|n| n.network_id == network_id);
+        self.transponders
+            .iter()
+            .filter_map(|t| t.sdt.as_ref())
+            .map(|sdt| (sdt.transport_stream_id, sdt.original_network_id))
+            .filter(|(transport_stream_id, original_network_id)| {
+                !network.is_some_and(|n| n.describes(*transport_stream_id, *original_network_id))
+            })
+            .collect()
+    }
+
+    /// Get the channels data as a `ChannelsFile` for processing in Rust.
+    ///
+    /// libdvbv5 gives no access to the data other than writing it, so the data is written
+    /// to a file in a private temporary directory which is then read back.
+    pub fn channels_file(&self) -> Result<ChannelsFile, String> {
+        let directory = TemporaryDirectory::new().map_err(|e| e.to_string())?;
+        let path = directory.path().join("channels.conf");
+        if !dvbv5::write_file_format(
+            &path,
             &self.file_ptr,
-            self.frontend_parameters_ptr.get_current_sys(),
+            self.frontend.current_sys(),
             dvbv5::dvb_file_formats::FILE_DVBV5,
         ) {
-            self.frontend_parameters_ptr.log(
+            return Err(format!("could not write {}.", path.display()));
+        }
+        ChannelsFile::read(&path)
+    }
+
+    /// Get the services of the data in this `ChannelData` instance as they are to be
+    /// written.
+    ///
+    /// Each entry is annotated with the statistics at lock of its transponder, the
+    /// details of its service, and its logical channel number before the processing
+    /// requested by `options` is applied.
+    pub fn services(&self, options: &WriteOptions) -> Result<ChannelsFile, String> {
+        let mut file = self.channels_file()?;
+        self.annotate(&mut file, options);
+        if let Some(bouquet_id) = options.bouquet_id {
+            let bouquet = self
+                .bats
+                .iter()
+                .find(|bat| bat.bouquet_id == bouquet_id)
+                .ok_or_else(|| format!("no BAT of bouquet {} was received.", bouquet_id))?;
+            file = Bouquet::from_bat(bouquet, options.lcn_region.as_ref()).apply(&file);
+        }
+        if !options.filter.is_empty() {
+            file = options.filter.apply(&file);
+        }
+        if options.deduplicate {
+            file = dedup::deduplicate(&file, LockStatistics::from_entry);
+        }
+        if options.sort_by_lcn {
+            lcn::sort(&mut file);
+        }
+        Ok(file)
+    }
+
+    /// Write the data in this `ChannelData` instance to a file on the filestore, as given
+    /// by `services`.
+    pub fn write(&self, output_path: &Path, options: &WriteOptions) -> bool {
+        let result = self
+            .services(options)
+            .and_then(|file| file.write_format(output_path, options.format));
+        match result {
+            Ok(()) => {
+                self.frontend.log(
+                    dvbv5::log_level::LOG_INFO,
+                    &format!(
+                        "\nWrote virtual channels file to: {}",
+                        output_path.display()
+                    ),
+                );
+                true
+            }
+            Err(e) => {
+                self.frontend.log(
+                    dvbv5::log_level::LOG_INFO,
+                    &format!("\nWrite to {} failed: {}", output_path.display(), e),
+                );
+                false
+            }
+        }
+    }
+
+    /// Write a file per bouquet received, named by `bouquet::output_path`, each with only the
+    /// services of the bouquet. Return whether all were written.
+    pub fn write_bouquets(&self, output_path: &Path, options: &WriteOptions) -> bool {
+        if self.bats.is_empty() {
+            self.frontend
+                .log(dvbv5::log_level::LOG_INFO, "\nNo BAT was received.");
+            return false;
+        }
+        let mut all_written = true;
+        for bat in &self.bats {
+            self.frontend.log(
                 dvbv5::log_level::LOG_INFO,
-                &format!(
-                    "\nWrote virtual channels file to: {}",
-                    output_path.display()
-                ),
+                &format!("\nBouquet {}: {}", bat.bouquet_id, bat.bouquet_name),
+            );
+            let options = WriteOptions {
+                bouquet_id: Some(bat.bouquet_id),
+                ..options.clone()
+            };
+            all_written &= self.write(&bouquet::output_path(output_path, bat.bouquet_id), &options);
+        }
+        all_written
+    }
+}
+
+/// Display the current statistics of a frontend on stdout, overwriting the previous display
+/// if stdout is a terminal, and return the frontend status.
+///
+/// # Safety
+///
+/// `frontend_parameters` must point at an open frontend.
+pub unsafe fn show_frontend_status(
+    frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+    n_status_lines: &mut u32,
+) -> u32 {
+    let logger = (*frontend_parameters).logfunc.unwrap();
+    let mut status = dvbv5::fe_status::FE_NONE as u32;
+    let mut stream = stdout();
+    if dvbv5_sys::dvb_fe_get_stats(frontend_parameters) != 0 {
+        logger(
+            dvbv5::log_level::LOG_INFO as i32,
+            CString::new("dvb_fe_get_stats failed.").unwrap().as_ptr(),
+        );
+    } else {
+        if dvbv5_sys::dvb_fe_retrieve_stats(
+            frontend_parameters,
+            dvbv5::dtv_retrievable_properties::DTV_STATUS as u32,
+            &mut status,
+        ) != 0
+        {
+            logger(
+                dvbv5::log_level::LOG_INFO as i32,
+                CString::new("dvb_fe_retrieve_stats of DVT_STATUS failed.")
+                    .unwrap()
+                    .as_ptr(),
             );
-            true
+            status = dvbv5::fe_status::FE_NONE as u32;
         } else {
-            self.frontend_parameters_ptr.log(
-                dvbv5::log_level::LOG_INFO,
-                &format!("\nWrite to {} failed.", output_path.display()),
+            if libc::isatty(stream.as_raw_fd()) != 0 {
+                if *n_status_lines != 0 {
+                    // If there are status lines then return to the beginning of the line go back up a line and clear it.
+                    write!(stream, "\r\x1b[{}A\x1b[J", n_status_lines).unwrap();
+                    *n_status_lines = 0;
+                }
+                if status & dvbv5::fe_status::FE_HAS_LOCK as u32 != 0 {
+                    // Set colour to bold green
+                    write!(stream, "\x1b[1;32m").unwrap();
+                } else {
+                    // Set colour to yellow
+                    // TODO Should remember the pre-Green colour and set that!
+                    write!(stream, "\x1b[33m").unwrap();
+                }
+            }
+            let buffer_size = 1024;
+            let buffer = ['a'; 1024]; // Can't use the "variable" buffer_size here :-(.
+            let mut current_position = buffer.as_ptr() as *mut i8;
+            let mut usable_length = buffer_size;
+            //
+            // TODO dvb_fe_snprintf_stat returns the number of characters "printed", and a negative value on error.
+            //  Should the code be testing that there are no errors?
+            //
+            let mut show = 0;
+            dvbv5_sys::dvb_fe_snprintf_stat(
+                frontend_parameters,
+                dvbv5::dtv_retrievable_properties::DTV_STATUS as u32,
+                0 as *mut i8,
+                0,
+                &mut current_position,
+                &mut usable_length,
+                &mut show,
             );
-            false
+            for i in 0..dvbv5_sys::MAX_DTV_STATS as i32 {
+                show = 1;
+                dvbv5_sys::dvb_fe_snprintf_stat(
+                    frontend_parameters,
+                    dvbv5::dtv_retrievable_properties::DTV_QUALITY as u32,
+                    CString::new("Quality").unwrap().as_ptr() as *mut i8,
+                    i,
+                    &mut current_position,
+                    &mut usable_length,
+                    &mut show,
+                );
+                dvbv5_sys::dvb_fe_snprintf_stat(
+                    frontend_parameters,
+                    dvbv5::dtv_retrievable_properties::DTV_STAT_SIGNAL_STRENGTH as u32,
+                    CString::new("Signal").unwrap().as_ptr() as *mut i8,
+                    i,
+                    &mut current_position,
+                    &mut usable_length,
+                    &mut show,
+                );
+                dvbv5_sys::dvb_fe_snprintf_stat(
+                    frontend_parameters,
+                    dvbv5::dtv_retrievable_properties::DTV_STAT_CNR as u32,
+                    CString::new("C/N").unwrap().as_ptr() as *mut i8,
+                    i,
+                    &mut current_position,
+                    &mut usable_length,
+                    &mut show,
+                );
+                dvbv5_sys::dvb_fe_snprintf_stat(
+                    frontend_parameters,
+                    dvbv5::dtv_retrievable_properties::DTV_STAT_ERROR_BLOCK_COUNT as u32,
+                    CString::new("UCB").unwrap().as_ptr() as *mut i8,
+                    i,
+                    &mut current_position,
+                    &mut usable_length,
+                    &mut show,
+                );
+                dvbv5_sys::dvb_fe_snprintf_stat(
+                    frontend_parameters,
+                    dvbv5::dtv_retrievable_properties::DTV_BER as u32,
+                    CString::new("postBER").unwrap().as_ptr() as *mut i8,
+                    i,
+                    &mut current_position,
+                    &mut usable_length,
+                    &mut show,
+                );
+                dvbv5_sys::dvb_fe_snprintf_stat(
+                    frontend_parameters,
+                    dvbv5::dtv_retrievable_properties::DTV_PRE_BER as u32,
+                    CString::new("preBER").unwrap().as_ptr() as *mut i8,
+                    i,
+                    &mut current_position,
+                    &mut usable_length,
+                    &mut show,
+                );
+                dvbv5_sys::dvb_fe_snprintf_stat(
+                    frontend_parameters,
+                    dvbv5::dtv_retrievable_properties::DTV_PER as u32,
+                    CString::new("PER").unwrap().as_ptr() as *mut i8,
+                    i,
+                    &mut current_position,
+                    &mut usable_length,
+                    &mut show,
+                );
+                if current_position != buffer.as_ptr() as *mut i8 {
+                    let line = CStr::from_ptr(buffer.as_ptr() as *mut i8).to_str().unwrap();
+                    let layer =
+                        isdbt::layer_name((*frontend_parameters).current_sys as u32, i as usize);
+                    let line = match layer {
+                        Some(layer) => format!("Layer {}: {}", layer, line),
+                        None => line.to_string(),
+                    };
+                    if *n_status_lines != 0 {
+                        writeln!(stream, "\t{}", line).unwrap();
+                    } else {
+                        writeln!(stream, "{}", line).unwrap();
+                    }
+                    *n_status_lines += 1;
+                    current_position = buffer.as_ptr() as *mut i8;
+                    usable_length = buffer_size;
+                }
+            }
+            stream.flush().unwrap();
+        }
+    }
+    if libc::isatty(stream.as_raw_fd()) != 0 {
+        //  Set default foreground colour without bold.
+        write!(stream, "\x1b[22;39m").unwrap();
+        stream.flush().unwrap();
+    }
+    status
+}
+
+/// The options of a scan, see `TransmitterData::scan`.
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    /// Use alternate table IDs for NIT and other tables.
+    pub other_nit: bool,
+    /// Increases the timeout for each table reception.
+    pub timeout_multiplier: u32,
+    /// Use the frontend parameters obtained from the device driver (such as modulation,
+    /// FEC, etc).
+    pub get_detected: bool,
+    /// Use the parameters obtained from the MPEG-TS NIT table to add newly detected
+    /// transponders.
+    pub get_nit: bool,
+    /// Do not scan newly found frequencies for channels.
+    pub dont_add_new_frequencies: bool,
+    /// The libdvbv5 level of verbosity.
+    pub verbose: u32,
+    /// Use DVBv3 calls rather than DVBv5 ones.
+    pub use_legacy_call: bool,
+    /// Collect the BATs of bouquets for writing channels files by bouquet.
+    pub get_bat: bool,
+    /// The LNB for satellite transponders, the universal LNB if not given.
+    pub lnb: Option<Lnb>,
+    pub lnb_power: LnbPower,
+    /// The time to wait between DiSEqC messages beyond the minimum, for slow switches.
+    pub diseqc_wait: Duration,
+    /// The motorised dish, if satellite positions have positioner positions.
+    pub positioner: Positioner,
+    /// The user band of the single cable router satellite transponders are received
+    /// through, if there is one.
+    pub user_band: Option<UserBand>,
+    /// Scan each PLP of DVB-T2 transponders and each input stream of DVB-S2 ones as a
+    /// transponder of its own: those signalled in the NIT and those of `stream_ids`.
+    pub multistream: bool,
+    /// Stream ids to scan on every multistream capable transponder.
+    pub stream_ids: Vec<u32>,
+    /// The transponders announced in NITs to scan.
+    pub network_restriction: NetworkRestriction,
+    /// The variations of tuning parameters to try on transponders that do not lock.
+    pub retry: RetryStrategy,
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            other_nit: false,
+            timeout_multiplier: 1,
+            get_detected: true,
+            get_nit: true,
+            dont_add_new_frequencies: false,
+            verbose: 0,
+            use_legacy_call: false,
+            get_bat: false,
+            lnb: None,
+            lnb_power: LnbPower::On,
+            diseqc_wait: Duration::from_millis(0),
+            positioner: Positioner::default(),
+            user_band: None,
+            multistream: false,
+            stream_ids: Vec::new(),
+            network_restriction: NetworkRestriction::default(),
+            retry: RetryStrategy::default(),
+        }
+    }
+}
+
+/// Get the value of a property of a libdvbv5 entry.
+///
+/// # Safety
+///
+/// `entry` must point at a valid entry.
+unsafe fn entry_property(entry: *mut dvbv5_sys::dvb_entry, command: u32) -> Option<u32> {
+    let mut value = 0;
+    if dvbv5_sys::dvb_retrieve_entry_prop(entry, command, &mut value) == 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+/// Set the value of a property of a libdvbv5 entry.
+///
+/// # Safety
+///
+/// `entry` must point at a valid entry.
+unsafe fn set_entry_property(entry: *mut dvbv5_sys::dvb_entry, command: u32, value: u32) {
+    dvbv5_sys::dvb_store_entry_prop(entry, command, value);
+}
+
+/// The properties of a libdvbv5 entry, saved to put them back exactly as they were,
+/// those it did not have included.
+struct SavedProperties {
+    props: Vec<dvbv5_sys::dtv_property>,
+}
+
+impl SavedProperties {
+    /// Save the properties of an entry.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point at a valid entry.
+    unsafe fn new(entry: *const dvbv5_sys::dvb_entry) -> SavedProperties {
+        let entry = &*entry;
+        SavedProperties {
+            props: entry.props[..entry.n_props as usize].to_vec(),
+        }
+    }
+
+    /// Give an entry the saved properties, and no others.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point at a valid entry.
+    unsafe fn restore(&self, entry: *mut dvbv5_sys::dvb_entry) {
+        let entry = &mut *entry;
+        entry.props[..self.props.len()].copy_from_slice(&self.props);
+        entry.n_props = self.props.len() as u32;
+    }
+}
+
+/// How a satellite transponder is tuned when libdvbv5 has no LNB for it: the entry is
+/// given the intermediate frequency, and entries tuned are given back the transponder
+/// frequency and the name of the LNB, which libdvbv5 only records for its own.
+struct IntermediateTuning {
+    conversion: Conversion,
+    lnb: CString,
+}
+
+impl IntermediateTuning {
+    /// Give an entry tuned at the intermediate frequency its transponder frequency.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point at a valid entry.
+    unsafe fn restore(&self, entry: *mut dvbv5_sys::dvb_entry) {
+        if let Some(frequency) = entry_property(entry, dvbv5_sys::DTV_FREQUENCY) {
+            set_entry_property(
+                entry,
+                dvbv5_sys::DTV_FREQUENCY,
+                self.conversion.transponder_frequency(frequency),
+            );
+        }
+    }
+
+    /// Give the entries of a libdvbv5 file from the one at index `first` on, stored from
+    /// the transponder, its frequency and the name of the LNB.
+    ///
+    /// # Safety
+    ///
+    /// `file` must be null or point at a valid file.
+    unsafe fn restore_entries(&self, file: *mut dvbv5_sys::dvb_file, first: usize) {
+        if !file.is_null() {
+            let mut entry = (*file).first_entry;
+            let mut index = 0;
+            while !entry.is_null() {
+                if index >= first {
+                    self.restore(entry);
+                    if (*entry).lnb.is_null() {
+                        (*entry).lnb = libc::strdup(self.lnb.as_ptr());
+                    }
+                }
+                index += 1;
+                entry = (*entry).next;
+            }
+        }
+    }
+}
+
+/// A libdvbv5 frequency of a delivery system in kHz, as the NIT gives frequencies. libdvbv5
+/// gives satellite frequencies in kHz and all others in Hz.
+fn nit_frequency(delivery_system: u32, frequency: u32) -> u32 {
+    if satellite::is_satellite(delivery_system) {
+        frequency
+    } else {
+        frequency / 1000
+    }
+}
+
+/// The frequency of a libdvbv5 entry in kHz, as the NIT gives frequencies.
+///
+/// # Safety
+///
+/// `entry` must point at a valid entry.
+unsafe fn entry_frequency(entry: *mut dvbv5_sys::dvb_entry) -> Option<u32> {
+    let frequency = entry_property(entry, dvbv5_sys::DTV_FREQUENCY)?;
+    let delivery_system = entry_property(entry, dvbv5_sys::DTV_DELIVERY_SYSTEM).unwrap_or(0);
+    Some(nit_frequency(delivery_system, frequency))
+}
+
+/// The polarization of a libdvbv5 entry, if it is of a satellite transponder.
+///
+/// # Safety
+///
+/// `entry` must point at a valid entry.
+unsafe fn entry_polarization(entry: *mut dvbv5_sys::dvb_entry) -> Option<u32> {
+    let delivery_system = entry_property(entry, dvbv5_sys::DTV_DELIVERY_SYSTEM).unwrap_or(0);
+    entry_property(entry, dvbv5_sys::DTV_POLARIZATION)
+        .filter(|_| satellite::is_satellite(delivery_system))
+}
+
+/// Free a libdvbv5 entry that is no longer in a file, as `dvb_file_free` frees those in
+/// one.
+///
+/// # Safety
+///
+/// `entry` must point at a valid entry allocated by libdvbv5 or `insert_stream`, which is
+/// not used after.
+unsafe fn free_entry(entry: *mut dvbv5_sys::dvb_entry) {
+    for pointer in [
+        (*entry).channel as *mut libc::c_void,
+        (*entry).vchannel as *mut libc::c_void,
+        (*entry).location as *mut libc::c_void,
+        (*entry).lnb as *mut libc::c_void,
+        (*entry).video_pid as *mut libc::c_void,
+        (*entry).audio_pid as *mut libc::c_void,
+        (*entry).other_el_pid as *mut libc::c_void,
+    ] {
+        libc::free(pointer);
+    }
+    libc::free(entry as *mut libc::c_void);
+}
+
+/// The last entry of a libdvbv5 file.
+///
+/// # Safety
+///
+/// `file` must point at a valid file with at least one entry.
+unsafe fn last_entry(file: *mut dvbv5_sys::dvb_file) -> *mut dvbv5_sys::dvb_entry {
+    let mut entry = (*file).first_entry;
+    while !(*entry).next.is_null() {
+        entry = (*entry).next;
+    }
+    entry
+}
+
+/// Whether a libdvbv5 file has an entry for a stream of the transponder of an entry.
+///
+/// # Safety
+///
+/// `file` and `entry` must point at a valid file and entry.
+unsafe fn has_stream(
+    file: *mut dvbv5_sys::dvb_file,
+    entry: *mut dvbv5_sys::dvb_entry,
+    stream_id: u32,
+) -> bool {
+    let commands = [dvbv5_sys::DTV_FREQUENCY, dvbv5_sys::DTV_POLARIZATION];
+    let mut other = (*file).first_entry;
+    while !other.is_null() {
+        if commands
+            .iter()
+            .all(|c| entry_property(other, *c) == entry_property(entry, *c))
+            && entry_property(other, dvbv5_sys::DTV_STREAM_ID) == Some(stream_id)
+        {
+            return true;
         }
+        other = (*other).next;
+    }
+    false
+}
+
+/// Insert a copy of the tuning parameters of an entry, for another stream id, after it.
+///
+/// # Safety
+///
+/// `entry` must point at a valid entry of a file, which frees the copy.
+unsafe fn insert_stream(entry: *mut dvbv5_sys::dvb_entry, stream_id: u32) {
+    let copy =
+        libc::calloc(1, std::mem::size_of::<dvbv5_sys::dvb_entry>()) as *mut dvbv5_sys::dvb_entry;
+    if copy.is_null() {
+        return;
+    }
+    (*copy).props = (*entry).props;
+    (*copy).n_props = (*entry).n_props;
+    (*copy).sat_number = (*entry).sat_number;
+    (*copy).freq_bpf = (*entry).freq_bpf;
+    (*copy).diseqc_wait = (*entry).diseqc_wait;
+    if !(*entry).lnb.is_null() {
+        (*copy).lnb = libc::strdup((*entry).lnb);
     }
+    set_entry_property(copy, dvbv5_sys::DTV_STREAM_ID, stream_id);
+    (*copy).next = (*entry).next;
+    (*entry).next = copy;
 }
 
-/// A wrapper around a `dvbv5::FilePtr` for processing the file of transmitter data.
+/// The number of entries of a libdvbv5 file, which may be null.
+///
+/// # Safety
 ///
-/// The file must be a transmitter channel/transponder data
+/// `file` must be null or point at a valid file.
+unsafe fn entry_count(file: *mut dvbv5_sys::dvb_file) -> usize {
+    let mut count = 0;
+    if !file.is_null() {
+        let mut entry = (*file).first_entry;
+        while !entry.is_null() {
+            count += 1;
+            entry = (*entry).next;
+        }
+    }
+    count
+}
+
+/// A wrapper around a `*mut dvbv5_sys::dvb_v5_descriptors`, the tables read by a scan of
+/// a transponder, providing automated RAII.
+struct ScanHandler {
+    ptr: *mut dvbv5_sys::dvb_v5_descriptors,
+}
+
+impl Drop for ScanHandler {
+    fn drop(&mut self) {
+        unsafe {
+            dvbv5_sys::dvb_scan_free_handler_table(self.ptr);
+        }
+    }
+}
+
+/// A transmitter file read by libdvbv5, with the satellite position it is received from.
+#[derive(Debug)]
+struct TransmitterFile {
+    ptr: *mut dvbv5_sys::dvb_file,
+    /// The owner of `ptr`, freeing it when dropped.
+    _file: dvbv5::FilePtr,
+    position: Option<SatellitePosition>,
+}
+
+/// A wrapper around the files of transmitter data to be scanned.
+///
+/// The files must be transmitter channel/transponder data
 /// (e.g. /usr/share/dvb/dvb-t/uk-CrystalPalace). This abstraction allows for a scan to be
 /// undertaken using a frontend of a DVB device.
 #[derive(Debug)]
 pub struct TransmitterData {
-    ptr: dvbv5::FilePtr,
+    files: Vec<TransmitterFile>,
 }
 
 impl TransmitterData {
     /// Open the transmitter file.
     pub fn new(transmitter_file: &Path) -> Result<TransmitterData, ()> {
-        match dvbv5::FilePtr::new(transmitter_file, None, None) {
-            Ok(ptr) => Ok(TransmitterData { ptr }),
-            Err(_) => Err(()),
+        TransmitterData::open(transmitter_file, None)
+    }
+
+    /// Open the transmitter file of the satellites at a position, selected by the switch
+    /// settings of `position`.
+    pub fn at_position(
+        transmitter_file: &Path,
+        position: SatellitePosition,
+    ) -> Result<TransmitterData, ()> {
+        TransmitterData::open(transmitter_file, Some(position))
+    }
+
+    fn open(
+        transmitter_file: &Path,
+        position: Option<SatellitePosition>,
+    ) -> Result<TransmitterData, ()> {
+        let path = CString::new(transmitter_file.to_string_lossy().as_bytes()).map_err(|_| ())?;
+        let ptr = unsafe {
+            dvbv5_sys::dvb_read_file_format(
+                path.as_ptr(),
+                dvbv5_sys::fe_delivery_system::SYS_UNDEFINED as u32,
+                dvbv5_sys::dvb_file_formats::FILE_DVBV5,
+            )
+        };
+        let file = dvbv5::FilePtr::new_from_dvb_file_ptr(ptr)?;
+        Ok(TransmitterData {
+            files: vec![TransmitterFile {
+                ptr,
+                _file: file,
+                position,
+            }],
+        })
+    }
+
+    /// The transmitter data of the cable network `network_id`, from the NIT received on its
+    /// home transponder, selected by `options.other_nit` as for a scan.
+    pub fn cable_network(
+        frontend_id: &dvbv5::FrontendId,
+        home: &CableTransponder,
+        network_id: u16,
+        options: &ScanOptions,
+    ) -> Result<TransmitterData, String> {
+        let directory = TemporaryDirectory::new().map_err(|e| e.to_string())?;
+        let path = directory.path().join("cable.conf");
+        cable::transmitter_file(std::slice::from_ref(home)).write(&path)?;
+        let channels = Channel::read_all(&path)?;
+        let channel = channels
+            .first()
+            .ok_or_else(|| "the home transponder could not be read back.".to_string())?;
+        let frontend = Frontend::open(frontend_id, options.verbose)
+            .map_err(|_| "could not open the frontend.".to_string())?;
+        frontend.log(
+            dvbv5::log_level::LOG_INFO,
+            &format!("Reading the NIT on the home transponder {}", home.frequency),
+        );
+        frontend.tune(channel)?;
+        if !frontend.wait_for_lock(&mut 0) {
+            return Err("no lock on the home transponder.".to_string());
+        }
+        let nits = read_nits(frontend_id, options.other_nit, options.timeout_multiplier);
+        drop(frontend);
+        if !nits.iter().any(|nit| nit.network_id == network_id) {
+            return Err(format!(
+                "no NIT of network {} was received on the home transponder.",
+                network_id
+            ));
         }
+        let transponders = cable::network_transponders(home, &nits, network_id);
+        cable::transmitter_file(&transponders).write(&path)?;
+        TransmitterData::new(&path)
+            .map_err(|_| "the network's transponders could not be read back.".to_string())
+    }
+
+    /// Add the transmitter files of `other`, to be scanned after those of this.
+    pub fn extend(&mut self, other: TransmitterData) {
+        self.files.extend(other.files);
     }
 
     /// Write scan status data to stderr. If stderr is a terminal then use the codes to
@@ -111,160 +1138,24 @@ impl TransmitterData {
         frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
     ) -> i32 {
         unsafe {
-            let logger = (*frontend_parameters).logfunc.unwrap();
+            if LNB_POWER_OFF.with(|p| *p.borrow()) {
+                dvbv5_sys::dvb_fe_sec_voltage(frontend_parameters, 0, 0);
+            }
             let mut status = dvbv5::fe_status::FE_NONE as u32;
             let mut n_status_lines = 0 as u32;
-            let mut stream = stdout();
             for _ in 0..20 {
                 if (*frontend_parameters).abort != 0 {
                     return 0;
                 }
-                if dvbv5_sys::dvb_fe_get_stats(frontend_parameters) != 0 {
-                    logger(
-                        dvbv5::log_level::LOG_INFO as i32,
-                        CString::new("dvb_fe_get_stats failed.").unwrap().as_ptr(),
-                    );
-                } else {
-                    if dvbv5_sys::dvb_fe_retrieve_stats(
-                        frontend_parameters,
-                        dvbv5::dtv_retrievable_properties::DTV_STATUS as u32,
-                        &mut status,
-                    ) != 0
-                    {
-                        logger(
-                            dvbv5::log_level::LOG_INFO as i32,
-                            CString::new("dvb_fe_retrieve_stats of DVT_STATUS failed.")
-                                .unwrap()
-                                .as_ptr(),
-                        );
-                        status = dvbv5::fe_status::FE_NONE as u32;
-                    } else {
-                        if libc::isatty(stream.as_raw_fd()) != 0 {
-                            if n_status_lines != 0 {
-                                // If there are status lines then return to the beginning of the line go back up a line and clear it.
-                                write!(stream, "\r\x1b[{}A\x1b[J", n_status_lines).unwrap();
-                                n_status_lines = 0;
-                            }
-                            if status & dvbv5::fe_status::FE_HAS_LOCK as u32 != 0 {
-                                // Set colour to bold green
-                                write!(stream, "\x1b[1;32m").unwrap();
-                            } else {
-                                // Set colour to yellow
-                                // TODO Should remember the pre-Green colour and set that!
-                                write!(stream, "\x1b[33m").unwrap();
-                            }
-                        }
-                        let buffer_size = 1024;
-                        let buffer = ['a'; 1024]; // Can't use the "variable" buffer_size here :-(.
-                        let mut current_position = buffer.as_ptr() as *mut i8;
-                        let mut usable_length = buffer_size;
-                        //
-                        // TODO dvb_fe_snprintf_stat returns the number of characters "printed", and a negative value on error.
-                        //  Should the code be testing that there are no errors?
-                        //
-                        let mut show = 0;
-                        dvbv5_sys::dvb_fe_snprintf_stat(
-                            frontend_parameters,
-                            dvbv5::dtv_retrievable_properties::DTV_STATUS as u32,
-                            0 as *mut i8,
-                            0,
-                            &mut current_position,
-                            &mut usable_length,
-                            &mut show,
-                        );
-                        for i in 0..dvbv5_sys::MAX_DTV_STATS as i32 {
-                            show = 1;
-                            dvbv5_sys::dvb_fe_snprintf_stat(
-                                frontend_parameters,
-                                dvbv5::dtv_retrievable_properties::DTV_QUALITY as u32,
-                                CString::new("Quality").unwrap().as_ptr() as *mut i8,
-                                i,
-                                &mut current_position,
-                                &mut usable_length,
-                                &mut show,
-                            );
-                            dvbv5_sys::dvb_fe_snprintf_stat(
-                                frontend_parameters,
-                                dvbv5::dtv_retrievable_properties::DTV_STAT_SIGNAL_STRENGTH as u32,
-                                CString::new("Signal").unwrap().as_ptr() as *mut i8,
-                                i,
-                                &mut current_position,
-                                &mut usable_length,
-                                &mut show,
-                            );
-                            dvbv5_sys::dvb_fe_snprintf_stat(
-                                frontend_parameters,
-                                dvbv5::dtv_retrievable_properties::DTV_STAT_CNR as u32,
-                                CString::new("C/N").unwrap().as_ptr() as *mut i8,
-                                i,
-                                &mut current_position,
-                                &mut usable_length,
-                                &mut show,
-                            );
-                            dvbv5_sys::dvb_fe_snprintf_stat(
-                                frontend_parameters,
-                                dvbv5::dtv_retrievable_properties::DTV_STAT_ERROR_BLOCK_COUNT
-                                    as u32,
-                                CString::new("UCB").unwrap().as_ptr() as *mut i8,
-                                i,
-                                &mut current_position,
-                                &mut usable_length,
-                                &mut show,
-                            );
-                            dvbv5_sys::dvb_fe_snprintf_stat(
-                                frontend_parameters,
-                                dvbv5::dtv_retrievable_properties::DTV_BER as u32,
-                                CString::new("postBER").unwrap().as_ptr() as *mut i8,
-                                i,
-                                &mut current_position,
-                                &mut usable_length,
-                                &mut show,
-                            );
-                            dvbv5_sys::dvb_fe_snprintf_stat(
-                                frontend_parameters,
-                                dvbv5::dtv_retrievable_properties::DTV_PRE_BER as u32,
-                                CString::new("preBER").unwrap().as_ptr() as *mut i8,
-                                i,
-                                &mut current_position,
-                                &mut usable_length,
-                                &mut show,
-                            );
-                            dvbv5_sys::dvb_fe_snprintf_stat(
-                                frontend_parameters,
-                                dvbv5::dtv_retrievable_properties::DTV_PER as u32,
-                                CString::new("PER").unwrap().as_ptr() as *mut i8,
-                                i,
-                                &mut current_position,
-                                &mut usable_length,
-                                &mut show,
-                            );
-                            if current_position != buffer.as_ptr() as *mut i8 {
-                                let line =
-                                    CStr::from_ptr(buffer.as_ptr() as *mut i8).to_str().unwrap();
-                                if n_status_lines != 0 {
-                                    write!(stream, "\t{}\n", line).unwrap();
-                                } else {
-                                    write!(stream, "{}\n", line).unwrap();
-                                }
-                                n_status_lines += 1;
-                                current_position = buffer.as_ptr() as *mut i8;
-                                usable_length = buffer_size;
-                            }
-                        }
-                        stream.flush().unwrap();
-                    }
-                }
+                status = show_frontend_status(frontend_parameters, &mut n_status_lines);
                 if status & dvbv5::fe_status::FE_HAS_LOCK as u32 != 0 {
                     break;
                 }
                 sleep(Duration::from_millis(400));
             }
-            if libc::isatty(stream.as_raw_fd()) != 0 {
-                //  Set default foreground colour without bold.
-                write!(stream, "\x1b[22;39m").unwrap();
-                stream.flush().unwrap();
-            }
             if status & dvbv5::fe_status::FE_HAS_LOCK as u32 != 0 {
+                let statistics = LockStatistics::read(frontend_parameters);
+                LOCK_STATISTICS.with(|s| *s.borrow_mut() = Some(statistics));
                 0
             } else {
                 -1
@@ -272,120 +1163,562 @@ impl TransmitterData {
         }
     }
 
-    /// Perform a scan on the channel/transponders listed in this transmitter file.
+    /// Set up the frontend for a satellite transponder: the LNB, its power and the switches
+    /// selecting the satellite position. libdvbv5 is given no satellite number so that it
+    /// sends no DiSEqC messages of its own.
+    ///
+    /// libdvbv5 has no LNB for an LNB given by its oscillator frequencies or for a single
+    /// cable router, through which the committed port selects the router's satellite
+    /// position. The entry is then given the intermediate frequency, and how to restore it
+    /// is returned.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point at a valid entry.
+    unsafe fn prepare_satellite(
+        frontend: &Frontend,
+        entry: *mut dvbv5_sys::dvb_entry,
+        position: Option<&SatellitePosition>,
+        options: &ScanOptions,
+    ) -> Result<Option<IntermediateTuning>, String> {
+        let delivery_system = entry_property(entry, dvbv5_sys::DTV_DELIVERY_SYSTEM).unwrap_or(0);
+        if !satellite::is_satellite(delivery_system) {
+            frontend.set_lnb(None);
+            return Ok(None);
+        }
+        let universal;
+        let lnb = match &options.lnb {
+            Some(lnb) => lnb,
+            None => {
+                universal = Lnb::from_name("universal")?;
+                &universal
+            }
+        };
+        let parameters = frontend.as_ptr();
+        (*parameters).sat_number = -1;
+        (*parameters).diseqc_wait = options.diseqc_wait.as_millis() as u32;
+        dvbv5_sys::dvb_fe_lnb_high_voltage(
+            parameters,
+            (options.lnb_power == LnbPower::High) as i32,
+        );
+        let frequency = entry_property(entry, dvbv5_sys::DTV_FREQUENCY).unwrap_or(0);
+        let polarization = entry_property(entry, dvbv5_sys::DTV_POLARIZATION).unwrap_or(0);
+        if let Some(user_band) = &options.user_band {
+            let tuning_frequency = user_band.select(
+                parameters,
+                lnb,
+                frequency,
+                position.and_then(|p| p.committed).unwrap_or(0),
+                satellite::is_horizontal(polarization),
+            )?;
+            frontend.set_lnb(None);
+            frontend.set_voltage_and_tone(false, false);
+            set_entry_property(entry, dvbv5_sys::DTV_FREQUENCY, tuning_frequency);
+            return Ok(Some(IntermediateTuning {
+                conversion: UserBand::conversion(frequency, tuning_frequency),
+                lnb: CString::new(lnb.alias.as_str()).unwrap_or_default(),
+            }));
+        }
+        let horizontal = satellite::is_horizontal(polarization);
+        let high_band = lnb.is_high_band(frequency);
+        if let Some(position) = position {
+            position.select(parameters, horizontal, high_band, options.diseqc_wait)?;
+        }
+        if frontend.set_lnb(Some(lnb)) {
+            return Ok(None);
+        }
+        frontend.set_voltage_and_tone(horizontal, high_band);
+        let conversion = lnb.conversion(frequency);
+        set_entry_property(
+            entry,
+            dvbv5_sys::DTV_FREQUENCY,
+            conversion.intermediate_frequency(frequency),
+        );
+        Ok(Some(IntermediateTuning {
+            conversion,
+            lnb: CString::new(lnb.alias.as_str()).unwrap_or_default(),
+        }))
+    }
+
+    /// The stream id of the PLP or input stream a frontend is receiving, as the driver
+    /// reports it, or as the entry tuned to gives it.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point at a valid entry.
+    unsafe fn received_stream_id(
+        frontend: &Frontend,
+        entry: *mut dvbv5_sys::dvb_entry,
+    ) -> Option<u32> {
+        let mut stream_id = dvbv5_sys::NO_STREAM_ID_FILTER as u32;
+        if dvbv5_sys::dvb_fe_get_parms(frontend.as_ptr()) == 0 {
+            dvbv5_sys::dvb_fe_retrieve_parm(
+                frontend.as_ptr(),
+                dvbv5_sys::DTV_STREAM_ID,
+                &mut stream_id,
+            );
+        }
+        if stream_id == dvbv5_sys::NO_STREAM_ID_FILTER as u32 {
+            entry_property(entry, dvbv5_sys::DTV_STREAM_ID)
+                .filter(|id| *id != dvbv5_sys::NO_STREAM_ID_FILTER as u32)
+        } else {
+            Some(stream_id)
+        }
+    }
+
+    /// Add an entry after the one just scanned for each other stream of its transponder,
+    /// signalled in a NIT or given in the options, that has no entry yet. The Linux DVB
+    /// API does not report the streams signalled in the L1 signalling, only the one being
+    /// received.
+    ///
+    /// # Safety
+    ///
+    /// `file` and `entry` must point at a valid file and entry of it.
+    unsafe fn add_streams(
+        file: *mut dvbv5_sys::dvb_file,
+        entry: *mut dvbv5_sys::dvb_entry,
+        networks: &[si::Nit],
+        options: &ScanOptions,
+    ) {
+        use dvbv5_sys::fe_delivery_system::*;
+        let delivery_system = entry_property(entry, dvbv5_sys::DTV_DELIVERY_SYSTEM).unwrap_or(0);
+        if delivery_system != SYS_DVBT2 as u32 && delivery_system != SYS_DVBS2 as u32 {
+            return;
+        }
+        let frequency = entry_frequency(entry).unwrap_or(0);
+        let polarization = entry_polarization(entry);
+        let mut stream_ids = options.stream_ids.clone();
+        for nit in networks {
+            stream_ids.extend(nit.stream_ids(frequency, polarization));
+        }
+        stream_ids.sort_unstable();
+        stream_ids.dedup();
+        // Inserting each after the entry, in reverse order, leaves them in order.
+        for stream_id in stream_ids.into_iter().rev() {
+            if !has_stream(file, entry, stream_id) {
+                insert_stream(entry, stream_id);
+            }
+        }
+    }
+
+    /// Scan a transponder, returning the tables read if lock was achieved.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point at a valid entry.
+    unsafe fn scan_transponder(
+        frontend: &Frontend,
+        entry: *mut dvbv5_sys::dvb_entry,
+        dmx_fd: &Demux,
+        options: &ScanOptions,
+    ) -> Option<ScanHandler> {
+        let ptr = dvbv5_sys::dvb_scan_transponder(
+            frontend.as_ptr(),
+            entry,
+            dmx_fd.fd(),
+            Some(Self::frontend_check),
+            std::ptr::null_mut(),
+            options.other_nit as u32,
+            options.timeout_multiplier,
+        );
+        if ptr.is_null() {
+            None
+        } else {
+            Some(ScanHandler { ptr })
+        }
+    }
+
+    /// Scan a transponder that did not lock with each variant of its tuning parameters of
+    /// `options.retry` in turn until one locks, returning the variant and the tables read.
+    /// The entry is left with the parameters of the variant that locked, so that they are
+    /// the ones written, or as it was if none did.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point at a valid entry.
+    unsafe fn retry(
+        frontend: &Frontend,
+        entry: *mut dvbv5_sys::dvb_entry,
+        dmx_fd: &Demux,
+        options: &ScanOptions,
+    ) -> Option<(Variant, ScanHandler)> {
+        let frequency = entry_property(entry, dvbv5_sys::DTV_FREQUENCY)?;
+        let bandwidth = entry_property(entry, dvbv5_sys::DTV_BANDWIDTH_HZ);
+        let inversion = entry_property(entry, dvbv5_sys::DTV_INVERSION);
+        let saved = SavedProperties::new(entry);
+        let delivery_system = entry_property(entry, dvbv5_sys::DTV_DELIVERY_SYSTEM).unwrap_or(0);
+        // libdvbv5 gives satellite frequencies in kHz and all others in Hz.
+        let unit = if satellite::is_satellite(delivery_system) {
+            1
+        } else {
+            1000
+        };
+        let set = |variant: &Variant| {
+            let offset = i64::from(variant.offset) * unit;
+            set_entry_property(
+                entry,
+                dvbv5_sys::DTV_FREQUENCY,
+                (i64::from(frequency) + offset) as u32,
+            );
+            if let Some(bandwidth) = variant.bandwidth.or(bandwidth) {
+                set_entry_property(entry, dvbv5_sys::DTV_BANDWIDTH_HZ, bandwidth);
+            }
+            if let Some(inversion) = variant.inversion.or(inversion) {
+                set_entry_property(entry, dvbv5_sys::DTV_INVERSION, inversion);
+            }
+        };
+        for variant in options.retry.variants(bandwidth, inversion) {
+            if frontend.is_aborted() {
+                break;
+            }
+            frontend.log(
+                dvbv5::log_level::LOG_INFO,
+                &format!("Retrying with {}", variant),
+            );
+            set(&variant);
+            if let Some(scan_handler) = Self::scan_transponder(frontend, entry, dmx_fd, options) {
+                return Some((variant, scan_handler));
+            }
+        }
+        saved.restore(entry);
+        None
+    }
+
+    /// Add the transponders announced in the NIT received by a scan of an entry to the end
+    /// of the transmitter file, for scanning in turn, other than those `restriction`
+    /// excludes at `depth`, the depth in the NITs at which they were announced. Returns the
+    /// number of transponders added.
+    ///
+    /// # Safety
+    ///
+    /// `file` and `entry` must point at a valid file and entry of it.
+    unsafe fn add_transponders(
+        frontend: &Frontend,
+        scan_handler: &ScanHandler,
+        file: *mut dvbv5_sys::dvb_file,
+        entry: *mut dvbv5_sys::dvb_entry,
+        depth: u32,
+        networks: &[si::Nit],
+        restriction: &NetworkRestriction,
+    ) -> usize {
+        let mut added_count = 0;
+        let mut last = last_entry(file);
+        dvbv5_sys::dvb_add_scaned_transponders(
+            frontend.as_ptr(),
+            scan_handler.ptr,
+            (*file).first_entry,
+            entry,
+        );
+        // libdvbv5 appends the transponders to the end of the file.
+        while !(*last).next.is_null() {
+            let added = (*last).next;
+            let frequency = entry_frequency(added).unwrap_or(0);
+            if restriction.follows(networks, frequency, entry_polarization(added), depth) {
+                added_count += 1;
+                last = added;
+            } else {
+                frontend.log(
+                    dvbv5::log_level::LOG_INFO,
+                    &format!("Not following frequency {}", frequency),
+                );
+                (*last).next = (*added).next;
+                free_entry(added);
+            }
+        }
+        added_count
+    }
+
+    /// Send a motorised dish to a position and wait for it to get there, returning the
+    /// angle it turned to if that is known. `angle` is the angle it was at, if known.
+    fn move_dish(
+        frontend: &Frontend,
+        dish: &DishPosition,
+        angle: Option<f64>,
+        options: &ScanOptions,
+    ) -> Result<Option<f64>, String> {
+        let (new_angle, message) = options.positioner.goto(dish)?;
+        unsafe { Positioner::send(frontend.as_ptr(), &message)? };
+        let slew_time = options.positioner.slew_time(angle, new_angle);
+        frontend.log(
+            dvbv5::log_level::LOG_INFO,
+            &format!(
+                "Moving the dish to {}, expected to take {}s",
+                dish,
+                slew_time.as_secs()
+            ),
+        );
+        sleep(slew_time);
+        Ok(new_angle)
+    }
+
+    /// Perform a scan on the channel/transponders listed in the transmitter files, those
+    /// of satellite positions after selecting the position and moving the dish to it.
     ///
     /// * `frontend_id` – the frontend to use for the scan.
-    /// * `other_nit` – an `Option` `bool`; use alternate table IDs for NIT and other
-    /// tables. Default `false`.
-    /// * `timeout_multiplier` – an `Option` unsigned integer; increases the timeout for each
-    /// table reception. Default 1.
-    /// * `get_detected` –an `Option` `bool`; if `true`, uses the frontend parameters obtained
-    /// from the device driver (such as modulation, FEC, etc). Default `true`.
-    /// * `get_nit` – an `Option` `bool`; if true, uses the parameters obtained from the
-    /// MPEG-TS NIT table to add newly detected transponders. Default `true`.
-    /// * `dont_add_new_frequencies` – an `Option` `bool` determining whether newly found
-    /// frequencies should be scanned for channels. Default `false`.
-    /// * `verbose` – an `Option` `u32` stating the level of verbosity. Default 0.
-    /// * `use_legacy_call` – an `Option` `bool` specifying whether DVBv3 format should be used
-    /// rather than DVBv5 format. Default `false`.
+    /// * `options` – the options of the scan, see `ScanOptions`.
     pub fn scan(
         &self,
         frontend_id: &dvbv5::FrontendId,
-        other_nit: Option<bool>,
-        timeout_multiplier: Option<u32>,
-        get_detected: Option<bool>,
-        get_nit: Option<bool>,
-        dont_add_new_frequencies: Option<bool>,
-        verbose: Option<u32>,
-        use_legacy_call: Option<bool>,
+        options: &ScanOptions,
     ) -> Result<ChannelsData, ()> {
-        let get_detected = get_detected.unwrap_or(true);
-        let get_nit = get_nit.unwrap_or(true);
-        let dont_add_new_frequencies = dont_add_new_frequencies.unwrap_or(false);
-        match dvbv5::FrontendParametersPtr::new(&frontend_id, verbose, use_legacy_call) {
-            Ok(frontend_parameters) => {
-                let dmx_fd = dvbv5::DmxFd::new(&frontend_id).unwrap();
-                // TODO Is there a way of using FilePtr instead of *mut dvbv5_sys::dvb_file?
-                //   Possibly not because of the RAII of FilePtr instances.
-                let mut channels_file = 0 as *mut dvbv5_sys::dvb_file;
-                for (index, entry) in self.ptr.iter().enumerate() {
-                    match dvbv5::retrieve_entry_prop(
-                        &entry,
-                        dvbv5::dtv_retrievable_properties::DTV_FREQUENCY,
-                    ) {
-                        Ok(frequency) => {
-                            frontend_parameters.log(
+        let frontend =
+            Frontend::open_with_legacy_call(frontend_id, options.verbose, options.use_legacy_call)?;
+        let dmx_fd = Demux::open(frontend_id)?;
+        LNB_POWER_OFF.with(|p| *p.borrow_mut() = options.lnb_power == LnbPower::Off);
+        let timeout_multiplier = options.timeout_multiplier;
+        let mut channels_file = std::ptr::null_mut::<dvbv5_sys::dvb_file>();
+        let mut transponders = Vec::new();
+        let mut networks: Vec<si::Nit> = Vec::new();
+        let mut bats: Vec<si::Bat> = Vec::new();
+        let mut unlocked = Vec::new();
+        let mut index = 0;
+        let mut dish_angle = None;
+        for file in &self.files {
+            if let Some(position) = &file.position {
+                frontend.log(
+                    dvbv5::log_level::LOG_INFO,
+                    &format!("\nSatellite position {} ({})", position.name, position),
+                );
+                if let Some(dish) = &position.dish {
+                    match Self::move_dish(&frontend, dish, dish_angle, options) {
+                        Ok(angle) => dish_angle = angle,
+                        Err(e) => {
+                            frontend.log(
                                 dvbv5::log_level::LOG_INFO,
-                                &format!("\nScanning frequency #{} {}", index + 1, frequency),
+                                &format!("Failed to move the dish: {}.", e),
                             );
-                            if let Ok(channel) = entry.get_channel() {
-                                frontend_parameters.log(
-                                    dvbv5::log_level::LOG_INFO,
-                                    &format!("Channel name: {}", channel),
-                                );
-                            }
-                            if let Ok(vchannel) = entry.get_vchannel() {
-                                frontend_parameters.log(
-                                    dvbv5::log_level::LOG_INFO,
-                                    &format!("Channel number: {}", vchannel),
-                                );
-                            }
-                            if let Ok(location) = entry.get_location() {
-                                frontend_parameters.log(
-                                    dvbv5::log_level::LOG_INFO,
-                                    &format!("Channel location: {}", location),
-                                );
-                            }
-                            match dvbv5::ScanHandlerPtr::new(
-                                &frontend_parameters,
-                                &entry,
-                                &dmx_fd,
-                                Some(Self::frontend_check),
-                                other_nit,
-                                timeout_multiplier,
-                            ) {
-                                Ok(scan_handler) => {
-                                    if frontend_parameters.get_abort() {
-                                        break;
-                                    }
-                                    match dvbv5::store_channel(
-                                        channels_file,
-                                        &frontend_parameters,
-                                        &scan_handler,
-                                        get_detected,
-                                        get_nit,
-                                    ) {
-                                        Ok(c_f) => channels_file = c_f,
-                                        Err(_) => frontend_parameters.log(
-                                            dvbv5::log_level::LOG_INFO,
-                                            "Failed to store some channels.",
-                                        ),
-                                    }
-                                    if !dont_add_new_frequencies {
-                                        dvbv5::add_scaned_transponders(
-                                            &frontend_parameters,
-                                            &scan_handler,
-                                            &self.ptr,
-                                            &entry,
-                                        );
-                                    }
-                                }
-                                Err(_) => frontend_parameters.log(
-                                    dvbv5::log_level::LOG_INFO,
-                                    "Failed to initialise scan handler.",
-                                ),
-                            }
+                            continue;
                         }
-                        Err(_) => {}
                     }
                 }
-                Ok(ChannelsData::new(
-                    dvbv5::FilePtr::new_from_dvb_file_ptr(channels_file).unwrap(),
-                    frontend_parameters,
-                ))
             }
-            Err(e) => Err(e),
+            // The depth in the NITs at which each entry of the file was announced, in the
+            // order of the entries; those of the file as read are at depth 0.
+            let mut depths = vec![0; unsafe { entry_count(file.ptr) }];
+            let mut position = 0;
+            let mut entry = std::ptr::null_mut::<dvbv5_sys::dvb_entry>();
+            loop {
+                // Transponders found in the NIT are added to the end of the list as it is
+                // scanned, so the next entry is only taken once an entry has been scanned.
+                entry = unsafe {
+                    if entry.is_null() {
+                        (*file.ptr).first_entry
+                    } else {
+                        position += 1;
+                        (*entry).next
+                    }
+                };
+                if entry.is_null() {
+                    break;
+                }
+                let depth = depths[position];
+                index += 1;
+                let frequency = match unsafe { entry_property(entry, dvbv5_sys::DTV_FREQUENCY) } {
+                    Some(frequency) => frequency,
+                    None => continue,
+                };
+                frontend.log(
+                    dvbv5::log_level::LOG_INFO,
+                    &format!("\nScanning frequency #{} {}", index, frequency),
+                );
+                if let Ok(entry_ptr) = dvbv5::EntryPtr::new_from_dvb_entry_ptr(entry) {
+                    if let Ok(channel) = entry_ptr.get_channel() {
+                        frontend.log(
+                            dvbv5::log_level::LOG_INFO,
+                            &format!("Channel name: {}", channel),
+                        );
+                    }
+                    if let Ok(vchannel) = entry_ptr.get_vchannel() {
+                        frontend.log(
+                            dvbv5::log_level::LOG_INFO,
+                            &format!("Channel number: {}", vchannel),
+                        );
+                    }
+                    if let Ok(location) = entry_ptr.get_location() {
+                        frontend.log(
+                            dvbv5::log_level::LOG_INFO,
+                            &format!("Channel location: {}", location),
+                        );
+                    }
+                }
+                let intermediate_tuning = match unsafe {
+                    Self::prepare_satellite(&frontend, entry, file.position.as_ref(), options)
+                } {
+                    Ok(intermediate_tuning) => intermediate_tuning,
+                    Err(e) => {
+                        frontend.log(
+                            dvbv5::log_level::LOG_INFO,
+                            &format!("Failed to select the satellite: {}.", e),
+                        );
+                        continue;
+                    }
+                };
+                LOCK_STATISTICS.with(|s| *s.borrow_mut() = None);
+                let mut scan_handler =
+                    unsafe { Self::scan_transponder(&frontend, entry, &dmx_fd, options) };
+                let mut variant = None;
+                if scan_handler.is_none() && !options.retry.is_empty() && !frontend.is_aborted() {
+                    if let Some((v, handler)) =
+                        unsafe { Self::retry(&frontend, entry, &dmx_fd, options) }
+                    {
+                        frontend.log(dvbv5::log_level::LOG_INFO, &format!("Locked with {}", v));
+                        variant = Some(v);
+                        scan_handler = Some(handler);
+                    }
+                }
+                if let Some(intermediate_tuning) = &intermediate_tuning {
+                    unsafe { intermediate_tuning.restore(entry) };
+                }
+                let scan_handler = match scan_handler {
+                    Some(scan_handler) => scan_handler,
+                    None => {
+                        unlocked.push((transponders.len(), frequency));
+                        frontend.log(
+                            dvbv5::log_level::LOG_INFO,
+                            "Failed to initialise scan handler.",
+                        );
+                        continue;
+                    }
+                };
+                if frontend.is_aborted() {
+                    break;
+                }
+                let stream_id = if options.multistream {
+                    unsafe { Self::received_stream_id(&frontend, entry) }
+                } else {
+                    None
+                };
+                if let Some(stream_id) = stream_id {
+                    unsafe { set_entry_property(entry, dvbv5_sys::DTV_STREAM_ID, stream_id) };
+                }
+                let statistics = LOCK_STATISTICS.with(|s| s.borrow_mut().take());
+                if let Some(statistics) = &statistics {
+                    frontend.log(
+                        dvbv5::log_level::LOG_INFO,
+                        &format!("Lock statistics: {}", statistics),
+                    );
+                }
+                let delivery_system =
+                    unsafe { entry_property(entry, dvbv5_sys::DTV_DELIVERY_SYSTEM) }.unwrap_or(0);
+                let atsc = psip::is_atsc(delivery_system);
+                let (sdt, vct) = if atsc {
+                    let cable =
+                        delivery_system == dvbv5_sys::fe_delivery_system::SYS_DVBC_ANNEX_B as u32;
+                    let vct = read_vct(frontend_id, cable, timeout_multiplier);
+                    if vct.is_none() {
+                        frontend.log(dvbv5::log_level::LOG_INFO, "Failed to read VCT.");
+                    }
+                    (None, vct)
+                } else {
+                    let sdt = read_sdt(frontend_id, timeout_multiplier);
+                    if sdt.is_none() {
+                        frontend.log(dvbv5::log_level::LOG_INFO, "Failed to read SDT.");
+                    }
+                    (sdt, None)
+                };
+                let pmts = read_pmts(frontend_id, timeout_multiplier);
+                if pmts.is_empty() {
+                    frontend.log(dvbv5::log_level::LOG_INFO, "Failed to read PMT.");
+                }
+                // The NIT is usually the same on every transponder of a network so it is
+                // only read if no NIT yet received describes this transport stream. ATSC
+                // has neither NIT nor BAT.
+                let described = atsc
+                    || sdt
+                        .as_ref()
+                        .map(|sdt| {
+                            networks.iter().any(|n| {
+                                n.describes(sdt.transport_stream_id, sdt.original_network_id)
+                            })
+                        })
+                        .unwrap_or(false);
+                if !described {
+                    let nits = read_nits(frontend_id, options.other_nit, timeout_multiplier);
+                    if nits.is_empty() {
+                        frontend.log(dvbv5::log_level::LOG_INFO, "Failed to read NIT.");
+                    }
+                    for nit in nits {
+                        networks.retain(|n| n.network_id != nit.network_id);
+                        networks.push(nit);
+                    }
+                }
+                // Transponders may carry different bouquets, or different parts of
+                // them, so the BATs of every transponder are merged.
+                if options.get_bat && !atsc {
+                    for bat in read_bats(frontend_id, timeout_multiplier) {
+                        match bats.iter_mut().find(|b| b.bouquet_id == bat.bouquet_id) {
+                            Some(known) => known.merge(bat),
+                            None => bats.push(bat),
+                        }
+                    }
+                }
+                let first_entry = unsafe { entry_count(channels_file) };
+                if unsafe {
+                    dvbv5_sys::dvb_store_channel(
+                        &mut channels_file,
+                        frontend.as_ptr(),
+                        scan_handler.ptr,
+                        options.get_detected as i32,
+                        options.get_nit as i32,
+                    )
+                } != 0
+                {
+                    frontend.log(dvbv5::log_level::LOG_INFO, "Failed to store some channels.");
+                }
+                if let Some(intermediate_tuning) = &intermediate_tuning {
+                    unsafe { intermediate_tuning.restore_entries(channels_file, first_entry) };
+                }
+                if options.multistream {
+                    unsafe {
+                        let next = (*entry).next;
+                        Self::add_streams(file.ptr, entry, &networks, options);
+                        // The streams are of the same transponder so at the same depth.
+                        let mut stream = (*entry).next;
+                        while stream != next {
+                            depths.insert(position + 1, depth);
+                            stream = (*stream).next;
+                        }
+                    }
+                }
+                transponders.push(ScannedTransponder {
+                    frequency,
+                    position: file.position.clone(),
+                    stream_id,
+                    variant,
+                    statistics,
+                    sdt,
+                    vct,
+                    pmts,
+                    entries: first_entry..unsafe { entry_count(channels_file) },
+                });
+                if !options.dont_add_new_frequencies {
+                    let added = unsafe {
+                        Self::add_transponders(
+                            &frontend,
+                            &scan_handler,
+                            file.ptr,
+                            entry,
+                            depth + 1,
+                            &networks,
+                            &options.network_restriction,
+                        )
+                    };
+                    depths.resize(depths.len() + added, depth + 1);
+                }
+            }
+            if frontend.is_aborted() {
+                break;
+            }
         }
+        Ok(ChannelsData::new(
+            dvbv5::FilePtr::new_from_dvb_file_ptr(channels_file).unwrap(),
+            frontend,
+            transponders,
+            unlocked,
+            networks,
+            bats,
+            options.lnb_power,
+        ))
     }
 }
 
@@ -395,6 +1728,55 @@ mod tests {
     use std::path::Path;
 
     use super::*;
+    use crate::si::tests::{make_nit_payload, make_section};
+    use crate::si::{Nit, Section, NIT_ACTUAL_TABLE_ID};
+
+    #[test]
+    fn restore_saved_properties() {
+        let property = |cmd: u32| {
+            let mut property: dvbv5_sys::dtv_property = unsafe { std::mem::zeroed() };
+            property.cmd = cmd;
+            property
+        };
+        let mut entry: dvbv5_sys::dvb_entry = unsafe { std::mem::zeroed() };
+        entry.props[0] = property(dvbv5_sys::DTV_FREQUENCY);
+        entry.props[1] = property(dvbv5_sys::DTV_BANDWIDTH_HZ);
+        entry.n_props = 2;
+        let saved = unsafe { SavedProperties::new(&entry) };
+        // A retry changes the bandwidth and adds an inversion.
+        entry.props[1] = property(dvbv5_sys::DTV_DELIVERY_SYSTEM);
+        entry.props[2] = property(dvbv5_sys::DTV_INVERSION);
+        entry.n_props = 3;
+        unsafe { saved.restore(&mut entry) };
+        assert_eq!(entry.n_props, 2);
+        let commands: Vec<u32> = entry.props[..2].iter().map(|p| p.cmd).collect();
+        assert_eq!(
+            commands,
+            vec![dvbv5_sys::DTV_FREQUENCY, dvbv5_sys::DTV_BANDWIDTH_HZ]
+        );
+    }
+
+    #[test]
+    fn stream_ids_of_t2_entry_in_hz() {
+        use dvbv5_sys::fe_delivery_system::*;
+        // Two PLPs of a T2 multiplex at 626 MHz, which libdvbv5 gives in Hz.
+        let t2 = |plp_id: u8| {
+            vec![
+                0x7f, 0x0d, 0x04, plp_id, 0x00, 0x01, 0x02, 0x00, 0x30, 0x39, 0x03, 0xbb, 0x33,
+                0x40, 0x00,
+            ]
+        };
+        let payload = make_nit_payload("T2", &[(1, 9018, t2(0)), (2, 9018, t2(1))]);
+        let section =
+            Section::parse(&make_section(NIT_ACTUAL_TABLE_ID, 12293, 0, &payload)).unwrap();
+        let nit = Nit::parse(&[&section]).unwrap();
+        let frequency = nit_frequency(SYS_DVBT2 as u32, 626_000_000);
+        assert_eq!(frequency, 626_000);
+        assert_eq!(nit.stream_ids(frequency, None), vec![0, 1]);
+        assert!(nit.stream_ids(626_000_000, None).is_empty());
+        let frequency = nit_frequency(SYS_DVBS2 as u32, 11_494_000);
+        assert_eq!(frequency, 11_494_000);
+    }
 
     #[test]
     fn fail_to_scan_with_silly_frontend() {
@@ -411,13 +1793,7 @@ mod tests {
                                 adapter_number: 254,
                                 frontend_number: 254,
                             },
-                            None,
-                            None,
-                            None,
-                            None,
-                            None,
-                            None,
-                            None,
+                            &ScanOptions::default(),
                         )
                         .is_ok()
                     {
@@ -430,4 +1806,41 @@ mod tests {
             println!("Path {} did not exist, no test undertaken.", path.display());
         }
     }
+
+    #[test]
+    fn transponders_in_scan_order() {
+        let transponders = [522_000_000, 546_000_000]
+            .iter()
+            .enumerate()
+            .map(|(index, frequency)| ScannedTransponder {
+                frequency: *frequency,
+                position: None,
+                stream_id: None,
+                variant: None,
+                statistics: None,
+                sdt: None,
+                vct: None,
+                pmts: Vec::new(),
+                entries: index..index + 1,
+            })
+            .collect::<Vec<_>>();
+        let unlocked = [(0, 498_000_000), (1, 530_000_000), (1, 538_000_000)];
+        let frequencies = in_scan_order(&transponders, &unlocked)
+            .iter()
+            .map(|tried| match tried {
+                TriedTransponder::Locked(t) => (t.frequency, true),
+                TriedTransponder::Unlocked(frequency) => (*frequency, false),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            frequencies,
+            vec![
+                (498_000_000, false),
+                (522_000_000, true),
+                (530_000_000, false),
+                (538_000_000, false),
+                (546_000_000, true),
+            ]
+        );
+    }
 }
@@ -0,0 +1,392 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A Rust-side model of a DVBv5 channels file, the format written by `ChannelsData::write`.
+//!
+//! libdvbv5 keeps the content of a channels file hidden behind a `dvb_file` pointer, which
+//! makes it awkward to compare or rearrange entries. This module reads and writes the
+//! textual format directly so that such processing can be done in Rust.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::json;
+
+/// The DVB triplet that identifies a service: original network id, transport stream id
+/// and service id.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ServiceTriplet {
+    pub network_id: u16,
+    pub transport_id: u16,
+    pub service_id: u16,
+}
+
+impl fmt::Display for ServiceTriplet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.network_id, self.transport_id, self.service_id
+        )
+    }
+}
+
+/// A single `[name]` section of a DVBv5 channels file.
+///
+/// Properties are kept in the order they were read so that writing an unmodified entry
+/// reproduces the original text. Annotations are extra information this application
+/// records about an entry; they are written as `# KEY = VALUE` comment lines so that
+/// libdvbv5, and so tools such as dvbv5-zap, ignore them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelEntry {
+    pub name: String,
+    pub properties: Vec<(String, String)>,
+    pub annotations: Vec<(String, String)>,
+}
+
+impl ChannelEntry {
+    /// Create an entry with the given name and no properties.
+    pub fn new(name: &str) -> ChannelEntry {
+        ChannelEntry {
+            name: name.to_string(),
+            properties: Vec::new(),
+            annotations: Vec::new(),
+        }
+    }
+
+    /// Get the value of a property, if present.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set the value of a property, replacing any existing value. A new `VCHANNEL` is
+    /// placed after `SERVICE_ID`, where libdvbv5 writes it.
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.properties.iter_mut().find(|(k, _)| k == key) {
+            Some(property) => property.1 = value.to_string(),
+            None => {
+                let index = match key {
+                    "VCHANNEL" => self
+                        .properties
+                        .iter()
+                        .position(|(k, _)| k == "SERVICE_ID")
+                        .map(|i| i + 1),
+                    _ => None,
+                };
+                let index = index.unwrap_or(self.properties.len());
+                self.properties
+                    .insert(index, (key.to_string(), value.to_string()));
+            }
+        }
+    }
+
+    /// Get the value of a numeric property, if present and parseable.
+    pub fn get_u32(&self, key: &str) -> Option<u32> {
+        self.get(key).and_then(|v| v.trim().parse::<u32>().ok())
+    }
+
+    /// The frequency of the transponder carrying this entry.
+    pub fn frequency(&self) -> Option<u32> {
+        self.get_u32("FREQUENCY")
+    }
+
+    /// Get the value of an annotation, if present.
+    pub fn get_annotation(&self, key: &str) -> Option<&str> {
+        self.annotations
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set the value of an annotation, replacing any existing value.
+    pub fn set_annotation(&mut self, key: &str, value: &str) {
+        match self.annotations.iter_mut().find(|(k, _)| k == key) {
+            Some(annotation) => annotation.1 = value.to_string(),
+            None => self.annotations.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    /// Other frequencies on which this service has been received.
+    pub fn alternative_frequencies(&self) -> Vec<u32> {
+        self.get_annotation("ALTERNATIVE_FREQUENCIES")
+            .map(|v| {
+                v.split_whitespace()
+                    .filter_map(|f| f.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Set the other frequencies on which this service has been received.
+    pub fn set_alternative_frequencies(&mut self, frequencies: &[u32]) {
+        self.annotations
+            .retain(|(k, _)| k != "ALTERNATIVE_FREQUENCIES");
+        if !frequencies.is_empty() {
+            let value = frequencies
+                .iter()
+                .map(|f| f.to_string())
+                .collect::<Vec<String>>()
+                .join(" ");
+            self.set_annotation("ALTERNATIVE_FREQUENCIES", &value);
+        }
+    }
+
+    /// The DVB triplet for this entry. Entries without a `SERVICE_ID` are transponder
+    /// descriptions, as in a transmitter file, and so have no triplet. libdvbv5 omits
+    /// `NETWORK_ID` and `TRANSPORT_ID` when they are zero.
+    pub fn triplet(&self) -> Option<ServiceTriplet> {
+        let service_id = self.get_u32("SERVICE_ID")? as u16;
+        Some(ServiceTriplet {
+            network_id: self.get_u32("NETWORK_ID").unwrap_or(0) as u16,
+            transport_id: self.get_u32("TRANSPORT_ID").unwrap_or(0) as u16,
+            service_id,
+        })
+    }
+}
+
+impl fmt::Display for ChannelEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[{}]", self.name)?;
+        for (key, value) in &self.properties {
+            writeln!(f, "\t{} = {}", key, value)?;
+        }
+        for (key, value) in &self.annotations {
+            writeln!(f, "\t# {} = {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the text of a comment as an annotation. Only comments of the form
+/// `KEY = VALUE` with an upper case key are annotations, all others are ignored.
+fn parse_annotation(comment: &str) -> Option<(String, String)> {
+    let position = comment.find('=')?;
+    let key = comment[..position].trim();
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    {
+        return None;
+    }
+    Some((key.to_string(), comment[position + 1..].trim().to_string()))
+}
+
+/// The formats in which a `ChannelsFile` can be written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// The DVBv5 format, annotations being written as comments.
+    Dvbv5,
+    /// A JSON array of entries, annotations being written as fields.
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "dvbv5" => Some(OutputFormat::Dvbv5),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The content of a DVBv5 channels (or transmitter) file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelsFile {
+    pub entries: Vec<ChannelEntry>,
+}
+
+impl ChannelsFile {
+    /// Read and parse a DVBv5 file from the filestore.
+    pub fn read(path: &Path) -> Result<ChannelsFile, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e)),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    /// Parse the text of a DVBv5 file.
+    pub fn parse(text: &str) -> Result<ChannelsFile, String> {
+        let mut entries = Vec::new();
+        let mut current: Option<ChannelEntry> = None;
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(entry) = current.as_mut() {
+                    if let Some(annotation) = parse_annotation(comment) {
+                        entry.annotations.push(annotation);
+                    }
+                }
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(format!("line {}: unterminated entry name.", index + 1));
+                }
+                if let Some(entry) = current.take() {
+                    entries.push(entry);
+                }
+                current = Some(ChannelEntry::new(&line[1..line.len() - 1]));
+            } else {
+                match (current.as_mut(), line.find('=')) {
+                    (Some(entry), Some(position)) => entry.properties.push((
+                        line[..position].trim().to_string(),
+                        line[position + 1..].trim().to_string(),
+                    )),
+                    (None, _) => {
+                        return Err(format!("line {}: property outside of an entry.", index + 1))
+                    }
+                    (_, None) => return Err(format!("line {}: expected KEY = VALUE.", index + 1)),
+                }
+            }
+        }
+        if let Some(entry) = current.take() {
+            entries.push(entry);
+        }
+        Ok(ChannelsFile { entries })
+    }
+
+    /// Write this file to the filestore in DVBv5 format.
+    pub fn write(&self, output_path: &Path) -> Result<(), String> {
+        self.write_format(output_path, OutputFormat::Dvbv5)
+    }
+
+    /// Write this file to the filestore in the given format.
+    pub fn write_format(&self, output_path: &Path, format: OutputFormat) -> Result<(), String> {
+        let text = match format {
+            OutputFormat::Dvbv5 => self.to_string(),
+            OutputFormat::Json => self.to_json(),
+        };
+        fs::write(output_path, text).map_err(|e| format!("{}: {}", output_path.display(), e))
+    }
+
+    /// Render this file as JSON.
+    pub fn to_json(&self) -> String {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let mut members = vec![("name", json::string(&entry.name))];
+                if let Some(triplet) = entry.triplet() {
+                    members.push(("triplet", json::string(&triplet.to_string())));
+                }
+                if let Some(channel_number) = entry.get("VCHANNEL") {
+                    members.push(("channel_number", json::string(channel_number)));
+                }
+                let properties = entry
+                    .properties
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), json::string(v)))
+                    .collect::<Vec<_>>();
+                members.push(("properties", json::object(&properties)));
+                let annotations = entry
+                    .annotations
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), json::string(v)))
+                    .collect::<Vec<_>>();
+                members.push(("annotations", json::object(&annotations)));
+                json::object(&members)
+            })
+            .collect::<Vec<String>>();
+        json::array(&entries) + "\n"
+    }
+}
+
+impl fmt::Display for ChannelsFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, entry) in self.entries.iter().enumerate() {
+            if index != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// A service of the BBC multiplex at a frequency.
+    pub fn service(name: &str, service_id: &str, frequency: &str) -> ChannelEntry {
+        let mut entry = ChannelEntry::new(name);
+        for (key, value) in &[
+            ("SERVICE_ID", service_id),
+            ("NETWORK_ID", "9018"),
+            ("TRANSPORT_ID", "4164"),
+            ("FREQUENCY", frequency),
+        ] {
+            entry.properties.push((key.to_string(), value.to_string()));
+        }
+        entry
+    }
+
+    const SAMPLE: &str = "[BBC ONE Lon]
+\tSERVICE_ID = 4164
+\tNETWORK_ID = 9018
+\tTRANSPORT_ID = 4164
+\tVIDEO_PID = 101
+\tAUDIO_PID = 102 106
+\tFREQUENCY = 490000000
+\tDELIVERY_SYSTEM = DVBT
+\t# ALTERNATIVE_FREQUENCIES = 506000000 514000000
+
+[BBC TWO]
+\tSERVICE_ID = 4287
+\tNETWORK_ID = 9018
+\tTRANSPORT_ID = 4164
+\tFREQUENCY = 490000000
+";
+
+    #[test]
+    fn parse_and_write_round_trip() {
+        let file = ChannelsFile::parse(SAMPLE).unwrap();
+        assert_eq!(file.entries.len(), 2);
+        assert_eq!(file.entries[0].name, "BBC ONE Lon");
+        assert_eq!(file.entries[0].get("AUDIO_PID"), Some("102 106"));
+        assert_eq!(
+            file.entries[0].alternative_frequencies(),
+            vec![506000000, 514000000]
+        );
+        assert_eq!(
+            file.entries[1].triplet(),
+            Some(ServiceTriplet {
+                network_id: 9018,
+                transport_id: 4164,
+                service_id: 4287
+            })
+        );
+        assert_eq!(file.to_string(), SAMPLE);
+    }
+
+    #[test]
+    fn parse_rejects_property_outside_entry() {
+        assert!(ChannelsFile::parse("\tFREQUENCY = 490000000\n").is_err());
+    }
+}
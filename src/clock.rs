@@ -0,0 +1,355 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The time broadcast in the TDT and TOT, and the local time offsets of the TOT.
+
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use crate::demux::SectionFilter;
+use crate::si::{self, TimeTable};
+use crate::ts::{PacketSplitter, SectionAssembler};
+
+/// Descriptor tag of the local time offset descriptor.
+const LOCAL_TIME_OFFSET_DESCRIPTOR: u8 = 0x58;
+
+/// The capability needed to set the system clock, see capabilities(7).
+const CAP_SYS_TIME: u32 = 25;
+
+/// The amount of a capture read at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Convert a time in seconds since the Unix epoch to year, month, day, hour, minute and
+/// second, using Howard Hinnant's algorithm for the civil date.
+pub fn civil_time(time: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let (days, seconds) = (time.div_euclid(86400), time.rem_euclid(86400) as u32);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+    )
+}
+
+/// Format an offset from UTC in minutes as, e.g., `+01:00`.
+pub fn format_offset(offset: i32) -> String {
+    format!(
+        "{}{:02}:{:02}",
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+/// Format a time in seconds since the Unix epoch as the local time with the given offset
+/// from UTC in minutes.
+pub fn format_time(time: i64, offset: i32) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(time + offset as i64 * 60);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        format_offset(offset)
+    )
+}
+
+/// The local time offset of a country, or region of one, from a local time offset
+/// descriptor.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocalTimeOffset {
+    /// ISO 3166 country code.
+    pub country: String,
+    /// The region of the country, 0 for the whole country.
+    pub region: u8,
+    /// The offset from UTC in minutes.
+    pub offset: i32,
+    /// The UTC time in seconds since the Unix epoch of the next change of offset.
+    pub time_of_change: Option<i64>,
+    /// The offset from UTC in minutes after the next change.
+    pub next_offset: i32,
+}
+
+impl LocalTimeOffset {
+    /// Parse the local time offset descriptors of a descriptor loop.
+    pub fn parse_descriptors(descriptors: &[u8]) -> Vec<LocalTimeOffset> {
+        // An offset is four binary coded decimal digits of hours and minutes.
+        let offset = |data: &[u8], negative: bool| {
+            si::parse_bcd_duration(&[data[0], data[1], 0])
+                .map(|seconds| seconds as i32 / 60 * if negative { -1 } else { 1 })
+                .unwrap_or(0)
+        };
+        si::descriptors(descriptors)
+            .filter(|(tag, _)| *tag == LOCAL_TIME_OFFSET_DESCRIPTOR)
+            .flat_map(|(_, content)| content.chunks(13).filter(|c| c.len() == 13))
+            .map(|data| {
+                let negative = data[3] & 0x01 != 0;
+                LocalTimeOffset {
+                    country: String::from_utf8_lossy(&data[..3]).into_owned(),
+                    region: data[3] >> 2,
+                    offset: offset(&data[4..6], negative),
+                    time_of_change: si::parse_utc_time(&data[6..11]),
+                    next_offset: offset(&data[11..13], negative),
+                }
+            })
+            .collect()
+    }
+
+    /// The offset in minutes at a UTC time.
+    pub fn offset_at(&self, time: i64) -> i32 {
+        match self.time_of_change {
+            Some(change) if time >= change => self.next_offset,
+            _ => self.offset,
+        }
+    }
+}
+
+/// The time from a TDT or TOT.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BroadcastTime {
+    /// The UTC time in seconds since the Unix epoch.
+    pub utc_time: i64,
+    /// When the time was received from a frontend, to allow for the time since.
+    received: Option<Instant>,
+    /// Whether the time is from a TOT rather than a TDT.
+    pub from_tot: bool,
+    pub offsets: Vec<LocalTimeOffset>,
+}
+
+impl BroadcastTime {
+    fn new(table: &TimeTable, received: Option<Instant>) -> BroadcastTime {
+        BroadcastTime {
+            utc_time: table.utc_time,
+            received,
+            from_tot: table.table_id == si::TOT_TABLE_ID,
+            offsets: LocalTimeOffset::parse_descriptors(&table.descriptors),
+        }
+    }
+
+    /// Wait at most `timeout` for a TOT on the transport stream a frontend is tuned to,
+    /// settling for a TDT if that is all there is.
+    pub fn from_frontend(
+        frontend_id: &dvbv5::FrontendId,
+        timeout: Duration,
+    ) -> Result<BroadcastTime, String> {
+        // The TDT and TOT table ids differ only in the low two bits.
+        let filter = SectionFilter::with_mask(frontend_id, si::TDT_PID, si::TDT_TABLE_ID, 0xfc)
+            .map_err(|_| "could not set the demux filter".to_string())?;
+        let deadline = Instant::now() + timeout;
+        let mut tdt = None;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            let data = match filter.read_section(deadline - now) {
+                Some(data) => data,
+                None => break,
+            };
+            if let Ok(table) = TimeTable::parse(&data) {
+                let time = BroadcastTime::new(&table, Some(Instant::now()));
+                if time.from_tot {
+                    return Ok(time);
+                }
+                tdt = Some(time);
+            }
+        }
+        tdt.ok_or_else(|| "no TDT or TOT received".to_string())
+    }
+
+    /// Find the first TOT of a transport stream capture, or failing that the first TDT.
+    pub fn from_capture<R: Read>(mut source: R) -> io::Result<Option<BroadcastTime>> {
+        let mut splitter = PacketSplitter::new();
+        let mut assembler = SectionAssembler::new();
+        let mut buffer = vec![0u8; READ_SIZE];
+        let mut tdt = None;
+        let mut tot = None;
+        while tot.is_none() {
+            let count = match source.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            splitter.push(&buffer[..count], |packet| {
+                if packet.pid() != si::TDT_PID || tot.is_some() {
+                    return;
+                }
+                for data in assembler.push(&packet) {
+                    if let Ok(table) = TimeTable::parse(&data) {
+                        let time = BroadcastTime::new(&table, None);
+                        if time.from_tot {
+                            tot = Some(time);
+                        } else if tdt.is_none() {
+                            tdt = Some(time);
+                        }
+                    }
+                }
+            });
+        }
+        Ok(tot.or(tdt))
+    }
+
+    /// The UTC time now: the broadcast time plus the time since it was received from a
+    /// frontend.
+    pub fn now(&self) -> i64 {
+        self.utc_time
+            + self
+                .received
+                .map(|r| r.elapsed().as_secs() as i64)
+                .unwrap_or(0)
+    }
+
+    /// The local time offset for a country, and region of it, the whole country or the
+    /// first given if not specified.
+    pub fn local_offset(
+        &self,
+        country: Option<&str>,
+        region: Option<u8>,
+    ) -> Option<&LocalTimeOffset> {
+        self.offsets.iter().find(|o| {
+            country
+                .map(|c| o.country.eq_ignore_ascii_case(c))
+                .unwrap_or(true)
+                && region.map(|r| o.region == r).unwrap_or(true)
+        })
+    }
+}
+
+/// Whether the process has a capability in its effective set.
+fn has_capability(capability: u32) -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|value| u64::from_str_radix(value.trim(), 16).ok())
+        })
+        .map(|capabilities| capabilities & (1 << capability) != 0)
+        .unwrap_or(false)
+}
+
+/// Set the system clock to a UTC time in seconds since the Unix epoch.
+pub fn set_system_clock(time: i64) -> Result<(), String> {
+    if !has_capability(CAP_SYS_TIME) {
+        return Err(
+            "setting the clock needs the CAP_SYS_TIME capability, run as root or grant it"
+                .to_string(),
+        );
+    }
+    let timespec = libc::timespec {
+        tv_sec: time as libc::time_t,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &timespec) } != 0 {
+        return Err(io::Error::last_os_error().to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::si::crc32;
+    use crate::ts;
+
+    /// Build a TOT for 2020-10-25 00:30:00 UTC with offsets for GBR, changing from BST to
+    /// GMT at 01:00, and for two regions of ESP.
+    fn make_tot() -> Vec<u8> {
+        let mut descriptor = vec![LOCAL_TIME_OFFSET_DESCRIPTOR, 39];
+        descriptor.extend_from_slice(b"GBR\x02\x01\x00\xe7\x0b\x01\x00\x00\x00\x00");
+        descriptor.extend_from_slice(b"ESP\x06\x01\x00\xff\xff\xff\xff\xff\x01\x00");
+        descriptor.extend_from_slice(b"ESP\x0a\x00\x00\xff\xff\xff\xff\xff\x00\x00");
+        let mut data = vec![si::TOT_TABLE_ID, 0x70, 0, 0xe7, 0x0b, 0x00, 0x30, 0x00];
+        data.extend_from_slice(&(0xf000 | descriptor.len() as u16).to_be_bytes());
+        data.extend_from_slice(&descriptor);
+        data[2] = (data.len() + 4 - 3) as u8;
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn parse_tot_offsets() {
+        let table = TimeTable::parse(&make_tot()).unwrap();
+        let time = BroadcastTime::new(&table, None);
+        assert!(time.from_tot);
+        assert_eq!(time.now(), 1_603_585_800);
+        assert_eq!(format_time(time.utc_time, 0), "2020-10-25 00:30:00 +00:00");
+        let gbr = time.local_offset(Some("gbr"), None).unwrap();
+        assert_eq!(gbr.offset_at(time.utc_time), 60);
+        assert_eq!(gbr.offset_at(time.utc_time + 1800), 0);
+        assert_eq!(
+            format_time(time.utc_time, gbr.offset_at(time.utc_time)),
+            "2020-10-25 01:30:00 +01:00"
+        );
+        let canaries = time.local_offset(Some("ESP"), Some(2)).unwrap();
+        assert_eq!(canaries.offset_at(time.utc_time), 0);
+        assert_eq!(time.local_offset(Some("ESP"), None).unwrap().offset, 60);
+        assert_eq!(time.local_offset(Some("FRA"), None), None);
+        assert_eq!(format_time(0, -90), "1969-12-31 22:30:00 -01:30");
+    }
+
+    #[test]
+    fn time_from_capture() {
+        let tdt = [si::TDT_TABLE_ID, 0x70, 0x05, 0xe7, 0x0b, 0x00, 0x29, 0x59];
+        let mut capture = Vec::new();
+        let mut counter = 0x0f;
+        capture.extend(ts::packetize_section(si::TDT_PID, &tdt, &mut counter));
+        let time = BroadcastTime::from_capture(Cursor::new(&capture))
+            .unwrap()
+            .unwrap();
+        assert!(!time.from_tot);
+        assert_eq!(time.utc_time, 1_603_585_799);
+        capture.extend(ts::packetize_section(
+            si::TDT_PID,
+            &make_tot(),
+            &mut counter,
+        ));
+        let time = BroadcastTime::from_capture(Cursor::new(&capture))
+            .unwrap()
+            .unwrap();
+        assert!(time.from_tot);
+        assert_eq!(time.offsets.len(), 3);
+    }
+}
@@ -0,0 +1,589 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Comparing and merging channels files, matching services by their DVB triplet, or by
+//! frequency and service id if a file does not give the triplet.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+
+use crate::channels_file::{ChannelEntry, ChannelsFile, ServiceTriplet};
+use crate::json;
+
+/// What matches versions of a service in two files: its triplet, or its frequency and
+/// service id if the file does not give its network and transport stream ids, as files
+/// written by libdvbv5 often do not.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ServiceKey {
+    Triplet(ServiceTriplet),
+    Frequency { frequency: u32, service_id: u16 },
+}
+
+impl ServiceKey {
+    /// The key of an entry. Entries without a `SERVICE_ID` are transponder descriptions
+    /// and so have no key.
+    pub fn of(entry: &ChannelEntry) -> Option<ServiceKey> {
+        let triplet = entry.triplet()?;
+        if entry.get("NETWORK_ID").is_some() && entry.get("TRANSPORT_ID").is_some() {
+            Some(ServiceKey::Triplet(triplet))
+        } else {
+            Some(ServiceKey::Frequency {
+                frequency: entry.get_u32("FREQUENCY").unwrap_or(0),
+                service_id: triplet.service_id,
+            })
+        }
+    }
+}
+
+impl fmt::Display for ServiceKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceKey::Triplet(triplet) => write!(f, "{}", triplet),
+            ServiceKey::Frequency {
+                frequency,
+                service_id,
+            } => write!(f, "{}@{}", service_id, frequency),
+        }
+    }
+}
+
+/// Why two channels files could not be merged.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MergeError {
+    /// Services that are more than once in a file, so cannot be matched.
+    Duplicates(Vec<ServiceKey>),
+    /// Services in both files with differing details.
+    Conflicts(Vec<ServiceKey>),
+}
+
+/// A property whose value differs between two versions of a service. `None` means the
+/// property is absent from that version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PropertyChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A difference between two channels files.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    Added(ChannelEntry),
+    Removed(ChannelEntry),
+    Changed {
+        old: ChannelEntry,
+        new: ChannelEntry,
+        changes: Vec<PropertyChange>,
+    },
+}
+
+/// The ways of presenting a list of differences.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiffFormat {
+    Text,
+    Json,
+    Unified,
+}
+
+impl DiffFormat {
+    pub fn from_name(name: &str) -> Option<DiffFormat> {
+        match name {
+            "text" => Some(DiffFormat::Text),
+            "json" => Some(DiffFormat::Json),
+            "unified" => Some(DiffFormat::Unified),
+            _ => None,
+        }
+    }
+}
+
+/// What to do when a service is in both files being merged but with different details.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    /// Keep the version from the first file.
+    Left,
+    /// Keep the version from the second file.
+    Right,
+    /// Refuse to merge.
+    Fail,
+}
+
+impl ConflictPolicy {
+    pub fn from_name(name: &str) -> Option<ConflictPolicy> {
+        match name {
+            "left" => Some(ConflictPolicy::Left),
+            "right" => Some(ConflictPolicy::Right),
+            "fail" => Some(ConflictPolicy::Fail),
+            _ => None,
+        }
+    }
+}
+
+/// The property changes needed to turn `old` into `new`. A change of name is reported
+/// as a change of the pseudo-property `NAME`.
+fn property_changes(old: &ChannelEntry, new: &ChannelEntry) -> Vec<PropertyChange> {
+    let mut changes = Vec::new();
+    if old.name != new.name {
+        changes.push(PropertyChange {
+            key: "NAME".to_string(),
+            old: Some(old.name.clone()),
+            new: Some(new.name.clone()),
+        });
+    }
+    for (key, value) in &old.properties {
+        match new.get(key) {
+            Some(v) if v == value => {}
+            v => changes.push(PropertyChange {
+                key: key.clone(),
+                old: Some(value.clone()),
+                new: v.map(|s| s.to_string()),
+            }),
+        }
+    }
+    for (key, value) in &new.properties {
+        if old.get(key).is_none() {
+            changes.push(PropertyChange {
+                key: key.clone(),
+                old: None,
+                new: Some(value.clone()),
+            });
+        }
+    }
+    changes
+}
+
+/// The services of a file by key.
+type Index<'a> = HashMap<ServiceKey, &'a ChannelEntry>;
+
+/// Index the services of a file by key, returning the keys of services that are more
+/// than once in the file as the error. Entries without a key are not indexed.
+fn index(file: &ChannelsFile) -> Result<Index<'_>, Vec<ServiceKey>> {
+    let mut index = HashMap::new();
+    let mut duplicates = Vec::new();
+    for entry in &file.entries {
+        if let Some(key) = ServiceKey::of(entry) {
+            if index.insert(key, entry).is_some() && !duplicates.contains(&key) {
+                duplicates.push(key);
+            }
+        }
+    }
+    if duplicates.is_empty() {
+        Ok(index)
+    } else {
+        Err(duplicates)
+    }
+}
+
+/// Index the services of two files, returning the keys of services that are more than
+/// once in either as the error.
+fn index_pair<'a>(
+    a: &'a ChannelsFile,
+    b: &'a ChannelsFile,
+) -> Result<(Index<'a>, Index<'a>), Vec<ServiceKey>> {
+    match (index(a), index(b)) {
+        (Ok(a_index), Ok(b_index)) => Ok((a_index, b_index)),
+        (a_index, b_index) => {
+            let mut duplicates = a_index.err().unwrap_or_default();
+            for key in b_index.err().unwrap_or_default() {
+                if !duplicates.contains(&key) {
+                    duplicates.push(key);
+                }
+            }
+            Err(duplicates)
+        }
+    }
+}
+
+/// Compare the services of two channels files. Removed and changed services are listed
+/// in the order of `a`, added services in the order of `b`. Entries without a service
+/// id are matched by their whole text. Services that are more than once in a file
+/// cannot be matched, their keys are returned as the error.
+pub fn diff(a: &ChannelsFile, b: &ChannelsFile) -> Result<Vec<Difference>, Vec<ServiceKey>> {
+    let (a_index, b_index) = index_pair(a, b)?;
+    let mut differences = Vec::new();
+    for entry in &a.entries {
+        match ServiceKey::of(entry) {
+            Some(key) => match b_index.get(&key) {
+                Some(other) => {
+                    let changes = property_changes(entry, other);
+                    if !changes.is_empty() {
+                        differences.push(Difference::Changed {
+                            old: entry.clone(),
+                            new: (*other).clone(),
+                            changes,
+                        });
+                    }
+                }
+                None => differences.push(Difference::Removed(entry.clone())),
+            },
+            None => {
+                if !b.entries.contains(entry) {
+                    differences.push(Difference::Removed(entry.clone()));
+                }
+            }
+        }
+    }
+    for entry in &b.entries {
+        let present = match ServiceKey::of(entry) {
+            Some(key) => a_index.contains_key(&key),
+            None => a.entries.contains(entry),
+        };
+        if !present {
+            differences.push(Difference::Added(entry.clone()));
+        }
+    }
+    Ok(differences)
+}
+
+fn key_string(entry: &ChannelEntry) -> String {
+    ServiceKey::of(entry)
+        .map(|k| k.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn format_text(differences: &[Difference]) -> String {
+    let mut result = String::new();
+    for difference in differences {
+        match difference {
+            Difference::Added(entry) => {
+                writeln!(result, "+ {} ({})", entry.name, key_string(entry)).unwrap()
+            }
+            Difference::Removed(entry) => {
+                writeln!(result, "- {} ({})", entry.name, key_string(entry)).unwrap()
+            }
+            Difference::Changed { new, changes, .. } => {
+                writeln!(result, "~ {} ({})", new.name, key_string(new)).unwrap();
+                for change in changes {
+                    writeln!(
+                        result,
+                        "    {}: {} -> {}",
+                        change.key,
+                        change.old.as_deref().unwrap_or("<absent>"),
+                        change.new.as_deref().unwrap_or("<absent>")
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+    result
+}
+
+fn json_optional(value: &Option<String>) -> String {
+    match value {
+        Some(v) => json::string(v),
+        None => "null".to_string(),
+    }
+}
+
+fn json_entry(entry: &ChannelEntry) -> String {
+    let properties = entry
+        .properties
+        .iter()
+        .map(|(k, v)| (k.as_str(), json::string(v)))
+        .collect::<Vec<_>>();
+    json::object(&[
+        ("name", json::string(&entry.name)),
+        ("triplet", json::string(&key_string(entry))),
+        ("properties", json::object(&properties)),
+    ])
+}
+
+fn format_json(differences: &[Difference]) -> String {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for difference in differences {
+        match difference {
+            Difference::Added(entry) => added.push(json_entry(entry)),
+            Difference::Removed(entry) => removed.push(json_entry(entry)),
+            Difference::Changed { old, new, changes } => {
+                let changes = changes
+                    .iter()
+                    .map(|c| {
+                        json::object(&[
+                            ("property", json::string(&c.key)),
+                            ("old", json_optional(&c.old)),
+                            ("new", json_optional(&c.new)),
+                        ])
+                    })
+                    .collect::<Vec<_>>();
+                changed.push(json::object(&[
+                    ("name", json::string(&new.name)),
+                    ("old_name", json::string(&old.name)),
+                    ("triplet", json::string(&key_string(new))),
+                    ("changes", json::array(&changes)),
+                ]));
+            }
+        }
+    }
+    json::object(&[
+        ("added", json::array(&added)),
+        ("removed", json::array(&removed)),
+        ("changed", json::array(&changed)),
+    ]) + "\n"
+}
+
+fn format_unified(differences: &[Difference], a_label: &str, b_label: &str) -> String {
+    let mut result = String::new();
+    if differences.is_empty() {
+        return result;
+    }
+    writeln!(result, "--- {}", a_label).unwrap();
+    writeln!(result, "+++ {}", b_label).unwrap();
+    for difference in differences {
+        match difference {
+            Difference::Added(entry) => {
+                writeln!(result, "@@ {} @@", key_string(entry)).unwrap();
+                for line in entry.to_string().lines() {
+                    writeln!(result, "+{}", line).unwrap();
+                }
+            }
+            Difference::Removed(entry) => {
+                writeln!(result, "@@ {} @@", key_string(entry)).unwrap();
+                for line in entry.to_string().lines() {
+                    writeln!(result, "-{}", line).unwrap();
+                }
+            }
+            Difference::Changed { old, new, .. } => {
+                writeln!(result, "@@ {} @@", key_string(new)).unwrap();
+                if old.name == new.name {
+                    writeln!(result, " [{}]", old.name).unwrap();
+                } else {
+                    writeln!(result, "-[{}]", old.name).unwrap();
+                    writeln!(result, "+[{}]", new.name).unwrap();
+                }
+                for (key, value) in &old.properties {
+                    match new.get(key) {
+                        Some(v) if v == value => {
+                            writeln!(result, " \t{} = {}", key, value).unwrap()
+                        }
+                        Some(v) => {
+                            writeln!(result, "-\t{} = {}", key, value).unwrap();
+                            writeln!(result, "+\t{} = {}", key, v).unwrap();
+                        }
+                        None => writeln!(result, "-\t{} = {}", key, value).unwrap(),
+                    }
+                }
+                for (key, value) in &new.properties {
+                    if old.get(key).is_none() {
+                        writeln!(result, "+\t{} = {}", key, value).unwrap();
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Render a list of differences. The labels name the two files being compared and are
+/// only used by the unified format.
+pub fn format(
+    differences: &[Difference],
+    format: DiffFormat,
+    a_label: &str,
+    b_label: &str,
+) -> String {
+    match format {
+        DiffFormat::Text => format_text(differences),
+        DiffFormat::Json => format_json(differences),
+        DiffFormat::Unified => format_unified(differences, a_label, b_label),
+    }
+}
+
+/// Merge two channels files. The result has the entries of `a` in order, with the
+/// services only in `b` appended in their order in `b`. Services in both files with
+/// differing details are resolved according to `policy`; with `ConflictPolicy::Fail`
+/// the keys of the conflicting services are returned as the error. Annotations are not
+/// details, a difference in them alone is not a conflict. Services that are more than
+/// once in a file cannot be matched, so are an error whatever the policy.
+pub fn merge(
+    a: &ChannelsFile,
+    b: &ChannelsFile,
+    policy: ConflictPolicy,
+) -> Result<ChannelsFile, MergeError> {
+    let (a_index, b_index) = index_pair(a, b).map_err(MergeError::Duplicates)?;
+    let mut conflicts = Vec::new();
+    let mut merged = ChannelsFile::default();
+    for entry in &a.entries {
+        let chosen = match ServiceKey::of(entry).and_then(|k| b_index.get(&k).map(|o| (k, o))) {
+            Some((key, other)) if !property_changes(entry, other).is_empty() => match policy {
+                ConflictPolicy::Left => entry,
+                ConflictPolicy::Right => *other,
+                ConflictPolicy::Fail => {
+                    conflicts.push(key);
+                    entry
+                }
+            },
+            _ => entry,
+        };
+        merged.entries.push(chosen.clone());
+    }
+    for entry in &b.entries {
+        let present = match ServiceKey::of(entry) {
+            Some(key) => a_index.contains_key(&key),
+            None => a.entries.contains(entry),
+        };
+        if !present {
+            merged.entries.push(entry.clone());
+        }
+    }
+    if conflicts.is_empty() {
+        Ok(merged)
+    } else {
+        Err(MergeError::Conflicts(conflicts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::channels_file::tests::service;
+
+    fn files() -> (ChannelsFile, ChannelsFile) {
+        let a = ChannelsFile {
+            entries: vec![
+                service("BBC ONE", "4164", "490000000"),
+                service("BBC TWO", "4287", "490000000"),
+            ],
+        };
+        let b = ChannelsFile {
+            entries: vec![
+                service("BBC ONE", "4164", "498000000"),
+                service("CBBC", "4671", "490000000"),
+            ],
+        };
+        (a, b)
+    }
+
+    #[test]
+    fn diff_finds_added_removed_and_changed() {
+        let (a, b) = files();
+        let differences = diff(&a, &b).unwrap();
+        assert_eq!(differences.len(), 3);
+        match &differences[0] {
+            Difference::Changed { changes, .. } => assert_eq!(
+                changes,
+                &vec![PropertyChange {
+                    key: "FREQUENCY".to_string(),
+                    old: Some("490000000".to_string()),
+                    new: Some("498000000".to_string()),
+                }]
+            ),
+            d => panic!("Unexpected difference {:?}", d),
+        }
+        assert_eq!(differences[1], Difference::Removed(a.entries[1].clone()));
+        assert_eq!(differences[2], Difference::Added(b.entries[1].clone()));
+    }
+
+    #[test]
+    fn merge_applies_conflict_policy() {
+        let (a, b) = files();
+        let left = merge(&a, &b, ConflictPolicy::Left).unwrap();
+        assert_eq!(left.entries.len(), 3);
+        assert_eq!(left.entries[0].get("FREQUENCY"), Some("490000000"));
+        let right = merge(&a, &b, ConflictPolicy::Right).unwrap();
+        assert_eq!(right.entries[0].get("FREQUENCY"), Some("498000000"));
+        assert_eq!(right.entries[2].name, "CBBC");
+        assert_eq!(
+            merge(&a, &b, ConflictPolicy::Fail),
+            Err(MergeError::Conflicts(vec![ServiceKey::Triplet(
+                a.entries[0].triplet().unwrap()
+            )]))
+        );
+    }
+
+    /// A service without network and transport stream ids, as libdvbv5 writes them.
+    fn service_without_ids(name: &str, service_id: &str, frequency: &str) -> ChannelEntry {
+        let mut entry = service(name, service_id, frequency);
+        entry
+            .properties
+            .retain(|(key, _)| key != "NETWORK_ID" && key != "TRANSPORT_ID");
+        entry
+    }
+
+    #[test]
+    fn services_without_ids_matched_by_frequency() {
+        // Two multiplexes using the same service id.
+        let a = ChannelsFile {
+            entries: vec![
+                service_without_ids("BBC ONE", "4164", "490000000"),
+                service_without_ids("ITV", "4164", "506000000"),
+            ],
+        };
+        let mut b = a.clone();
+        b.entries[1].name = "ITV1".to_string();
+        let differences = diff(&a, &b).unwrap();
+        assert_eq!(differences.len(), 1);
+        match &differences[0] {
+            Difference::Changed { old, new, .. } => {
+                assert_eq!(old.name, "ITV");
+                assert_eq!(new.name, "ITV1");
+            }
+            d => panic!("Unexpected difference {:?}", d),
+        }
+        let merged = merge(&a, &b, ConflictPolicy::Right).unwrap();
+        assert_eq!(merged.entries, b.entries);
+    }
+
+    #[test]
+    fn duplicate_services_reported() {
+        let a = ChannelsFile {
+            entries: vec![
+                service_without_ids("BBC ONE", "4164", "490000000"),
+                service_without_ids("BBC ONE HD", "4164", "490000000"),
+            ],
+        };
+        let key = ServiceKey::Frequency {
+            frequency: 490_000_000,
+            service_id: 4164,
+        };
+        assert_eq!(diff(&a, &ChannelsFile::default()), Err(vec![key]));
+        assert_eq!(
+            merge(&ChannelsFile::default(), &a, ConflictPolicy::Left),
+            Err(MergeError::Duplicates(vec![key]))
+        );
+    }
+
+    #[test]
+    fn entries_without_service_id_compared() {
+        let transponder = |frequency: &str| {
+            let mut entry = ChannelEntry::new("");
+            entry
+                .properties
+                .push(("FREQUENCY".to_string(), frequency.to_string()));
+            entry
+        };
+        let a = ChannelsFile {
+            entries: vec![transponder("490000000")],
+        };
+        let b = ChannelsFile {
+            entries: vec![transponder("498000000")],
+        };
+        assert_eq!(
+            diff(&a, &b).unwrap(),
+            vec![
+                Difference::Removed(a.entries[0].clone()),
+                Difference::Added(b.entries[0].clone()),
+            ]
+        );
+    }
+}
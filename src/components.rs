@@ -0,0 +1,411 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! The elementary streams of a service, from the descriptors of its PMT.
+//!
+//! The stream type alone does not say what a private data stream (type 0x06) carries, so
+//! the descriptors of EN 300 468 annex D are used to tell AC-3, E-AC-3 and AAC audio,
+//! DVB subtitles and teletext apart.
+
+use std::fmt;
+
+use crate::channels_file::ChannelEntry;
+use crate::si;
+
+/// Descriptor tags used in this module.
+const ISO_639_LANGUAGE_DESCRIPTOR: u8 = 0x0a;
+const VBI_TELETEXT_DESCRIPTOR: u8 = 0x46;
+const TELETEXT_DESCRIPTOR: u8 = 0x56;
+const SUBTITLING_DESCRIPTOR: u8 = 0x59;
+const AC3_DESCRIPTOR: u8 = 0x6a;
+const ENHANCED_AC3_DESCRIPTOR: u8 = 0x7a;
+const DTS_DESCRIPTOR: u8 = 0x7b;
+const AAC_DESCRIPTOR: u8 = 0x7c;
+const EXTENSION_DESCRIPTOR: u8 = 0x7f;
+const SUPPLEMENTARY_AUDIO_DESCRIPTOR_EXTENSION: u8 = 0x06;
+const AC4_DESCRIPTOR_EXTENSION: u8 = 0x15;
+
+/// What an elementary stream carries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Video,
+    Audio,
+    Subtitles,
+    Teletext,
+    Data,
+}
+
+/// The audience of an audio stream, from the ISO 639 language descriptor or the
+/// supplementary audio descriptor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioType {
+    Undefined,
+    CleanEffects,
+    HearingImpaired,
+    VisualImpairedCommentary,
+}
+
+impl AudioType {
+    /// The audio type of an ISO 639 language descriptor entry.
+    fn from_iso_639(audio_type: u8) -> AudioType {
+        match audio_type {
+            0x01 => AudioType::CleanEffects,
+            0x02 => AudioType::HearingImpaired,
+            0x03 => AudioType::VisualImpairedCommentary,
+            _ => AudioType::Undefined,
+        }
+    }
+
+    fn name(self) -> Option<&'static str> {
+        match self {
+            AudioType::Undefined => None,
+            AudioType::CleanEffects => Some("clean-effects"),
+            AudioType::HearingImpaired => Some("hearing-impaired"),
+            AudioType::VisualImpairedCommentary => Some("visual-impaired"),
+        }
+    }
+}
+
+/// A DVB subtitle service listed in a subtitling descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subtitling {
+    pub language: String,
+    pub subtitling_type: u8,
+    pub composition_page: u16,
+    pub ancillary_page: u16,
+}
+
+impl Subtitling {
+    /// Whether the subtitles are for the hard of hearing rather than translations.
+    pub fn is_hard_of_hearing(&self) -> bool {
+        (0x20..=0x25).contains(&self.subtitling_type)
+    }
+}
+
+impl fmt::Display for Subtitling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}/{}",
+            self.language,
+            if self.is_hard_of_hearing() {
+                "hard-of-hearing"
+            } else {
+                "normal"
+            },
+            self.composition_page,
+            self.ancillary_page
+        )
+    }
+}
+
+/// A teletext page listed in a teletext descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TeletextPage {
+    pub language: String,
+    pub teletext_type: u8,
+    /// The page number as shown to viewers, e.g. 888.
+    pub page: u16,
+}
+
+impl TeletextPage {
+    fn type_name(&self) -> &'static str {
+        match self.teletext_type {
+            0x01 => "initial",
+            0x02 => "subtitles",
+            0x03 => "information",
+            0x04 => "schedule",
+            0x05 => "hearing-impaired-subtitles",
+            _ => "other",
+        }
+    }
+}
+
+impl fmt::Display for TeletextPage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.language, self.type_name(), self.page)
+    }
+}
+
+/// An elementary stream of a service.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Component {
+    pub pid: u16,
+    pub stream_type: u8,
+    pub kind: Kind,
+    /// The coding of audio and video streams, e.g. "H.264" or "E-AC-3".
+    pub codec: Option<&'static str>,
+    /// The ISO 639 language code of the stream, if given.
+    pub language: Option<String>,
+    pub audio_type: AudioType,
+    pub subtitles: Vec<Subtitling>,
+    pub teletext: Vec<TeletextPage>,
+}
+
+/// Decode an ISO 639 language code, three ISO 8859-1 letters.
+fn language(data: &[u8]) -> String {
+    data.iter()
+        .map(|&b| (b as char).to_ascii_lowercase())
+        .collect()
+}
+
+/// Parse the 5 byte entries of a teletext or VBI teletext descriptor.
+fn teletext_pages(data: &[u8]) -> Vec<TeletextPage> {
+    data.chunks(5)
+        .filter(|c| c.len() == 5)
+        .map(|c| {
+            // Magazine 0 is shown as 8, and the page number is two hexadecimal digits
+            // which are in practice decimal.
+            let magazine = match c[3] & 0x07 {
+                0 => 8,
+                m => m as u16,
+            };
+            let page = (c[4] >> 4) as u16 * 10 + (c[4] & 0x0f) as u16;
+            TeletextPage {
+                language: language(&c[..3]),
+                teletext_type: c[3] >> 3,
+                page: magazine * 100 + page,
+            }
+        })
+        .collect()
+}
+
+/// Parse the 8 byte entries of a subtitling descriptor.
+fn subtitling(data: &[u8]) -> Vec<Subtitling> {
+    data.chunks(8)
+        .filter(|c| c.len() == 8)
+        .map(|c| Subtitling {
+            language: language(&c[..3]),
+            subtitling_type: c[3],
+            composition_page: ((c[4] as u16) << 8) | c[5] as u16,
+            ancillary_page: ((c[6] as u16) << 8) | c[7] as u16,
+        })
+        .collect()
+}
+
+impl Component {
+    /// Describe an elementary stream of a PMT.
+    pub fn from_stream(stream: &si::PmtStream) -> Component {
+        let (kind, codec) = match stream.stream_type {
+            0x01 => (Kind::Video, Some("MPEG-1")),
+            0x02 => (Kind::Video, Some("MPEG-2")),
+            0x10 => (Kind::Video, Some("MPEG-4")),
+            0x1b => (Kind::Video, Some("H.264")),
+            0x24 => (Kind::Video, Some("H.265")),
+            0x03 | 0x04 => (Kind::Audio, Some("MPEG")),
+            0x0f => (Kind::Audio, Some("AAC")),
+            0x11 => (Kind::Audio, Some("HE-AAC")),
+            0x81 => (Kind::Audio, Some("AC-3")),
+            0x87 => (Kind::Audio, Some("E-AC-3")),
+            _ => (Kind::Data, None),
+        };
+        let mut component = Component {
+            pid: stream.pid,
+            stream_type: stream.stream_type,
+            kind,
+            codec,
+            language: None,
+            audio_type: AudioType::Undefined,
+            subtitles: Vec::new(),
+            teletext: Vec::new(),
+        };
+        let private = stream.stream_type == 0x06;
+        for (tag, content) in si::descriptors(&stream.descriptors) {
+            match tag {
+                ISO_639_LANGUAGE_DESCRIPTOR if content.len() >= 4 => {
+                    component.language = Some(language(&content[..3]));
+                    component.audio_type = AudioType::from_iso_639(content[3]);
+                }
+                AC3_DESCRIPTOR if private => component.set_audio("AC-3"),
+                ENHANCED_AC3_DESCRIPTOR if private => component.set_audio("E-AC-3"),
+                DTS_DESCRIPTOR if private => component.set_audio("DTS"),
+                AAC_DESCRIPTOR if private => component.set_audio("AAC"),
+                SUBTITLING_DESCRIPTOR if private => {
+                    component.kind = Kind::Subtitles;
+                    component.subtitles = subtitling(content);
+                }
+                TELETEXT_DESCRIPTOR | VBI_TELETEXT_DESCRIPTOR if private => {
+                    component.kind = Kind::Teletext;
+                    component.teletext = teletext_pages(content);
+                }
+                EXTENSION_DESCRIPTOR if !content.is_empty() => match content[0] {
+                    AC4_DESCRIPTOR_EXTENSION if private => component.set_audio("AC-4"),
+                    SUPPLEMENTARY_AUDIO_DESCRIPTOR_EXTENSION if content.len() >= 2 => {
+                        // The editorial classification takes precedence over the audio
+                        // type of an ISO 639 language descriptor.
+                        match (content[1] >> 2) & 0x1f {
+                            0x01 => component.audio_type = AudioType::VisualImpairedCommentary,
+                            0x02 => component.audio_type = AudioType::HearingImpaired,
+                            _ => {}
+                        }
+                        if content[1] & 0x01 != 0 && content.len() >= 5 {
+                            component.language = Some(language(&content[2..5]));
+                        }
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        if component.kind == Kind::Subtitles || component.kind == Kind::Teletext {
+            component.language = None;
+        }
+        component
+    }
+
+    fn set_audio(&mut self, codec: &'static str) {
+        self.kind = Kind::Audio;
+        self.codec = Some(codec);
+    }
+}
+
+/// Describe the elementary streams of a PMT.
+pub fn components(pmt: &si::Pmt) -> Vec<Component> {
+    pmt.streams.iter().map(Component::from_stream).collect()
+}
+
+/// Describe a video or audio component as PID, codec, language and audio type.
+fn describe(component: &Component) -> String {
+    let mut fields = vec![
+        component.pid.to_string(),
+        component.codec.unwrap_or("unknown").to_string(),
+    ];
+    if component.kind == Kind::Audio {
+        fields.push(
+            component
+                .language
+                .clone()
+                .unwrap_or_else(|| "und".to_string()),
+        );
+        fields.extend(component.audio_type.name().map(str::to_string));
+    }
+    fields.join(" ")
+}
+
+/// Record the components of a service as annotations of its channels file entry, one
+/// each for video, audio, subtitles and teletext, listing the streams of that kind
+/// separated by commas:
+///
+/// * `VIDEO` – PID and codec, e.g. `101 H.264`.
+/// * `AUDIO` – PID, codec, language and any audio type, e.g. `106 E-AC-3 eng visual-impaired`.
+/// * `SUBTITLES` – PID, language, type and composition/ancillary pages, e.g. `105 eng normal 1/1`.
+/// * `TELETEXT` – PID, language, page type and page, e.g. `104 eng subtitles 888`.
+pub fn annotate(entry: &mut ChannelEntry, components: &[Component]) {
+    let mut video = Vec::new();
+    let mut audio = Vec::new();
+    let mut subtitles = Vec::new();
+    let mut teletext = Vec::new();
+    for component in components {
+        match component.kind {
+            Kind::Video => video.push(describe(component)),
+            Kind::Audio => audio.push(describe(component)),
+            Kind::Subtitles => subtitles.extend(
+                component
+                    .subtitles
+                    .iter()
+                    .map(|s| format!("{} {}", component.pid, s)),
+            ),
+            Kind::Teletext => teletext.extend(
+                component
+                    .teletext
+                    .iter()
+                    .map(|t| format!("{} {}", component.pid, t)),
+            ),
+            Kind::Data => {}
+        }
+    }
+    for (key, values) in &[
+        ("VIDEO", video),
+        ("AUDIO", audio),
+        ("SUBTITLES", subtitles),
+        ("TELETEXT", teletext),
+    ] {
+        if !values.is_empty() {
+            entry.set_annotation(key, &values.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn stream(stream_type: u8, pid: u16, descriptors: &[u8]) -> si::PmtStream {
+        si::PmtStream {
+            stream_type,
+            pid,
+            descriptors: descriptors.to_vec(),
+        }
+    }
+
+    #[test]
+    fn components_of_a_pmt() {
+        let pmt = si::Pmt {
+            program_number: 4164,
+            pcr_pid: 101,
+            streams: vec![
+                stream(0x1b, 101, &[]),
+                stream(0x03, 102, &[0x0a, 0x04, b'e', b'n', b'g', 0x00]),
+                stream(
+                    0x06,
+                    106,
+                    &[
+                        0x0a, 0x04, b'e', b'n', b'g', 0x03, // language, visually impaired
+                        0x7a, 0x01, 0x00, // E-AC-3
+                    ],
+                ),
+                stream(
+                    0x06,
+                    105,
+                    &[0x59, 0x08, b'e', b'n', b'g', 0x20, 0x00, 0x01, 0x00, 0x02],
+                ),
+                stream(0x06, 104, &[0x56, 0x05, b'e', b'n', b'g', 0x10, 0x88]),
+                stream(0x0b, 150, &[]),
+            ],
+            ..si::Pmt::default()
+        };
+        let components = components(&pmt);
+        assert_eq!(components.len(), 6);
+        assert_eq!(components[2].kind, Kind::Audio);
+        assert_eq!(components[2].codec, Some("E-AC-3"));
+        assert_eq!(
+            components[2].audio_type,
+            AudioType::VisualImpairedCommentary
+        );
+        assert!(components[3].subtitles[0].is_hard_of_hearing());
+        assert_eq!(components[4].teletext[0].page, 888);
+        assert_eq!(components[5].kind, Kind::Data);
+        let mut entry = ChannelEntry::new("BBC ONE Lon");
+        annotate(&mut entry, &components);
+        assert_eq!(entry.get_annotation("VIDEO"), Some("101 H.264"));
+        assert_eq!(
+            entry.get_annotation("AUDIO"),
+            Some("102 MPEG eng, 106 E-AC-3 eng visual-impaired")
+        );
+        assert_eq!(
+            entry.get_annotation("SUBTITLES"),
+            Some("105 eng hard-of-hearing 1/2")
+        );
+        assert_eq!(
+            entry.get_annotation("TELETEXT"),
+            Some("104 eng subtitles 888")
+        );
+    }
+}
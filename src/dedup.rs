@@ -0,0 +1,130 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Removal of services received more than once, for example from two transmitters with
+//! overlapping coverage.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::channels_file::{ChannelEntry, ChannelsFile, ServiceTriplet};
+use crate::signal::LockStatistics;
+
+/// Reduce the services of a channels file to one entry per DVB triplet.
+///
+/// Of the entries for a service the one whose transponder had the best statistics at
+/// lock, as given by `statistics`, is kept, the earliest winning a tie. The frequencies
+/// of the other entries are recorded on the kept entry as alternative frequencies.
+/// Entries without a triplet are kept as is.
+pub fn deduplicate<F>(file: &ChannelsFile, statistics: F) -> ChannelsFile
+where
+    F: Fn(&ChannelEntry) -> Option<LockStatistics>,
+{
+    let mut groups: Vec<Vec<&ChannelEntry>> = Vec::new();
+    let mut positions: HashMap<ServiceTriplet, usize> = HashMap::new();
+    for entry in &file.entries {
+        match entry.triplet() {
+            Some(triplet) => match positions.get(&triplet) {
+                Some(&position) => groups[position].push(entry),
+                None => {
+                    positions.insert(triplet, groups.len());
+                    groups.push(vec![entry]);
+                }
+            },
+            None => groups.push(vec![entry]),
+        }
+    }
+    let entries = groups
+        .into_iter()
+        .map(|group| {
+            let mut best = group[0];
+            let mut best_statistics = statistics(best);
+            for &candidate in &group[1..] {
+                let candidate_statistics = statistics(candidate);
+                let better = match (&candidate_statistics, &best_statistics) {
+                    (Some(c), Some(b)) => c.compare(b) == Ordering::Greater,
+                    (Some(_), None) => true,
+                    _ => false,
+                };
+                if better {
+                    best = candidate;
+                    best_statistics = candidate_statistics;
+                }
+            }
+            let mut alternatives = Vec::new();
+            for entry in &group {
+                let frequencies = entry
+                    .frequency()
+                    .into_iter()
+                    .chain(entry.alternative_frequencies());
+                for frequency in frequencies {
+                    if Some(frequency) != best.frequency() && !alternatives.contains(&frequency) {
+                        alternatives.push(frequency);
+                    }
+                }
+            }
+            let mut kept = best.clone();
+            kept.set_alternative_frequencies(&alternatives);
+            kept
+        })
+        .collect();
+    ChannelsFile { entries }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::channels_file::tests::service;
+    use crate::signal::{Measurement, Quality};
+
+    #[test]
+    fn best_copy_is_kept_with_alternatives() {
+        let file = ChannelsFile {
+            entries: vec![
+                service("BBC ONE", "4164", "490000000"),
+                service("BBC TWO", "4287", "490000000"),
+                service("BBC ONE", "4164", "506000000"),
+                service("BBC TWO", "4287", "506000000"),
+            ],
+        };
+        let deduplicated = deduplicate(&file, |entry| {
+            Some(LockStatistics {
+                quality: Quality::Good,
+                strength: None,
+                cnr: match entry.frequency() {
+                    Some(506000000) => Some(Measurement::Decibel(25.0)),
+                    _ => Some(Measurement::Decibel(18.5)),
+                },
+                ber: None,
+                ucb: None,
+                layers: [None; 3],
+            })
+        });
+        assert_eq!(deduplicated.entries.len(), 2);
+        assert_eq!(deduplicated.entries[0].name, "BBC ONE");
+        assert_eq!(deduplicated.entries[0].frequency(), Some(506000000));
+        assert_eq!(
+            deduplicated.entries[0].alternative_frequencies(),
+            vec![490000000]
+        );
+        assert_eq!(deduplicated.entries[1].frequency(), Some(506000000));
+    }
+}
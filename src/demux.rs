@@ -0,0 +1,310 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Reading PSI/SI sections from the demux device of a tuned frontend, and passing the
+//! packets of PIDs to the DVR device.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::os::raw::c_int;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::si::{Section, SectionCollector};
+
+/// The largest possible section.
+const MAXIMUM_SECTION_SIZE: usize = 4096;
+
+/// The pseudo PID that passes every packet of the transport stream.
+pub const ALL_PIDS: u16 = 0x2000;
+
+/// The demux buffer size for a filter passing the whole transport stream, larger than the
+/// driver default to avoid overflows at high bit rates.
+const TRANSPORT_STREAM_BUFFER_SIZE: i32 = 2 * 1024 * 1024;
+
+/// Open the demux device of a frontend.
+fn open(frontend_id: &dvbv5::FrontendId) -> Result<c_int, ()> {
+    let fd = unsafe {
+        dvbv5_sys::dvb_dmx_open(
+            frontend_id.adapter_number as i32,
+            frontend_id.frontend_number as i32,
+        )
+    };
+    if fd < 0 {
+        Err(())
+    } else {
+        Ok(fd)
+    }
+}
+
+/// The path of the DVR device to which PES filters of a frontend pass packets.
+pub fn dvr_path(frontend_id: &dvbv5::FrontendId) -> PathBuf {
+    PathBuf::from(format!(
+        "/dev/dvb/adapter{}/dvr{}",
+        frontend_id.adapter_number, frontend_id.frontend_number
+    ))
+}
+
+/// Find the PID of the PMT of a service from the PAT of the transport stream a frontend
+/// is tuned to.
+pub fn pmt_pid(frontend_id: &dvbv5::FrontendId, service_id: u16) -> Option<u16> {
+    let fd = open(frontend_id).ok()?;
+    let pid = unsafe {
+        let pid = dvbv5_sys::dvb_get_pmt_pid(fd, service_id as i32);
+        dvbv5_sys::dvb_dmx_close(fd);
+        pid
+    };
+    if pid > 0 {
+        Some(pid as u16)
+    } else {
+        None
+    }
+}
+
+/// A wrapper around the file descriptor of a demux device on which libdvbv5 sets its own
+/// filters, providing automated RAII.
+#[derive(Debug)]
+pub struct Demux {
+    fd: c_int,
+}
+
+impl Demux {
+    /// Open the demux device of a frontend.
+    pub fn open(frontend_id: &dvbv5::FrontendId) -> Result<Demux, ()> {
+        Ok(Demux {
+            fd: open(frontend_id)?,
+        })
+    }
+
+    pub fn fd(&self) -> c_int {
+        self.fd
+    }
+}
+
+impl Drop for Demux {
+    fn drop(&mut self) {
+        unsafe {
+            dvbv5_sys::dvb_dmx_close(self.fd);
+        }
+    }
+}
+
+/// A wrapper around the file descriptor of a demux device with a section filter set,
+/// providing automated RAII.
+#[derive(Debug)]
+pub struct SectionFilter {
+    fd: c_int,
+}
+
+impl SectionFilter {
+    /// Open the demux device of a frontend and filter for sections with the given table id
+    /// on the given PID.
+    pub fn new(
+        frontend_id: &dvbv5::FrontendId,
+        pid: u16,
+        table_id: u8,
+    ) -> Result<SectionFilter, ()> {
+        SectionFilter::with_mask(frontend_id, pid, table_id, 0xff)
+    }
+
+    /// Open the demux device of a frontend and filter for sections on the given PID with
+    /// a table id matching `table_id` in the bits set in `mask`.
+    pub fn with_mask(
+        frontend_id: &dvbv5::FrontendId,
+        pid: u16,
+        table_id: u8,
+        mask: u8,
+    ) -> Result<SectionFilter, ()> {
+        let fd = open(frontend_id)?;
+        let filter = SectionFilter { fd };
+        let mut value = [table_id];
+        let mut mask = [mask];
+        if unsafe {
+            dvbv5_sys::dvb_set_section_filter(
+                fd,
+                pid as i32,
+                1,
+                value.as_mut_ptr(),
+                mask.as_mut_ptr(),
+                std::ptr::null_mut(),
+                dvbv5_sys::DMX_CHECK_CRC | dvbv5_sys::DMX_IMMEDIATE_START,
+            )
+        } < 0
+        {
+            return Err(());
+        }
+        Ok(filter)
+    }
+
+    /// Read the next section, waiting at most `timeout` for one to arrive.
+    pub fn read_section(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, timeout.as_millis() as i32) } <= 0 {
+            return None;
+        }
+        let mut buffer = vec![0u8; MAXIMUM_SECTION_SIZE];
+        let count = unsafe {
+            libc::read(
+                self.fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if count <= 0 {
+            return None;
+        }
+        buffer.truncate(count as usize);
+        Some(buffer)
+    }
+
+    /// Gather sections until every table seen is complete or `timeout` has passed.
+    /// Sections that fail to parse are skipped.
+    pub fn collect(&self, timeout: Duration) -> SectionCollector {
+        self.collect_until(timeout, SectionCollector::is_complete)
+    }
+
+    /// Gather sections until a whole cycle of the tables broadcast has been seen, with
+    /// every table complete, or `timeout` has passed. For filters passing several tables,
+    /// the NITs of other networks or the BATs of several bouquets say, where the first
+    /// tables may be complete before any section of the others arrives.
+    pub fn collect_cycle(&self, timeout: Duration) -> SectionCollector {
+        self.collect_until(timeout, SectionCollector::is_cycle_complete)
+    }
+
+    fn collect_until(
+        &self,
+        timeout: Duration,
+        is_done: fn(&SectionCollector) -> bool,
+    ) -> SectionCollector {
+        let mut collector = SectionCollector::new();
+        let deadline = Instant::now() + timeout;
+        while !is_done(&collector) {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match self.read_section(deadline - now) {
+                Some(data) => {
+                    if let Ok(section) = Section::parse(&data) {
+                        collector.add(section);
+                    }
+                }
+                None => break,
+            }
+        }
+        collector
+    }
+}
+
+impl Drop for SectionFilter {
+    fn drop(&mut self) {
+        unsafe {
+            dvbv5_sys::dvb_dmx_close(self.fd);
+        }
+    }
+}
+
+/// A wrapper around the file descriptor of a demux device with a PES filter set, passing
+/// the packets of one PID, or of all PIDs, to the DVR device of the adapter.
+#[derive(Debug)]
+pub struct PesFilter {
+    fd: c_int,
+}
+
+impl PesFilter {
+    /// Open the demux device of a frontend and pass the packets of `pid` to the DVR device.
+    /// `ALL_PIDS` passes the whole transport stream.
+    pub fn new(frontend_id: &dvbv5::FrontendId, pid: u16) -> Result<PesFilter, ()> {
+        let fd = open(frontend_id)?;
+        let filter = PesFilter { fd };
+        let buffer_size = if pid == ALL_PIDS {
+            TRANSPORT_STREAM_BUFFER_SIZE
+        } else {
+            0
+        };
+        if unsafe {
+            dvbv5_sys::dvb_set_pesfilter(
+                fd,
+                pid as i32,
+                dvbv5_sys::dmx_ts_pes::DMX_PES_OTHER,
+                dvbv5_sys::dmx_output::DMX_OUT_TS_TAP,
+                buffer_size,
+            )
+        } < 0
+        {
+            return Err(());
+        }
+        Ok(filter)
+    }
+}
+
+impl Drop for PesFilter {
+    fn drop(&mut self) {
+        unsafe {
+            dvbv5_sys::dvb_dmx_close(self.fd);
+        }
+    }
+}
+
+/// The DVR device of an adapter, from which the packets passed by PES filters are read.
+/// Reads wait at most `timeout` for data, returning a `TimedOut` error if none arrives, so
+/// that a reader can check for being stopped.
+#[derive(Debug)]
+pub struct Dvr {
+    file: File,
+    timeout: Duration,
+}
+
+impl Dvr {
+    pub fn open(frontend_id: &dvbv5::FrontendId, timeout: Duration) -> io::Result<Dvr> {
+        Ok(Dvr {
+            file: File::open(dvr_path(frontend_id))?,
+            timeout,
+        })
+    }
+}
+
+impl Read for Dvr {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll_fd, 1, self.timeout.as_millis() as i32) } {
+            0 => Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "no data from the DVR device",
+            )),
+            n if n < 0 => Err(io::Error::last_os_error()),
+            _ => match self.file.read(buffer) {
+                // The driver buffer overflowed and packets were lost, the stream continues.
+                Err(e) if e.raw_os_error() == Some(libc::EOVERFLOW) => {
+                    Err(io::Error::new(ErrorKind::Interrupted, e))
+                }
+                result => result,
+            },
+        }
+    }
+}
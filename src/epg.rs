@@ -0,0 +1,593 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Gathering the electronic programme guide from the EIT and writing it as XMLTV.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::demux::SectionFilter;
+use crate::si::{self, Eit, EitEvent, Sdt, Section};
+use crate::text::{self, Charset};
+use crate::ts::{PacketSplitter, SectionAssembler};
+
+/// Descriptor tags used in this module.
+const SHORT_EVENT_DESCRIPTOR: u8 = 0x4d;
+const EXTENDED_EVENT_DESCRIPTOR: u8 = 0x4e;
+const CONTENT_DESCRIPTOR: u8 = 0x54;
+const PARENTAL_RATING_DESCRIPTOR: u8 = 0x55;
+
+/// How long to wait for the SDTs giving the service names.
+const SDT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The amount of a capture read at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// The content genres of EN 300 468 table 28 by content nibbles. Level 2 nibble 0 is the
+/// general genre of level 1.
+const GENRES: &[(u8, &str)] = &[
+    (0x10, "Movie / Drama"),
+    (0x11, "Detective / Thriller"),
+    (0x12, "Adventure / Western / War"),
+    (0x13, "Science fiction / Fantasy / Horror"),
+    (0x14, "Comedy"),
+    (0x15, "Soap / Melodrama / Folklore"),
+    (0x16, "Romance"),
+    (
+        0x17,
+        "Serious / Classical / Religious / Historical movie / Drama",
+    ),
+    (0x18, "Adult movie / Drama"),
+    (0x20, "News / Current affairs"),
+    (0x21, "News / Weather report"),
+    (0x22, "News magazine"),
+    (0x23, "Documentary"),
+    (0x24, "Discussion / Interview / Debate"),
+    (0x30, "Show / Game show"),
+    (0x31, "Game show / Quiz / Contest"),
+    (0x32, "Variety show"),
+    (0x33, "Talk show"),
+    (0x40, "Sports"),
+    (0x41, "Special events"),
+    (0x42, "Sports magazines"),
+    (0x43, "Football / Soccer"),
+    (0x44, "Tennis / Squash"),
+    (0x45, "Team sports"),
+    (0x46, "Athletics"),
+    (0x47, "Motor sport"),
+    (0x48, "Water sport"),
+    (0x49, "Winter sports"),
+    (0x4a, "Equestrian"),
+    (0x4b, "Martial sports"),
+    (0x50, "Children's / Youth programmes"),
+    (0x51, "Pre-school children's programmes"),
+    (0x52, "Entertainment programmes for 6 to 14"),
+    (0x53, "Entertainment programmes for 10 to 16"),
+    (0x54, "Informational / Educational / School programmes"),
+    (0x55, "Cartoons / Puppets"),
+    (0x60, "Music / Ballet / Dance"),
+    (0x61, "Rock / Pop"),
+    (0x62, "Serious music / Classical music"),
+    (0x63, "Folk / Traditional music"),
+    (0x64, "Jazz"),
+    (0x65, "Musical / Opera"),
+    (0x66, "Ballet"),
+    (0x70, "Arts / Culture"),
+    (0x71, "Performing arts"),
+    (0x72, "Fine arts"),
+    (0x73, "Religion"),
+    (0x74, "Popular culture / Traditional arts"),
+    (0x75, "Literature"),
+    (0x76, "Film / Cinema"),
+    (0x77, "Experimental film / Video"),
+    (0x78, "Broadcasting / Press"),
+    (0x79, "New media"),
+    (0x7a, "Arts / Culture magazines"),
+    (0x7b, "Fashion"),
+    (0x80, "Social / Political issues / Economics"),
+    (0x81, "Magazines / Reports / Documentary"),
+    (0x82, "Economics / Social advisory"),
+    (0x83, "Remarkable people"),
+    (0x90, "Education / Science / Factual topics"),
+    (0x91, "Nature / Animals / Environment"),
+    (0x92, "Technology / Natural sciences"),
+    (0x93, "Medicine / Physiology / Psychology"),
+    (0x94, "Foreign countries / Expeditions"),
+    (0x95, "Social / Spiritual sciences"),
+    (0x96, "Further education"),
+    (0x97, "Languages"),
+    (0xa0, "Leisure hobbies"),
+    (0xa1, "Tourism / Travel"),
+    (0xa2, "Handicraft"),
+    (0xa3, "Motoring"),
+    (0xa4, "Fitness and health"),
+    (0xa5, "Cooking"),
+    (0xa6, "Advertisement / Shopping"),
+    (0xa7, "Gardening"),
+    (0xb0, "Original language"),
+    (0xb1, "Black and white"),
+    (0xb2, "Unpublished"),
+    (0xb3, "Live broadcast"),
+    (0xb4, "Plano-stereoscopic"),
+    (0xb5, "Local or regional"),
+];
+
+/// The genre of a content descriptor's content nibbles, falling back to the level 1 genre
+/// for level 2 nibbles not defined.
+pub fn genre(nibbles: u8) -> Option<&'static str> {
+    let find = |nibbles| {
+        GENRES
+            .iter()
+            .find(|(n, _)| *n == nibbles)
+            .map(|(_, name)| *name)
+    };
+    find(nibbles).or_else(|| find(nibbles & 0xf0))
+}
+
+/// A service, identified by its DVB triplet.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ServiceKey {
+    pub original_network_id: u16,
+    pub transport_stream_id: u16,
+    pub service_id: u16,
+}
+
+impl ServiceKey {
+    /// The XMLTV channel id of the service.
+    pub fn channel_id(&self) -> String {
+        format!(
+            "{}.{}.{}.dvb",
+            self.original_network_id, self.transport_stream_id, self.service_id
+        )
+    }
+}
+
+/// A programme.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Event {
+    /// Start time in seconds since the Unix epoch.
+    pub start: i64,
+    /// Duration in seconds.
+    pub duration: u32,
+    /// The ISO 639-2 code of the language of the texts.
+    pub language: String,
+    pub title: String,
+    /// The short event text, typically a synopsis.
+    pub short_text: String,
+    /// The extended event text and items.
+    pub extended_text: String,
+    pub genres: Vec<&'static str>,
+    /// Minimum age by ISO 3166 country code.
+    pub parental_ratings: Vec<(String, u8)>,
+}
+
+impl Event {
+    /// Build an event from an EIT event, if it has a start time and duration.
+    fn from_eit_event(eit_event: &EitEvent, charset_override: Option<Charset>) -> Option<Event> {
+        let mut event = Event {
+            start: eit_event.start_time?,
+            duration: eit_event.duration?,
+            ..Event::default()
+        };
+        let mut extended: BTreeMap<String, BTreeMap<u8, String>> = BTreeMap::new();
+        for (tag, content) in si::descriptors(&eit_event.descriptors) {
+            match tag {
+                // Only the first language broadcast is used.
+                SHORT_EVENT_DESCRIPTOR if content.len() >= 5 && event.language.is_empty() => {
+                    event.language = String::from_utf8_lossy(&content[..3]).into_owned();
+                    let name_length = content[3] as usize;
+                    let name = content.get(4..4 + name_length).unwrap_or(&[]);
+                    event.title = text::decode_name(name, charset_override);
+                    let rest = content.get(4 + name_length..).unwrap_or(&[]);
+                    if let Some((length, data)) = rest.split_first() {
+                        let data = data.get(..*length as usize).unwrap_or(data);
+                        event.short_text = text::decode(data, charset_override);
+                    }
+                }
+                EXTENDED_EVENT_DESCRIPTOR if content.len() >= 6 => {
+                    let language = String::from_utf8_lossy(&content[1..4]).into_owned();
+                    extended.entry(language).or_default().insert(
+                        content[0] >> 4,
+                        extended_event_text(&content[4..], charset_override),
+                    );
+                }
+                CONTENT_DESCRIPTOR => {
+                    for nibbles in content.chunks(2).map(|c| c[0]) {
+                        if let Some(genre) = genre(nibbles) {
+                            if !event.genres.contains(&genre) {
+                                event.genres.push(genre);
+                            }
+                        }
+                    }
+                }
+                PARENTAL_RATING_DESCRIPTOR => {
+                    for rating in content.chunks(4).filter(|c| c.len() == 4) {
+                        // 0x01 to 0x0f give a minimum age of the rating plus 3; others are
+                        // undefined or broadcaster defined.
+                        if (0x01..=0x0f).contains(&rating[3]) {
+                            event.parental_ratings.push((
+                                String::from_utf8_lossy(&rating[..3]).into_owned(),
+                                rating[3] + 3,
+                            ));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let extended = extended
+            .remove(&event.language)
+            .or_else(|| extended.into_iter().next().map(|(_, texts)| texts));
+        if let Some(texts) = extended {
+            event.extended_text = texts.into_values().collect::<String>().trim().to_string();
+        }
+        Some(event)
+    }
+}
+
+/// Decode the items and text of an extended event descriptor, starting at the length of
+/// the items.
+fn extended_event_text(data: &[u8], charset_override: Option<Charset>) -> String {
+    let mut result = String::new();
+    let items_length = data[0] as usize;
+    let mut items = data.get(1..1 + items_length).unwrap_or(&[]);
+    while let Some((length, rest)) = items.split_first() {
+        let (description, rest) = rest.split_at((*length as usize).min(rest.len()));
+        let (item, rest) = match rest.split_first() {
+            Some((length, rest)) => rest.split_at((*length as usize).min(rest.len())),
+            None => (&[][..], rest),
+        };
+        result += &format!(
+            "{}: {}\n",
+            text::decode_name(description, charset_override),
+            text::decode_name(item, charset_override)
+        );
+        items = rest;
+    }
+    if let Some((length, rest)) = data.get(1 + items_length..).and_then(|d| d.split_first()) {
+        result += &text::decode(
+            rest.get(..*length as usize).unwrap_or(rest),
+            charset_override,
+        );
+    }
+    result
+}
+
+/// The programme guide gathered from EIT and SDT sections.
+#[derive(Debug, Default)]
+pub struct Epg {
+    charset_override: Option<Charset>,
+    /// Table id, service, section number and version of each EIT section added.
+    sections_seen: BTreeSet<(u8, ServiceKey, u8, u8)>,
+    /// Service names from the SDTs.
+    names: BTreeMap<ServiceKey, String>,
+    /// Events by event id of each service.
+    events: BTreeMap<ServiceKey, BTreeMap<u16, Event>>,
+}
+
+impl Epg {
+    pub fn new(charset_override: Option<Charset>) -> Epg {
+        Epg {
+            charset_override,
+            ..Epg::default()
+        }
+    }
+
+    /// The number of events gathered.
+    pub fn event_count(&self) -> usize {
+        self.events.values().map(|events| events.len()).sum()
+    }
+
+    /// Add an EIT or SDT section; sections of other tables are ignored.
+    pub fn add_section(&mut self, section: &Section) {
+        if !section.current_next {
+            return;
+        }
+        if section.table_id == si::SDT_ACTUAL_TABLE_ID || section.table_id == si::SDT_OTHER_TABLE_ID
+        {
+            if let Ok(sdt) = Sdt::parse(&[section]) {
+                for service in &sdt.services {
+                    let key = ServiceKey {
+                        original_network_id: sdt.original_network_id,
+                        transport_stream_id: sdt.transport_stream_id,
+                        service_id: service.service_id,
+                    };
+                    let name = text::decode_name(&service.service_name, self.charset_override);
+                    if !name.is_empty() {
+                        self.names.insert(key, name);
+                    }
+                }
+            }
+        } else if si::EIT_TABLE_IDS.contains(&section.table_id) {
+            let eit = match Eit::parse(section) {
+                Ok(eit) => eit,
+                Err(_) => return,
+            };
+            let key = ServiceKey {
+                original_network_id: eit.original_network_id,
+                transport_stream_id: eit.transport_stream_id,
+                service_id: eit.service_id,
+            };
+            // Sections are repeated continually, only new ones need parsing.
+            if !self.sections_seen.insert((
+                section.table_id,
+                key,
+                section.section_number,
+                section.version,
+            )) {
+                return;
+            }
+            let events = self.events.entry(key).or_default();
+            for eit_event in &eit.events {
+                if let Some(event) = Event::from_eit_event(eit_event, self.charset_override) {
+                    events.insert(eit_event.event_id, event);
+                }
+            }
+        }
+    }
+
+    /// Gather the SDTs and then the EIT sections of the transport stream a frontend is
+    /// tuned to, for `duration`.
+    pub fn collect_frontend(
+        &mut self,
+        frontend_id: &dvbv5::FrontendId,
+        duration: Duration,
+    ) -> Result<(), String> {
+        let error = |_| "could not set the demux filter".to_string();
+        // The actual and other SDT table ids differ only in bit 2.
+        let filter =
+            SectionFilter::with_mask(frontend_id, si::SDT_PID, si::SDT_ACTUAL_TABLE_ID, 0xfb)
+                .map_err(error)?;
+        for table in filter.collect(SDT_TIMEOUT).tables() {
+            for section in table {
+                self.add_section(section);
+            }
+        }
+        drop(filter);
+        let filter =
+            SectionFilter::with_mask(frontend_id, si::EIT_PID, 0x40, 0xc0).map_err(error)?;
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            if let Some(data) = filter.read_section(deadline - now) {
+                if let Ok(section) = Section::parse(&data) {
+                    self.add_section(&section);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gather the SDT and EIT sections of a transport stream capture.
+    pub fn collect_capture<R: Read>(&mut self, mut source: R) -> io::Result<()> {
+        let mut splitter = PacketSplitter::new();
+        let mut sdt_assembler = SectionAssembler::new();
+        let mut eit_assembler = SectionAssembler::new();
+        let mut buffer = vec![0u8; READ_SIZE];
+        loop {
+            let count = match source.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(count) => count,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            splitter.push(&buffer[..count], |packet| {
+                let sections = match packet.pid() {
+                    si::SDT_PID => sdt_assembler.push(&packet),
+                    si::EIT_PID => eit_assembler.push(&packet),
+                    _ => return,
+                };
+                for data in sections {
+                    if let Ok(section) = Section::parse(&data) {
+                        self.add_section(&section);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Write the guide as an XMLTV document.
+    pub fn to_xmltv(&self) -> String {
+        let mut xml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                       <!DOCTYPE tv SYSTEM \"xmltv.dtd\">\n\
+                       <tv generator-info-name=\"dvb-tune\">\n"
+            .to_string();
+        let services = self
+            .names
+            .keys()
+            .chain(self.events.keys())
+            .collect::<BTreeSet<_>>();
+        for key in &services {
+            xml += &format!("  <channel id=\"{}\">\n", key.channel_id());
+            let name = self
+                .names
+                .get(key)
+                .cloned()
+                .unwrap_or_else(|| key.channel_id());
+            xml += &format!("    <display-name>{}</display-name>\n", escape(&name));
+            xml += "  </channel>\n";
+        }
+        for (key, events) in &self.events {
+            let mut events = events.values().collect::<Vec<_>>();
+            events.sort_by_key(|event| event.start);
+            for event in events {
+                xml += &format!(
+                    "  <programme start=\"{}\" stop=\"{}\" channel=\"{}\">\n",
+                    xmltv_time(event.start),
+                    xmltv_time(event.start + event.duration as i64),
+                    key.channel_id()
+                );
+                let element = |name: &str, content: &str| {
+                    format!(
+                        "    <{0} lang=\"{1}\">{2}</{0}>\n",
+                        name,
+                        escape(&event.language),
+                        escape(content)
+                    )
+                };
+                xml += &element("title", &event.title);
+                // With both texts the short one is the sub-title and the extended one the
+                // description, else the short one is the description.
+                if event.extended_text.is_empty() {
+                    if !event.short_text.is_empty() {
+                        xml += &element("desc", &event.short_text);
+                    }
+                } else {
+                    if !event.short_text.is_empty() {
+                        xml += &element("sub-title", &event.short_text);
+                    }
+                    xml += &element("desc", &event.extended_text);
+                }
+                for genre in &event.genres {
+                    xml += &format!("    <category lang=\"en\">{}</category>\n", escape(genre));
+                }
+                for (country, age) in &event.parental_ratings {
+                    xml += &format!(
+                        "    <rating system=\"DVB:{}\">\n      <value>{}</value>\n    </rating>\n",
+                        escape(country),
+                        age
+                    );
+                }
+                xml += "  </programme>\n";
+            }
+        }
+        xml += "</tv>\n";
+        xml
+    }
+}
+
+/// Format a time in seconds since the Unix epoch as an XMLTV time in UTC.
+pub fn xmltv_time(time: i64) -> String {
+    let (year, month, day, hour, minute, second) = clock::civil_time(time);
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02} +0000",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Escape text for XML content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            // Control characters other than white space are not allowed in XML 1.0.
+            c if c.is_control() && c != '\n' && c != '\t' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::si::tests::make_section;
+    use crate::ts;
+
+    /// Build the descriptor loop of an event.
+    fn make_event_descriptors() -> Vec<u8> {
+        let mut descriptors = Vec::new();
+        let mut add = |tag: u8, content: &[u8]| {
+            descriptors.extend_from_slice(&[tag, content.len() as u8]);
+            descriptors.extend_from_slice(content);
+        };
+        add(
+            SHORT_EVENT_DESCRIPTOR,
+            b"eng\x04News\x14Headlines & \x8aweather",
+        );
+        add(
+            EXTENDED_EVENT_DESCRIPTOR,
+            b"\x00eng\x0f\x09Presenter\x04Jane\x06<More>",
+        );
+        add(CONTENT_DESCRIPTOR, &[0x21, 0x00, 0x2f, 0x00]);
+        add(PARENTAL_RATING_DESCRIPTOR, b"GBR\x09");
+        descriptors
+    }
+
+    fn make_capture() -> Vec<u8> {
+        let descriptors = make_event_descriptors();
+        let mut payload = vec![0x10, 0x44, 0x23, 0x3a, 0x00, 0x4e];
+        payload.extend_from_slice(&[0x00, 0x01, 0xc0, 0x79, 0x12, 0x45, 0x00, 0x00, 0x30, 0x00]);
+        payload.push(0x80 | (descriptors.len() >> 8) as u8);
+        payload.push(descriptors.len() as u8);
+        payload.extend_from_slice(&descriptors);
+        let eit = make_section(0x4e, 4164, 0, &payload);
+        let mut sdt_payload = vec![0x23, 0x3a, 0xff, 0x10, 0x44, 0xfd, 0x80, 0x0c];
+        sdt_payload.extend_from_slice(&[0x48, 0x0a, 0x01, 0x00, 0x07]);
+        sdt_payload.extend_from_slice(b"BBC ONE");
+        let sdt = make_section(si::SDT_ACTUAL_TABLE_ID, 4164, 0, &sdt_payload);
+        let mut capture = Vec::new();
+        let (mut eit_counter, mut sdt_counter) = (0x0f, 0x0f);
+        // Repeated as broadcast.
+        for _ in 0..2 {
+            capture.extend(ts::packetize_section(si::EIT_PID, &eit, &mut eit_counter));
+            capture.extend(ts::packetize_section(si::SDT_PID, &sdt, &mut sdt_counter));
+        }
+        capture
+    }
+
+    #[test]
+    fn epg_from_capture_to_xmltv() {
+        let mut epg = Epg::new(None);
+        epg.collect_capture(Cursor::new(make_capture())).unwrap();
+        assert_eq!(epg.event_count(), 1);
+        assert_eq!(
+            epg.to_xmltv(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE tv SYSTEM \"xmltv.dtd\">\n\
+             <tv generator-info-name=\"dvb-tune\">\n  \
+             <channel id=\"9018.4164.4164.dvb\">\n    \
+             <display-name>BBC ONE</display-name>\n  \
+             </channel>\n  \
+             <programme start=\"19931013124500 +0000\" stop=\"19931013131500 +0000\" \
+             channel=\"9018.4164.4164.dvb\">\n    \
+             <title lang=\"eng\">News</title>\n    \
+             <sub-title lang=\"eng\">Headlines &amp; \nweather</sub-title>\n    \
+             <desc lang=\"eng\">Presenter: Jane\n&lt;More&gt;</desc>\n    \
+             <category lang=\"en\">News / Weather report</category>\n    \
+             <category lang=\"en\">News / Current affairs</category>\n    \
+             <rating system=\"DVB:GBR\">\n      <value>12</value>\n    </rating>\n  \
+             </programme>\n\
+             </tv>\n"
+        );
+    }
+
+    #[test]
+    fn genres_and_times() {
+        assert_eq!(genre(0x43), Some("Football / Soccer"));
+        assert_eq!(genre(0x4f), Some("Sports"));
+        assert_eq!(genre(0xf0), None);
+        assert_eq!(xmltv_time(0), "19700101000000 +0000");
+        assert_eq!(xmltv_time(951_825_600), "20000229120000 +0000");
+    }
+}
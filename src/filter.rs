@@ -0,0 +1,304 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Selecting which services of a channels file to keep, and which transponders announced
+//! in NITs a scan follows.
+
+use regex::Regex;
+
+use crate::channels_file::{ChannelEntry, ChannelsFile};
+use crate::si::Nit;
+
+/// The broad kinds of service.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServiceKind {
+    Television,
+    Radio,
+    Data,
+}
+
+impl ServiceKind {
+    /// Classify a service type from a service descriptor (EN 300 468 table 87).
+    pub fn from_service_type(service_type: u8) -> ServiceKind {
+        match service_type {
+            0x01 | 0x04 | 0x05 | 0x11 | 0x16..=0x1f | 0x20 => ServiceKind::Television,
+            0x02 | 0x07 | 0x0a => ServiceKind::Radio,
+            _ => ServiceKind::Data,
+        }
+    }
+
+    /// Classify the service of a channels file entry. The `SERVICE_TYPE` annotation is
+    /// used if present, otherwise the presence of video and audio PIDs is used.
+    pub fn of(entry: &ChannelEntry) -> ServiceKind {
+        match entry
+            .get_annotation("SERVICE_TYPE")
+            .and_then(|v| v.parse().ok())
+        {
+            Some(service_type) => ServiceKind::from_service_type(service_type),
+            None => {
+                if entry.get("VIDEO_PID").is_some() {
+                    ServiceKind::Television
+                } else if entry.get("AUDIO_PID").is_some() {
+                    ServiceKind::Radio
+                } else {
+                    ServiceKind::Data
+                }
+            }
+        }
+    }
+}
+
+/// Criteria a service must meet to be kept. Empty lists impose no restriction.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceFilter {
+    /// The kinds of service to keep.
+    pub kinds: Vec<ServiceKind>,
+    /// Keep only services that are not scrambled.
+    pub free_to_air_only: bool,
+    /// Keep only services that are scrambled.
+    pub scrambled_only: bool,
+    /// Drop ISDB-T one-seg services.
+    pub exclude_one_seg: bool,
+    /// Keep only ISDB-T one-seg services.
+    pub one_seg_only: bool,
+    /// Keep only services whose name matches one of these.
+    pub names: Vec<Regex>,
+    /// Drop services whose name matches one of these.
+    pub excluded_names: Vec<Regex>,
+    /// Keep only services whose provider name matches one of these.
+    pub providers: Vec<Regex>,
+    /// Keep only services with one of these original network ids.
+    pub network_ids: Vec<u16>,
+}
+
+impl ServiceFilter {
+    /// Whether this filter keeps every service.
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+            && !self.free_to_air_only
+            && !self.scrambled_only
+            && !self.exclude_one_seg
+            && !self.one_seg_only
+            && self.names.is_empty()
+            && self.excluded_names.is_empty()
+            && self.providers.is_empty()
+            && self.network_ids.is_empty()
+    }
+
+    /// Whether a channels file entry meets the criteria. Entries without a triplet are
+    /// transponders rather than services and are always kept.
+    pub fn accepts(&self, entry: &ChannelEntry) -> bool {
+        let triplet = match entry.triplet() {
+            Some(triplet) => triplet,
+            None => return true,
+        };
+        let scrambled = entry.get_annotation("SCRAMBLED") == Some("1");
+        let one_seg = entry.get_annotation("ONE_SEG") == Some("1");
+        let provider = entry.get_annotation("PROVIDER").unwrap_or("");
+        (self.kinds.is_empty() || self.kinds.contains(&ServiceKind::of(entry)))
+            && (!scrambled || !self.free_to_air_only)
+            && (scrambled || !self.scrambled_only)
+            && (!one_seg || !self.exclude_one_seg)
+            && (one_seg || !self.one_seg_only)
+            && (self.names.is_empty() || self.names.iter().any(|r| r.is_match(&entry.name)))
+            && !self.excluded_names.iter().any(|r| r.is_match(&entry.name))
+            && (self.providers.is_empty() || self.providers.iter().any(|r| r.is_match(provider)))
+            && (self.network_ids.is_empty() || self.network_ids.contains(&triplet.network_id))
+    }
+
+    /// Return a channels file with only the entries this filter accepts.
+    pub fn apply(&self, file: &ChannelsFile) -> ChannelsFile {
+        ChannelsFile {
+            entries: file
+                .entries
+                .iter()
+                .filter(|e| self.accepts(e))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+/// Criteria a transponder announced in a NIT must meet for a scan to follow it. Empty lists
+/// impose no restriction.
+#[derive(Clone, Debug, Default)]
+pub struct NetworkRestriction {
+    /// Follow only transponders of these networks.
+    pub network_ids: Vec<u16>,
+    /// Do not follow transponders of these networks.
+    pub excluded_network_ids: Vec<u16>,
+    /// Follow only transponders carrying transport streams of these original networks.
+    pub original_network_ids: Vec<u16>,
+    /// Do not follow transponders carrying only transport streams of these original
+    /// networks.
+    pub excluded_original_network_ids: Vec<u16>,
+    /// The number of NITs to follow from the transponders of the transmitter files: those
+    /// announced on the transponders of the files are at depth 1, those announced on them
+    /// at depth 2, and so on.
+    pub max_depth: Option<u32>,
+}
+
+impl NetworkRestriction {
+    /// Whether a transport stream of the network `network_id` is acceptable.
+    fn accepts(&self, network_id: u16, original_network_id: u16) -> bool {
+        (self.network_ids.is_empty() || self.network_ids.contains(&network_id))
+            && !self.excluded_network_ids.contains(&network_id)
+            && (self.original_network_ids.is_empty()
+                || self.original_network_ids.contains(&original_network_id))
+            && !self
+                .excluded_original_network_ids
+                .contains(&original_network_id)
+    }
+
+    /// Whether to follow a transponder at a frequency in kHz, and polarization for
+    /// satellite, announced at `depth` in the NITs `networks`. A transponder is followed if
+    /// any transport stream it carries is acceptable; one not found in `networks` is only
+    /// followed if no networks are required.
+    pub fn follows(
+        &self,
+        networks: &[Nit],
+        frequency: u32,
+        polarization: Option<u32>,
+        depth: u32,
+    ) -> bool {
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return false;
+        }
+        let mut transports = networks
+            .iter()
+            .flat_map(|nit| {
+                nit.transports_at(frequency, polarization)
+                    .map(move |(t, _)| (nit.network_id, t.original_network_id))
+            })
+            .peekable();
+        if transports.peek().is_none() {
+            self.network_ids.is_empty() && self.original_network_ids.is_empty()
+        } else {
+            transports.any(|(network_id, original_network_id)| {
+                self.accepts(network_id, original_network_id)
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::si::NitTransport;
+
+    fn service(name: &str, service_type: &str, scrambled: &str) -> ChannelEntry {
+        let mut entry = ChannelEntry::new(name);
+        entry
+            .properties
+            .push(("SERVICE_ID".to_string(), "1".to_string()));
+        entry.set_annotation("SERVICE_TYPE", service_type);
+        entry.set_annotation("SCRAMBLED", scrambled);
+        entry.set_annotation("PROVIDER", "BBC");
+        entry
+    }
+
+    #[test]
+    fn filter_by_kind_scrambling_and_name() {
+        let file = ChannelsFile {
+            entries: vec![
+                service("BBC ONE", "1", "0"),
+                service("BBC Radio 4", "2", "0"),
+                service("Sky Sports", "25", "1"),
+                service("BBC RB 1", "12", "0"),
+            ],
+        };
+        let filter = ServiceFilter {
+            kinds: vec![ServiceKind::Television],
+            free_to_air_only: true,
+            ..ServiceFilter::default()
+        };
+        let names = filter
+            .apply(&file)
+            .entries
+            .iter()
+            .map(|e| e.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["BBC ONE"]);
+        let filter = ServiceFilter {
+            excluded_names: vec![Regex::new("^BBC").unwrap()],
+            ..ServiceFilter::default()
+        };
+        assert_eq!(filter.apply(&file).entries.len(), 1);
+    }
+
+    #[test]
+    fn filter_one_seg_services() {
+        let mut one_seg = service("Globo 1seg", "1", "0");
+        one_seg.set_annotation("ONE_SEG", "1");
+        let file = ChannelsFile {
+            entries: vec![service("Globo HD", "1", "0"), one_seg],
+        };
+        let filter = ServiceFilter {
+            exclude_one_seg: true,
+            ..ServiceFilter::default()
+        };
+        assert_eq!(filter.apply(&file).entries[0].name, "Globo HD");
+        let filter = ServiceFilter {
+            one_seg_only: true,
+            ..ServiceFilter::default()
+        };
+        let names = filter
+            .apply(&file)
+            .entries
+            .iter()
+            .map(|e| e.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["Globo 1seg"]);
+    }
+
+    #[test]
+    fn follow_transponders_of_allowed_networks() {
+        let nit = |network_id: u16, original_network_id: u16, frequency: u32| Nit {
+            network_id,
+            transports: vec![NitTransport {
+                transport_stream_id: 1,
+                original_network_id,
+                descriptors: {
+                    let mut descriptors = vec![0x5a, 0x0b];
+                    descriptors.extend_from_slice(&(frequency * 100).to_be_bytes());
+                    descriptors.extend_from_slice(&[0; 7]);
+                    descriptors
+                },
+            }],
+            ..Nit::default()
+        };
+        let networks = [nit(12293, 9018, 490_000), nit(8468, 8468, 506_000)];
+        let restriction = NetworkRestriction {
+            excluded_network_ids: vec![8468],
+            ..NetworkRestriction::default()
+        };
+        assert!(restriction.follows(&networks, 490_000, None, 1));
+        assert!(!restriction.follows(&networks, 506_000, None, 1));
+        assert!(restriction.follows(&networks, 522_000, None, 1));
+        let restriction = NetworkRestriction {
+            original_network_ids: vec![9018],
+            max_depth: Some(1),
+            ..NetworkRestriction::default()
+        };
+        assert!(restriction.follows(&networks, 490_000, None, 1));
+        assert!(!restriction.follows(&networks, 490_000, None, 2));
+        assert!(!restriction.follows(&networks, 522_000, None, 1));
+    }
+}
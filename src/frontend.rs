@@ -0,0 +1,538 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Tuning a frontend to a service of a channels file.
+//!
+//! `dvbv5::FrontendParametersPtr` keeps its pointer to itself and only offers what a scan
+//! needs, so tuning directly uses libdvbv5 through `dvbv5_sys`.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::channels;
+use crate::channels_file::{ChannelEntry, ChannelsFile};
+use crate::satellite::{self, Lnb, LnbPower, SatellitePosition};
+use crate::unicable::UserBand;
+
+/// Stream type of PES private data, which carries DVB subtitles and teletext.
+const PES_PRIVATE_DATA_STREAM_TYPE: u8 = 0x06;
+
+/// Get the string a C string pointer points to, if it is not null.
+unsafe fn optional_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+    }
+}
+
+/// A service of a channels file as read by libdvbv5, with all that is needed to tune to it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Channel {
+    pub name: String,
+    pub vchannel: Option<String>,
+    pub service_id: u16,
+    pub video_pids: Vec<u16>,
+    pub audio_pids: Vec<u16>,
+    /// The other elementary streams as stream type and PID.
+    pub other_pids: Vec<(u8, u16)>,
+    /// The DVBv5 properties as command and value.
+    properties: Vec<(u32, u32)>,
+    lnb: Option<String>,
+    sat_number: i32,
+    freq_bpf: u32,
+    diseqc_wait: u32,
+    /// The satellite position the scan selected, from the annotations libdvbv5 ignores.
+    position: Option<SatellitePosition>,
+    /// The LNB power of the scan, if not on.
+    lnb_power: Option<LnbPower>,
+}
+
+impl Channel {
+    /// Read the services of a DVBv5 channels file.
+    pub fn read_all(path: &Path) -> Result<Vec<Channel>, String> {
+        let path_string = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| format!("{} is not a valid path.", path.display()))?;
+        let ptr = unsafe {
+            dvbv5_sys::dvb_read_file_format(
+                path_string.as_ptr(),
+                dvbv5_sys::fe_delivery_system::SYS_UNDEFINED as u32,
+                dvbv5_sys::dvb_file_formats::FILE_DVBV5,
+            )
+        };
+        // The FilePtr frees the file when dropped.
+        let _file = dvbv5::FilePtr::new_from_dvb_file_ptr(ptr)
+            .map_err(|_| format!("could not read {}.", path.display()))?;
+        // libdvbv5 ignores the comments the annotations are written as.
+        let annotated = ChannelsFile::read(path).unwrap_or_default();
+        let mut channels = Vec::new();
+        let mut entry = unsafe { (*ptr).first_entry };
+        while !entry.is_null() {
+            let mut channel = unsafe { Channel::from_entry(&*entry) };
+            if let Some(entry) = annotated.entries.iter().find(|e| channel.is_entry(e)) {
+                channel.annotate(entry)?;
+            }
+            channels.push(channel);
+            entry = unsafe { (*entry).next };
+        }
+        Ok(channels)
+    }
+
+    /// Whether an entry of the channels file read is that of this service.
+    fn is_entry(&self, entry: &ChannelEntry) -> bool {
+        entry.name == self.name
+            && entry.get_u32("SERVICE_ID") == Some(self.service_id as u32)
+            && entry.frequency() == self.property(dvbv5_sys::DTV_FREQUENCY)
+    }
+
+    /// The satellite position of a single cable router: the committed port the scan used,
+    /// else the satellite number of the channels file.
+    fn router_position(&self) -> u8 {
+        self.position
+            .as_ref()
+            .and_then(|p| p.committed)
+            .unwrap_or_else(|| self.sat_number.max(0) as u8)
+    }
+
+    /// Take the satellite position and LNB power a scan recorded in the annotations of the
+    /// entry of this service.
+    fn annotate(&mut self, entry: &ChannelEntry) -> Result<(), String> {
+        if let Some(settings) = entry.get_annotation("DISEQC") {
+            let name = entry.get_annotation("POSITION").unwrap_or_default();
+            self.position = Some(
+                SatellitePosition::parse(name, settings)
+                    .map_err(|e| format!("{} of {}.", e, self.name))?,
+            );
+        }
+        if let Some(power) = entry.get_annotation("LNB_POWER") {
+            self.lnb_power = Some(
+                LnbPower::from_name(power)
+                    .ok_or_else(|| format!("invalid LNB power {} of {}.", power, self.name))?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Copy the data of a libdvbv5 entry.
+    unsafe fn from_entry(entry: &dvbv5_sys::dvb_entry) -> Channel {
+        fn pids(ptr: *const u16, length: u32) -> Vec<u16> {
+            if ptr.is_null() {
+                Vec::new()
+            } else {
+                unsafe { std::slice::from_raw_parts(ptr, length as usize).to_vec() }
+            }
+        }
+        let other_pids = if entry.other_el_pid.is_null() {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(entry.other_el_pid, entry.other_el_pid_len as usize)
+                .iter()
+                .map(|p| (p.type_, p.pid))
+                .collect()
+        };
+        Channel {
+            name: optional_string(entry.channel).unwrap_or_default(),
+            vchannel: optional_string(entry.vchannel),
+            service_id: entry.service_id,
+            video_pids: pids(entry.video_pid, entry.video_pid_len),
+            audio_pids: pids(entry.audio_pid, entry.audio_pid_len),
+            other_pids,
+            properties: entry.props[..entry.n_props as usize]
+                .iter()
+                .map(|p| (p.cmd, p.u.data))
+                .collect(),
+            lnb: optional_string(entry.lnb),
+            sat_number: entry.sat_number,
+            freq_bpf: entry.freq_bpf,
+            diseqc_wait: entry.diseqc_wait,
+            position: None,
+            lnb_power: None,
+        }
+    }
+
+    /// Find a service by name, ignoring case, or by channel number.
+    pub fn find<'a>(channels: &'a [Channel], name: &str) -> Option<&'a Channel> {
+        channels
+            .iter()
+            .find(|c| c.name == name)
+            .or_else(|| channels.iter().find(|c| c.name.eq_ignore_ascii_case(name)))
+            .or_else(|| {
+                channels
+                    .iter()
+                    .find(|c| c.vchannel.as_deref() == Some(name))
+            })
+    }
+
+    /// Find the first service on the transponder with the given frequency, as given in the
+    /// channels file.
+    pub fn find_frequency(channels: &[Channel], frequency: u32) -> Option<&Channel> {
+        channels
+            .iter()
+            .find(|c| c.property(dvbv5_sys::DTV_FREQUENCY) == Some(frequency))
+    }
+
+    /// Whether two services are carried by the same transport stream, and so can be
+    /// received with one frontend at the same time.
+    pub fn same_transponder(&self, other: &Channel) -> bool {
+        [
+            dvbv5_sys::DTV_DELIVERY_SYSTEM,
+            dvbv5_sys::DTV_FREQUENCY,
+            dvbv5_sys::DTV_POLARIZATION,
+            dvbv5_sys::DTV_STREAM_ID,
+        ]
+        .iter()
+        .all(|command| self.property(*command) == other.property(*command))
+            && self.sat_number == other.sat_number
+            && self.position == other.position
+    }
+
+    /// Get the value of a DVBv5 property.
+    pub fn property(&self, command: u32) -> Option<u32> {
+        self.properties
+            .iter()
+            .find(|(c, _)| *c == command)
+            .map(|(_, v)| *v)
+    }
+
+    /// The PIDs of the subtitle and teletext streams.
+    pub fn subtitle_pids(&self) -> Vec<u16> {
+        self.other_pids
+            .iter()
+            .filter(|(stream_type, _)| *stream_type == PES_PRIVATE_DATA_STREAM_TYPE)
+            .map(|(_, pid)| *pid)
+            .collect()
+    }
+}
+
+/// A wrapper around a `*mut dvbv5_sys::dvb_v5_fe_parms` of an open frontend providing
+/// automated RAII.
+#[derive(Debug)]
+pub struct Frontend {
+    ptr: *mut dvbv5_sys::dvb_v5_fe_parms,
+    /// The user band of the single cable router satellite transponders are received
+    /// through, if there is one.
+    user_band: Option<UserBand>,
+}
+
+impl Frontend {
+    /// Open a frontend. `verbose` is the libdvbv5 level of verbosity.
+    pub fn open(frontend_id: &dvbv5::FrontendId, verbose: u32) -> Result<Frontend, ()> {
+        Frontend::open_with_legacy_call(frontend_id, verbose, false)
+    }
+
+    /// Open a frontend, using the DVBv3 calls rather than the DVBv5 ones if
+    /// `use_legacy_call` is set.
+    pub fn open_with_legacy_call(
+        frontend_id: &dvbv5::FrontendId,
+        verbose: u32,
+        use_legacy_call: bool,
+    ) -> Result<Frontend, ()> {
+        let ptr = unsafe {
+            dvbv5_sys::dvb_fe_open(
+                frontend_id.adapter_number as i32,
+                frontend_id.frontend_number as i32,
+                verbose,
+                use_legacy_call as u32,
+            )
+        };
+        if ptr.is_null() {
+            Err(())
+        } else {
+            Ok(Frontend {
+                ptr,
+                user_band: None,
+            })
+        }
+    }
+
+    /// The libdvbv5 frontend, valid for as long as this is.
+    pub fn as_ptr(&self) -> *mut dvbv5_sys::dvb_v5_fe_parms {
+        self.ptr
+    }
+
+    /// Log a message with the log function of the frontend.
+    pub fn log(&self, level: dvbv5::log_level, message: &str) {
+        let message = CString::new(message).unwrap_or_default();
+        unsafe {
+            if let Some(logger) = (*self.ptr).logfunc {
+                logger(
+                    level as i32,
+                    b"%s\0".as_ptr() as *const c_char,
+                    message.as_ptr(),
+                );
+            }
+        }
+    }
+
+    /// The delivery system the frontend is set to.
+    pub fn current_sys(&self) -> dvbv5_sys::fe_delivery_system {
+        unsafe { (*self.ptr).current_sys }
+    }
+
+    /// Whether the user has asked libdvbv5 to abort.
+    pub fn is_aborted(&self) -> bool {
+        unsafe { (*self.ptr).abort != 0 }
+    }
+
+    /// Give libdvbv5 its own LNB of the same name as `lnb` to convert satellite
+    /// frequencies with, or none, returning whether it has one. libdvbv5 has no LNB for
+    /// one given by its oscillator frequencies: it then tunes to the frequency as given,
+    /// so that is to be the intermediate frequency, and sets neither the supply voltage
+    /// nor the tone, see `set_voltage_and_tone`.
+    pub fn set_lnb(&self, lnb: Option<&Lnb>) -> bool {
+        let index = lnb
+            .and_then(|lnb| CString::new(lnb.alias.as_bytes()).ok())
+            .map(|name| unsafe { dvbv5_sys::dvb_sat_search_lnb(name.as_ptr()) })
+            .unwrap_or(-1);
+        unsafe {
+            (*self.ptr).lnb = if index >= 0 {
+                dvbv5_sys::dvb_sat_get_lnb(index)
+            } else {
+                std::ptr::null()
+            };
+        }
+        index >= 0
+    }
+
+    /// Set the supply voltage, 18 V rather than 13 V if `horizontal` is set, and the
+    /// 22 kHz tone, for tuning to an intermediate frequency without a libdvbv5 LNB.
+    pub fn set_voltage_and_tone(&self, horizontal: bool, tone: bool) {
+        use dvbv5_sys::fe_sec_tone_mode::*;
+        unsafe {
+            dvbv5_sys::dvb_fe_sec_voltage(self.ptr, 1, horizontal as i32);
+            dvbv5_sys::dvb_fe_sec_tone(self.ptr, if tone { SEC_TONE_ON } else { SEC_TONE_OFF });
+        }
+    }
+
+    /// Receive satellite transponders through a user band of a single cable router, or
+    /// directly.
+    pub fn set_user_band(&mut self, user_band: Option<UserBand>) {
+        self.user_band = user_band;
+    }
+
+    /// Have the single cable router convert the transponder carrying a service to the
+    /// user band and set libdvbv5 to tune to it, with the supply at 13 V and no tone. The
+    /// satellite number of the service is the router's satellite position.
+    unsafe fn select_user_band(
+        &self,
+        user_band: &UserBand,
+        lnb: &str,
+        channel: &Channel,
+    ) -> Result<(), String> {
+        let lnb = Lnb::from_name(lnb)
+            .map_err(|_| format!("a single cable router cannot be used with the LNB {}.", lnb))?;
+        let frequency = channel.property(dvbv5_sys::DTV_FREQUENCY).unwrap_or(0);
+        let polarization = channel.property(dvbv5_sys::DTV_POLARIZATION).unwrap_or(0);
+        let tuning_frequency = user_band
+            .select(
+                self.ptr,
+                &lnb,
+                frequency,
+                channel.router_position(),
+                satellite::is_horizontal(polarization),
+            )
+            .map_err(|e| e + ".")?;
+        self.set_lnb(None);
+        self.set_voltage_and_tone(false, false);
+        dvbv5_sys::dvb_fe_store_parm(self.ptr, dvbv5_sys::DTV_FREQUENCY, tuning_frequency);
+        (*self.ptr).sat_number = -1;
+        Ok(())
+    }
+
+    /// Set the frontend to receive the transponder carrying a service. This returns once
+    /// the parameters are set; lock follows some time later. The satellite position and
+    /// LNB power are those the scan used. A motorised dish is not moved, `dish goto` does
+    /// that.
+    pub fn tune(&self, channel: &Channel) -> Result<(), String> {
+        unsafe {
+            if let Some(delivery_system) = channel.property(dvbv5_sys::DTV_DELIVERY_SYSTEM) {
+                if dvbv5_sys::dvb_set_compat_delivery_system(self.ptr, delivery_system) != 0 {
+                    return Err("the frontend does not support the delivery system.".to_string());
+                }
+            }
+            for (command, value) in &channel.properties {
+                if *command != dvbv5_sys::DTV_DELIVERY_SYSTEM {
+                    dvbv5_sys::dvb_fe_store_parm(self.ptr, *command, *value);
+                }
+            }
+            if let (Some(lnb), Some(user_band)) = (&channel.lnb, &self.user_band) {
+                self.select_user_band(user_band, lnb, channel)?;
+            } else if let Some(lnb) = &channel.lnb {
+                let frequency = channel.property(dvbv5_sys::DTV_FREQUENCY).unwrap_or(0);
+                let horizontal = satellite::is_horizontal(
+                    channel.property(dvbv5_sys::DTV_POLARIZATION).unwrap_or(0),
+                );
+                // libdvbv5 has LNBs that are not presets here, whose band is not known.
+                let high_band = Lnb::from_name(lnb)
+                    .map(|lnb| lnb.is_high_band(frequency))
+                    .unwrap_or(false);
+                dvbv5_sys::dvb_fe_lnb_high_voltage(
+                    self.ptr,
+                    (channel.lnb_power == Some(LnbPower::High)) as i32,
+                );
+                if let Some(position) = &channel.position {
+                    position
+                        .select(
+                            self.ptr,
+                            horizontal,
+                            high_band,
+                            Duration::from_millis(channel.diseqc_wait as u64),
+                        )
+                        .map_err(|e| e + ".")?;
+                }
+                let name = CString::new(lnb.as_bytes()).unwrap_or_default();
+                let index = dvbv5_sys::dvb_sat_search_lnb(name.as_ptr());
+                if index >= 0 {
+                    (*self.ptr).lnb = dvbv5_sys::dvb_sat_get_lnb(index);
+                } else {
+                    // An LNB given by its oscillator frequencies for a scan.
+                    let lnb = Lnb::from_name(lnb).map_err(|e| e + ".")?;
+                    self.set_lnb(None);
+                    self.set_voltage_and_tone(horizontal, high_band);
+                    dvbv5_sys::dvb_fe_store_parm(
+                        self.ptr,
+                        dvbv5_sys::DTV_FREQUENCY,
+                        lnb.intermediate_frequency(frequency),
+                    );
+                }
+                // The switches of a position have been set here, not by libdvbv5.
+                (*self.ptr).sat_number = if channel.position.is_some() {
+                    -1
+                } else {
+                    channel.sat_number
+                };
+                (*self.ptr).freq_bpf = channel.freq_bpf;
+                (*self.ptr).diseqc_wait = channel.diseqc_wait;
+            }
+            if dvbv5_sys::dvb_fe_set_parms(self.ptr) != 0 {
+                return Err("setting the frontend parameters failed.".to_string());
+            }
+            if channel.lnb.is_some() && channel.lnb_power == Some(LnbPower::Off) {
+                dvbv5_sys::dvb_fe_sec_voltage(self.ptr, 0, 0);
+            }
+        }
+        Ok(())
+    }
+
+    /// Display the current statistics and return the frontend status, see
+    /// `channels::show_frontend_status`.
+    pub fn show_status(&self, n_status_lines: &mut u32) -> u32 {
+        unsafe { channels::show_frontend_status(self.ptr, n_status_lines) }
+    }
+
+    /// Wait for the frontend to lock, displaying the statistics meanwhile, trying for as
+    /// long as the check of a scan does. Return whether lock was achieved.
+    pub fn wait_for_lock(&self, n_status_lines: &mut u32) -> bool {
+        for _ in 0..20 {
+            if Frontend::has_lock(self.show_status(n_status_lines)) {
+                return true;
+            }
+            sleep(Duration::from_millis(400));
+        }
+        false
+    }
+
+    /// Whether a status returned by `show_status` shows lock.
+    pub fn has_lock(status: u32) -> bool {
+        status & dvbv5::fe_status::FE_HAS_LOCK as u32 != 0
+    }
+}
+
+impl Drop for Frontend {
+    fn drop(&mut self) {
+        unsafe {
+            dvbv5_sys::dvb_fe_close(self.ptr);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// Build a service on a transponder of the given frequency, for tests.
+    pub fn make_channel(name: &str, service_id: u16, frequency: u32) -> Channel {
+        Channel {
+            name: name.to_string(),
+            service_id,
+            properties: vec![(dvbv5_sys::DTV_FREQUENCY, frequency)],
+            ..Channel::default()
+        }
+    }
+
+    #[test]
+    fn annotations_of_scan() {
+        let mut entry = crate::channels_file::tests::service("BBC ONE", "10301", "10773000");
+        entry.set_annotation("POSITION", "Astra-28.2E");
+        entry.set_annotation("DISEQC", "c2,u3");
+        entry.set_annotation("LNB_POWER", "off");
+        let mut channel = make_channel("BBC ONE", 10301, 10_773_000);
+        assert!(channel.is_entry(&entry));
+        assert!(!make_channel("BBC ONE", 10301, 11_954_000).is_entry(&entry));
+        channel.annotate(&entry).unwrap();
+        let position = channel.position.clone().unwrap();
+        assert_eq!(position.name, "Astra-28.2E");
+        assert_eq!(position.committed, Some(1));
+        assert_eq!(position.uncommitted, Some(2));
+        assert_eq!(channel.lnb_power, Some(LnbPower::Off));
+        assert_eq!(channel.router_position(), 1);
+        // Services received through different switch ports are on different transponders.
+        assert!(!channel.same_transponder(&make_channel("BBC ONE", 10301, 10_773_000)));
+        entry.set_annotation("DISEQC", "c9");
+        assert!(channel.annotate(&entry).is_err());
+    }
+
+    #[test]
+    fn find_by_name_or_channel_number() {
+        let channels = vec![
+            Channel {
+                name: "BBC ONE Lon".to_string(),
+                vchannel: Some("1".to_string()),
+                ..Channel::default()
+            },
+            Channel {
+                name: "BBC TWO".to_string(),
+                vchannel: Some("2".to_string()),
+                ..Channel::default()
+            },
+        ];
+        assert_eq!(Channel::find(&channels, "bbc two"), Some(&channels[1]));
+        assert_eq!(Channel::find(&channels, "1"), Some(&channels[0]));
+        assert_eq!(Channel::find(&channels, "ITV1"), None);
+    }
+
+    #[test]
+    fn find_transponder() {
+        let channels = vec![
+            make_channel("BBC ONE Lon", 4164, 490_000_000),
+            make_channel("BBC TWO", 4287, 490_000_000),
+            make_channel("ITV1", 8261, 514_000_000),
+        ];
+        assert_eq!(
+            Channel::find_frequency(&channels, 514_000_000),
+            Some(&channels[2])
+        );
+        assert!(channels[0].same_transponder(&channels[1]));
+        assert!(!channels[0].same_transponder(&channels[2]));
+    }
+}
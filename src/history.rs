@@ -0,0 +1,476 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A SQLite database of scans, for following how reception of the same transmitters
+//! changes over time: each scan is recorded as a run with the transponders tried, their
+//! statistics at lock, and the services found.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::channels::ScannedTransponder;
+use crate::channels_file::ChannelsFile;
+
+/// The database used unless another is given.
+pub const DEFAULT_PATH: &str = "dvb-history.sqlite";
+
+/// The tables of the database, created if they do not exist.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        adapter INTEGER NOT NULL,
+        frontend INTEGER NOT NULL,
+        source TEXT NOT NULL,
+        options TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS transponders (
+        run INTEGER NOT NULL REFERENCES runs (id),
+        frequency INTEGER NOT NULL,
+        position TEXT,
+        stream_id INTEGER,
+        locked INTEGER NOT NULL,
+        variant TEXT,
+        quality TEXT,
+        strength TEXT,
+        cnr TEXT,
+        ber REAL,
+        ucb INTEGER,
+        transport_stream_id INTEGER,
+        original_network_id INTEGER,
+        services INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS services (
+        run INTEGER NOT NULL REFERENCES runs (id),
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        network_id INTEGER,
+        transport_id INTEGER,
+        service_id INTEGER,
+        frequency INTEGER,
+        entry TEXT NOT NULL
+    );
+";
+
+/// A scan recorded in the history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Run {
+    /// The id of the run, assigned when it is recorded.
+    pub id: i64,
+    /// The time of the scan in seconds since the Unix epoch.
+    pub time: i64,
+    pub adapter: u8,
+    pub frontend: u8,
+    /// The transmitter files scanned, or the cable network.
+    pub source: String,
+    /// The command line options of the scan.
+    pub options: String,
+}
+
+/// A transponder tried by a recorded scan. The statistics are as they are written as
+/// annotations of channels files.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransponderRecord {
+    /// The frequency as given in the transmitter file.
+    pub frequency: u32,
+    /// The name of the satellite position.
+    pub position: Option<String>,
+    pub stream_id: Option<u32>,
+    pub locked: bool,
+    /// The variation of the tuning parameters with which lock was achieved.
+    pub variant: Option<String>,
+    pub quality: Option<String>,
+    pub strength: Option<String>,
+    pub cnr: Option<String>,
+    pub ber: Option<f64>,
+    pub ucb: Option<i64>,
+    pub transport_stream_id: Option<u16>,
+    pub original_network_id: Option<u16>,
+    /// The number of services stored from the transponder.
+    pub services: u32,
+}
+
+impl TransponderRecord {
+    /// The record of a transponder on which lock was achieved.
+    pub fn locked(transponder: &ScannedTransponder) -> TransponderRecord {
+        let statistics = transponder.statistics.as_ref();
+        TransponderRecord {
+            frequency: transponder.frequency,
+            position: transponder.position.as_ref().map(|p| p.name.clone()),
+            stream_id: transponder.stream_id,
+            locked: true,
+            variant: transponder.variant.as_ref().map(|v| v.to_string()),
+            quality: statistics.map(|s| format!("{:?}", s.quality)),
+            strength: statistics.and_then(|s| s.strength).map(|m| m.format("dBm")),
+            cnr: statistics.and_then(|s| s.cnr).map(|m| m.format("dB")),
+            ber: statistics.and_then(|s| s.ber),
+            ucb: statistics.and_then(|s| s.ucb).map(|u| u as i64),
+            transport_stream_id: transponder.sdt.as_ref().map(|s| s.transport_stream_id),
+            original_network_id: transponder.sdt.as_ref().map(|s| s.original_network_id),
+            services: transponder.entries.len() as u32,
+        }
+    }
+
+    /// The record of a transponder on which no lock was achieved.
+    pub fn unlocked(frequency: u32) -> TransponderRecord {
+        TransponderRecord {
+            frequency,
+            ..TransponderRecord::default()
+        }
+    }
+
+    /// What identifies a transponder across runs.
+    fn key(&self) -> (u32, Option<&str>, Option<u32>) {
+        (self.frequency, self.position.as_deref(), self.stream_id)
+    }
+
+    /// The name of the transponder: its frequency, satellite position and stream id.
+    fn name(&self) -> String {
+        let mut name = self.frequency.to_string();
+        if let Some(position) = &self.position {
+            name += &format!(" {}", position);
+        }
+        if let Some(stream_id) = self.stream_id {
+            name += &format!(" stream {}", stream_id);
+        }
+        name
+    }
+
+    /// The statistics, as compared between runs.
+    fn statistics(&self) -> String {
+        if !self.locked {
+            return "no lock".to_string();
+        }
+        let mut statistics = Vec::new();
+        if let Some(quality) = &self.quality {
+            statistics.push(format!("Quality {}", quality));
+        }
+        if let Some(strength) = &self.strength {
+            statistics.push(format!("Signal {}", strength));
+        }
+        if let Some(cnr) = &self.cnr {
+            statistics.push(format!("C/N {}", cnr));
+        }
+        if statistics.is_empty() {
+            statistics.push("locked".to_string());
+        }
+        statistics.join(" ")
+    }
+}
+
+impl fmt::Display for TransponderRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.name(), self.statistics())?;
+        if !self.locked {
+            return Ok(());
+        }
+        if let Some(ber) = self.ber {
+            write!(f, " BER {:.2e}", ber)?;
+        }
+        if let Some(ucb) = self.ucb {
+            write!(f, " UCB {}", ucb)?;
+        }
+        if let (Some(transport_stream_id), Some(original_network_id)) =
+            (self.transport_stream_id, self.original_network_id)
+        {
+            write!(
+                f,
+                ", transport stream {} of network {}",
+                transport_stream_id, original_network_id
+            )?;
+        }
+        write!(f, ", {} services", self.services)?;
+        if let Some(variant) = &self.variant {
+            write!(f, ", with {}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describe how the transponders of two runs differ: transponders tried in only one of
+/// them, and those whose lock or statistics changed.
+pub fn compare_transponders(a: &[TransponderRecord], b: &[TransponderRecord]) -> Vec<String> {
+    let b_index = b.iter().map(|t| (t.key(), t)).collect::<HashMap<_, _>>();
+    let a_index = a.iter().map(|t| (t.key(), t)).collect::<HashMap<_, _>>();
+    let mut changes = Vec::new();
+    for old in a {
+        match b_index.get(&old.key()) {
+            None => changes.push(format!("{}: not tried", old.name())),
+            Some(new) if old.statistics() != new.statistics() => changes.push(format!(
+                "{}: {} -> {}",
+                old.name(),
+                old.statistics(),
+                new.statistics()
+            )),
+            Some(_) => {}
+        }
+    }
+    for new in b {
+        if !a_index.contains_key(&new.key()) {
+            changes.push(format!("{}: newly tried, {}", new.name(), new.statistics()));
+        }
+    }
+    changes
+}
+
+/// A history database.
+pub struct History {
+    connection: Connection,
+}
+
+impl History {
+    /// Open a history database, creating it if it does not exist.
+    pub fn open(path: &Path) -> Result<History, String> {
+        History::create(Connection::open(path).map_err(|e| e.to_string())?)
+    }
+
+    fn create(connection: Connection) -> Result<History, String> {
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|e| e.to_string())?;
+        Ok(History { connection })
+    }
+
+    /// Record a run, the id of `run` being ignored, with the transponders tried and the
+    /// services found. Return the id assigned to the run.
+    pub fn record(
+        &mut self,
+        run: &Run,
+        transponders: &[TransponderRecord],
+        services: &ChannelsFile,
+    ) -> Result<i64, String> {
+        let transaction = self.connection.transaction().map_err(|e| e.to_string())?;
+        transaction
+            .execute(
+                "INSERT INTO runs (time, adapter, frontend, source, options)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![run.time, run.adapter, run.frontend, run.source, run.options],
+            )
+            .map_err(|e| e.to_string())?;
+        let id = transaction.last_insert_rowid();
+        for t in transponders {
+            transaction
+                .execute(
+                    "INSERT INTO transponders (run, frequency, position, stream_id, locked,
+                     variant, quality, strength, cnr, ber, ucb, transport_stream_id,
+                     original_network_id, services)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
+                    params![
+                        id,
+                        t.frequency,
+                        t.position,
+                        t.stream_id,
+                        t.locked,
+                        t.variant,
+                        t.quality,
+                        t.strength,
+                        t.cnr,
+                        t.ber,
+                        t.ucb,
+                        t.transport_stream_id,
+                        t.original_network_id,
+                        t.services
+                    ],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        for (position, entry) in services.entries.iter().enumerate() {
+            let triplet = entry.triplet();
+            transaction
+                .execute(
+                    "INSERT INTO services (run, position, name, network_id, transport_id,
+                     service_id, frequency, entry)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    params![
+                        id,
+                        position as i64,
+                        entry.name,
+                        triplet.map(|t| t.network_id),
+                        triplet.map(|t| t.transport_id),
+                        triplet.map(|t| t.service_id),
+                        entry.frequency(),
+                        entry.to_string()
+                    ],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        transaction.commit().map_err(|e| e.to_string())?;
+        Ok(id)
+    }
+
+    /// The runs recorded, oldest first.
+    pub fn runs(&self) -> Result<Vec<Run>, String> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, time, adapter, frontend, source, options FROM runs ORDER BY id")
+            .map_err(|e| e.to_string())?;
+        let runs = statement
+            .query_map([], |row| {
+                Ok(Run {
+                    id: row.get(0)?,
+                    time: row.get(1)?,
+                    adapter: row.get(2)?,
+                    frontend: row.get(3)?,
+                    source: row.get(4)?,
+                    options: row.get(5)?,
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        runs.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    /// A run recorded.
+    pub fn run(&self, id: i64) -> Result<Run, String> {
+        self.connection
+            .query_row(
+                "SELECT id, time, adapter, frontend, source, options FROM runs WHERE id = ?1",
+                params![id],
+                |row| {
+                    Ok(Run {
+                        id: row.get(0)?,
+                        time: row.get(1)?,
+                        adapter: row.get(2)?,
+                        frontend: row.get(3)?,
+                        source: row.get(4)?,
+                        options: row.get(5)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(|e| e.to_string())?
+            .ok_or_else(|| format!("there is no run {}", id))
+    }
+
+    /// The transponders tried by a run, in the order they were tried.
+    pub fn transponders(&self, run: i64) -> Result<Vec<TransponderRecord>, String> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT frequency, position, stream_id, locked, variant, quality, strength, cnr,
+                 ber, ucb, transport_stream_id, original_network_id, services
+                 FROM transponders WHERE run = ?1 ORDER BY rowid",
+            )
+            .map_err(|e| e.to_string())?;
+        let transponders = statement
+            .query_map(params![run], |row| {
+                Ok(TransponderRecord {
+                    frequency: row.get(0)?,
+                    position: row.get(1)?,
+                    stream_id: row.get(2)?,
+                    locked: row.get(3)?,
+                    variant: row.get(4)?,
+                    quality: row.get(5)?,
+                    strength: row.get(6)?,
+                    cnr: row.get(7)?,
+                    ber: row.get(8)?,
+                    ucb: row.get(9)?,
+                    transport_stream_id: row.get(10)?,
+                    original_network_id: row.get(11)?,
+                    services: row.get(12)?,
+                })
+            })
+            .map_err(|e| e.to_string())?;
+        transponders
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())
+    }
+
+    /// The services found by a run, all of them whatever was written.
+    pub fn services(&self, run: i64) -> Result<ChannelsFile, String> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT entry FROM services WHERE run = ?1 ORDER BY position")
+            .map_err(|e| e.to_string())?;
+        let entries = statement
+            .query_map(params![run], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        ChannelsFile::parse(&entries.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::channels_file::tests::service;
+
+    #[test]
+    fn record_and_compare_runs() {
+        let mut history = History::create(Connection::open_in_memory().unwrap()).unwrap();
+        let run = Run {
+            id: 0,
+            time: 1_600_000_000,
+            adapter: 0,
+            frontend: 0,
+            source: "uk-CrystalPalace".to_string(),
+            options: "--deduplicate".to_string(),
+        };
+        let locked = TransponderRecord {
+            frequency: 490_000_000,
+            locked: true,
+            quality: Some("Good".to_string()),
+            cnr: Some("21.50dB".to_string()),
+            transport_stream_id: Some(4164),
+            original_network_id: Some(9018),
+            services: 2,
+            ..TransponderRecord::default()
+        };
+        let mut services = ChannelsFile {
+            entries: vec![
+                service("BBC ONE", "4164", "490000000"),
+                service("BBC TWO", "4287", "490000000"),
+            ],
+        };
+        for entry in &mut services.entries {
+            entry.set_annotation("CNR", "21.50dB");
+        }
+        let first = history
+            .record(
+                &run,
+                &[locked.clone(), TransponderRecord::unlocked(506_000_000)],
+                &services,
+            )
+            .unwrap();
+        let weaker = TransponderRecord {
+            quality: Some("Ok".to_string()),
+            cnr: Some("17.00dB".to_string()),
+            ..locked.clone()
+        };
+        let second = history.record(&run, &[weaker], &services).unwrap();
+        assert_eq!(history.runs().unwrap().len(), 2);
+        assert_eq!(history.run(first).unwrap().source, "uk-CrystalPalace");
+        assert!(history.run(3).is_err());
+        let transponders = history.transponders(first).unwrap();
+        assert_eq!(transponders[0], locked);
+        assert!(!transponders[1].locked);
+        assert_eq!(history.services(second).unwrap(), services);
+        assert_eq!(
+            compare_transponders(&transponders, &history.transponders(second).unwrap()),
+            vec![
+                "490000000: Quality Good C/N 21.50dB -> Quality Ok C/N 17.00dB",
+                "506000000: not tried",
+            ]
+        );
+    }
+}
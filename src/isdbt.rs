@@ -0,0 +1,178 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! ISDB-T hierarchical transmission: the layers A, B and C of a channel, and the one-seg
+//! services carried by partial reception of the central segment of layer A.
+
+use crate::channels_file::ChannelEntry;
+use crate::si::{descriptors, Nit};
+
+/// The NIT transport descriptor listing the services of a transport stream that are
+/// broadcast for partial reception, ARIB STD-B10.
+const PARTIAL_RECEPTION_DESCRIPTOR: u8 = 0xfb;
+
+/// The service type bits of an ISDB-T service id marking a partial reception service,
+/// ARIB TR-B14 and ABNT NBR 15603.
+const PARTIAL_RECEPTION_SERVICE_TYPE: u16 = 0x03;
+
+/// The layers of an ISDB-T channel.
+pub const LAYERS: [char; 3] = ['A', 'B', 'C'];
+
+/// The number of segments in an ISDB-T channel.
+const SEGMENTS: u32 = 13;
+
+/// The name of the layer whose statistics a frontend reports at `index` of its statistics,
+/// if any. ISDB-T frontends report the whole channel at index 0 and layers A, B and C at
+/// indices 1 to 3.
+pub fn layer_name(delivery_system: u32, index: usize) -> Option<char> {
+    if delivery_system != dvbv5_sys::fe_delivery_system::SYS_ISDBT as u32 || index == 0 {
+        return None;
+    }
+    LAYERS.get(index - 1).copied()
+}
+
+/// Whether a channels file entry is of an ISDB-T channel.
+pub fn is_isdbt(entry: &ChannelEntry) -> bool {
+    entry.get("DELIVERY_SYSTEM") == Some("ISDBT")
+}
+
+/// The ids of the services of a transport stream the NITs list as broadcast for partial
+/// reception.
+pub fn partial_reception_services(
+    networks: &[Nit],
+    original_network_id: u16,
+    transport_stream_id: u16,
+) -> Vec<u16> {
+    networks
+        .iter()
+        .flat_map(|nit| nit.transports.iter())
+        .filter(|t| {
+            t.transport_stream_id == transport_stream_id
+                && t.original_network_id == original_network_id
+        })
+        .flat_map(|t| descriptors(&t.descriptors))
+        .filter(|(tag, _)| *tag == PARTIAL_RECEPTION_DESCRIPTOR)
+        .flat_map(|(_, content)| content.chunks(2).filter(|c| c.len() == 2))
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect()
+}
+
+/// Whether a service id has the service type bits of a partial reception service, for
+/// when the NIT has no partial reception descriptor.
+fn is_partial_reception_service_id(service_id: u16) -> bool {
+    service_id >> 3 & 0x03 == PARTIAL_RECEPTION_SERVICE_TYPE
+}
+
+/// The layers of a channel carrying segments, with whether layer A is a single segment
+/// broadcast for partial reception, from the parameters libdvbv5 detected at lock.
+fn active_layers(entry: &ChannelEntry) -> (Vec<char>, bool) {
+    let layers = LAYERS
+        .iter()
+        .filter(|layer| {
+            entry
+                .get_u32(&format!("ISDBT_LAYER{}_SEGMENT_COUNT", layer))
+                .is_some_and(|count| count > 0 && count <= SEGMENTS)
+        })
+        .copied()
+        .collect();
+    (layers, entry.get_u32("ISDBT_PARTIAL_RECEPTION") == Some(1))
+}
+
+/// Annotate an ISDB-T service with the layers carrying it, `LAYER`, and with whether it is
+/// a one-seg service, `ONE_SEG`. One-seg services are carried by layer A when the channel
+/// has partial reception, full-seg services by the other layers. A channel without partial
+/// reception has no one-seg services.
+pub fn annotate(entry: &mut ChannelEntry, partial_reception_services: &[u16]) {
+    let service_id = match entry.triplet() {
+        Some(triplet) => triplet.service_id,
+        None => return,
+    };
+    let (layers, partial_reception) = active_layers(entry);
+    let one_seg = partial_reception
+        && if partial_reception_services.is_empty() {
+            is_partial_reception_service_id(service_id)
+        } else {
+            partial_reception_services.contains(&service_id)
+        };
+    let layers = if !partial_reception {
+        layers
+    } else if one_seg {
+        vec!['A']
+    } else {
+        layers.into_iter().filter(|l| *l != 'A').collect()
+    };
+    if !layers.is_empty() {
+        let layers = layers.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        entry.set_annotation("LAYER", &layers.join("+"));
+    }
+    entry.set_annotation("ONE_SEG", if one_seg { "1" } else { "0" });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::si::NitTransport;
+
+    fn service(service_id: u16) -> ChannelEntry {
+        let mut entry = ChannelEntry::new("TV Globo");
+        for (key, value) in &[
+            ("DELIVERY_SYSTEM", "ISDBT"),
+            ("SERVICE_ID", &service_id.to_string() as &str),
+            ("ISDBT_PARTIAL_RECEPTION", "1"),
+            ("ISDBT_LAYERA_SEGMENT_COUNT", "1"),
+            ("ISDBT_LAYERB_SEGMENT_COUNT", "12"),
+            ("ISDBT_LAYERC_SEGMENT_COUNT", "0"),
+        ] {
+            entry.properties.push((key.to_string(), value.to_string()));
+        }
+        entry
+    }
+
+    #[test]
+    fn one_seg_services_are_on_layer_a() {
+        let nit = Nit {
+            transports: vec![NitTransport {
+                transport_stream_id: 0x0640,
+                original_network_id: 0x0640,
+                descriptors: vec![PARTIAL_RECEPTION_DESCRIPTOR, 2, 0x32, 0x18],
+            }],
+            ..Nit::default()
+        };
+        let one_seg_services = partial_reception_services(&[nit], 0x0640, 0x0640);
+        assert_eq!(one_seg_services, vec![0x3218]);
+        let mut full_seg = service(0x3200);
+        annotate(&mut full_seg, &one_seg_services);
+        assert_eq!(full_seg.get_annotation("LAYER"), Some("B"));
+        assert_eq!(full_seg.get_annotation("ONE_SEG"), Some("0"));
+        let mut one_seg = service(0x3218);
+        annotate(&mut one_seg, &[]);
+        assert_eq!(one_seg.get_annotation("LAYER"), Some("A"));
+        assert_eq!(one_seg.get_annotation("ONE_SEG"), Some("1"));
+    }
+
+    #[test]
+    fn no_one_seg_services_without_partial_reception() {
+        let mut entry = service(0x3218);
+        entry.set("ISDBT_PARTIAL_RECEPTION", "0");
+        annotate(&mut entry, &[0x3218]);
+        assert_eq!(entry.get_annotation("LAYER"), Some("A+B"));
+        assert_eq!(entry.get_annotation("ONE_SEG"), Some("0"));
+    }
+}
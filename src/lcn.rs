@@ -0,0 +1,473 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Logical channel numbers from the logical channel descriptors of a NIT or BAT.
+//!
+//! The logical channel descriptor is not defined by EN 300 468, so its meaning depends on
+//! the private data specifier in force:
+//!
+//! * EACEM (as used in Australia and by Freeview) – tag 0x83 with a 10 bit number.
+//! * Nordig version 1 – tag 0x83 with a 14 bit number.
+//! * Nordig version 2 – tag 0x87 with a channel list per country.
+//! * Freeview HD simulcast – tag 0x88 with a 10 bit number taking precedence over 0x83.
+//!
+//! A transport descriptor loop containing a target region descriptor applies only to
+//! receivers in one of the regions given, which is how Freeview gives services different
+//! numbers in different regions.
+
+use std::cmp::Ordering;
+
+use crate::channels_file::{ChannelEntry, ChannelsFile, ServiceTriplet};
+use crate::si;
+
+/// Private data specifiers of the organisations defining logical channel descriptors.
+const PRIVATE_DATA_SPECIFIER_NORDIG: u32 = 0x0000_0029;
+
+/// Descriptor tags used in this module.
+const PRIVATE_DATA_SPECIFIER_DESCRIPTOR: u8 = 0x5f;
+const EXTENSION_DESCRIPTOR: u8 = 0x7f;
+const TARGET_REGION_DESCRIPTOR_EXTENSION: u8 = 0x09;
+const LOGICAL_CHANNEL_DESCRIPTOR: u8 = 0x83;
+const NORDIG_LOGICAL_CHANNEL_V2_DESCRIPTOR: u8 = 0x87;
+const HD_SIMULCAST_LOGICAL_CHANNEL_DESCRIPTOR: u8 = 0x88;
+
+/// A target region: a country and optionally a primary, secondary and tertiary region
+/// within it, each only being present if the one before is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TargetRegion {
+    /// ISO 3166 three letter country code, in upper case.
+    pub country: String,
+    pub primary: Option<u8>,
+    pub secondary: Option<u8>,
+    pub tertiary: Option<u16>,
+}
+
+impl TargetRegion {
+    /// Parse a region of the form `COUNTRY[/PRIMARY[/SECONDARY[/TERTIARY]]]`, for example
+    /// `GBR/1/2`.
+    pub fn parse(text: &str) -> Result<TargetRegion, String> {
+        let mut parts = text.split('/');
+        let country = parts.next().unwrap_or("").to_uppercase();
+        if country.len() != 3 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(format!("{} is not a three letter country code.", country));
+        }
+        let codes = parts
+            .map(|p| {
+                p.parse::<u16>()
+                    .map_err(|_| format!("{} is not a region code.", p))
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+        if codes.len() > 3 || codes.iter().take(2).any(|c| *c > 0xff) {
+            return Err(format!("{} is not a valid region.", text));
+        }
+        Ok(TargetRegion {
+            country,
+            primary: codes.first().map(|c| *c as u8),
+            secondary: codes.get(1).map(|c| *c as u8),
+            tertiary: codes.get(2).cloned(),
+        })
+    }
+
+    /// The number of region codes given.
+    fn depth(&self) -> usize {
+        [
+            self.primary.is_some(),
+            self.secondary.is_some(),
+            self.tertiary.is_some(),
+        ]
+        .iter()
+        .filter(|p| **p)
+        .count()
+    }
+
+    /// Whether a receiver in region `region` is in this region.
+    fn contains(&self, region: &TargetRegion) -> bool {
+        fn covers<T: PartialEq>(this: Option<T>, that: Option<T>) -> bool {
+            this.is_none() || this == that
+        }
+        self.country == region.country
+            && covers(self.primary, region.primary)
+            && covers(self.secondary, region.secondary)
+            && covers(self.tertiary, region.tertiary)
+    }
+
+    /// Parse the content of a target region descriptor, the extension tag included.
+    fn parse_descriptor(content: &[u8]) -> Vec<TargetRegion> {
+        let mut regions = Vec::new();
+        if content.len() < 4 {
+            return regions;
+        }
+        let country = String::from_utf8_lossy(&content[1..4]).to_uppercase();
+        let mut data = &content[4..];
+        while !data.is_empty() {
+            let flags = data[0];
+            data = &data[1..];
+            let country = if flags & 0x04 != 0 {
+                if data.len() < 3 {
+                    break;
+                }
+                let country = String::from_utf8_lossy(&data[..3]).to_uppercase();
+                data = &data[3..];
+                country
+            } else {
+                country.clone()
+            };
+            let depth = (flags & 0x03) as usize;
+            let length = [0, 1, 2, 4][depth];
+            if data.len() < length {
+                break;
+            }
+            regions.push(TargetRegion {
+                country,
+                primary: if depth >= 1 { Some(data[0]) } else { None },
+                secondary: if depth >= 2 { Some(data[1]) } else { None },
+                tertiary: if depth == 3 {
+                    Some(((data[2] as u16) << 8) | data[3] as u16)
+                } else {
+                    None
+                },
+            });
+            data = &data[length..];
+        }
+        regions
+    }
+}
+
+/// The logical channel of a service.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogicalChannel {
+    pub number: u16,
+    /// Whether the service is to be shown in a receiver's channel list.
+    pub visible: bool,
+}
+
+/// The logical channel a NIT or BAT assigns to a service, with the conditions of the assignment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    pub transport_stream_id: u16,
+    pub original_network_id: u16,
+    pub service_id: u16,
+    pub channel: LogicalChannel,
+    /// Whether this assignment is from an HD simulcast logical channel descriptor.
+    pub hd_simulcast: bool,
+    /// The regions the assignment applies to, empty meaning everywhere.
+    pub regions: Vec<TargetRegion>,
+}
+
+impl Assignment {
+    /// How well this assignment suits a receiver in `region`: `None` if it does not apply,
+    /// otherwise greater the more specific it is.
+    fn suitability(&self, region: Option<&TargetRegion>) -> Option<usize> {
+        if self.regions.is_empty() {
+            return Some(0);
+        }
+        let region = region?;
+        self.regions
+            .iter()
+            .filter(|r| r.contains(region))
+            .map(|r| 1 + r.depth())
+            .max()
+    }
+}
+
+/// Parse the 4 byte entries of a logical channel descriptor, the number having
+/// `number_bits` bits.
+fn parse_channels(data: &[u8], number_bits: u32) -> Vec<(u16, LogicalChannel)> {
+    data.chunks(4)
+        .filter(|c| c.len() == 4)
+        .map(|c| {
+            let service_id = ((c[0] as u16) << 8) | c[1] as u16;
+            let flags = ((c[2] as u16) << 8) | c[3] as u16;
+            let channel = LogicalChannel {
+                number: flags & ((1 << number_bits) - 1),
+                visible: flags & 0x8000 != 0,
+            };
+            (service_id, channel)
+        })
+        .collect()
+}
+
+/// Get all the logical channel assignments of a NIT.
+pub fn assignments(nit: &si::Nit) -> Vec<Assignment> {
+    transport_assignments(&nit.transports)
+}
+
+/// Get all the logical channel assignments of a BAT, which uses the descriptors of a NIT.
+pub fn bouquet_assignments(bat: &si::Bat) -> Vec<Assignment> {
+    transport_assignments(&bat.transports)
+}
+
+/// Get the logical channel assignments of the transport stream loop of a NIT or BAT.
+fn transport_assignments(transports: &[si::NitTransport]) -> Vec<Assignment> {
+    let mut assignments = Vec::new();
+    for transport in transports {
+        let mut regions = Vec::new();
+        // Each entry is the channels, whether HD simulcast, and the country of a Nordig
+        // version 2 channel list.
+        let mut found = Vec::new();
+        let mut private_data_specifier = 0;
+        for (tag, content) in si::descriptors(&transport.descriptors) {
+            match tag {
+                PRIVATE_DATA_SPECIFIER_DESCRIPTOR if content.len() == 4 => {
+                    private_data_specifier =
+                        u32::from_be_bytes([content[0], content[1], content[2], content[3]])
+                }
+                EXTENSION_DESCRIPTOR
+                    if content.first() == Some(&TARGET_REGION_DESCRIPTOR_EXTENSION) =>
+                {
+                    regions.extend(TargetRegion::parse_descriptor(content))
+                }
+                LOGICAL_CHANNEL_DESCRIPTOR => {
+                    let number_bits = if private_data_specifier == PRIVATE_DATA_SPECIFIER_NORDIG {
+                        14
+                    } else {
+                        10
+                    };
+                    found.push((parse_channels(content, number_bits), false, None));
+                }
+                NORDIG_LOGICAL_CHANNEL_V2_DESCRIPTOR
+                    if private_data_specifier == PRIVATE_DATA_SPECIFIER_NORDIG =>
+                {
+                    let mut data = content;
+                    while data.len() >= 2 {
+                        let name_length = data[1] as usize;
+                        if data.len() < 6 + name_length {
+                            break;
+                        }
+                        let country =
+                            String::from_utf8_lossy(&data[2 + name_length..5 + name_length])
+                                .to_uppercase();
+                        let length = data[5 + name_length] as usize;
+                        let list = &data[6 + name_length..];
+                        if list.len() < length {
+                            break;
+                        }
+                        found.push((parse_channels(&list[..length], 10), false, Some(country)));
+                        data = &list[length..];
+                    }
+                }
+                HD_SIMULCAST_LOGICAL_CHANNEL_DESCRIPTOR
+                    if private_data_specifier != PRIVATE_DATA_SPECIFIER_NORDIG =>
+                {
+                    found.push((parse_channels(content, 10), true, None))
+                }
+                _ => {}
+            }
+        }
+        for (channels, hd_simulcast, country) in found {
+            let regions = match country {
+                Some(country) => vec![TargetRegion {
+                    country,
+                    ..TargetRegion::default()
+                }],
+                None => regions.clone(),
+            };
+            for (service_id, channel) in channels {
+                assignments.push(Assignment {
+                    transport_stream_id: transport.transport_stream_id,
+                    original_network_id: transport.original_network_id,
+                    service_id,
+                    channel,
+                    hd_simulcast,
+                    regions: regions.clone(),
+                });
+            }
+        }
+    }
+    assignments
+}
+
+/// Choose the logical channel of a service for a receiver in `region`. The most specific
+/// applicable assignment is chosen, HD simulcast assignments winning over others; if no
+/// assignment applies the first found is used.
+pub fn logical_channel(
+    assignments: &[Assignment],
+    triplet: ServiceTriplet,
+    region: Option<&TargetRegion>,
+) -> Option<LogicalChannel> {
+    let candidates = assignments
+        .iter()
+        .filter(|a| {
+            a.service_id == triplet.service_id
+                && a.transport_stream_id == triplet.transport_id
+                && (triplet.network_id == 0 || a.original_network_id == triplet.network_id)
+        })
+        .collect::<Vec<_>>();
+    let mut best: Option<(&Assignment, (usize, bool))> = None;
+    for candidate in &candidates {
+        if let Some(suitability) = candidate.suitability(region) {
+            let key = (suitability, candidate.hd_simulcast);
+            if best.map(|(_, k)| key > k).unwrap_or(true) {
+                best = Some((candidate, key));
+            }
+        }
+    }
+    best.map(|(a, _)| a)
+        .or_else(|| candidates.first().cloned())
+        .map(|a| a.channel)
+}
+
+/// Record a logical channel in a channels file entry: the number as the `VCHANNEL`
+/// property, and an annotation if the service is not to be shown.
+pub fn annotate(entry: &mut ChannelEntry, channel: LogicalChannel) {
+    entry.set("VCHANNEL", &channel.number.to_string());
+    if !channel.visible {
+        entry.set_annotation("HIDDEN", "1");
+    }
+}
+
+/// Remove a logical channel recorded in a channels file entry.
+pub fn clear(entry: &mut ChannelEntry) {
+    entry.properties.retain(|(key, _)| key != "VCHANNEL");
+    entry.annotations.retain(|(key, _)| key != "HIDDEN");
+}
+
+/// The channel number of an entry as its dot separated numeric parts, so that ATSC style
+/// major.minor numbers also order correctly.
+fn channel_number(entry: &ChannelEntry) -> Option<Vec<u32>> {
+    entry
+        .get("VCHANNEL")?
+        .split('.')
+        .map(|p| p.trim().parse().ok())
+        .collect()
+}
+
+/// Sort the entries of a channels file by channel number. Entries without a channel
+/// number follow those with, in their original order.
+pub fn sort(file: &mut ChannelsFile) {
+    file.entries
+        .sort_by(|a, b| match (channel_number(a), channel_number(b)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn transport(descriptors: &[&[u8]]) -> si::NitTransport {
+        si::NitTransport {
+            transport_stream_id: 4164,
+            original_network_id: 9018,
+            descriptors: descriptors.concat(),
+        }
+    }
+
+    fn triplet(service_id: u16) -> ServiceTriplet {
+        ServiceTriplet {
+            network_id: 9018,
+            transport_id: 4164,
+            service_id,
+        }
+    }
+
+    #[test]
+    fn eacem_and_hd_simulcast_numbers() {
+        let nit = si::Nit {
+            transports: vec![transport(&[
+                &[0x83, 0x08, 0x10, 0x44, 0xfc, 0x01, 0x10, 0x45, 0x7c, 0x65],
+                &[0x88, 0x04, 0x10, 0x44, 0xfc, 0x65],
+            ])],
+            ..si::Nit::default()
+        };
+        let assignments = assignments(&nit);
+        assert_eq!(assignments.len(), 3);
+        assert_eq!(
+            logical_channel(&assignments, triplet(0x1044), None),
+            Some(LogicalChannel {
+                number: 101,
+                visible: true
+            })
+        );
+        assert_eq!(
+            logical_channel(&assignments, triplet(0x1045), None),
+            Some(LogicalChannel {
+                number: 101,
+                visible: false
+            })
+        );
+    }
+
+    #[test]
+    fn nordig_version_1_has_14_bit_numbers() {
+        let nit = si::Nit {
+            transports: vec![transport(&[
+                &[0x5f, 0x04, 0x00, 0x00, 0x00, 0x29],
+                &[0x83, 0x04, 0x10, 0x44, 0xd0, 0x00],
+            ])],
+            ..si::Nit::default()
+        };
+        let channel = logical_channel(&assignments(&nit), triplet(0x1044), None).unwrap();
+        assert_eq!(channel.number, 0x1000);
+    }
+
+    #[test]
+    fn region_specific_numbers() {
+        // The same service is 1 by default and 7 in GBR primary region 3.
+        let nit = si::Nit {
+            transports: vec![
+                transport(&[&[0x83, 0x04, 0x10, 0x44, 0xfc, 0x01]]),
+                transport(&[
+                    &[0x7f, 0x06, 0x09, b'G', b'B', b'R', 0x01, 0x03],
+                    &[0x83, 0x04, 0x10, 0x44, 0xfc, 0x07],
+                ]),
+            ],
+            ..si::Nit::default()
+        };
+        let assignments = assignments(&nit);
+        let number = |region: Option<&str>| {
+            let region = region.map(|r| TargetRegion::parse(r).unwrap());
+            logical_channel(&assignments, triplet(0x1044), region.as_ref())
+                .unwrap()
+                .number
+        };
+        assert_eq!(number(None), 1);
+        assert_eq!(number(Some("gbr/3/1")), 7);
+        assert_eq!(number(Some("GBR/2")), 1);
+        assert_eq!(number(Some("FRA")), 1);
+    }
+
+    #[test]
+    fn sort_by_channel_number() {
+        let mut file = ChannelsFile::default();
+        for (name, vchannel) in &[
+            ("a", Some("10")),
+            ("b", None),
+            ("c", Some("2")),
+            ("d", Some("2.1")),
+        ] {
+            let mut entry = ChannelEntry::new(name);
+            if let Some(vchannel) = vchannel {
+                entry.set("VCHANNEL", vchannel);
+            }
+            file.entries.push(entry);
+        }
+        sort(&mut file);
+        let names = file
+            .entries
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["c", "d", "a", "b"]);
+    }
+}
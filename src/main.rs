@@ -31,16 +31,264 @@
 //! provides the Rust FFI to the C API of libdvbv5.
 
 use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
+mod bouquet;
+mod cable;
 mod channels;
+mod channels_file;
+mod clock;
+mod compare;
+mod components;
+mod dedup;
+mod demux;
+mod epg;
+mod filter;
+mod frontend;
+mod history;
+mod isdbt;
+mod json;
+mod lcn;
+mod positioner;
+mod psip;
+mod record;
+mod retry;
+mod satellite;
+mod serve;
+mod si;
+mod signal;
+mod spts;
+mod stream;
+mod temporary;
+mod text;
+mod ts;
+mod unicable;
+
+/// Read the two channels files named by the `A` and `B` arguments of a subcommand.
+fn read_channels_file_pair(
+    matches: &ArgMatches,
+) -> Option<(channels_file::ChannelsFile, channels_file::ChannelsFile)> {
+    let a = channels_file::ChannelsFile::read(Path::new(matches.value_of("A").unwrap()));
+    let b = channels_file::ChannelsFile::read(Path::new(matches.value_of("B").unwrap()));
+    match (a, b) {
+        (Ok(a), Ok(b)) => Some((a, b)),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("**** Could not read channels file {}. ****", e);
+            None
+        }
+    }
+}
+
+/// Get the motorised dish described by the `site` and `dish_speed` arguments.
+fn positioner(matches: &ArgMatches) -> Result<positioner::Positioner, String> {
+    let site = matches
+        .value_of("site")
+        .map(positioner::Site::parse)
+        .transpose()?;
+    let speed = match matches.value_of("dish_speed").unwrap().parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => speed,
+        _ => return Err("the dish speed must be a positive number of degrees".to_string()),
+    };
+    Ok(positioner::Positioner { site, speed })
+}
+
+/// Get the user band of a single cable router given by the global `unicable` or `jess`
+/// argument, if either is given.
+fn user_band(matches: &ArgMatches) -> Result<Option<unicable::UserBand>, String> {
+    if let Some(text) = matches.value_of("unicable") {
+        unicable::UserBand::parse(unicable::Standard::Unicable, text).map(Some)
+    } else if let Some(text) = matches.value_of("jess") {
+        unicable::UserBand::parse(unicable::Standard::Jess, text).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Get the frontend to use from the command line.
+fn frontend_id(matches: &ArgMatches) -> dvbv5::FrontendId {
+    let adapter_number = matches
+        .value_of("adapter")
+        .unwrap()
+        .parse::<u8>()
+        .expect("Couldn't parse adapter value as a positive integer.");
+    let frontend_number = matches
+        .value_of("frontend")
+        .unwrap()
+        .parse::<u8>()
+        .expect("Couldn't parse frontend value as a positive integer.");
+    dvbv5::FrontendId {
+        adapter_number,
+        frontend_number,
+    }
+}
+
+/// Get the verbosity level from the command line.
+fn verbose(matches: &ArgMatches) -> u32 {
+    matches
+        .value_of("verbose")
+        .unwrap()
+        .parse::<u32>()
+        .expect("Couldn't parse verbose value value as an unsigned integer.")
+}
+
+/// Run the diff subcommand.
+fn diff(matches: &ArgMatches) {
+    if let Some((a, b)) = read_channels_file_pair(matches) {
+        let format = compare::DiffFormat::from_name(matches.value_of("format").unwrap()).unwrap();
+        match compare::diff(&a, &b) {
+            Ok(differences) => print!(
+                "{}",
+                compare::format(
+                    &differences,
+                    format,
+                    matches.value_of("A").unwrap(),
+                    matches.value_of("B").unwrap()
+                )
+            ),
+            Err(duplicates) => {
+                println!("**** Services found more than once, cannot compare. ****");
+                for key in duplicates {
+                    println!("\t{}", key);
+                }
+            }
+        }
+    }
+}
+
+/// Run the merge subcommand.
+fn merge(matches: &ArgMatches) {
+    if let Some((a, b)) = read_channels_file_pair(matches) {
+        let policy =
+            compare::ConflictPolicy::from_name(matches.value_of("conflict").unwrap()).unwrap();
+        let output_path = Path::new(matches.value_of("output_path").unwrap());
+        match compare::merge(&a, &b, policy) {
+            Ok(merged) => {
+                if let Err(e) = merged.write(output_path) {
+                    println!("**** Error writing merged channels file {}. ****", e);
+                }
+            }
+            Err(compare::MergeError::Duplicates(duplicates)) => {
+                println!("**** Services found more than once, nothing written. ****");
+                for key in duplicates {
+                    println!("\t{}", key);
+                }
+            }
+            Err(compare::MergeError::Conflicts(conflicts)) => {
+                println!("**** Conflicting services, nothing written. ****");
+                for key in conflicts {
+                    println!("\t{}", key);
+                }
+            }
+        }
+    }
+}
+
+/// Get the character table to decode texts with, if one is given, from the command line.
+fn charset_override(matches: &ArgMatches) -> Result<Option<text::Charset>, String> {
+    match matches.value_of("charset_override") {
+        Some(name) => text::Charset::from_name(name)
+            .map(Some)
+            .ok_or_else(|| name.to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Get the network ids given as values of an option on the command line.
+fn network_ids(matches: &ArgMatches, name: &str) -> Result<Vec<u16>, String> {
+    match matches.values_of(name) {
+        Some(values) => values
+            .map(|v| {
+                v.parse::<u16>()
+                    .map_err(|_| format!("{} is not a valid network id", v))
+            })
+            .collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Build the restriction on the transponders announced in NITs to follow from the scan
+/// command line options.
+fn network_restriction(matches: &ArgMatches) -> Result<filter::NetworkRestriction, String> {
+    let max_depth = match matches.value_of("max_nit_depth") {
+        Some(depth) => Some(
+            depth
+                .parse::<u32>()
+                .map_err(|_| format!("{} is not a valid NIT depth", depth))?,
+        ),
+        None => None,
+    };
+    Ok(filter::NetworkRestriction {
+        network_ids: network_ids(matches, "allow_network_id")?,
+        excluded_network_ids: network_ids(matches, "deny_network_id")?,
+        original_network_ids: network_ids(matches, "allow_original_network_id")?,
+        excluded_original_network_ids: network_ids(matches, "deny_original_network_id")?,
+        max_depth,
+    })
+}
+
+/// Build the variations of tuning parameters to retry transponders with from the scan
+/// command line options.
+fn retry_strategy(matches: &ArgMatches) -> Result<retry::RetryStrategy, String> {
+    Ok(retry::RetryStrategy {
+        offsets: matches
+            .value_of("retry_offsets")
+            .map(retry::parse_offsets)
+            .transpose()?
+            .unwrap_or_default(),
+        bandwidths: matches
+            .value_of("retry_bandwidths")
+            .map(retry::parse_bandwidths)
+            .transpose()?
+            .unwrap_or_default(),
+        inversions: matches.is_present("retry_inversion"),
+    })
+}
+
+/// Build the service filter from the scan command line options.
+fn service_filter(matches: &ArgMatches) -> Result<filter::ServiceFilter, String> {
+    fn regexes(matches: &ArgMatches, name: &str) -> Result<Vec<regex::Regex>, String> {
+        matches
+            .values_of(name)
+            .map(|values| {
+                values
+                    .map(|v| regex::Regex::new(v).map_err(|e| e.to_string()))
+                    .collect()
+            })
+            .unwrap_or_else(|| Ok(Vec::new()))
+    }
+    let mut kinds = Vec::new();
+    if matches.is_present("tv_only") {
+        kinds.push(filter::ServiceKind::Television);
+    }
+    if matches.is_present("radio_only") {
+        kinds.push(filter::ServiceKind::Radio);
+    }
+    if matches.is_present("data_only") {
+        kinds.push(filter::ServiceKind::Data);
+    }
+    let network_ids = network_ids(matches, "network_id")?;
+    Ok(filter::ServiceFilter {
+        kinds,
+        free_to_air_only: matches.is_present("fta_only"),
+        scrambled_only: matches.is_present("scrambled_only"),
+        exclude_one_seg: matches.is_present("exclude_one_seg"),
+        one_seg_only: matches.is_present("one_seg_only"),
+        names: regexes(matches, "name")?,
+        excluded_names: regexes(matches, "exclude_name")?,
+        providers: regexes(matches, "provider")?,
+        network_ids,
+    })
+}
 
 fn main() {
     let matches = App::new("dvb-tune")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Russel Winder <russel@winder.org.uk>")
         .about("Generate a DVBv5 channels file from a DVBv5 transmitter file.")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("adapter")
                 .short("a")
@@ -48,6 +296,7 @@ fn main() {
                 .value_name("NUMBER")
                 .help("Sets the adapter number to use.")
                 .takes_value(true)
+                .global(true)
                 .default_value("0"),
         )
         .arg(
@@ -57,21 +306,56 @@ fn main() {
                 .value_name("NUMBER")
                 .help("Sets the frontend number to use.")
                 .takes_value(true)
+                .global(true)
                 .default_value("0"),
         )
+        .arg(
+            Arg::with_name("unicable")
+                .long("unicable")
+                .value_name("SLOT,FREQUENCY[,PIN]")
+                .help(
+                    "Receive satellite transponders through a Unicable I (EN 50494) router: \
+                     the user band, from 1, its frequency in MHz and its PIN if it has one.",
+                )
+                .takes_value(true)
+                .global(true)
+                .conflicts_with("jess"),
+        )
+        .arg(
+            Arg::with_name("jess")
+                .long("jess")
+                .value_name("SLOT,FREQUENCY[,PIN]")
+                .help(
+                    "Receive satellite transponders through a JESS (EN 50607) router: the \
+                     user band, from 1, its frequency in MHz and its PIN if it has one.",
+                )
+                .takes_value(true)
+                .global(true),
+        )
         .arg(
             Arg::with_name("output_path")
                 .short("o")
-                .long("output_path")
+                .long("output-path")
+                .alias("output_path")
                 .value_name("PATH")
                 .help("Path to output file.")
                 .takes_value(true)
                 .default_value("dvb-channels.conf"),
         )
+        .arg(
+            Arg::with_name("output_format")
+                .long("output-format")
+                .value_name("FORMAT")
+                .help("Format of the output file.")
+                .takes_value(true)
+                .possible_values(&["dvbv5", "json"])
+                .default_value("dvbv5"),
+        )
         .arg(
             Arg::with_name("timeout_multiplier")
                 .short("t")
-                .long("timeout_multiplier")
+                .long("timeout-multiplier")
+                .alias("timeout_multiplier")
                 .value_name("MULTIPLIER")
                 .help("Multiplier used for timeouts to obtain tables.")
                 .takes_value(true)
@@ -84,61 +368,1922 @@ fn main() {
                 .value_name("verbose")
                 .help("Verbosity level: the bigger the integer the more messages get output.")
                 .takes_value(true)
+                .global(true)
                 .default_value("0"),
         )
+        .arg(
+            Arg::with_name("deduplicate")
+                .long("deduplicate")
+                .help("Keep only the best received copy of services found on more than one transponder."),
+        )
+        .arg(
+            Arg::with_name("tv_only")
+                .long("tv-only")
+                .help("Write television services, may be combined with other kinds."),
+        )
+        .arg(
+            Arg::with_name("radio_only")
+                .long("radio-only")
+                .help("Write radio services, may be combined with other kinds."),
+        )
+        .arg(
+            Arg::with_name("data_only")
+                .long("data-only")
+                .help("Write data services, may be combined with other kinds."),
+        )
+        .arg(
+            Arg::with_name("fta_only")
+                .long("fta-only")
+                .conflicts_with("scrambled_only")
+                .help("Write only services that are free to air."),
+        )
+        .arg(
+            Arg::with_name("scrambled_only")
+                .long("scrambled-only")
+                .help("Write only services that are scrambled."),
+        )
+        .arg(
+            Arg::with_name("exclude_one_seg")
+                .long("exclude-one-seg")
+                .conflicts_with("one_seg_only")
+                .help("Write no ISDB-T one-seg services, those broadcast for partial reception."),
+        )
+        .arg(
+            Arg::with_name("one_seg_only")
+                .long("one-seg-only")
+                .help("Write only ISDB-T one-seg services, those broadcast for partial reception."),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("REGEX")
+                .help("Write only services with a name matching a regular expression.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("exclude_name")
+                .long("exclude-name")
+                .value_name("REGEX")
+                .help("Do not write services with a name matching a regular expression.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("provider")
+                .long("provider")
+                .value_name("REGEX")
+                .help("Write only services with a provider name matching a regular expression.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("network_id")
+                .long("network-id")
+                .value_name("ID")
+                .help("Write only services with a given original network id.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("sort_by_lcn")
+                .long("sort-by-lcn")
+                .help("Write services in logical channel number order."),
+        )
+        .arg(
+            Arg::with_name("lcn_region")
+                .long("lcn-region")
+                .value_name("REGION")
+                .help(
+                    "The region, COUNTRY[/PRIMARY[/SECONDARY[/TERTIARY]]], used to choose \
+                     region specific logical channel numbers, e.g. GBR/1/2.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bat")
+                .long("bat")
+                .help("Collect the bouquet association tables and report the bouquets found."),
+        )
+        .arg(
+            Arg::with_name("bouquet")
+                .long("bouquet")
+                .value_name("ID")
+                .help(
+                    "Write only the services of this bouquet, numbered by the bouquet's \
+                     logical channel numbers.",
+                )
+                .takes_value(true)
+                .conflicts_with("per_bouquet"),
+        )
+        .arg(Arg::with_name("per_bouquet").long("per-bouquet").help(
+            "Write a channels file for each bouquet, the bouquet id added to the \
+                     output file name.",
+        ))
+        .arg(Arg::with_name("multistream").long("multistream").help(
+            "Scan each PLP of DVB-T2 transponders and each input stream of DVB-S2 \
+                     ones signalled in the NIT as a transponder of its own.",
+        ))
+        .arg(
+            Arg::with_name("stream_id")
+                .long("stream-id")
+                .value_name("ID")
+                .help("Scan this PLP or input stream of every DVB-T2 and DVB-S2 transponder.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("lnb")
+                .long("lnb")
+                .value_name("LNB")
+                .help(
+                    "The LNB for satellite transponders: universal (the default), dbs, \
+                     standard, enhanced, l10700, l10750, l11300, c-band, or the oscillator \
+                     frequencies in MHz as LOW or LOW,HIGH,SWITCH.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("lnb_power")
+                .long("lnb-power")
+                .value_name("POWER")
+                .help("The LNB power: off, on, or high for long cables.")
+                .takes_value(true)
+                .possible_values(&["on", "off", "high"])
+                .default_value("on"),
+        )
+        .arg(
+            Arg::with_name("diseqc")
+                .long("diseqc")
+                .value_name("SETTINGS")
+                .help(
+                    "The settings selecting the satellite position of a transmitter file, \
+                     given once for each file in order: comma separated cN for committed \
+                     port N, uN for uncommitted port N, a or b for tone burst, pN for stored \
+                     positioner position N, or an orbital position, e.g. 19.2E, for USALS.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("diseqc_wait")
+                .long("diseqc-wait")
+                .value_name("MILLISECONDS")
+                .help("Extra time to wait between DiSEqC messages, for slow switches.")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("site")
+                .long("site")
+                .value_name("LATITUDE,LONGITUDE")
+                .help(
+                    "The location of a motorised dish, for USALS, in degrees signed or with \
+                     hemisphere letters, e.g. 51.5N,0.1W.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dish_speed")
+                .long("dish-speed")
+                .value_name("DEGREES_PER_SECOND")
+                .help("How fast the motorised dish turns, for the time to wait for it.")
+                .takes_value(true)
+                .default_value("1.5"),
+        )
+        .arg(
+            Arg::with_name("charset_override")
+                .long("charset-override")
+                .value_name("CHARSET")
+                .help(
+                    "Decode service and provider names with this character table, e.g. \
+                     iso-8859-2, whatever the broadcaster selects.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("other_nit")
+                .long("other-nit")
+                .help("Use the NITs of other networks as well as that of the network received."),
+        )
+        .arg(
+            Arg::with_name("allow_network_id")
+                .long("allow-network-id")
+                .value_name("ID")
+                .help("Follow only transponders of this network announced in NITs.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("deny_network_id")
+                .long("deny-network-id")
+                .value_name("ID")
+                .help("Do not follow transponders of this network announced in NITs.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("allow_original_network_id")
+                .long("allow-original-network-id")
+                .value_name("ID")
+                .help(
+                    "Follow only transponders announced in NITs carrying transport streams of \
+                     this original network.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("deny_original_network_id")
+                .long("deny-original-network-id")
+                .value_name("ID")
+                .help(
+                    "Do not follow transponders announced in NITs carrying only transport \
+                     streams of this original network.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("max_nit_depth")
+                .long("max-nit-depth")
+                .value_name("DEPTH")
+                .help(
+                    "Follow transponders announced in NITs only this many NITs away from the \
+                     transmitter files, 0 to scan only the transmitter files.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_offsets")
+                .long("retry-offsets")
+                .value_name("KHZ,...")
+                .help(
+                    "Retry transponders that do not lock offset by these frequencies in kHz, \
+                     e.g. 166,-166.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_bandwidths")
+                .long("retry-bandwidths")
+                .value_name("MHZ,...")
+                .help("Retry transponders that do not lock with these bandwidths in MHz, e.g. 7,8.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retry_inversion")
+                .long("retry-inversion")
+                .help("Retry transponders that do not lock with automatic, off and on inversion."),
+        )
+        .arg(
+            Arg::with_name("history")
+                .long("history")
+                .value_name("PATH")
+                .help(
+                    "Record the scan, its transponders and all the services found in a SQLite \
+                     history database, dvb-history.sqlite unless given as --history=PATH.",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("cable_home")
+                .long("cable-home")
+                .value_name("FREQUENCY")
+                .help(
+                    "Scan the cable network whose NIT is carried by the home transponder at \
+                     this frequency, in Hz, rather than the transponders of transmitter files.",
+                )
+                .takes_value(true)
+                .requires("cable_network_id")
+                .conflicts_with("TRANSMITTER_FILE"),
+        )
+        .arg(
+            Arg::with_name("cable_network_id")
+                .long("cable-network-id")
+                .value_name("ID")
+                .help("The network id of the cable network, whose transport streams are scanned.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("symbol_rate")
+                .long("symbol-rate")
+                .value_name("SYMBOLS_PER_SECOND")
+                .help("The symbol rate of the cable home transponder.")
+                .takes_value(true)
+                .default_value("6900000"),
+        )
+        .arg(
+            Arg::with_name("modulation")
+                .long("modulation")
+                .value_name("MODULATION")
+                .help("The modulation of the cable home transponder.")
+                .takes_value(true)
+                .possible_values(&["auto", "qam16", "qam32", "qam64", "qam128", "qam256"])
+                .default_value("auto"),
+        )
         .arg(
             Arg::with_name("TRANSMITTER_FILE")
-                .help("Path to the transmitter file to use as input.")
-                .required(true)
+                .help(
+                    "Path to the transmitter file to use as input, or several for the \
+                     satellite positions selected with --diseqc.",
+                )
+                .required_unless("cable_home")
+                .multiple(true)
                 .index(1),
         )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare the services of two DVBv5 channels files.")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Output format for the differences.")
+                        .takes_value(true)
+                        .possible_values(&["text", "json", "unified"])
+                        .default_value("text"),
+                )
+                .arg(
+                    Arg::with_name("A")
+                        .help("Path to the original channels file.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("B")
+                        .help("Path to the new channels file.")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge the services of two DVBv5 channels files.")
+                .arg(
+                    Arg::with_name("conflict")
+                        .long("conflict")
+                        .value_name("POLICY")
+                        .help(
+                            "Which version to keep of a service in both files with different details.",
+                        )
+                        .takes_value(true)
+                        .possible_values(&["left", "right", "fail"])
+                        .default_value("fail"),
+                )
+                .arg(
+                    Arg::with_name("output_path")
+                        .short("o")
+                        .long("output-path")
+                        .alias("output_path")
+                        .value_name("PATH")
+                        .help("Path to output file.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("A")
+                        .help("Path to the first channels file.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("B")
+                        .help("Path to the second channels file.")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("zap")
+                .about("Tune to a service and hold lock, passing its streams to the DVR device.")
+                .arg(
+                    Arg::with_name("all_pids")
+                        .long("all-pids")
+                        .help("Pass the whole transport stream rather than the service's PIDs."),
+                )
+                .arg(
+                    Arg::with_name("duration")
+                        .short("d")
+                        .long("duration")
+                        .value_name("DURATION")
+                        .help(
+                            "Hold lock for this long, e.g. 30m or 1h30m, rather than until \
+                             interrupted.",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("CHANNELS_FILE")
+                        .help("Path to the channels file to find the service in.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("SERVICE")
+                        .help("The name or channel number of the service.")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("record")
+                .about("Record a service, or a whole transport stream, to a file.")
+                .arg(
+                    Arg::with_name("channels_file")
+                        .short("c")
+                        .long("channels-file")
+                        .value_name("PATH")
+                        .help("Path to the channels file to find the service in.")
+                        .takes_value(true)
+                        .default_value("dvb-channels.conf"),
+                )
+                .arg(
+                    Arg::with_name("output_path")
+                        .short("o")
+                        .long("output-path")
+                        .alias("output_path")
+                        .value_name("PATH")
+                        .help("Path to output file.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("duration")
+                        .short("d")
+                        .long("duration")
+                        .value_name("DURATION")
+                        .help(
+                            "Record for this long, e.g. 30m or 1h30m, rather than until \
+                             interrupted.",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("full_mux")
+                        .long("full-mux")
+                        .help("Record the whole transport stream carrying the service."),
+                )
+                .arg(
+                    Arg::with_name("rollover_size")
+                        .long("rollover-size")
+                        .value_name("SIZE")
+                        .help("Start a new file when a file reaches this size, e.g. 500M.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rollover_duration")
+                        .long("rollover-duration")
+                        .value_name("DURATION")
+                        .help("Start a new file when a file has been recorded for this long.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("fsync")
+                        .long("fsync")
+                        .help("Flush each file to the storage device when it is completed."),
+                )
+                .arg(
+                    Arg::with_name("fsync_interval")
+                        .long("fsync-interval")
+                        .value_name("DURATION")
+                        .help("Flush to the storage device at this interval while recording.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("TARGET")
+                        .help(
+                            "The name or channel number of the service, or the frequency of \
+                             a transponder to record whole.",
+                        )
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("remux")
+                .about(
+                    "Extract one service of a transport stream file as a single program \
+                        transport stream.",
+                )
+                .arg(
+                    Arg::with_name("output_path")
+                        .short("o")
+                        .long("output-path")
+                        .alias("output_path")
+                        .value_name("PATH")
+                        .help("Path to output file.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Path to the transport stream file.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("SERVICE_ID")
+                        .help("The service id, or program number, of the service to extract.")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stream")
+                .about("Send a service, or a whole transport stream, over UDP or RTP.")
+                .arg(
+                    Arg::with_name("channels_file")
+                        .short("c")
+                        .long("channels-file")
+                        .value_name("PATH")
+                        .help("Path to the channels file to find the service in.")
+                        .takes_value(true)
+                        .default_value("dvb-channels.conf"),
+                )
+                .arg(
+                    Arg::with_name("group")
+                        .short("g")
+                        .long("group")
+                        .value_name("ADDRESS")
+                        .help("The multicast group, or unicast address, to send to.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("port")
+                        .short("p")
+                        .long("port")
+                        .value_name("PORT")
+                        .help("The port to send to.")
+                        .takes_value(true)
+                        .default_value("1234"),
+                )
+                .arg(
+                    Arg::with_name("protocol")
+                        .long("protocol")
+                        .value_name("PROTOCOL")
+                        .help("Send the packets in plain UDP datagrams or in RTP packets.")
+                        .takes_value(true)
+                        .possible_values(&["udp", "rtp"])
+                        .default_value("rtp"),
+                )
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("HOPS")
+                        .help("The time to live, or IPv6 hop limit, of the datagrams.")
+                        .takes_value(true)
+                        .default_value("1"),
+                )
+                .arg(
+                    Arg::with_name("interface")
+                        .long("interface")
+                        .value_name("ADDRESS")
+                        .help("The IPv4 address of the interface to send IPv4 multicast from.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("duration")
+                        .short("d")
+                        .long("duration")
+                        .value_name("DURATION")
+                        .help(
+                            "Stream for this long, e.g. 30m or 1h30m, rather than until \
+                             interrupted.",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("full_mux")
+                        .long("full-mux")
+                        .help("Send the whole transport stream carrying the service."),
+                )
+                .arg(
+                    Arg::with_name("TARGET")
+                        .help(
+                            "The name or channel number of the service, or the frequency of \
+                             a transponder to send whole.",
+                        )
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve the services of a channels file over HTTP.")
+                .arg(
+                    Arg::with_name("channels_file")
+                        .short("c")
+                        .long("channels")
+                        .value_name("PATH")
+                        .help("Path to the channels file of the services to serve.")
+                        .takes_value(true)
+                        .default_value("dvb-channels.conf"),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .value_name("ADDRESS")
+                        .help("The address and port to listen on.")
+                        .takes_value(true)
+                        .default_value("0.0.0.0:8080"),
+                )
+                .arg(
+                    Arg::with_name("idle_timeout")
+                        .long("idle-timeout")
+                        .value_name("DURATION")
+                        .help("Release the tuner after it has had no clients for this long.")
+                        .takes_value(true)
+                        .default_value("10s"),
+                )
+                .arg(
+                    Arg::with_name("simulate")
+                        .long("simulate")
+                        .value_name("TS_FILE")
+                        .help(
+                            "Rather than tuning, receive every service from this transport \
+                             stream file played in a loop.",
+                        )
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("epg")
+                .about("Collect the programme guide of the services of a channels file as XMLTV.")
+                .arg(
+                    Arg::with_name("channels_file")
+                        .short("c")
+                        .long("channels-file")
+                        .value_name("PATH")
+                        .help("Path to the channels file of the transport streams to tune.")
+                        .takes_value(true)
+                        .default_value("dvb-channels.conf"),
+                )
+                .arg(
+                    Arg::with_name("capture")
+                        .long("capture")
+                        .value_name("TS_FILE")
+                        .help("Read the guide from a transport stream file rather than tuning.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output_path")
+                        .short("o")
+                        .long("output-path")
+                        .alias("output_path")
+                        .value_name("PATH")
+                        .help("Path to output file.")
+                        .takes_value(true)
+                        .default_value("epg.xml"),
+                )
+                .arg(
+                    Arg::with_name("duration")
+                        .short("d")
+                        .long("duration")
+                        .value_name("DURATION")
+                        .help("How long to collect the EIT of each transport stream.")
+                        .takes_value(true)
+                        .default_value("60s"),
+                )
+                .arg(
+                    Arg::with_name("charset_override")
+                        .long("charset-override")
+                        .value_name("CHARSET")
+                        .help(
+                            "Decode texts with this character table, e.g. iso-8859-2, \
+                             whatever the broadcaster selects.",
+                        )
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dish")
+                .about("Control a motorised dish.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("goto")
+                        .about(
+                            "Turn the dish to a satellite with USALS, or to a position stored \
+                             in the positioner.",
+                        )
+                        .arg(
+                            Arg::with_name("POSITION")
+                                .help(
+                                    "The orbital position of the satellite, e.g. 19.2E, or \
+                                     the number of the stored position, 0 for the reference.",
+                                )
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("site")
+                                .long("site")
+                                .value_name("LATITUDE,LONGITUDE")
+                                .help(
+                                    "The location of the dish in degrees signed or with \
+                                     hemisphere letters, e.g. 51.5N,0.1W.",
+                                )
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::with_name("dish_speed")
+                                .long("dish-speed")
+                                .value_name("DEGREES_PER_SECOND")
+                                .help("How fast the dish turns, for the time to wait for it.")
+                                .takes_value(true)
+                                .default_value("1.5"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Look at the scans recorded in a history database.")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .arg(
+                    Arg::with_name("database")
+                        .long("database")
+                        .value_name("PATH")
+                        .help("Path to the SQLite history database.")
+                        .takes_value(true)
+                        .global(true)
+                        .default_value(history::DEFAULT_PATH),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the scans recorded."))
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show the transponders and services of a scan.")
+                        .arg(
+                            Arg::with_name("RUN")
+                                .help("The id of the scan.")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("compare")
+                        .about("Compare the transponders and services of two scans.")
+                        .arg(
+                            Arg::with_name("format")
+                                .long("format")
+                                .value_name("FORMAT")
+                                .help("Output format for the differences between services.")
+                                .takes_value(true)
+                                .possible_values(&["text", "json", "unified"])
+                                .default_value("text"),
+                        )
+                        .arg(
+                            Arg::with_name("RUN1")
+                                .help("The id of the original scan.")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("RUN2")
+                                .help("The id of the new scan.")
+                                .required(true)
+                                .index(2),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("time")
+                .about("Get the time and local time offset broadcast in the TDT and TOT.")
+                .arg(
+                    Arg::with_name("channels_file")
+                        .short("c")
+                        .long("channels-file")
+                        .value_name("PATH")
+                        .help("Path to the channels file of the transport streams to try.")
+                        .takes_value(true)
+                        .default_value("dvb-channels.conf"),
+                )
+                .arg(
+                    Arg::with_name("capture")
+                        .long("capture")
+                        .value_name("TS_FILE")
+                        .help("Read the time from a transport stream file rather than tuning.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .short("t")
+                        .long("timeout")
+                        .value_name("DURATION")
+                        .help("How long to wait for the TOT of a transport stream.")
+                        .takes_value(true)
+                        .default_value("35s"),
+                )
+                .arg(
+                    Arg::with_name("country")
+                        .long("country")
+                        .value_name("CODE")
+                        .help("The ISO 3166 country code, e.g. GBR, of the local time offset.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("region")
+                        .long("region")
+                        .value_name("NUMBER")
+                        .help("The region of the country of the local time offset.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("set_system_clock")
+                        .long("set-system-clock")
+                        .help("Set the system clock, which needs the CAP_SYS_TIME capability."),
+                ),
+        )
         .get_matches();
-    let adapter_number = matches
-        .value_of("adapter")
-        .unwrap()
-        .parse::<u8>()
-        .expect("Couldn't parse adapter value as a positive integer.");
-    let frontend_number = matches
-        .value_of("frontend")
+    match matches.subcommand() {
+        ("diff", Some(m)) => diff(m),
+        ("dish", Some(m)) => dish(m),
+        ("epg", Some(m)) => epg(m),
+        ("history", Some(m)) => history(m),
+        ("merge", Some(m)) => merge(m),
+        ("zap", Some(m)) => zap(m),
+        ("record", Some(m)) => record(m),
+        ("remux", Some(m)) => remux(m),
+        ("serve", Some(m)) => serve(m),
+        ("stream", Some(m)) => stream(m),
+        ("time", Some(m)) => time(m),
+        _ => scan(&matches),
+    }
+}
+
+/// Read the services of a channels file.
+fn read_channels(channels_file_path: &Path) -> Option<Vec<frontend::Channel>> {
+    match frontend::Channel::read_all(channels_file_path) {
+        Ok(channels) => Some(channels),
+        Err(e) => {
+            println!("**** Could not read channels file, {} ****", e);
+            None
+        }
+    }
+}
+
+/// Open the frontend given on the command line and tune to the transponder of a service,
+/// waiting for lock.
+fn tune(matches: &ArgMatches, channel: &frontend::Channel) -> Option<frontend::Frontend> {
+    let user_band = match user_band(matches) {
+        Ok(user_band) => user_band,
+        Err(e) => {
+            println!("**** Invalid user band, {} ****", e);
+            return None;
+        }
+    };
+    let mut frontend = match frontend::Frontend::open(&frontend_id(matches), verbose(matches)) {
+        Ok(frontend) => frontend,
+        Err(_) => {
+            println!("**** Could not open the frontend ****");
+            return None;
+        }
+    };
+    frontend.set_user_band(user_band);
+    if let Err(e) = frontend.tune(channel) {
+        println!("**** Could not tune to {}, {} ****", channel.name, e);
+        return None;
+    }
+    let mut n_status_lines = 0;
+    if !frontend.wait_for_lock(&mut n_status_lines) {
+        println!("**** No lock on the transponder of {} ****", channel.name);
+        return None;
+    }
+    Some(frontend)
+}
+
+/// Open the history database given on the command line.
+fn open_history(matches: &ArgMatches) -> Option<history::History> {
+    match history::History::open(Path::new(matches.value_of("database").unwrap())) {
+        Ok(history) => Some(history),
+        Err(e) => {
+            println!("**** Could not open the history database, {} ****", e);
+            None
+        }
+    }
+}
+
+/// Get a run of the history database from the command line.
+fn history_run(
+    history: &history::History,
+    matches: &ArgMatches,
+    name: &str,
+) -> Option<history::Run> {
+    let id = matches.value_of(name).unwrap();
+    match id
+        .parse::<i64>()
+        .map_err(|_| format!("{} is not a valid run id", id))
+        .and_then(|id| history.run(id))
+    {
+        Ok(run) => Some(run),
+        Err(e) => {
+            println!("**** Invalid run, {} ****", e);
+            None
+        }
+    }
+}
+
+/// Run the history subcommand.
+fn history(matches: &ArgMatches) {
+    let history = match open_history(matches) {
+        Some(history) => history,
+        None => return,
+    };
+    let result = match matches.subcommand() {
+        ("list", Some(_)) => history_list(&history),
+        ("show", Some(m)) => match history_run(&history, m, "RUN") {
+            Some(run) => history_show(&history, &run),
+            None => return,
+        },
+        ("compare", Some(m)) => match (
+            history_run(&history, m, "RUN1"),
+            history_run(&history, m, "RUN2"),
+        ) {
+            (Some(a), Some(b)) => history_compare(&history, &a, &b, m),
+            _ => return,
+        },
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        println!("**** Could not read the history database, {} ****", e);
+    }
+}
+
+/// Run the history list subcommand.
+fn history_list(history: &history::History) -> Result<(), String> {
+    for run in history.runs()? {
+        let transponders = history.transponders(run.id)?;
+        let locked = transponders.iter().filter(|t| t.locked).count();
+        let services = transponders.iter().map(|t| t.services).sum::<u32>();
+        println!(
+            "{}\t{}\tadapter {} frontend {}\t{}\t{}/{} transponders locked, {} services",
+            run.id,
+            clock::format_time(run.time, 0),
+            run.adapter,
+            run.frontend,
+            run.source,
+            locked,
+            transponders.len(),
+            services
+        );
+    }
+    Ok(())
+}
+
+/// Run the history show subcommand.
+fn history_show(history: &history::History, run: &history::Run) -> Result<(), String> {
+    println!("Run {} at {}", run.id, clock::format_time(run.time, 0));
+    println!("Adapter {} frontend {}", run.adapter, run.frontend);
+    println!("Source: {}", run.source);
+    println!("Options: {}", run.options);
+    println!("Transponders:");
+    for transponder in history.transponders(run.id)? {
+        println!("\t{}", transponder);
+    }
+    println!("Services:");
+    for entry in history.services(run.id)?.entries {
+        match entry.triplet() {
+            Some(triplet) => println!("\t{} {}", triplet, entry.name),
+            None => println!("\t{}", entry.name),
+        }
+    }
+    Ok(())
+}
+
+/// Run the history compare subcommand.
+fn history_compare(
+    history: &history::History,
+    a: &history::Run,
+    b: &history::Run,
+    matches: &ArgMatches,
+) -> Result<(), String> {
+    let a_name = format!("run {}", a.id);
+    let b_name = format!("run {}", b.id);
+    for change in
+        history::compare_transponders(&history.transponders(a.id)?, &history.transponders(b.id)?)
+    {
+        println!("Transponder {}", change);
+    }
+    let format = compare::DiffFormat::from_name(matches.value_of("format").unwrap()).unwrap();
+    let differences = compare::diff(&history.services(a.id)?, &history.services(b.id)?).map_err(
+        |duplicates| {
+            let keys = duplicates.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+            format!("services found more than once: {}", keys.join(", "))
+        },
+    )?;
+    print!(
+        "{}",
+        compare::format(&differences, format, &a_name, &b_name)
+    );
+    Ok(())
+}
+
+/// Record a scan in the history database given on the command line. All the services found
+/// are recorded, whatever the bouquet, filter and deduplication of those written.
+fn record_history(
+    matches: &ArgMatches,
+    frontend_id: &dvbv5::FrontendId,
+    channels_data: &channels::ChannelsData,
+    write_options: &channels::WriteOptions,
+) -> Result<i64, String> {
+    let path = Path::new(matches.value_of("history").unwrap_or(history::DEFAULT_PATH));
+    let source = match matches.value_of("cable_home") {
+        Some(frequency) => format!(
+            "cable network {} at {}",
+            matches.value_of("cable_network_id").unwrap(),
+            frequency
+        ),
+        None => matches
+            .values_of("TRANSMITTER_FILE")
+            .unwrap()
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    let run = history::Run {
+        id: 0,
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs() as i64,
+        adapter: frontend_id.adapter_number,
+        frontend: frontend_id.frontend_number,
+        source,
+        options: std::env::args().skip(1).collect::<Vec<_>>().join(" "),
+    };
+    let transponders = channels_data
+        .tried()
+        .into_iter()
+        .map(|tried| match tried {
+            channels::TriedTransponder::Locked(t) => history::TransponderRecord::locked(t),
+            channels::TriedTransponder::Unlocked(frequency) => {
+                history::TransponderRecord::unlocked(frequency)
+            }
+        })
+        .collect::<Vec<_>>();
+    let services = channels_data.services(&channels::WriteOptions {
+        deduplicate: false,
+        filter: filter::ServiceFilter::default(),
+        sort_by_lcn: false,
+        bouquet_id: None,
+        ..write_options.clone()
+    })?;
+    history::History::open(path)?.record(&run, &transponders, &services)
+}
+
+/// Run the dish subcommand.
+fn dish(matches: &ArgMatches) {
+    if let ("goto", Some(m)) = matches.subcommand() {
+        dish_goto(m)
+    }
+}
+
+/// Run the dish goto subcommand.
+fn dish_goto(matches: &ArgMatches) {
+    let position = match positioner::DishPosition::parse(matches.value_of("POSITION").unwrap()) {
+        Ok(position) => position,
+        Err(e) => {
+            println!("**** Invalid dish position, {} ****", e);
+            return;
+        }
+    };
+    let positioner = match positioner(matches) {
+        Ok(positioner) => positioner,
+        Err(e) => {
+            println!("**** Invalid dish, {} ****", e);
+            return;
+        }
+    };
+    let (angle, message) = match positioner.goto(&position) {
+        Ok(goto) => goto,
+        Err(e) => {
+            println!("**** Cannot move the dish to {}, {} ****", position, e);
+            return;
+        }
+    };
+    let frontend = match frontend::Frontend::open(&frontend_id(matches), verbose(matches)) {
+        Ok(frontend) => frontend,
+        Err(_) => {
+            println!("**** Could not open the frontend ****");
+            return;
+        }
+    };
+    if let Err(e) = unsafe { positioner::Positioner::send(frontend.as_ptr(), &message) } {
+        println!("**** {} ****", e);
+        return;
+    }
+    // Where the dish was is not known, so the wait allows for it turning from the far end.
+    let slew_time = positioner.slew_time(None, angle);
+    match angle {
+        Some(angle) => println!(
+            "Moving the dish to {}, a motor angle of {:.1}°{}, expected to take {}s",
+            position,
+            angle.abs(),
+            if angle < 0.0 { "W" } else { "E" },
+            slew_time.as_secs()
+        ),
+        None => println!(
+            "Moving the dish to stored position {}, expected to take up to {}s",
+            position,
+            slew_time.as_secs()
+        ),
+    }
+    // The frontend powers the positioner so it is kept open until the dish has arrived.
+    sleep(slew_time);
+    println!("Done.");
+}
+
+/// Run the zap subcommand.
+fn zap(matches: &ArgMatches) {
+    let frontend_id = frontend_id(matches);
+    let channels_file_path = Path::new(matches.value_of("CHANNELS_FILE").unwrap());
+    let service = matches.value_of("SERVICE").unwrap();
+    let duration = match matches
+        .value_of("duration")
+        .map(record::parse_duration)
+        .transpose()
+    {
+        Ok(duration) => duration,
+        Err(e) => {
+            println!("**** {} ****", e);
+            return;
+        }
+    };
+    let channels = match read_channels(channels_file_path) {
+        Some(channels) => channels,
+        None => return,
+    };
+    let channel = match frontend::Channel::find(&channels, service) {
+        Some(channel) => channel,
+        None => {
+            println!(
+                "**** No service {} in {} ****",
+                service,
+                channels_file_path.display()
+            );
+            return;
+        }
+    };
+    let frontend = match tune(matches, channel) {
+        Some(frontend) => frontend,
+        None => return,
+    };
+    let pids = if matches.is_present("all_pids") {
+        vec![demux::ALL_PIDS]
+    } else {
+        // The PAT and PMT are included so that players can find the streams.
+        let mut pids = vec![0];
+        pids.extend(demux::pmt_pid(&frontend_id, channel.service_id));
+        pids.extend(&channel.video_pids);
+        pids.extend(&channel.audio_pids);
+        pids.extend(channel.subtitle_pids());
+        pids
+    };
+    let filters = pids
+        .iter()
+        .map(|pid| demux::PesFilter::new(&frontend_id, *pid))
+        .collect::<Result<Vec<_>, _>>();
+    if filters.is_err() {
+        println!("**** Could not set the demux filters ****");
+        return;
+    }
+    println!(
+        "Tuned to {}, passing {} to {}",
+        channel.name,
+        if matches.is_present("all_pids") {
+            "the whole transport stream".to_string()
+        } else {
+            format!("PIDs {:?}", pids)
+        },
+        demux::dvr_path(&frontend_id).display()
+    );
+    let mut n_status_lines = 0;
+    let start = Instant::now();
+    while duration.map(|d| start.elapsed() < d).unwrap_or(true) {
+        sleep(Duration::from_secs(1));
+        frontend.show_status(&mut n_status_lines);
+    }
+}
+
+/// Find the service named by the `TARGET` argument, or if it is not a service, the first
+/// service on the transponder with it as frequency. Return the service and whether the
+/// whole transport stream is wanted, as it is for a transponder.
+fn find_target<'a>(
+    channels: &'a [frontend::Channel],
+    matches: &ArgMatches,
+) -> Option<(&'a frontend::Channel, bool)> {
+    let target = matches.value_of("TARGET").unwrap();
+    match frontend::Channel::find(channels, target) {
+        Some(channel) => Some((channel, matches.is_present("full_mux"))),
+        None => match target
+            .parse::<u32>()
+            .ok()
+            .and_then(|f| frontend::Channel::find_frequency(channels, f))
+        {
+            Some(channel) => Some((channel, true)),
+            None => {
+                println!(
+                    "**** No service or transponder {} in {} ****",
+                    target,
+                    matches.value_of("channels_file").unwrap()
+                );
+                None
+            }
+        },
+    }
+}
+
+/// Pass the whole transport stream of a tuned frontend to the DVR device and open it for
+/// reading. The filter must be kept for as long as the stream is read.
+fn open_transport_stream(
+    frontend_id: &dvbv5::FrontendId,
+) -> Option<(demux::PesFilter, demux::Dvr)> {
+    let filter = match demux::PesFilter::new(frontend_id, demux::ALL_PIDS) {
+        Ok(filter) => filter,
+        Err(_) => {
+            println!("**** Could not set the demux filter ****");
+            return None;
+        }
+    };
+    match demux::Dvr::open(frontend_id, Duration::from_secs(1)) {
+        Ok(dvr) => Some((filter, dvr)),
+        Err(e) => {
+            println!("**** Could not open the DVR device, {} ****", e);
+            None
+        }
+    }
+}
+
+/// Run the record subcommand.
+fn record(matches: &ArgMatches) {
+    let frontend_id = frontend_id(matches);
+    let channels_file_path = Path::new(matches.value_of("channels_file").unwrap());
+    let output_path = Path::new(matches.value_of("output_path").unwrap());
+    let (duration, rollover_size, rollover_duration, fsync_interval) = match (
+        matches
+            .value_of("duration")
+            .map(record::parse_duration)
+            .transpose(),
+        matches
+            .value_of("rollover_size")
+            .map(record::parse_size)
+            .transpose(),
+        matches
+            .value_of("rollover_duration")
+            .map(record::parse_duration)
+            .transpose(),
+        matches
+            .value_of("fsync_interval")
+            .map(record::parse_duration)
+            .transpose(),
+    ) {
+        (Ok(a), Ok(b), Ok(c), Ok(d)) => (a, b, c, d),
+        (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+            println!("**** {} ****", e);
+            return;
+        }
+    };
+    let channels = match read_channels(channels_file_path) {
+        Some(channels) => channels,
+        None => return,
+    };
+    let (channel, full_mux) = match find_target(&channels, matches) {
+        Some(target) => target,
+        None => return,
+    };
+    let _frontend = match tune(matches, channel) {
+        Some(frontend) => frontend,
+        None => return,
+    };
+    let (_filter, dvr) = match open_transport_stream(&frontend_id) {
+        Some(opened) => opened,
+        None => return,
+    };
+    let remuxer = if full_mux {
+        println!("Recording the transport stream of {}", channel.name);
+        None
+    } else {
+        println!("Recording {}", channel.name);
+        Some(spts::SptsRemuxer::new(channel.service_id))
+    };
+    let rollover = record::Rollover {
+        size: rollover_size,
+        duration: rollover_duration,
+    };
+    let mut output = record::OutputFiles::new(
+        output_path,
+        rollover,
+        matches.is_present("fsync"),
+        fsync_interval,
+    );
+    let stop = record::handle_interrupts();
+    let result = record::copy(dvr, remuxer, &mut output, duration, stop);
+    let paths = output.paths().to_vec();
+    match result.and_then(|count| output.finish().map(|_| count)) {
+        Ok(count) => {
+            println!("Wrote {} bytes to:", count);
+            for path in paths {
+                println!("\t{}", path.display());
+            }
+        }
+        Err(e) => println!("**** Error recording, {} ****", e),
+    }
+}
+
+/// Run the serve subcommand.
+fn serve(matches: &ArgMatches) {
+    let channels_file_path = Path::new(matches.value_of("channels_file").unwrap());
+    let idle_timeout = match record::parse_duration(matches.value_of("idle_timeout").unwrap()) {
+        Ok(idle_timeout) => idle_timeout,
+        Err(e) => {
+            println!("**** {} ****", e);
+            return;
+        }
+    };
+    let channels = match read_channels(channels_file_path) {
+        Some(channels) => channels,
+        None => return,
+    };
+    let tuner: Box<dyn serve::Tuner> = match matches.value_of("simulate") {
+        Some(path) => match serve::SimulatedTuner::from_file(Path::new(path), 2_500_000) {
+            Ok(tuner) => Box::new(tuner),
+            Err(e) => {
+                println!(
+                    "**** Could not read the simulation transport stream, {} ****",
+                    e
+                );
+                return;
+            }
+        },
+        None => match user_band(matches) {
+            Ok(user_band) => Box::new(serve::DvbTuner {
+                frontend_id: frontend_id(matches),
+                verbose: verbose(matches),
+                user_band,
+            }),
+            Err(e) => {
+                println!("**** Invalid user band, {} ****", e);
+                return;
+            }
+        },
+    };
+    let listen = matches.value_of("listen").unwrap();
+    let listener = match std::net::TcpListener::bind(listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("**** Could not listen on {}, {} ****", listen, e);
+            return;
+        }
+    };
+    println!(
+        "Serving {} services on http://{}/playlist.m3u",
+        channels.len(),
+        listen
+    );
+    serve::Server::new(channels, tuner, idle_timeout).serve(listener);
+}
+
+/// Run the stream subcommand.
+fn stream(matches: &ArgMatches) {
+    let frontend_id = frontend_id(matches);
+    let channels_file_path = Path::new(matches.value_of("channels_file").unwrap());
+    let duration = match matches
+        .value_of("duration")
+        .map(record::parse_duration)
+        .transpose()
+    {
+        Ok(duration) => duration,
+        Err(e) => {
+            println!("**** {} ****", e);
+            return;
+        }
+    };
+    let destination = match (
+        matches
+            .value_of("group")
+            .unwrap()
+            .parse::<std::net::IpAddr>(),
+        matches.value_of("port").unwrap().parse::<u16>(),
+    ) {
+        (Ok(group), Ok(port)) => std::net::SocketAddr::new(group, port),
+        _ => {
+            println!("**** Invalid group address or port ****");
+            return;
+        }
+    };
+    let interface = match matches
+        .value_of("interface")
+        .map(|i| i.parse::<std::net::Ipv4Addr>())
+        .transpose()
+    {
+        Ok(interface) => interface,
+        Err(_) => {
+            println!("**** The interface must be given by its IPv4 address ****");
+            return;
+        }
+    };
+    let options = stream::StreamOptions {
+        destination,
+        protocol: stream::Protocol::from_name(matches.value_of("protocol").unwrap()).unwrap(),
+        ttl: matches
+            .value_of("ttl")
+            .unwrap()
+            .parse::<u32>()
+            .expect("Couldn't parse ttl value as an unsigned integer."),
+        interface,
+    };
+    let channels = match read_channels(channels_file_path) {
+        Some(channels) => channels,
+        None => return,
+    };
+    let (channel, full_mux) = match find_target(&channels, matches) {
+        Some(target) => target,
+        None => return,
+    };
+    let mut sender = match stream::Sender::new(&options) {
+        Ok(sender) => sender,
+        Err(e) => {
+            println!("**** Could not open the socket, {} ****", e);
+            return;
+        }
+    };
+    let _frontend = match tune(matches, channel) {
+        Some(frontend) => frontend,
+        None => return,
+    };
+    let (_filter, dvr) = match open_transport_stream(&frontend_id) {
+        Some(opened) => opened,
+        None => return,
+    };
+    let remuxer = if full_mux {
+        None
+    } else {
+        Some(spts::SptsRemuxer::new(channel.service_id))
+    };
+    println!(
+        "Streaming {} to {}",
+        if full_mux {
+            format!("the transport stream of {}", channel.name)
+        } else {
+            channel.name.clone()
+        },
+        destination
+    );
+    let stop = record::handle_interrupts();
+    if let Err(e) = record::copy(dvr, remuxer, &mut sender, duration, stop) {
+        println!("**** Error streaming, {} ****", e);
+    }
+}
+
+/// Run the remux subcommand.
+fn remux(matches: &ArgMatches) {
+    let input_path = Path::new(matches.value_of("INPUT").unwrap());
+    let output_path = Path::new(matches.value_of("output_path").unwrap());
+    let service_id = match matches.value_of("SERVICE_ID").unwrap().parse::<u16>() {
+        Ok(service_id) => service_id,
+        Err(_) => {
+            println!("**** The service id must be an integer from 0 to 65535 ****");
+            return;
+        }
+    };
+    match spts::remux_file(input_path, output_path, service_id) {
+        Ok(count) => println!("Wrote {} bytes to {}", count, output_path.display()),
+        Err(e) => println!("**** Could not remux, {} ****", e),
+    }
+}
+
+/// Run the epg subcommand.
+fn epg(matches: &ArgMatches) {
+    let output_path = Path::new(matches.value_of("output_path").unwrap());
+    let duration = match record::parse_duration(matches.value_of("duration").unwrap()) {
+        Ok(duration) => duration,
+        Err(e) => {
+            println!("**** {} ****", e);
+            return;
+        }
+    };
+    let charset_override = match charset_override(matches) {
+        Ok(charset_override) => charset_override,
+        Err(name) => {
+            println!("**** Unknown character table {} ****", name);
+            return;
+        }
+    };
+    let mut guide = epg::Epg::new(charset_override);
+    if let Some(capture_path) = matches.value_of("capture") {
+        let result = std::fs::File::open(capture_path).and_then(|f| guide.collect_capture(f));
+        if let Err(e) = result {
+            println!("**** Could not read {}, {} ****", capture_path, e);
+            return;
+        }
+    } else {
+        let frontend_id = frontend_id(matches);
+        let channels_file_path = Path::new(matches.value_of("channels_file").unwrap());
+        let channels = match read_channels(channels_file_path) {
+            Some(channels) => channels,
+            None => return,
+        };
+        // One service of each transport stream is enough, the EIT covers them all.
+        let mut transponders: Vec<&frontend::Channel> = Vec::new();
+        for channel in &channels {
+            if !transponders.iter().any(|t| t.same_transponder(channel)) {
+                transponders.push(channel);
+            }
+        }
+        for channel in transponders {
+            println!(
+                "Collecting the EIT of the transport stream of {}",
+                channel.name
+            );
+            let _frontend = match tune(matches, channel) {
+                Some(frontend) => frontend,
+                None => continue,
+            };
+            if let Err(e) = guide.collect_frontend(&frontend_id, duration) {
+                println!("**** Could not collect the EIT, {} ****", e);
+            }
+        }
+    }
+    match std::fs::write(output_path, guide.to_xmltv()) {
+        Ok(_) => println!(
+            "Wrote {} events to {}",
+            guide.event_count(),
+            output_path.display()
+        ),
+        Err(e) => println!("**** Error writing {}, {} ****", output_path.display(), e),
+    }
+}
+
+/// Run the time subcommand.
+fn time(matches: &ArgMatches) {
+    let timeout = match record::parse_duration(matches.value_of("timeout").unwrap()) {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            println!("**** {} ****", e);
+            return;
+        }
+    };
+    // The region id of the local time offset descriptor has six bits.
+    let region = match matches
+        .value_of("region")
+        .map(|r| r.parse::<u8>().ok().filter(|region| *region < 64).ok_or(()))
+        .transpose()
+    {
+        Ok(region) => region,
+        Err(_) => {
+            println!("**** The region must be an integer from 0 to 63 ****");
+            return;
+        }
+    };
+    let time = if let Some(capture_path) = matches.value_of("capture") {
+        match std::fs::File::open(capture_path).and_then(clock::BroadcastTime::from_capture) {
+            Ok(Some(time)) => time,
+            Ok(None) => {
+                println!("**** No TDT or TOT in {} ****", capture_path);
+                return;
+            }
+            Err(e) => {
+                println!("**** Could not read {}, {} ****", capture_path, e);
+                return;
+            }
+        }
+    } else {
+        let frontend_id = frontend_id(matches);
+        let channels_file_path = Path::new(matches.value_of("channels_file").unwrap());
+        let channels = match read_channels(channels_file_path) {
+            Some(channels) => channels,
+            None => return,
+        };
+        // Any transport stream will do, try each until one gives the time.
+        let mut time = None;
+        for channel in &channels {
+            if time.is_some() {
+                break;
+            }
+            if let Some(_frontend) = tune(matches, channel) {
+                match clock::BroadcastTime::from_frontend(&frontend_id, timeout) {
+                    Ok(t) => time = Some(t),
+                    Err(e) => println!(
+                        "**** {} on the transport stream of {} ****",
+                        e, channel.name
+                    ),
+                }
+            }
+        }
+        match time {
+            Some(time) => time,
+            None => {
+                println!("**** Could not get the time from any transport stream ****");
+                return;
+            }
+        }
+    };
+    let now = time.now();
+    println!("UTC time:          {}", clock::format_time(now, 0));
+    match time.local_offset(matches.value_of("country"), region) {
+        Some(offset) => {
+            println!(
+                "Local time:        {} ({} region {})",
+                clock::format_time(now, offset.offset_at(now)),
+                offset.country,
+                offset.region
+            );
+            if let Some(change) = offset.time_of_change {
+                println!(
+                    "Next change:       {}, to {}",
+                    clock::format_time(change, offset.offset_at(now)),
+                    clock::format_offset(offset.next_offset)
+                );
+            }
+        }
+        None if time.from_tot => println!("No local time offset for the country and region."),
+        None => println!("Only a TDT was received, there are no local time offsets."),
+    }
+    if matches.is_present("set_system_clock") {
+        match clock::set_system_clock(time.now()) {
+            Ok(_) => println!("System clock set."),
+            Err(e) => println!("**** Could not set the system clock, {} ****", e),
+        }
+    }
+}
+
+/// Read the transmitter files given on the command line, with the satellite positions
+/// given for them.
+fn transmitter_data(matches: &ArgMatches) -> Option<channels::TransmitterData> {
+    let transmitter_files = matches
+        .values_of("TRANSMITTER_FILE")
         .unwrap()
-        .parse::<u8>()
-        .expect("Couldn't parse frontend value as a positive integer.");
+        .map(Path::new)
+        .collect::<Vec<_>>();
+    let settings = matches
+        .values_of("diseqc")
+        .map(|v| v.collect::<Vec<_>>())
+        .unwrap_or_default();
+    if !settings.is_empty() && settings.len() != transmitter_files.len() {
+        println!("**** Give DiSEqC settings for each transmitter file ****");
+        return None;
+    }
+    let mut transmitter_data = Vec::new();
+    for (index, path) in transmitter_files.iter().enumerate() {
+        // A satellite position is named by its transmitter file, e.g. Astra-19.2E.
+        let position = match settings.get(index).map(|s| {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            satellite::SatellitePosition::parse(&name, s)
+        }) {
+            Some(Ok(position)) => Some(position),
+            Some(Err(e)) => {
+                println!("**** Invalid DiSEqC settings, {} ****", e);
+                return None;
+            }
+            None => None,
+        };
+        let data = match position {
+            Some(position) => channels::TransmitterData::at_position(path, position),
+            None => channels::TransmitterData::new(path),
+        };
+        match data {
+            Ok(data) => transmitter_data.push(data),
+            Err(_) => {
+                println!(
+                    "**** Could not get transmitter data from {}. ****",
+                    path.display()
+                );
+                return None;
+            }
+        }
+    }
+    let mut transmitter_data = transmitter_data.into_iter();
+    let mut all_transmitter_data = transmitter_data.next().unwrap();
+    transmitter_data.for_each(|data| all_transmitter_data.extend(data));
+    Some(all_transmitter_data)
+}
+
+/// Get the home transponder and network id of the cable network to scan, if one is given,
+/// from the command line.
+fn cable_network(matches: &ArgMatches) -> Result<Option<(cable::CableTransponder, u16)>, String> {
+    let frequency = match matches.value_of("cable_home") {
+        Some(frequency) => frequency
+            .parse::<u32>()
+            .map_err(|_| format!("{} is not a valid frequency", frequency))?,
+        None => return Ok(None),
+    };
+    let symbol_rate = matches.value_of("symbol_rate").unwrap();
+    let symbol_rate = symbol_rate
+        .parse::<u32>()
+        .map_err(|_| format!("{} is not a valid symbol rate", symbol_rate))?;
+    let network_id = matches.value_of("cable_network_id").unwrap();
+    let network_id = network_id
+        .parse::<u16>()
+        .map_err(|_| format!("{} is not a valid network id", network_id))?;
+    let home = cable::CableTransponder {
+        frequency,
+        symbol_rate,
+        modulation: cable::parse_modulation(matches.value_of("modulation").unwrap())?,
+        inner_fec: None,
+    };
+    Ok(Some((home, network_id)))
+}
+
+/// Run a scan of the transmitter file, the default action.
+fn scan(matches: &ArgMatches) {
     let output_path = Path::new(matches.value_of("output_path").unwrap());
     let timeout_multiplier = matches
         .value_of("timeout_multiplier")
         .unwrap()
         .parse::<u32>()
         .expect("Couldn't parse timeout multiplier value as an unsigned integer.");
-    let verbose = matches
-        .value_of("verbose")
-        .unwrap()
-        .parse::<u32>()
-        .expect("Couldn't parse verbose value value as an unsigned integer.");
-    let transmitter_file_path = Path::new(matches.value_of("TRANSMITTER_FILE").unwrap());
-    let frontend_id = dvbv5::FrontendId {
-        adapter_number,
-        frontend_number,
+    let verbose = verbose(matches);
+    let filter = match service_filter(matches) {
+        Ok(filter) => filter,
+        Err(e) => {
+            println!("**** Invalid service filter, {}. ****", e);
+            return;
+        }
+    };
+    let lcn_region = match matches.value_of("lcn_region").map(lcn::TargetRegion::parse) {
+        Some(Ok(region)) => Some(region),
+        Some(Err(e)) => {
+            println!("**** Invalid LCN region, {} ****", e);
+            return;
+        }
+        None => None,
+    };
+    let charset_override = match charset_override(matches) {
+        Ok(charset_override) => charset_override,
+        Err(name) => {
+            println!("**** Unknown character table {} ****", name);
+            return;
+        }
+    };
+    let bouquet_id = match matches.value_of("bouquet").map(str::parse::<u16>) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => {
+            println!("**** Invalid bouquet id ****");
+            return;
+        }
+        None => None,
+    };
+    let per_bouquet = matches.is_present("per_bouquet");
+    let get_bat = matches.is_present("bat") || bouquet_id.is_some() || per_bouquet;
+    let write_options = channels::WriteOptions {
+        format: channels_file::OutputFormat::from_name(matches.value_of("output_format").unwrap())
+            .unwrap(),
+        deduplicate: matches.is_present("deduplicate"),
+        filter,
+        lcn_region,
+        sort_by_lcn: matches.is_present("sort_by_lcn"),
+        charset_override,
+        bouquet_id,
+    };
+    let lnb = match matches.value_of("lnb").map(satellite::Lnb::from_name) {
+        Some(Ok(lnb)) => Some(lnb),
+        Some(Err(e)) => {
+            println!("**** Invalid LNB, {} ****", e);
+            return;
+        }
+        None => None,
+    };
+    let diseqc_wait = match matches.value_of("diseqc_wait").unwrap().parse::<u64>() {
+        Ok(milliseconds) => Duration::from_millis(milliseconds),
+        Err(_) => {
+            println!("**** Invalid DiSEqC wait ****");
+            return;
+        }
+    };
+    let positioner = match positioner(matches) {
+        Ok(positioner) => positioner,
+        Err(e) => {
+            println!("**** Invalid dish, {} ****", e);
+            return;
+        }
+    };
+    let user_band = match user_band(matches) {
+        Ok(user_band) => user_band,
+        Err(e) => {
+            println!("**** Invalid user band, {} ****", e);
+            return;
+        }
+    };
+    let stream_ids = match matches
+        .values_of("stream_id")
+        .map(|ids| ids.map(str::parse::<u32>).collect::<Result<Vec<_>, _>>())
+        .transpose()
+    {
+        Ok(stream_ids) => stream_ids.unwrap_or_default(),
+        Err(_) => {
+            println!("**** Invalid stream id ****");
+            return;
+        }
+    };
+    let cable_network = match cable_network(matches) {
+        Ok(cable_network) => cable_network,
+        Err(e) => {
+            println!("**** Invalid cable network, {} ****", e);
+            return;
+        }
     };
-    match channels::TransmitterData::new(transmitter_file_path) {
-        Ok(transmitter_data) => {
-            match transmitter_data.scan(
+    let network_restriction = match network_restriction(matches) {
+        Ok(network_restriction) => network_restriction,
+        Err(e) => {
+            println!("**** Invalid network restriction, {} ****", e);
+            return;
+        }
+    };
+    let retry = match retry_strategy(matches) {
+        Ok(retry) => retry,
+        Err(e) => {
+            println!("**** Invalid retry, {} ****", e);
+            return;
+        }
+    };
+    let scan_options = channels::ScanOptions {
+        other_nit: matches.is_present("other_nit"),
+        timeout_multiplier,
+        verbose,
+        get_bat,
+        lnb,
+        lnb_power: satellite::LnbPower::from_name(matches.value_of("lnb_power").unwrap()).unwrap(),
+        diseqc_wait,
+        positioner,
+        user_band,
+        multistream: matches.is_present("multistream") || !stream_ids.is_empty(),
+        stream_ids,
+        // The transponders of a cable network are all listed from its NIT already.
+        dont_add_new_frequencies: cable_network.is_some(),
+        network_restriction,
+        retry,
+        ..channels::ScanOptions::default()
+    };
+    let frontend_id = frontend_id(matches);
+    let all_transmitter_data = match &cable_network {
+        Some((home, network_id)) => {
+            match channels::TransmitterData::cable_network(
                 &frontend_id,
-                None,
-                Some(timeout_multiplier),
-                None,
-                None,
-                None,
-                Some(verbose),
-                None,
+                home,
+                *network_id,
+                &scan_options,
             ) {
-                Ok(channels_data) => {
-                    if !channels_data.write(output_path) {
-                        println!("**** Error writing channels data ****");
-                    }
+                Ok(data) => data,
+                Err(e) => {
+                    println!("**** Could not find the cable network, {} ****", e);
+                    return;
                 }
-                Err(_) => println!("**** No receiver, cannot scan. *****"),
+            }
+        }
+        None => match transmitter_data(matches) {
+            Some(data) => data,
+            None => return,
+        },
+    };
+    match all_transmitter_data.scan(&frontend_id, &scan_options) {
+        Ok(channels_data) => {
+            if let Some((_, network_id)) = cable_network {
+                for (transport_stream_id, original_network_id) in
+                    channels_data.foreign_transport_streams(network_id)
+                {
+                    println!(
+                        "**** Transport stream {} of original network {} is not in network {} ****",
+                        transport_stream_id, original_network_id, network_id
+                    );
+                }
+            }
+            if matches.is_present("bat") {
+                for bouquet in channels_data.bouquets(write_options.lcn_region.as_ref()) {
+                    println!(
+                        "Bouquet {}: {}, {} services",
+                        bouquet.bouquet_id,
+                        bouquet.name,
+                        bouquet.services.len()
+                    );
+                }
+            }
+            let written = if per_bouquet {
+                channels_data.write_bouquets(output_path, &write_options)
+            } else {
+                channels_data.write(output_path, &write_options)
             };
+            if !written {
+                println!("**** Error writing channels data ****");
+            }
+            if matches.is_present("history") {
+                match record_history(matches, &frontend_id, &channels_data, &write_options) {
+                    Ok(id) => println!("Scan recorded in the history as run {}.", id),
+                    Err(e) => println!("**** Could not record the scan in the history, {} ****", e),
+                }
+            }
         }
-        Err(_) => println!("**** Could not get transmitter data. ****"),
+        Err(_) => println!("**** No receiver, cannot scan. *****"),
     };
 }
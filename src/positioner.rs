@@ -0,0 +1,324 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Motorised dish positioners: DiSEqC 1.2 stored positions and USALS.
+//!
+//! A DiSEqC 1.2 positioner can be told to go to a position stored in it earlier, or to
+//! turn to an angle about its axis. USALS is the calculation of that angle from the
+//! latitude and longitude of the site and the orbital position of the satellite; the
+//! positioner, mounted with its axis parallel to the Earth's, then points at the arc of
+//! geostationary satellites.
+//!
+//! Positioners do not report where they are or when they have arrived, so the time a
+//! positioner takes to turn is estimated from its speed.
+
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::satellite::{DISEQC_COMMAND, DISEQC_GAP};
+
+/// DiSEqC address byte: polar or azimuth positioner.
+const DISEQC_POSITIONER: u8 = 0x31;
+
+/// DiSEqC 1.2 commands going to a stored position and to an angle.
+const DISEQC_GOTO_STORED: u8 = 0x6b;
+const DISEQC_GOTO_ANGLE: u8 = 0x6e;
+
+/// The radius of the Earth at the equator and of the geostationary orbit, in km.
+const EARTH_RADIUS: f64 = 6378.14;
+const ORBIT_RADIUS: f64 = 42164.2;
+
+/// The furthest a positioner turns either side of south, or of north in the southern
+/// hemisphere, in degrees.
+const MAXIMUM_ANGLE: f64 = 80.0;
+
+/// The speed of a typical positioner powered at 13 V, in degrees per second.
+const DEFAULT_SPEED: f64 = 1.5;
+
+/// The time allowed for a positioner to stop and the dish to settle after turning.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Parse an angle given in degrees either signed or with a hemisphere letter, e.g.
+/// `-0.1` or `0.1W`.
+fn parse_degrees(text: &str, positive: char, negative: char) -> Result<f64, String> {
+    let text = text.trim();
+    let (number, sign) = match text.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some(c) if c == positive => (&text[..text.len() - 1], 1.0),
+        Some(c) if c == negative => (&text[..text.len() - 1], -1.0),
+        _ => (text, 1.0),
+    };
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|d| d.is_finite())
+        .map(|d| d * sign)
+        .ok_or_else(|| format!("invalid angle {}", text))
+}
+
+/// Parse an orbital position, e.g. `19.2E` or `30W`, giving degrees east.
+pub fn parse_orbital_position(text: &str) -> Result<f64, String> {
+    match parse_degrees(text, 'E', 'W')? {
+        longitude if (-180.0..=180.0).contains(&longitude) => Ok(longitude),
+        _ => Err(format!("invalid orbital position {}", text)),
+    }
+}
+
+/// Format an orbital position in degrees east as it is usually written, e.g. `19.2E`.
+pub fn format_orbital_position(longitude: f64) -> String {
+    format!(
+        "{}{}",
+        (longitude.abs() * 10.0).round() / 10.0,
+        if longitude < 0.0 { 'W' } else { 'E' }
+    )
+}
+
+/// The location of the dish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Site {
+    /// Degrees north.
+    pub latitude: f64,
+    /// Degrees east.
+    pub longitude: f64,
+}
+
+impl Site {
+    /// Parse a site given as `LATITUDE,LONGITUDE` in degrees, signed or with hemisphere
+    /// letters, e.g. `51.5,-0.1` or `51.5N,0.1W`.
+    pub fn parse(text: &str) -> Result<Site, String> {
+        let parts = text.split(',').collect::<Vec<_>>();
+        if parts.len() != 2 {
+            return Err(format!("a site is LATITUDE,LONGITUDE, not {}", text));
+        }
+        let latitude = parse_degrees(parts[0], 'N', 'S')?;
+        let longitude = parse_degrees(parts[1], 'E', 'W')?;
+        if latitude.abs() > 90.0 || longitude.abs() > 180.0 {
+            return Err(format!("the site {} is not on Earth", text));
+        }
+        Ok(Site {
+            latitude,
+            longitude,
+        })
+    }
+
+    /// The angle, in degrees east, a positioner at this site turns to point at the
+    /// satellite at an orbital position, or an error if the satellite is not above the
+    /// horizon.
+    pub fn usals_angle(&self, orbital_position: f64) -> Result<f64, String> {
+        let latitude = self.latitude.to_radians();
+        let difference = (orbital_position - self.longitude).to_radians();
+        // The satellite is above the horizon if the line from the site to it makes an
+        // acute angle with the line from the centre of the Earth to the site.
+        if latitude.cos() * difference.cos() <= EARTH_RADIUS / ORBIT_RADIUS {
+            return Err(format!(
+                "the satellite at {} is below the horizon",
+                format_orbital_position(orbital_position)
+            ));
+        }
+        // The angle about the polar axis of the line from the site to the satellite.
+        Ok((ORBIT_RADIUS * difference.sin())
+            .atan2(ORBIT_RADIUS * difference.cos() - EARTH_RADIUS * latitude.cos())
+            .to_degrees())
+    }
+}
+
+/// The DiSEqC 1.2 message telling a positioner to go to a stored position, 1 to 255, or
+/// to its reference position, 0.
+pub fn goto_stored_message(position: u8) -> Vec<u8> {
+    vec![
+        DISEQC_COMMAND,
+        DISEQC_POSITIONER,
+        DISEQC_GOTO_STORED,
+        position,
+    ]
+}
+
+/// The DiSEqC 1.2 message telling a positioner to turn to an angle in degrees east. The
+/// angle is sent in sixteenths of a degree with a nibble giving the direction.
+pub fn goto_angle_message(angle: f64) -> Vec<u8> {
+    let sixteenths = ((angle.abs() * 16.0).round() as u16).min(0x0fff);
+    let direction = if angle < 0.0 { 0xd0 } else { 0xe0 };
+    vec![
+        DISEQC_COMMAND,
+        DISEQC_POSITIONER,
+        DISEQC_GOTO_ANGLE,
+        direction | (sixteenths >> 8) as u8,
+        sixteenths as u8,
+    ]
+}
+
+/// Where to send a dish: a position stored in the positioner, or the orbital position,
+/// in degrees east, of a satellite.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DishPosition {
+    Stored(u8),
+    Orbital(f64),
+}
+
+impl DishPosition {
+    /// Parse a stored position number or an orbital position, e.g. `3` or `19.2E`.
+    pub fn parse(text: &str) -> Result<DishPosition, String> {
+        match text.trim().parse::<u8>() {
+            Ok(position) => Ok(DishPosition::Stored(position)),
+            Err(_) => parse_orbital_position(text).map(DishPosition::Orbital),
+        }
+    }
+}
+
+impl fmt::Display for DishPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DishPosition::Stored(position) => write!(f, "{}", position),
+            DishPosition::Orbital(longitude) => {
+                write!(f, "{}", format_orbital_position(*longitude))
+            }
+        }
+    }
+}
+
+/// A motorised dish: where it is and how fast it turns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Positioner {
+    /// The location of the dish, needed to go to orbital positions.
+    pub site: Option<Site>,
+    /// Degrees per second.
+    pub speed: f64,
+}
+
+impl Default for Positioner {
+    fn default() -> Positioner {
+        Positioner {
+            site: None,
+            speed: DEFAULT_SPEED,
+        }
+    }
+}
+
+impl Positioner {
+    /// The angle the positioner turns to for a position, if it is known, and the DiSEqC
+    /// message sending it there.
+    pub fn goto(&self, position: &DishPosition) -> Result<(Option<f64>, Vec<u8>), String> {
+        match position {
+            DishPosition::Stored(number) => Ok((None, goto_stored_message(*number))),
+            DishPosition::Orbital(longitude) => {
+                let site = self
+                    .site
+                    .ok_or("the site must be given to go to an orbital position")?;
+                let angle = site.usals_angle(*longitude)?;
+                Ok((Some(angle), goto_angle_message(angle)))
+            }
+        }
+    }
+
+    /// The expected time to turn between two angles. An unknown angle, that of a stored
+    /// position or of the dish before it is first moved, may be as far away as the other
+    /// end of the positioner's travel.
+    pub fn slew_time(&self, from: Option<f64>, to: Option<f64>) -> Duration {
+        let distance = match (from, to) {
+            (Some(from), Some(to)) => (to - from).abs(),
+            (None, Some(angle)) | (Some(angle), None) => MAXIMUM_ANGLE + angle.abs(),
+            (None, None) => 2.0 * MAXIMUM_ANGLE,
+        };
+        Duration::from_secs_f64(distance / self.speed) + SETTLE_TIME
+    }
+
+    /// Send a DiSEqC message to the positioner of a frontend, switching the LNB supply,
+    /// which powers the positioner, to 13 V and the tone off.
+    ///
+    /// # Safety
+    ///
+    /// `frontend_parameters` must point at an open frontend.
+    pub unsafe fn send(
+        frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+        message: &[u8],
+    ) -> Result<(), String> {
+        dvbv5_sys::dvb_fe_sec_tone(
+            frontend_parameters,
+            dvbv5_sys::fe_sec_tone_mode::SEC_TONE_OFF,
+        );
+        dvbv5_sys::dvb_fe_sec_voltage(frontend_parameters, 1, 0);
+        sleep(DISEQC_GAP);
+        if dvbv5_sys::dvb_fe_diseqc_cmd(frontend_parameters, message.len() as u32, message.as_ptr())
+            != 0
+        {
+            return Err("sending a DiSEqC command to the positioner failed".to_string());
+        }
+        sleep(DISEQC_GAP);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn usals_angles_and_goto_messages() {
+        let london = Site::parse("51.5N,0.1W").unwrap();
+        assert_eq!(Site::parse("51.5,-0.1"), Ok(london));
+        let astra = london.usals_angle(19.2).unwrap();
+        assert!((astra - 21.26).abs() < 0.01);
+        assert_eq!(
+            goto_angle_message(astra),
+            vec![0xe0, 0x31, 0x6e, 0xe1, 0x54]
+        );
+        let intelsat = london
+            .usals_angle(parse_orbital_position("30W").unwrap())
+            .unwrap();
+        assert!((intelsat + 32.83).abs() < 0.01);
+        assert_eq!(
+            goto_angle_message(intelsat),
+            vec![0xe0, 0x31, 0x6e, 0xd2, 0x0d]
+        );
+        assert!(london.usals_angle(-0.1).unwrap().abs() < 1e-9);
+        assert_eq!(goto_angle_message(0.0), vec![0xe0, 0x31, 0x6e, 0xe0, 0x00]);
+        assert!(london.usals_angle(120.0).is_err());
+        assert!(Site::parse("95N,0E").is_err());
+        assert!(Site::parse("51.5").is_err());
+    }
+
+    #[test]
+    fn stored_positions_and_slew_times() {
+        assert_eq!(DishPosition::parse("3"), Ok(DishPosition::Stored(3)));
+        assert_eq!(goto_stored_message(3), vec![0xe0, 0x31, 0x6b, 0x03]);
+        let hotbird = DishPosition::parse("13e").unwrap();
+        assert_eq!(hotbird, DishPosition::Orbital(13.0));
+        assert_eq!(hotbird.to_string(), "13E");
+        assert!(DishPosition::parse("13X").is_err());
+        let positioner = Positioner::default();
+        assert!(positioner.goto(&hotbird).is_err());
+        let positioner = Positioner {
+            site: Some(Site::parse("51.5,-0.1").unwrap()),
+            speed: 2.0,
+        };
+        let (angle, message) = positioner.goto(&hotbird).unwrap();
+        assert_eq!(message[..3], [0xe0, 0x31, 0x6e]);
+        assert_eq!(
+            positioner.slew_time(Some(-5.0), Some(15.0)),
+            Duration::from_secs(11)
+        );
+        assert_eq!(
+            positioner.slew_time(None, Some(-10.0)),
+            Duration::from_secs(46)
+        );
+        assert!(positioner.slew_time(None, angle) > positioner.slew_time(Some(0.0), angle));
+    }
+}
@@ -0,0 +1,272 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Parsing of the ATSC PSIP virtual channel tables (A/65): the TVCT of terrestrial
+//! broadcasts and the CVCT of cable ones.
+//!
+//! ATSC services are named and numbered by their virtual channels, a major.minor number
+//! and a short name, rather than by an SDT and logical channel numbers. ATSC 3.0 signals
+//! the same in its service list table, which can fill the same `VirtualChannel`, though
+//! the Linux DVB API has no ATSC 3.0 delivery system to receive it with.
+
+use crate::channels_file::ChannelEntry;
+use crate::si::{descriptors, Section};
+
+/// The PID carrying the PSIP base tables.
+pub const PSIP_PID: u16 = 0x1ffb;
+
+/// Table id of the Terrestrial Virtual Channel Table.
+pub const TVCT_TABLE_ID: u8 = 0xc8;
+
+/// Table id of the Cable Virtual Channel Table.
+pub const CVCT_TABLE_ID: u8 = 0xc9;
+
+/// Tag of the extended channel name descriptor.
+const EXTENDED_CHANNEL_NAME_DESCRIPTOR: u8 = 0xa0;
+
+/// The length of the fixed part of a virtual channel in a VCT.
+const CHANNEL_LENGTH: usize = 32;
+
+/// Whether a delivery system is an ATSC one, signalling its services with PSIP.
+pub fn is_atsc(delivery_system: u32) -> bool {
+    use dvbv5_sys::fe_delivery_system::*;
+    [SYS_ATSC, SYS_ATSCMH, SYS_DVBC_ANNEX_B]
+        .iter()
+        .any(|s| *s as u32 == delivery_system)
+}
+
+/// A virtual channel: how a service is presented to viewers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VirtualChannel {
+    pub short_name: String,
+    /// The name of the extended channel name descriptor, if there is one.
+    pub long_name: Option<String>,
+    pub major: u16,
+    pub minor: u16,
+    /// The transport stream carrying the service.
+    pub channel_tsid: u16,
+    /// The MPEG-2 program number, the service id.
+    pub program_number: u16,
+    /// The id by which the EIT and ETT refer to the virtual channel.
+    pub source_id: u16,
+    /// The ATSC service type: 0x01 analog television, 0x02 digital television, 0x03 audio,
+    /// 0x04 data.
+    pub service_type: u8,
+    pub access_controlled: bool,
+    pub hidden: bool,
+}
+
+impl VirtualChannel {
+    /// The channel number as viewers know it: major.minor, or for the one-part numbers of
+    /// cable, signalled by a major number of 1008 or more, a single number.
+    pub fn number(&self) -> String {
+        if self.major >= 1008 {
+            (u32::from(self.major & 0x0f) << 10 | u32::from(self.minor)).to_string()
+        } else {
+            format!("{}.{}", self.major, self.minor)
+        }
+    }
+
+    /// The EN 300 468 service type equivalent to the ATSC one, if there is one, so that
+    /// ATSC services can be classified as DVB ones are.
+    pub fn dvb_service_type(&self) -> Option<u8> {
+        match self.service_type {
+            0x02 => Some(0x01),
+            0x03 => Some(0x02),
+            0x04 => Some(0x0c),
+            _ => None,
+        }
+    }
+}
+
+/// A Terrestrial or Cable Virtual Channel Table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Vct {
+    /// Whether this is a CVCT.
+    pub cable: bool,
+    pub transport_stream_id: u16,
+    pub channels: Vec<VirtualChannel>,
+}
+
+impl Vct {
+    /// Parse the sections of a TVCT or CVCT.
+    pub fn parse(sections: &[&Section]) -> Result<Vct, String> {
+        let mut vct = Vct::default();
+        for section in sections {
+            if section.table_id != TVCT_TABLE_ID && section.table_id != CVCT_TABLE_ID {
+                return Err("not a TVCT or CVCT.".to_string());
+            }
+            vct.cable = section.table_id == CVCT_TABLE_ID;
+            vct.transport_stream_id = section.extension;
+            // The protocol version, then the number of channels in the section.
+            let count = *section.payload.get(1).ok_or("VCT section too short.")? as usize;
+            let mut data = &section.payload[2..];
+            for _ in 0..count {
+                if data.len() < CHANNEL_LENGTH {
+                    return Err("VCT channel overruns section.".to_string());
+                }
+                let descriptors_length = ((data[30] as usize & 0x03) << 8) | data[31] as usize;
+                let descriptor_loop = data
+                    .get(CHANNEL_LENGTH..CHANNEL_LENGTH + descriptors_length)
+                    .ok_or("VCT channel descriptor loop overruns section.")?;
+                let short_name = data[..14]
+                    .chunks(2)
+                    .map(|c| ((c[0] as u16) << 8) | c[1] as u16)
+                    .collect::<Vec<u16>>();
+                vct.channels.push(VirtualChannel {
+                    short_name: String::from_utf16_lossy(&short_name)
+                        .trim_end_matches('\0')
+                        .trim()
+                        .to_string(),
+                    long_name: descriptors(descriptor_loop)
+                        .find(|(tag, _)| *tag == EXTENDED_CHANNEL_NAME_DESCRIPTOR)
+                        .and_then(|(_, content)| decode_multiple_string(content)),
+                    major: ((data[14] as u16 & 0x0f) << 6) | (data[15] as u16 >> 2),
+                    minor: ((data[15] as u16 & 0x03) << 8) | data[16] as u16,
+                    channel_tsid: ((data[22] as u16) << 8) | data[23] as u16,
+                    program_number: ((data[24] as u16) << 8) | data[25] as u16,
+                    source_id: ((data[28] as u16) << 8) | data[29] as u16,
+                    service_type: data[27] & 0x3f,
+                    access_controlled: data[26] & 0x20 != 0,
+                    hidden: data[26] & 0x10 != 0,
+                });
+                data = &data[CHANNEL_LENGTH + descriptors_length..];
+            }
+        }
+        Ok(vct)
+    }
+
+    /// The virtual channel of a program of a transport stream. A transport stream id of
+    /// zero, as libdvbv5 writes when it has none, matches any.
+    pub fn channel(&self, transport_id: u16, program_number: u16) -> Option<&VirtualChannel> {
+        self.channels.iter().find(|c| {
+            c.program_number == program_number
+                && (transport_id == 0 || c.channel_tsid == transport_id)
+        })
+    }
+}
+
+/// Decode the first string of an ATSC multiple string structure (A/65 6.10). Only
+/// uncompressed text is decoded, each byte being the low byte of a Unicode character
+/// whose high byte is the segment's mode.
+fn decode_multiple_string(data: &[u8]) -> Option<String> {
+    // The number of strings, the language code, then the number of segments.
+    let (count, mut data) = (*data.get(4)?, data.get(5..)?);
+    let mut text = String::new();
+    for _ in 0..count {
+        let (compression, mode, length) = (*data.first()?, *data.get(1)?, *data.get(2)? as usize);
+        let bytes = data.get(3..3 + length)?;
+        if compression == 0 && mode <= 0x33 {
+            text.extend(
+                bytes
+                    .iter()
+                    .filter_map(|b| char::from_u32(u32::from(mode) << 8 | u32::from(*b))),
+            );
+        }
+        data = &data[3 + length..];
+    }
+    Some(text.trim().to_string()).filter(|t| !t.is_empty())
+}
+
+/// Record a virtual channel in a channels file entry: its short name as the name, its
+/// number as the `VCHANNEL` property, and its other details as annotations.
+pub fn annotate(entry: &mut ChannelEntry, channel: &VirtualChannel) {
+    if !channel.short_name.is_empty() {
+        entry.name = channel.short_name.clone();
+    }
+    entry.set("VCHANNEL", &channel.number());
+    if let Some(long_name) = &channel.long_name {
+        entry.set_annotation("LONG_NAME", long_name);
+    }
+    entry.set_annotation("SOURCE_ID", &channel.source_id.to_string());
+    if let Some(service_type) = channel.dvb_service_type() {
+        entry.set_annotation("SERVICE_TYPE", &service_type.to_string());
+    }
+    entry.set_annotation(
+        "SCRAMBLED",
+        if channel.access_controlled { "1" } else { "0" },
+    );
+    if channel.hidden {
+        entry.set_annotation("HIDDEN", "1");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// A TVCT section, without its CRC, in the form a capture gives: KQED-HD 9.1 and,
+    /// with an extended channel name, KQED+ 9.2, on transport stream 0x0bc3.
+    const TVCT_SECTION: &str = "c8f0 600b c3c1 0000 0002 004b 0051 0045 0044 002d 0048 0044 \
+                                f024 0104 0000 0000 0bc3 0003 0d02 0001 fc00 004b 0051 0045 \
+                                0044 002b 0000 0000 f024 0204 0000 0000 0bc3 0004 0d02 0002 \
+                                fc13 a011 0165 6e67 0100 0009 4b51 4544 2050 6c75 73fc 00";
+
+    /// Decode the hexadecimal text of a section and append its CRC.
+    fn section(hex: &str) -> Section {
+        let digits = hex
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<Vec<char>>();
+        let mut data = digits
+            .chunks(2)
+            .map(|d| u8::from_str_radix(&d.iter().collect::<String>(), 16).unwrap())
+            .collect::<Vec<u8>>();
+        let crc = crate::si::crc32(&data);
+        data.extend_from_slice(&crc.to_be_bytes());
+        Section::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn parse_tvct() {
+        let vct = Vct::parse(&[&section(TVCT_SECTION)]).unwrap();
+        assert!(!vct.cable);
+        assert_eq!(vct.transport_stream_id, 0x0bc3);
+        assert_eq!(vct.channels.len(), 2);
+        let hd = &vct.channels[0];
+        assert_eq!(hd.short_name, "KQED-HD");
+        assert_eq!((hd.major, hd.minor), (9, 1));
+        assert_eq!(hd.number(), "9.1");
+        assert_eq!(hd.program_number, 3);
+        assert_eq!(hd.source_id, 1);
+        assert_eq!(hd.dvb_service_type(), Some(0x01));
+        let plus = vct.channel(0x0bc3, 4).unwrap();
+        assert_eq!(plus.short_name, "KQED+");
+        assert_eq!(plus.long_name.as_deref(), Some("KQED Plus"));
+        assert_eq!(plus.number(), "9.2");
+        assert!(vct.channel(0x0bc4, 4).is_none());
+        let mut entry = ChannelEntry::new("Program 4");
+        entry.set("SERVICE_ID", "4");
+        annotate(&mut entry, plus);
+        assert_eq!(entry.name, "KQED+");
+        assert_eq!(entry.get("VCHANNEL"), Some("9.2"));
+        assert_eq!(entry.get_annotation("SOURCE_ID"), Some("2"));
+    }
+
+    #[test]
+    fn one_part_cable_channel_numbers() {
+        let channel = VirtualChannel {
+            major: 1009,
+            minor: 0x0102,
+            ..VirtualChannel::default()
+        };
+        assert_eq!(channel.number(), "1282");
+    }
+}
@@ -0,0 +1,417 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Recording a transport stream to files.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::spts::SptsRemuxer;
+use crate::ts::PACKET_SIZE;
+
+/// The amount read from the source at a time, a whole number of packets.
+const READ_SIZE: usize = 348 * PACKET_SIZE;
+
+/// Set when the user interrupts the program, so that recording stops cleanly.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Arrange for SIGINT and SIGTERM to stop a recording rather than end the program, so that
+/// the files are completed.
+pub fn handle_interrupts() -> &'static AtomicBool {
+    unsafe {
+        let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    &INTERRUPTED
+}
+
+/// Parse a duration such as `90`, `45s`, `30m` or `1h30m`; a number without unit is in
+/// seconds.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let error = || format!("{} is not a duration.", text);
+    let mut seconds = 0u64;
+    let mut number = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+        } else {
+            let value = number.parse::<u64>().map_err(|_| error())?;
+            seconds += value
+                * match c {
+                    'h' => 3600,
+                    'm' => 60,
+                    's' => 1,
+                    _ => return Err(error()),
+                };
+            number.clear();
+        }
+    }
+    if !number.is_empty() {
+        seconds += number.parse::<u64>().map_err(|_| error())?;
+    } else if seconds == 0 && text.trim().is_empty() {
+        return Err(error());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parse a size in bytes such as `4096`, `500M` or `2G`, the multipliers being powers of
+/// 1024.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let digits = text.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match text[digits.len()..].to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("{} is not a size.", text)),
+    };
+    digits
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("{} is not a size.", text))
+}
+
+/// Where `copy` writes the transport stream.
+pub trait PacketOutput: Write {
+    /// Give the packets of the PAT and PMT of the single program being written, for an
+    /// output that starts new files to begin each with, so that every file plays on its
+    /// own.
+    fn set_tables(&mut self, _tables: Vec<u8>) {}
+}
+
+/// When to start a new output file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rollover {
+    pub size: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+impl Rollover {
+    fn is_enabled(&self) -> bool {
+        self.size.is_some() || self.duration.is_some()
+    }
+}
+
+/// The files written by a recording. Without rollover the single file is at the given
+/// path; with rollover a sequence number is added to the file stem of each, so `out.ts`
+/// gives `out-0000.ts`, `out-0001.ts`, and so on.
+#[derive(Debug)]
+pub struct OutputFiles {
+    path: PathBuf,
+    rollover: Rollover,
+    /// Flush each file to the storage device when it is completed.
+    fsync: bool,
+    /// Also flush to the storage device at this interval.
+    fsync_interval: Option<Duration>,
+    index: usize,
+    file: Option<File>,
+    /// The PAT and PMT to start files after the first with.
+    tables: Vec<u8>,
+    written: u64,
+    opened: Instant,
+    synced: Instant,
+    paths: Vec<PathBuf>,
+}
+
+impl OutputFiles {
+    pub fn new(
+        path: &Path,
+        rollover: Rollover,
+        fsync: bool,
+        fsync_interval: Option<Duration>,
+    ) -> OutputFiles {
+        OutputFiles {
+            path: path.to_path_buf(),
+            rollover,
+            fsync,
+            fsync_interval,
+            index: 0,
+            file: None,
+            tables: Vec::new(),
+            written: 0,
+            opened: Instant::now(),
+            synced: Instant::now(),
+            paths: Vec::new(),
+        }
+    }
+
+    /// The paths of the files written so far.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    fn next_path(&self) -> PathBuf {
+        if !self.rollover.is_enabled() {
+            return self.path.clone();
+        }
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(extension) => {
+                format!("{}-{:04}.{}", stem, self.index, extension.to_string_lossy())
+            }
+            None => format!("{}-{:04}", stem, self.index),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(file) = self.file.take() {
+            if self.fsync || self.fsync_interval.is_some() {
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a rollover limit has been reached by the current file: for size, when
+    /// another packet would exceed it.
+    fn is_full(&self) -> bool {
+        self.rollover
+            .size
+            .map(|size| self.written + PACKET_SIZE as u64 > size)
+            .unwrap_or(false)
+            || self
+                .rollover
+                .duration
+                .map(|duration| self.opened.elapsed() >= duration)
+                .unwrap_or(false)
+    }
+
+    /// Write data, which must be whole packets so that each file is a valid transport
+    /// stream, starting new files as rollover limits are reached. Files after the first
+    /// start with the tables given by `set_tables`.
+    pub fn write_packets(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.file.is_some() && self.is_full() {
+                self.close()?;
+                self.index += 1;
+            }
+            let new_file = self.file.is_none();
+            if new_file {
+                let path = self.next_path();
+                let mut file = File::create(&path)?;
+                self.written = 0;
+                if self.index > 0 {
+                    file.write_all(&self.tables)?;
+                    self.written = self.tables.len() as u64;
+                }
+                self.file = Some(file);
+                self.paths.push(path);
+                self.opened = Instant::now();
+            }
+            let length = match self.rollover.size {
+                Some(size) => {
+                    let room = size.saturating_sub(self.written) as usize;
+                    let whole = room - room % PACKET_SIZE;
+                    // A file gets at least one packet, however small the limit.
+                    if new_file {
+                        whole.max(PACKET_SIZE)
+                    } else {
+                        whole
+                    }
+                }
+                None => data.len(),
+            }
+            .min(data.len());
+            if let Some(file) = self.file.as_mut() {
+                file.write_all(&data[..length])?;
+                self.written += length as u64;
+                if let Some(interval) = self.fsync_interval {
+                    if self.synced.elapsed() >= interval {
+                        file.sync_data()?;
+                        self.synced = Instant::now();
+                    }
+                }
+            }
+            data = &data[length..];
+        }
+        Ok(())
+    }
+
+    /// Complete the last file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.close()
+    }
+}
+
+impl PacketOutput for OutputFiles {
+    fn set_tables(&mut self, tables: Vec<u8>) {
+        self.tables = tables;
+    }
+}
+
+impl Write for OutputFiles {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.write_packets(data)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Copy a transport stream from `source` to `output` until the source ends, `duration` has
+/// passed, or `stop` is set, remuxing to a single program if `remuxer` is given. Sources
+/// may return `TimedOut` or `WouldBlock` errors when there is no data so that `stop` and
+/// `duration` are checked. Return the number of bytes written.
+pub fn copy<R: Read, W: PacketOutput>(
+    mut source: R,
+    mut remuxer: Option<SptsRemuxer>,
+    output: &mut W,
+    duration: Option<Duration>,
+    stop: &AtomicBool,
+) -> io::Result<u64> {
+    let start = Instant::now();
+    let mut buffer = vec![0u8; READ_SIZE];
+    let mut pending = Vec::new();
+    let mut total = 0;
+    while !stop.load(Ordering::SeqCst) && duration.map(|d| start.elapsed() < d).unwrap_or(true) {
+        let count = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {
+                continue
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let packets = match remuxer.as_mut() {
+            Some(remuxer) => {
+                let mut packets = Vec::new();
+                remuxer.push(&buffer[..count], &mut packets);
+                output.set_tables(remuxer.tables());
+                packets
+            }
+            None => {
+                // Keep to whole packets so that rollover splits between packets.
+                pending.extend_from_slice(&buffer[..count]);
+                let whole = pending.len() - pending.len() % PACKET_SIZE;
+                pending.drain(..whole).collect()
+            }
+        };
+        if !packets.is_empty() {
+            output.write_all(&packets)?;
+            total += packets.len() as u64;
+        }
+    }
+    output.flush()?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::spts::tests::make_multiplex;
+    use crate::ts::Packet;
+
+    #[test]
+    fn parse_durations_and_sizes() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("1h30m15s"), Ok(Duration::from_secs(5415)));
+        assert!(parse_duration("30x").is_err());
+        assert!(parse_duration("").is_err());
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("500M"), Ok(500 * 1024 * 1024));
+        assert_eq!(parse_size("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("2Q").is_err());
+    }
+
+    #[test]
+    fn record_with_rollover() {
+        let directory =
+            std::env::temp_dir().join(format!("dvb-tune-record-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input = make_multiplex();
+        let rollover = Rollover {
+            size: Some(4 * PACKET_SIZE as u64),
+            duration: None,
+        };
+        let mut output = OutputFiles::new(&directory.join("out.ts"), rollover, true, None);
+        let stop = AtomicBool::new(false);
+        let written = copy(
+            Cursor::new(&input),
+            Some(SptsRemuxer::new(1)),
+            &mut output,
+            None,
+            &stop,
+        )
+        .unwrap();
+        assert_eq!(written, 8 * PACKET_SIZE as u64);
+        let paths = output.paths().to_vec();
+        output.finish().unwrap();
+        // Each file starts with the PAT and PMT, so the six packets of the program after
+        // them take two more files.
+        assert_eq!(
+            paths,
+            vec![
+                directory.join("out-0000.ts"),
+                directory.join("out-0001.ts"),
+                directory.join("out-0002.ts")
+            ]
+        );
+        for path in &paths {
+            let data = fs::read(path).unwrap();
+            assert_eq!(data.len(), 4 * PACKET_SIZE);
+            let pids = data
+                .chunks(PACKET_SIZE)
+                .map(|p| Packet::new(p).unwrap().pid())
+                .collect::<Vec<_>>();
+            assert_eq!(pids, vec![0x00, 0x100, 0x101, 0x102]);
+        }
+        // Files stop at the last whole packet under a limit that is not a multiple of
+        // the packet size.
+        let rollover = Rollover {
+            size: Some(4 * PACKET_SIZE as u64 + 100),
+            duration: None,
+        };
+        let mut output = OutputFiles::new(&directory.join("split.ts"), rollover, false, None);
+        output.write_packets(&input[..9 * PACKET_SIZE]).unwrap();
+        let sizes = output
+            .paths()
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len() / PACKET_SIZE as u64)
+            .collect::<Vec<_>>();
+        output.finish().unwrap();
+        assert_eq!(sizes, vec![4, 4, 1]);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Retrying transponders that do not lock with variations of the tuning parameters of the
+//! transmitter file: some transmitters are offset from the nominal frequency, and older
+//! transmitter files give the wrong bandwidth or spectral inversion.
+
+use std::fmt;
+
+use dvbv5_sys::fe_spectral_inversion::{self, *};
+
+/// The spectral inversions to try, in order.
+const INVERSIONS: [fe_spectral_inversion; 3] = [INVERSION_AUTO, INVERSION_OFF, INVERSION_ON];
+
+/// The variations of tuning parameters to try when a transponder does not lock.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetryStrategy {
+    /// Offsets from the frequency in kHz, e.g. ±166 kHz for DVB-T.
+    pub offsets: Vec<i32>,
+    /// Bandwidths in Hz, for the delivery systems that have one.
+    pub bandwidths: Vec<u32>,
+    /// Try automatic, off and on spectral inversion.
+    pub inversions: bool,
+}
+
+/// A variation of the tuning parameters of a transponder. Parameters that are not varied
+/// are `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Variant {
+    /// The offset from the frequency in kHz.
+    pub offset: i32,
+    /// The bandwidth in Hz.
+    pub bandwidth: Option<u32>,
+    /// The spectral inversion, as libdvbv5 numbers them.
+    pub inversion: Option<u32>,
+}
+
+impl RetryStrategy {
+    /// Whether no variations are to be tried.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty() && self.bandwidths.is_empty() && !self.inversions
+    }
+
+    /// The variants to try for a transponder with the given bandwidth and inversion, if
+    /// it has them, in order: those changing fewest parameters first.
+    pub fn variants(&self, bandwidth: Option<u32>, inversion: Option<u32>) -> Vec<Variant> {
+        let offsets = std::iter::once(0)
+            .chain(self.offsets.iter().copied().filter(|o| *o != 0))
+            .collect::<Vec<_>>();
+        let mut bandwidths = vec![None];
+        if bandwidth.is_some() {
+            bandwidths.extend(
+                self.bandwidths
+                    .iter()
+                    .filter(|b| Some(**b) != bandwidth)
+                    .map(|b| Some(*b)),
+            );
+        }
+        let mut inversions = vec![None];
+        if self.inversions {
+            inversions.extend(
+                INVERSIONS
+                    .iter()
+                    .map(|i| *i as u32)
+                    .filter(|i| Some(*i) != inversion)
+                    .map(Some),
+            );
+        }
+        let mut variants = Vec::new();
+        for offset in &offsets {
+            for bandwidth in &bandwidths {
+                for inversion in &inversions {
+                    variants.push(Variant {
+                        offset: *offset,
+                        bandwidth: *bandwidth,
+                        inversion: *inversion,
+                    });
+                }
+            }
+        }
+        // The first is the transponder as given, which has been tried already.
+        variants.remove(0);
+        variants.sort_by_key(Variant::changes);
+        variants
+    }
+}
+
+impl Variant {
+    /// The number of parameters varied.
+    fn changes(&self) -> usize {
+        [
+            self.offset != 0,
+            self.bandwidth.is_some(),
+            self.inversion.is_some(),
+        ]
+        .iter()
+        .filter(|c| **c)
+        .count()
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut changes = Vec::new();
+        if self.offset != 0 {
+            changes.push(format!("offset {:+}kHz", self.offset));
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            changes.push(format!("bandwidth {}MHz", bandwidth as f64 / 1_000_000.0));
+        }
+        if let Some(inversion) = self.inversion {
+            let name = match inversion {
+                i if i == INVERSION_OFF as u32 => "off",
+                i if i == INVERSION_ON as u32 => "on",
+                _ => "auto",
+            };
+            changes.push(format!("inversion {}", name));
+        }
+        write!(f, "{}", changes.join(", "))
+    }
+}
+
+/// Parse a comma separated list of frequency offsets in kHz, e.g. `166,-166`.
+pub fn parse_offsets(text: &str) -> Result<Vec<i32>, String> {
+    text.split(',')
+        .map(|o| {
+            o.trim()
+                .trim_start_matches('+')
+                .parse::<i32>()
+                .map_err(|_| format!("{} is not a valid frequency offset", o))
+        })
+        .collect()
+}
+
+/// Parse a comma separated list of bandwidths in MHz, e.g. `7,8`, giving them in Hz.
+pub fn parse_bandwidths(text: &str) -> Result<Vec<u32>, String> {
+    text.split(',')
+        .map(|b| {
+            b.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|b| *b > 0.0 && *b <= 10.0)
+                .map(|b| (b * 1_000_000.0).round() as u32)
+                .ok_or_else(|| format!("{} is not a valid bandwidth", b))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn variants_change_fewest_parameters_first() {
+        let strategy = RetryStrategy {
+            offsets: parse_offsets("+166,-166").unwrap(),
+            bandwidths: parse_bandwidths("7,8").unwrap(),
+            inversions: false,
+        };
+        let variants = strategy.variants(Some(8_000_000), Some(INVERSION_AUTO as u32));
+        assert_eq!(variants.len(), 5);
+        assert_eq!(variants[0].to_string(), "bandwidth 7MHz");
+        assert_eq!(variants[1].to_string(), "offset +166kHz");
+        assert_eq!(variants[2].to_string(), "offset -166kHz");
+        assert_eq!(variants[3].to_string(), "offset +166kHz, bandwidth 7MHz");
+        let strategy = RetryStrategy {
+            inversions: true,
+            ..strategy
+        };
+        let variants = strategy.variants(None, Some(INVERSION_AUTO as u32));
+        assert_eq!(variants.len(), 8);
+        assert_eq!(variants[1].to_string(), "inversion on");
+        assert!(parse_bandwidths("eight").is_err());
+    }
+}
@@ -0,0 +1,410 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Satellite equipment control: LNBs, LNB power and DiSEqC switching.
+//!
+//! libdvbv5 converts satellite frequencies to intermediate frequencies with the LNB it is
+//! given and sets the polarization voltage and 22 kHz tone, but its own DiSEqC support
+//! only covers a committed switch selected by a satellite number. So libdvbv5 is told
+//! not to use DiSEqC and the switch commands of DiSEqC 1.0 and 1.1 and the tone burst
+//! are sent here before each tune.
+
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::positioner::{self, DishPosition};
+
+/// The interval required by DiSEqC between changes of voltage or tone and messages.
+pub const DISEQC_GAP: Duration = Duration::from_millis(15);
+
+/// DiSEqC framing byte: command from the master, no reply required, first transmission.
+pub const DISEQC_COMMAND: u8 = 0xe0;
+
+/// DiSEqC address byte: any LNB, switcher or SMATV device.
+pub const DISEQC_ANY_SWITCH: u8 = 0x10;
+
+/// DiSEqC commands writing the committed and uncommitted switch port groups.
+const DISEQC_WRITE_N0: u8 = 0x38;
+const DISEQC_WRITE_N1: u8 = 0x39;
+
+/// An LNB: its local oscillator frequencies and, for a dual band LNB, the frequency at
+/// which the high band starts, all in MHz.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lnb {
+    /// The name written in channels files, a libdvbv5 LNB name for the presets so that
+    /// other libdvbv5 tools can use the files.
+    pub alias: String,
+    pub low_oscillator: u32,
+    pub high_oscillator: Option<u32>,
+    pub switch_frequency: Option<u32>,
+}
+
+/// A preset LNB: its name, libdvbv5 name, and oscillator and switch frequencies.
+type Preset = (&'static str, &'static str, u32, Option<u32>, Option<u32>);
+
+const PRESETS: &[Preset] = &[
+    ("universal", "UNIVERSAL", 9750, Some(10600), Some(11700)),
+    ("dbs", "DBS", 11250, None, None),
+    ("standard", "STANDARD", 10000, None, None),
+    ("enhanced", "ENHANCED", 9750, None, None),
+    ("l10700", "L10700", 10700, None, None),
+    ("l10750", "L10750", 10750, None, None),
+    ("l11300", "L11300", 11300, None, None),
+    ("c-band", "C-BAND", 5150, None, None),
+];
+
+impl Lnb {
+    /// Get an LNB from a preset name, ignoring case, or from its oscillator frequencies
+    /// in MHz: `LOW` for a single band LNB, or `LOW,HIGH,SWITCH` for a dual band one.
+    pub fn from_name(name: &str) -> Result<Lnb, String> {
+        if let Some((_, alias, low, high, switch)) = PRESETS
+            .iter()
+            .find(|(n, a, ..)| n.eq_ignore_ascii_case(name) || a.eq_ignore_ascii_case(name))
+        {
+            return Ok(Lnb {
+                alias: alias.to_string(),
+                low_oscillator: *low,
+                high_oscillator: *high,
+                switch_frequency: *switch,
+            });
+        }
+        let frequencies = name
+            .split(',')
+            .map(|f| f.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| format!("unknown LNB {}", name))?;
+        match frequencies[..] {
+            [low] => Ok(Lnb {
+                alias: low.to_string(),
+                low_oscillator: low,
+                high_oscillator: None,
+                switch_frequency: None,
+            }),
+            [low, high, switch] if low < high => Ok(Lnb {
+                alias: format!("{},{},{}", low, high, switch),
+                low_oscillator: low,
+                high_oscillator: Some(high),
+                switch_frequency: Some(switch),
+            }),
+            _ => Err(format!(
+                "an LNB is a preset or LOW or LOW,HIGH,SWITCH in MHz, not {}",
+                name
+            )),
+        }
+    }
+
+    /// Whether a frequency, in kHz, is received in the high band of the LNB.
+    pub fn is_high_band(&self, frequency: u32) -> bool {
+        match (self.high_oscillator, self.switch_frequency) {
+            (Some(_), Some(switch)) => frequency >= switch * 1000,
+            _ => false,
+        }
+    }
+
+    /// The intermediate frequency, in kHz, at which the LNB outputs a frequency in kHz.
+    pub fn intermediate_frequency(&self, frequency: u32) -> u32 {
+        self.conversion(frequency).intermediate_frequency(frequency)
+    }
+
+    /// The conversion by the LNB of a frequency in kHz: the oscillator of its band.
+    pub fn conversion(&self, frequency: u32) -> Conversion {
+        let oscillator = match self.high_oscillator {
+            Some(high) if self.is_high_band(frequency) => high,
+            _ => self.low_oscillator,
+        } * 1000;
+        Conversion {
+            oscillator,
+            inverted: oscillator > frequency,
+        }
+    }
+}
+
+/// The conversion of transponder frequencies to intermediate frequencies by a local
+/// oscillator, all in kHz. The oscillator is above the transponders of C band LNBs, so
+/// that the spectrum is inverted.
+///
+/// libdvbv5 only converts frequencies with LNBs of its own, so those given by their
+/// oscillator frequencies, and the user bands of single cable routers, are tuned by
+/// giving libdvbv5 no LNB and the intermediate frequency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conversion {
+    pub oscillator: u32,
+    pub inverted: bool,
+}
+
+impl Conversion {
+    /// The intermediate frequency of a transponder frequency.
+    pub fn intermediate_frequency(&self, frequency: u32) -> u32 {
+        if self.inverted {
+            self.oscillator.saturating_sub(frequency)
+        } else {
+            frequency.saturating_sub(self.oscillator)
+        }
+    }
+
+    /// The transponder frequency of an intermediate frequency.
+    pub fn transponder_frequency(&self, intermediate_frequency: u32) -> u32 {
+        if self.inverted {
+            self.oscillator.saturating_sub(intermediate_frequency)
+        } else {
+            self.oscillator + intermediate_frequency
+        }
+    }
+}
+
+/// The power supplied to the LNB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LnbPower {
+    /// 13 V or 18 V according to polarization.
+    On,
+    /// No power, for an LNB powered by another receiver or a powered multiswitch.
+    Off,
+    /// 14 V or 19 V, to make up for the voltage drop of a long cable.
+    High,
+}
+
+impl LnbPower {
+    pub fn from_name(name: &str) -> Option<LnbPower> {
+        match name {
+            "on" => Some(LnbPower::On),
+            "off" => Some(LnbPower::Off),
+            "high" => Some(LnbPower::High),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LnbPower::On => "on",
+            LnbPower::Off => "off",
+            LnbPower::High => "high",
+        }
+    }
+}
+
+/// The tone burst, or mini DiSEqC, selecting between two inputs of a simple switch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneBurst {
+    A,
+    B,
+}
+
+/// The settings selecting a satellite position: a DiSEqC 1.0 committed port, a DiSEqC 1.1
+/// uncommitted port, a tone burst and a DiSEqC 1.2 positioner position, any of which may
+/// be used.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SatellitePosition {
+    /// A name for the position, e.g. `Astra-19.2E`.
+    pub name: String,
+    /// The committed port, 0 to 3.
+    pub committed: Option<u8>,
+    /// The uncommitted port, 0 to 15.
+    pub uncommitted: Option<u8>,
+    pub tone_burst: Option<ToneBurst>,
+    /// Where a motorised dish is sent.
+    pub dish: Option<DishPosition>,
+}
+
+impl SatellitePosition {
+    /// Parse settings given as a comma separated list of `cN` for committed port N (1 to
+    /// 4), `uN` for uncommitted port N (1 to 16), `a` or `b` for a tone burst, `pN` for
+    /// stored positioner position N, and an orbital position, e.g. `19.2E`, for USALS.
+    pub fn parse(name: &str, settings: &str) -> Result<SatellitePosition, String> {
+        let mut position = SatellitePosition {
+            name: name.to_string(),
+            ..SatellitePosition::default()
+        };
+        for setting in settings.split(',').map(|s| s.trim().to_ascii_lowercase()) {
+            let port = |limit: u8| {
+                setting[1..]
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|p| (1..=limit).contains(p))
+                    .map(|p| p - 1)
+                    .ok_or_else(|| format!("invalid DiSEqC port {}", setting))
+            };
+            match setting.chars().next() {
+                Some('c') => position.committed = Some(port(4)?),
+                Some('u') => position.uncommitted = Some(port(16)?),
+                Some('a') if setting.len() == 1 => position.tone_burst = Some(ToneBurst::A),
+                Some('b') if setting.len() == 1 => position.tone_burst = Some(ToneBurst::B),
+                Some('p') => {
+                    let number = setting[1..]
+                        .parse::<u8>()
+                        .map_err(|_| format!("invalid positioner position {}", setting))?;
+                    position.dish = Some(DishPosition::Stored(number));
+                }
+                Some(c) if c.is_ascii_digit() && setting.ends_with(&['e', 'w'][..]) => {
+                    let longitude = positioner::parse_orbital_position(&setting)?;
+                    position.dish = Some(DishPosition::Orbital(longitude));
+                }
+                _ => return Err(format!("invalid DiSEqC setting {}", setting)),
+            }
+        }
+        Ok(position)
+    }
+
+    /// The DiSEqC messages selecting this position for a transponder of the given
+    /// polarization and band. The uncommitted switch, nearer the LNBs in a cascade, is
+    /// set first.
+    pub fn messages(&self, horizontal: bool, high_band: bool) -> Vec<[u8; 4]> {
+        let mut messages = Vec::new();
+        if let Some(port) = self.uncommitted {
+            messages.push([
+                DISEQC_COMMAND,
+                DISEQC_ANY_SWITCH,
+                DISEQC_WRITE_N1,
+                0xf0 | port,
+            ]);
+        }
+        if let Some(port) = self.committed {
+            messages.push([
+                DISEQC_COMMAND,
+                DISEQC_ANY_SWITCH,
+                DISEQC_WRITE_N0,
+                0xf0 | port << 2 | if horizontal { 0x02 } else { 0 } | high_band as u8,
+            ]);
+        }
+        messages
+    }
+
+    /// Send the DiSEqC messages and tone burst selecting this position to a frontend,
+    /// waiting `diseqc_wait` more than the minimum between messages. The tone for the
+    /// band is set when tuning.
+    ///
+    /// # Safety
+    ///
+    /// `frontend_parameters` must point at an open frontend.
+    pub unsafe fn select(
+        &self,
+        frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+        horizontal: bool,
+        high_band: bool,
+        diseqc_wait: Duration,
+    ) -> Result<(), String> {
+        dvbv5_sys::dvb_fe_sec_tone(
+            frontend_parameters,
+            dvbv5_sys::fe_sec_tone_mode::SEC_TONE_OFF,
+        );
+        dvbv5_sys::dvb_fe_sec_voltage(frontend_parameters, 1, horizontal as i32);
+        sleep(DISEQC_GAP);
+        for message in self.messages(horizontal, high_band) {
+            if dvbv5_sys::dvb_fe_diseqc_cmd(frontend_parameters, 4, message.as_ptr()) != 0 {
+                return Err("sending a DiSEqC command failed".to_string());
+            }
+            sleep(DISEQC_GAP + diseqc_wait);
+        }
+        if let Some(burst) = self.tone_burst {
+            if dvbv5_sys::dvb_fe_diseqc_burst(frontend_parameters, (burst == ToneBurst::B) as i32)
+                != 0
+            {
+                return Err("sending the tone burst failed".to_string());
+            }
+            sleep(DISEQC_GAP);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SatellitePosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut settings = Vec::new();
+        settings.extend(self.committed.map(|p| format!("c{}", p + 1)));
+        settings.extend(self.uncommitted.map(|p| format!("u{}", p + 1)));
+        settings.extend(self.tone_burst.map(|b| format!("{:?}", b).to_lowercase()));
+        settings.extend(self.dish.map(|d| match d {
+            DishPosition::Stored(number) => format!("p{}", number),
+            DishPosition::Orbital(_) => d.to_string(),
+        }));
+        write!(f, "{}", settings.join(","))
+    }
+}
+
+/// Whether a delivery system is a satellite one, needing an LNB.
+pub fn is_satellite(delivery_system: u32) -> bool {
+    use dvbv5_sys::fe_delivery_system::*;
+    [SYS_DVBS, SYS_DVBS2, SYS_TURBO, SYS_ISDBS, SYS_DSS]
+        .iter()
+        .any(|s| *s as u32 == delivery_system)
+}
+
+/// Whether a polarization is received with the 18 V supply.
+pub fn is_horizontal(polarization: u32) -> bool {
+    polarization == dvbv5_sys::dvb_sat_polarization::POLARIZATION_H as u32
+        || polarization == dvbv5_sys::dvb_sat_polarization::POLARIZATION_L as u32
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn lnb_presets_and_oscillator_frequencies() {
+        let universal = Lnb::from_name("Universal").unwrap();
+        assert_eq!(universal.alias, "UNIVERSAL");
+        assert!(!universal.is_high_band(11_494_000));
+        assert!(universal.is_high_band(12_188_000));
+        assert_eq!(universal.intermediate_frequency(12_188_000), 1_588_000);
+        let c_band = Lnb::from_name("c-band").unwrap();
+        assert_eq!(c_band.low_oscillator, 5150);
+        assert!(!c_band.is_high_band(4_000_000));
+        assert_eq!(c_band.intermediate_frequency(4_000_000), 1_150_000);
+        let conversion = c_band.conversion(4_000_000);
+        assert!(conversion.inverted);
+        assert_eq!(conversion.transponder_frequency(1_150_000), 4_000_000);
+        assert_eq!(
+            universal
+                .conversion(11_494_000)
+                .transponder_frequency(1_744_000),
+            11_494_000
+        );
+        let custom = Lnb::from_name("9750,10750,11700").unwrap();
+        assert_eq!(custom.high_oscillator, Some(10750));
+        assert_eq!(Lnb::from_name(&custom.alias), Ok(custom));
+        assert!(Lnb::from_name("10600,9750,11700").is_err());
+        assert!(Lnb::from_name("dish").is_err());
+    }
+
+    #[test]
+    fn diseqc_switch_messages() {
+        let position = SatellitePosition::parse("Hotbird-13E", "u2,c3").unwrap();
+        assert_eq!(position.to_string(), "c3,u2");
+        assert_eq!(
+            position.messages(true, false),
+            vec![[0xe0, 0x10, 0x39, 0xf1], [0xe0, 0x10, 0x38, 0xfa]]
+        );
+        let position = SatellitePosition::parse("Astra-19.2E", "c1,B").unwrap();
+        assert_eq!(position.tone_burst, Some(ToneBurst::B));
+        assert_eq!(
+            position.messages(false, true),
+            vec![[0xe0, 0x10, 0x38, 0xf1]]
+        );
+        let position = SatellitePosition::parse("Hotbird-13E", "13e,u1").unwrap();
+        assert_eq!(position.dish, Some(DishPosition::Orbital(13.0)));
+        assert_eq!(position.to_string(), "u1,13E");
+        let position = SatellitePosition::parse("Eutelsat-5W", "p4").unwrap();
+        assert_eq!(position.dish, Some(DishPosition::Stored(4)));
+        assert_eq!(position.to_string(), "p4");
+        assert!(position.messages(false, false).is_empty());
+        assert!(SatellitePosition::parse("", "c5").is_err());
+        assert!(SatellitePosition::parse("", "x1").is_err());
+    }
+}
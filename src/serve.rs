@@ -0,0 +1,692 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! An HTTP server streaming the services of a channels file.
+//!
+//! `/stream/<service>` tunes on demand and streams the single program transport stream of
+//! the service with chunked transfer encoding, and `/playlist.m3u` lists every service.
+//! There is one tuner: clients of services on the same transport stream share it, a
+//! service on another is refused while it has clients and otherwise retunes it, and it
+//! is released once it has had no clients for the idle timeout.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::demux;
+use crate::frontend::{Channel, Frontend};
+use crate::spts::SptsRemuxer;
+use crate::ts::PACKET_SIZE;
+use crate::unicable::UserBand;
+
+/// The amount read from a tuner at a time, a whole number of packets.
+const READ_SIZE: usize = 64 * PACKET_SIZE;
+
+/// The number of reads buffered for each client before data for it is dropped.
+const CLIENT_BUFFER_SIZE: usize = 256;
+
+/// How long a client waits for the first data of its service.
+const START_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest request, request line and headers, read.
+const MAX_REQUEST_SIZE: u64 = 8192;
+
+/// A source of the transport stream carrying a service.
+pub trait Tuner: Send + Sync {
+    /// Tune to the transport stream carrying a service. Reads of the stream may return
+    /// `TimedOut` or `WouldBlock` errors when there is no data.
+    fn open(&self, channel: &Channel) -> Result<Box<dyn Read>, String>;
+}
+
+/// A frontend of a DVB adapter.
+#[derive(Debug)]
+pub struct DvbTuner {
+    pub frontend_id: dvbv5::FrontendId,
+    pub verbose: u32,
+    pub user_band: Option<UserBand>,
+}
+
+/// The transport stream of a tuned frontend, which stays tuned until this is dropped.
+struct DvbSource {
+    _frontend: Frontend,
+    _filter: demux::PesFilter,
+    dvr: demux::Dvr,
+}
+
+impl Read for DvbSource {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.dvr.read(buffer)
+    }
+}
+
+impl Tuner for DvbTuner {
+    fn open(&self, channel: &Channel) -> Result<Box<dyn Read>, String> {
+        let mut frontend = Frontend::open(&self.frontend_id, self.verbose)
+            .map_err(|_| "could not open the frontend".to_string())?;
+        frontend.set_user_band(self.user_band.clone());
+        frontend.tune(channel)?;
+        let mut n_status_lines = 0;
+        if !frontend.wait_for_lock(&mut n_status_lines) {
+            return Err("no lock".to_string());
+        }
+        let filter = demux::PesFilter::new(&self.frontend_id, demux::ALL_PIDS)
+            .map_err(|_| "could not set the demux filter".to_string())?;
+        let dvr = demux::Dvr::open(&self.frontend_id, Duration::from_secs(1))
+            .map_err(|e| format!("could not open the DVR device, {}", e))?;
+        Ok(Box::new(DvbSource {
+            _frontend: frontend,
+            _filter: filter,
+            dvr,
+        }))
+    }
+}
+
+/// A tuner for trying the server without hardware: every service is received from a
+/// transport stream, played in a loop at about the given rate.
+#[derive(Debug)]
+pub struct SimulatedTuner {
+    data: Arc<Vec<u8>>,
+    bytes_per_second: u64,
+}
+
+impl SimulatedTuner {
+    pub fn new(data: Vec<u8>, bytes_per_second: u64) -> SimulatedTuner {
+        SimulatedTuner {
+            data: Arc::new(data),
+            bytes_per_second,
+        }
+    }
+
+    /// A tuner playing a transport stream file.
+    pub fn from_file(path: &Path, bytes_per_second: u64) -> Result<SimulatedTuner, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if data.len() < PACKET_SIZE {
+            return Err(format!("{} is not a transport stream", path.display()));
+        }
+        Ok(SimulatedTuner::new(data, bytes_per_second))
+    }
+}
+
+/// The looped transport stream of a simulated tuner.
+struct SimulatedSource {
+    data: Arc<Vec<u8>>,
+    position: usize,
+    bytes_per_second: u64,
+}
+
+impl Read for SimulatedSource {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let count = buffer.len().min(self.data.len() - self.position);
+        buffer[..count].copy_from_slice(&self.data[self.position..self.position + count]);
+        self.position = (self.position + count) % self.data.len();
+        thread::sleep(Duration::from_micros(
+            count as u64 * 1_000_000 / self.bytes_per_second.max(1),
+        ));
+        Ok(count)
+    }
+}
+
+impl Tuner for SimulatedTuner {
+    fn open(&self, _channel: &Channel) -> Result<Box<dyn Read>, String> {
+        Ok(Box::new(SimulatedSource {
+            data: self.data.clone(),
+            position: 0,
+            bytes_per_second: self.bytes_per_second,
+        }))
+    }
+}
+
+/// A client of the tuned transport stream.
+struct Client {
+    remuxer: SptsRemuxer,
+    sender: SyncSender<Vec<u8>>,
+}
+
+/// The use of the tuner.
+struct Session {
+    /// Distinguishes a session from the one it replaced.
+    id: u64,
+    /// The service tuned to, identifying the transport stream.
+    channel: Channel,
+    clients: Vec<Client>,
+}
+
+/// The state shared by the threads of a server.
+struct Shared {
+    channels: Vec<Channel>,
+    tuner: Box<dyn Tuner>,
+    idle_timeout: Duration,
+    session: Mutex<Option<Session>>,
+    /// Held by the thread of a session while it has the tuner, so that the session
+    /// replacing it only tunes once it has let go.
+    tuner_in_use: Mutex<()>,
+    next_session_id: Mutex<u64>,
+}
+
+/// The HTTP streaming server.
+#[derive(Clone)]
+pub struct Server {
+    shared: Arc<Shared>,
+}
+
+impl Server {
+    pub fn new(channels: Vec<Channel>, tuner: Box<dyn Tuner>, idle_timeout: Duration) -> Server {
+        Server {
+            shared: Arc::new(Shared {
+                channels,
+                tuner,
+                idle_timeout,
+                session: Mutex::new(None),
+                tuner_in_use: Mutex::new(()),
+                next_session_id: Mutex::new(0),
+            }),
+        }
+    }
+
+    /// Serve the connections of a listener, each on its own thread. This does not return.
+    pub fn serve(&self, listener: TcpListener) {
+        for stream in listener.incoming().flatten() {
+            let server = self.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.handle(stream) {
+                    match e.kind() {
+                        ErrorKind::BrokenPipe
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::TimedOut
+                        | ErrorKind::WouldBlock => {}
+                        _ => println!("**** Error serving a client, {} ****", e),
+                    }
+                }
+            });
+        }
+    }
+
+    /// Handle one request.
+    fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_SIZE));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut host = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                if reader.get_ref().limit() == 0 {
+                    return respond(
+                        &mut stream,
+                        "431 Request Header Fields Too Large",
+                        "The request is too large.\n",
+                    );
+                }
+                break;
+            }
+            if line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("host") {
+                    host = Some(value.trim().to_string());
+                }
+            }
+        }
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method, path),
+            _ => return respond(&mut stream, "400 Bad Request", "Bad request.\n"),
+        };
+        if method != "GET" {
+            return respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "Only GET is supported.\n",
+            );
+        }
+        let host = host.unwrap_or_else(|| {
+            stream
+                .local_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_default()
+        });
+        if path == "/" || path == "/playlist.m3u" {
+            let playlist = playlist(&self.shared.channels, &host);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: audio/x-mpegurl\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                playlist.len(),
+                playlist
+            )
+        } else if let Some(name) = path.strip_prefix("/stream/") {
+            self.stream(&mut stream, &percent_decode(name))
+        } else {
+            respond(&mut stream, "404 Not Found", "No such resource.\n")
+        }
+    }
+
+    /// Stream a service to a client until it disconnects or the stream ends.
+    fn stream(&self, stream: &mut TcpStream, name: &str) -> io::Result<()> {
+        let channel = match Channel::find(&self.shared.channels, name) {
+            Some(channel) => channel,
+            None => return respond(stream, "404 Not Found", "No such service.\n"),
+        };
+        let receiver = match self.subscribe(channel) {
+            Ok(receiver) => receiver,
+            Err(e) => return respond(stream, "503 Service Unavailable", &format!("{}.\n", e)),
+        };
+        let first = match receiver.recv_timeout(START_TIMEOUT) {
+            Ok(data) => data,
+            Err(_) => {
+                return respond(
+                    stream,
+                    "504 Gateway Timeout",
+                    "The service could not be received.\n",
+                )
+            }
+        };
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: video/mp2t\r\nTransfer-Encoding: chunked\r\n\
+              Connection: close\r\n\r\n",
+        )?;
+        write_chunk(stream, &first)?;
+        loop {
+            match receiver.recv_timeout(START_TIMEOUT) {
+                Ok(data) => write_chunk(stream, &data)?,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        stream.write_all(b"0\r\n\r\n")
+    }
+
+    /// Add a client for a service, tuning if the tuner is not in use or has no clients.
+    /// The receiver gets the packets of the service's single program transport stream.
+    fn subscribe(&self, channel: &Channel) -> Result<Receiver<Vec<u8>>, String> {
+        let (sender, receiver) = mpsc::sync_channel(CLIENT_BUFFER_SIZE);
+        let client = Client {
+            remuxer: SptsRemuxer::new(channel.service_id),
+            sender,
+        };
+        let mut session = self.shared.session.lock().unwrap();
+        match session.as_mut() {
+            Some(session) if session.channel.same_transponder(channel) => {
+                session.clients.push(client);
+            }
+            Some(session) if !session.clients.is_empty() => {
+                return Err(format!(
+                    "the tuner is in use for the transport stream of {}",
+                    session.channel.name
+                ))
+            }
+            _ => {
+                // An idle session is replaced, its thread ending when it sees that.
+                let id = {
+                    let mut next_session_id = self.shared.next_session_id.lock().unwrap();
+                    *next_session_id += 1;
+                    *next_session_id
+                };
+                *session = Some(Session {
+                    id,
+                    channel: channel.clone(),
+                    clients: vec![client],
+                });
+                let shared = self.shared.clone();
+                let channel = channel.clone();
+                thread::spawn(move || run_session(&shared, id, &channel));
+            }
+        }
+        Ok(receiver)
+    }
+}
+
+/// End a session, unless it has already been replaced.
+fn end_session(shared: &Shared, id: u64) {
+    let mut session = shared.session.lock().unwrap();
+    if session.as_ref().map(|s| s.id) == Some(id) {
+        *session = None;
+    }
+}
+
+/// Tune and pass the transport stream to the clients of the session `id` until it has had
+/// no clients for the idle timeout, it is replaced, or the stream fails, then end the
+/// session.
+fn run_session(shared: &Shared, id: u64, channel: &Channel) {
+    // Declared before the source so that the tuner is let go of first.
+    let _tuner_in_use = shared.tuner_in_use.lock().unwrap();
+    let mut source = match shared.tuner.open(channel) {
+        Ok(source) => source,
+        Err(e) => {
+            println!("**** Could not tune to {}, {} ****", channel.name, e);
+            end_session(shared, id);
+            return;
+        }
+    };
+    let mut buffer = vec![0u8; READ_SIZE];
+    let mut idle_since = None;
+    loop {
+        let count = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e)
+                if e.kind() == ErrorKind::TimedOut
+                    || e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::Interrupted =>
+            {
+                0
+            }
+            Err(e) => {
+                println!("**** Error reading the transport stream, {} ****", e);
+                break;
+            }
+        };
+        let mut guard = shared.session.lock().unwrap();
+        let session = match guard.as_mut() {
+            Some(session) if session.id == id => session,
+            _ => return,
+        };
+        session.clients.retain_mut(|client| {
+            let mut packets = Vec::new();
+            client.remuxer.push(&buffer[..count], &mut packets);
+            // A client too slow to keep up loses data rather than holding up the others.
+            packets.is_empty()
+                || !matches!(
+                    client.sender.try_send(packets),
+                    Err(TrySendError::Disconnected(_))
+                )
+        });
+        if session.clients.is_empty() {
+            let since = *idle_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= shared.idle_timeout {
+                *guard = None;
+                return;
+            }
+        } else {
+            idle_since = None;
+        }
+    }
+    end_session(shared, id);
+}
+
+/// Write a response with a plain text body.
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Write a chunk of a chunked transfer encoded body.
+fn write_chunk(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    write!(stream, "{:x}\r\n", data.len())?;
+    stream.write_all(data)?;
+    stream.write_all(b"\r\n")
+}
+
+/// An M3U playlist of the services, with URLs on the given host.
+pub fn playlist(channels: &[Channel], host: &str) -> String {
+    let mut playlist = "#EXTM3U\n".to_string();
+    for channel in channels {
+        playlist += "#EXTINF:-1";
+        if let Some(vchannel) = &channel.vchannel {
+            playlist += &format!(" tvg-chno=\"{}\"", vchannel);
+        }
+        playlist += &format!(
+            ",{}\nhttp://{}/stream/{}\n",
+            channel.name,
+            host,
+            percent_encode(&channel.name)
+        );
+    }
+    playlist
+}
+
+/// Encode a service name for use as a URL path segment.
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+/// Decode a URL path segment.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::frontend::tests::make_channel;
+    use crate::spts::tests::make_multiplex;
+    use crate::ts::Packet;
+
+    /// A simulated tuner counting how often it is opened.
+    struct CountingTuner {
+        tuner: SimulatedTuner,
+        opens: Arc<AtomicUsize>,
+    }
+
+    impl Tuner for CountingTuner {
+        fn open(&self, channel: &Channel) -> Result<Box<dyn Read>, String> {
+            self.opens.fetch_add(1, Ordering::SeqCst);
+            self.tuner.open(channel)
+        }
+    }
+
+    fn start_server(opens: &Arc<AtomicUsize>, idle_timeout: Duration) -> String {
+        let mut one = make_channel("One", 1, 490_000_000);
+        one.vchannel = Some("1".to_string());
+        let channels = vec![
+            one,
+            make_channel("Two & More", 2, 490_000_000),
+            make_channel("Elsewhere", 1, 514_000_000),
+        ];
+        let tuner = CountingTuner {
+            tuner: SimulatedTuner::new(make_multiplex(), 1_000_000),
+            opens: opens.clone(),
+        };
+        let server = Server::new(channels, Box::new(tuner), idle_timeout);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve(listener));
+        address
+    }
+
+    /// Request a path, returning the status line and a reader positioned at the body.
+    fn get(address: &str, path: &str) -> (String, BufReader<TcpStream>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line.trim().is_empty() {
+                break;
+            }
+        }
+        (status.trim().to_string(), reader)
+    }
+
+    /// Read chunks of a streamed body until there are at least `count` packets.
+    fn read_packets(reader: &mut BufReader<TcpStream>, count: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        while data.len() < count * PACKET_SIZE {
+            let mut size = String::new();
+            reader.read_line(&mut size).unwrap();
+            let size = usize::from_str_radix(size.trim(), 16).unwrap();
+            let mut chunk = vec![0u8; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            data.extend_from_slice(&chunk[..size]);
+        }
+        data
+    }
+
+    fn pids(data: &[u8]) -> Vec<u16> {
+        data.chunks(PACKET_SIZE)
+            .map(|p| Packet::new(p).unwrap().pid())
+            .collect()
+    }
+
+    #[test]
+    fn serve_playlist_and_streams() {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let address = start_server(&opens, Duration::from_millis(200));
+        let (status, mut reader) = get(&address, "/playlist.m3u");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let mut playlist = String::new();
+        reader.read_to_string(&mut playlist).unwrap();
+        assert_eq!(
+            playlist,
+            format!(
+                "#EXTM3U\n#EXTINF:-1 tvg-chno=\"1\",One\nhttp://{0}/stream/One\n\
+                 #EXTINF:-1,Two & More\nhttp://{0}/stream/Two%20%26%20More\n\
+                 #EXTINF:-1,Elsewhere\nhttp://{0}/stream/Elsewhere\n",
+                address
+            )
+        );
+
+        // Services on one transport stream share the tuner.
+        let (status, mut one) = get(&address, "/stream/1");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let (status, mut two) = get(&address, "/stream/Two%20%26%20More");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            pids(&read_packets(&mut one, 8))[..4],
+            [0x00, 0x100, 0x101, 0x102]
+        );
+        assert_eq!(pids(&read_packets(&mut two, 5))[..3], [0x00, 0x200, 0x201]);
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+
+        // A service on another transport stream has to wait for the tuner.
+        let (status, _) = get(&address, "/stream/Elsewhere");
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+        let (status, _) = get(&address, "/stream/Nowhere");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+        // Once idle the tuner is released.
+        drop(one);
+        drop(two);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let (status, mut reader) = get(&address, "/stream/Elsewhere");
+            if status == "HTTP/1.1 200 OK" {
+                read_packets(&mut reader, 1);
+                break;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(opens.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn idle_session_replaced() {
+        let opens = Arc::new(AtomicUsize::new(0));
+        // An idle timeout longer than the test, so only replacement frees the tuner.
+        let address = start_server(&opens, Duration::from_secs(3600));
+        let (status, mut one) = get(&address, "/stream/One");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        read_packets(&mut one, 1);
+        drop(one);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let (status, mut reader) = get(&address, "/stream/Elsewhere");
+            if status == "HTTP/1.1 200 OK" {
+                read_packets(&mut reader, 1);
+                break;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(opens.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn request_size_limited() {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let address = start_server(&opens, Duration::from_millis(200));
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        // Exactly the limit, without the end of the headers, so all of it is read.
+        let mut request = "GET /playlist.m3u HTTP/1.1\r\n".to_string();
+        while request.len() < MAX_REQUEST_SIZE as usize {
+            request += "X-Padding: 1234\r\n";
+        }
+        request.truncate(MAX_REQUEST_SIZE as usize);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        assert_eq!(
+            status.trim(),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+    }
+
+    #[test]
+    fn percent_coding() {
+        assert_eq!(percent_encode("BBC ONE Lon"), "BBC%20ONE%20Lon");
+        assert_eq!(percent_decode("BBC%20ONE+Lon"), "BBC ONE Lon");
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+}
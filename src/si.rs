@@ -0,0 +1,1134 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Parsing of MPEG-2 PSI and DVB SI sections (ISO/IEC 13818-1 and EN 300 468).
+//!
+//! libdvbv5 parses the tables it needs for a scan but keeps them to itself, so the
+//! information this application needs beyond that is parsed here from raw sections.
+
+use std::collections::BTreeMap;
+
+use crate::text;
+
+/// The PID carrying the PAT.
+pub const PAT_PID: u16 = 0x00;
+
+/// Table id of the PAT.
+pub const PAT_TABLE_ID: u8 = 0x00;
+
+/// Table id of a PMT.
+pub const PMT_TABLE_ID: u8 = 0x02;
+
+/// The PCR PID of a PMT for a program without PCR, the null packet PID.
+pub const NO_PCR_PID: u16 = 0x1fff;
+
+/// The PID carrying the NIT.
+pub const NIT_PID: u16 = 0x10;
+
+/// Table id of the NIT for the network of the current transport stream.
+pub const NIT_ACTUAL_TABLE_ID: u8 = 0x40;
+
+/// Table id of the NIT for other networks.
+pub const NIT_OTHER_TABLE_ID: u8 = 0x41;
+
+/// The PID carrying the SDT and BAT.
+pub const SDT_PID: u16 = 0x11;
+
+/// Table id of the SDT for the current transport stream.
+pub const SDT_ACTUAL_TABLE_ID: u8 = 0x42;
+
+/// Table id of the BAT, which is carried with the SDT.
+pub const BAT_TABLE_ID: u8 = 0x4a;
+
+/// Table id of the SDT for other transport streams.
+pub const SDT_OTHER_TABLE_ID: u8 = 0x46;
+
+/// The PID carrying the EIT.
+pub const EIT_PID: u16 = 0x12;
+
+/// The range of table ids of the EIT: present/following for the current transport
+/// stream, then for other transport streams, then 16 of schedule for the current transport
+/// stream, then 16 for other transport streams.
+pub const EIT_TABLE_IDS: std::ops::RangeInclusive<u8> = 0x4e..=0x6f;
+
+/// The PID carrying the TDT and TOT.
+pub const TDT_PID: u16 = 0x14;
+
+/// Table id of the Time and Date Table.
+pub const TDT_TABLE_ID: u8 = 0x70;
+
+/// Table id of the Time Offset Table.
+pub const TOT_TABLE_ID: u8 = 0x73;
+
+/// Descriptor tags used in this module.
+const NETWORK_NAME_DESCRIPTOR: u8 = 0x40;
+const SERVICE_LIST_DESCRIPTOR: u8 = 0x41;
+const SATELLITE_DELIVERY_SYSTEM_DESCRIPTOR: u8 = 0x43;
+const CABLE_DELIVERY_SYSTEM_DESCRIPTOR: u8 = 0x44;
+const BOUQUET_NAME_DESCRIPTOR: u8 = 0x47;
+const SERVICE_DESCRIPTOR: u8 = 0x48;
+const CA_IDENTIFIER_DESCRIPTOR: u8 = 0x53;
+const TERRESTRIAL_DELIVERY_SYSTEM_DESCRIPTOR: u8 = 0x5a;
+const S2_SATELLITE_DELIVERY_SYSTEM_DESCRIPTOR: u8 = 0x79;
+const EXTENSION_DESCRIPTOR: u8 = 0x7f;
+
+/// Extension descriptor tag of the T2 delivery system descriptor.
+const T2_DELIVERY_SYSTEM_DESCRIPTOR: u8 = 0x04;
+
+/// How far, in kHz, a frequency in a NIT may be from the frequency tuned to and still be
+/// taken to be the same transponder.
+const FREQUENCY_TOLERANCE: u32 = 2000;
+
+/// Calculate the MPEG-2 CRC32 of some data. A section including its CRC gives zero.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A long form section, the header fields having been extracted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    pub table_id: u8,
+    /// The table id extension: transport stream id, service id, network id, etc. depending
+    /// on the table.
+    pub extension: u16,
+    pub version: u8,
+    pub current_next: bool,
+    pub section_number: u8,
+    pub last_section_number: u8,
+    /// The bytes after the header up to, but not including, the CRC.
+    pub payload: Vec<u8>,
+}
+
+impl Section {
+    /// Parse a complete long form section, checking the CRC.
+    pub fn parse(data: &[u8]) -> Result<Section, String> {
+        if data.len() < 12 {
+            return Err("section too short.".to_string());
+        }
+        let section_length = (((data[1] & 0x0f) as usize) << 8) | data[2] as usize;
+        if data[1] & 0x80 == 0 {
+            return Err("not a long form section.".to_string());
+        }
+        if section_length + 3 > data.len() || section_length < 9 {
+            return Err("section length inconsistent with data.".to_string());
+        }
+        let data = &data[..section_length + 3];
+        if crc32(data) != 0 {
+            return Err("section CRC incorrect.".to_string());
+        }
+        Ok(Section {
+            table_id: data[0],
+            extension: ((data[3] as u16) << 8) | data[4] as u16,
+            version: (data[5] >> 1) & 0x1f,
+            current_next: data[5] & 0x01 != 0,
+            section_number: data[6],
+            last_section_number: data[7],
+            payload: data[8..data.len() - 4].to_vec(),
+        })
+    }
+
+    /// Encode as a complete long form section, with CRC.
+    pub fn encode(&self) -> Vec<u8> {
+        let length = self.payload.len() + 9;
+        let mut data = vec![
+            self.table_id,
+            0xb0 | ((length >> 8) as u8 & 0x0f),
+            length as u8,
+            (self.extension >> 8) as u8,
+            self.extension as u8,
+            0xc0 | ((self.version & 0x1f) << 1) | self.current_next as u8,
+            self.section_number,
+            self.last_section_number,
+        ];
+        data.extend_from_slice(&self.payload);
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_be_bytes());
+        data
+    }
+}
+
+/// Decode two binary coded decimal digits.
+fn bcd(byte: u8) -> Option<u32> {
+    let (high, low) = ((byte >> 4) as u32, (byte & 0x0f) as u32);
+    if high > 9 || low > 9 {
+        None
+    } else {
+        Some(high * 10 + low)
+    }
+}
+
+/// Decode a duration, or time of day, coded as six binary coded decimal digits of hours,
+/// minutes and seconds, giving the number of seconds.
+pub fn parse_bcd_duration(data: &[u8]) -> Option<u32> {
+    match data {
+        [hours, minutes, seconds, ..] => {
+            Some(bcd(*hours)? * 3600 + bcd(*minutes)? * 60 + bcd(*seconds)?)
+        }
+        _ => None,
+    }
+}
+
+/// Decode a UTC time coded as a 16 bit Modified Julian Date followed by a binary coded
+/// decimal time of day, EN 300 468 annex C, giving seconds since the Unix epoch. All bits
+/// set means the time is undefined.
+pub fn parse_utc_time(data: &[u8]) -> Option<i64> {
+    if data.len() < 5 || data[..5].iter().all(|b| *b == 0xff) {
+        return None;
+    }
+    let mjd = ((data[0] as i64) << 8) | data[1] as i64;
+    // MJD 40587 is 1970-01-01.
+    Some((mjd - 40587) * 86400 + parse_bcd_duration(&data[2..5])? as i64)
+}
+
+/// Gathers the sections of tables until each table seen is complete.
+#[derive(Debug, Default)]
+pub struct SectionCollector {
+    tables: BTreeMap<(u8, u16), (u8, BTreeMap<u8, Section>)>,
+    /// The table id, extension and section number of the first section added.
+    first: Option<(u8, u16, u8)>,
+    /// Whether the first section added has been added again, so that the whole cycle of
+    /// the tables broadcast has been seen.
+    repeated: bool,
+}
+
+impl SectionCollector {
+    pub fn new() -> SectionCollector {
+        SectionCollector::default()
+    }
+
+    /// Add a section. Sections that are not current are ignored, and a new version of
+    /// a table replaces any sections of the old version.
+    pub fn add(&mut self, section: Section) {
+        if !section.current_next {
+            return;
+        }
+        let key = (section.table_id, section.extension, section.section_number);
+        match self.first {
+            Some(first) => self.repeated |= first == key,
+            None => self.first = Some(key),
+        }
+        let table = self
+            .tables
+            .entry((section.table_id, section.extension))
+            .or_insert_with(|| (section.version, BTreeMap::new()));
+        if table.0 != section.version {
+            *table = (section.version, BTreeMap::new());
+        }
+        table.1.insert(section.section_number, section);
+    }
+
+    /// Whether all the sections of every table seen have been added.
+    pub fn is_complete(&self) -> bool {
+        !self.tables.is_empty()
+            && self.tables.values().all(|(_, sections)| {
+                sections
+                    .values()
+                    .next()
+                    .map(|s| sections.len() == s.last_section_number as usize + 1)
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Whether the tables broadcast have been seen through a whole cycle of repetition,
+    /// and every table is complete. Until then there may be tables, of other networks or
+    /// bouquets say, yet to be seen.
+    pub fn is_cycle_complete(&self) -> bool {
+        self.repeated && self.is_complete()
+    }
+
+    /// The sections of each table gathered, in section number order.
+    pub fn tables(&self) -> Vec<Vec<&Section>> {
+        self.tables
+            .values()
+            .map(|(_, sections)| sections.values().collect())
+            .collect()
+    }
+}
+
+/// Iterate over a descriptor loop yielding tag and content of each descriptor.
+pub fn descriptors(data: &[u8]) -> Descriptors<'_> {
+    Descriptors { data }
+}
+
+/// Iterator over the descriptors of a descriptor loop.
+pub struct Descriptors<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 2 {
+            return None;
+        }
+        let tag = self.data[0];
+        let length = self.data[1] as usize;
+        if self.data.len() < 2 + length {
+            self.data = &[];
+            return None;
+        }
+        let content = &self.data[2..2 + length];
+        self.data = &self.data[2 + length..];
+        Some((tag, content))
+    }
+}
+
+/// Read a 12 bit length field and return it with the data following it.
+fn split_loop(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    if data.len() < 2 {
+        return None;
+    }
+    let length = (((data[0] & 0x0f) as usize) << 8) | data[1] as usize;
+    if data.len() < 2 + length {
+        return None;
+    }
+    Some((&data[2..2 + length], &data[2 + length..]))
+}
+
+/// A Program Association Table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pat {
+    pub transport_stream_id: u16,
+    pub version: u8,
+    /// Program number and PMT PID of each program; program number 0 gives the NIT PID.
+    pub programs: Vec<(u16, u16)>,
+}
+
+impl Pat {
+    /// Parse the sections of a PAT.
+    pub fn parse(sections: &[&Section]) -> Pat {
+        let mut pat = Pat::default();
+        for section in sections {
+            pat.transport_stream_id = section.extension;
+            pat.version = section.version;
+            pat.programs
+                .extend(section.payload.chunks(4).filter(|c| c.len() == 4).map(|c| {
+                    (
+                        ((c[0] as u16) << 8) | c[1] as u16,
+                        (((c[2] & 0x1f) as u16) << 8) | c[3] as u16,
+                    )
+                }));
+        }
+        pat
+    }
+
+    /// The PID of the PMT of a program.
+    pub fn pmt_pid(&self, program_number: u16) -> Option<u16> {
+        self.programs
+            .iter()
+            .find(|(number, _)| *number == program_number && program_number != 0)
+            .map(|(_, pid)| *pid)
+    }
+}
+
+/// An elementary stream of a program described in a PMT.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PmtStream {
+    pub stream_type: u8,
+    pub pid: u16,
+    /// The ES info descriptor loop.
+    pub descriptors: Vec<u8>,
+}
+
+/// A Program Map Table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pmt {
+    pub program_number: u16,
+    pub version: u8,
+    pub pcr_pid: u16,
+    /// The program info descriptor loop.
+    pub descriptors: Vec<u8>,
+    pub streams: Vec<PmtStream>,
+}
+
+impl Pmt {
+    /// Parse the section of a PMT.
+    pub fn parse(section: &Section) -> Result<Pmt, String> {
+        let payload = &section.payload;
+        if payload.len() < 4 {
+            return Err("PMT section too short.".to_string());
+        }
+        let (descriptors, mut data) = split_loop(&payload[2..])
+            .ok_or_else(|| "PMT program info loop overruns section.".to_string())?;
+        let mut pmt = Pmt {
+            program_number: section.extension,
+            version: section.version,
+            pcr_pid: (((payload[0] & 0x1f) as u16) << 8) | payload[1] as u16,
+            descriptors: descriptors.to_vec(),
+            streams: Vec::new(),
+        };
+        while data.len() >= 5 {
+            let (descriptors, rest) = split_loop(&data[3..])
+                .ok_or_else(|| "PMT ES info loop overruns section.".to_string())?;
+            pmt.streams.push(PmtStream {
+                stream_type: data[0],
+                pid: (((data[1] & 0x1f) as u16) << 8) | data[2] as u16,
+                descriptors: descriptors.to_vec(),
+            });
+            data = rest;
+        }
+        Ok(pmt)
+    }
+
+    /// The PIDs carrying the program: the PCR PID, if there is one, and the elementary
+    /// stream PIDs.
+    pub fn pids(&self) -> Vec<u16> {
+        let mut pids = Vec::new();
+        if self.pcr_pid != NO_PCR_PID {
+            pids.push(self.pcr_pid);
+        }
+        for stream in &self.streams {
+            if !pids.contains(&stream.pid) {
+                pids.push(stream.pid);
+            }
+        }
+        pids
+    }
+}
+
+/// A service described in an SDT.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SdtService {
+    pub service_id: u16,
+    pub eit_schedule: bool,
+    pub eit_present_following: bool,
+    pub running_status: u8,
+    pub free_ca_mode: bool,
+    /// The service type from the service descriptor, if there is one.
+    pub service_type: Option<u8>,
+    /// The provider name as broadcast, to be decoded with `text::decode`.
+    pub provider_name: Vec<u8>,
+    /// The service name as broadcast, to be decoded with `text::decode`.
+    pub service_name: Vec<u8>,
+    /// CA system ids from a CA identifier descriptor.
+    pub ca_system_ids: Vec<u16>,
+}
+
+impl SdtService {
+    /// Whether the service is, or may be, scrambled.
+    pub fn is_scrambled(&self) -> bool {
+        self.free_ca_mode || !self.ca_system_ids.is_empty()
+    }
+}
+
+/// A Service Description Table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sdt {
+    pub transport_stream_id: u16,
+    pub original_network_id: u16,
+    pub services: Vec<SdtService>,
+}
+
+impl Sdt {
+    /// Parse the sections of an SDT.
+    pub fn parse(sections: &[&Section]) -> Result<Sdt, String> {
+        let mut sdt = Sdt::default();
+        for section in sections {
+            let payload = &section.payload;
+            if payload.len() < 3 {
+                return Err("SDT section too short.".to_string());
+            }
+            sdt.transport_stream_id = section.extension;
+            sdt.original_network_id = ((payload[0] as u16) << 8) | payload[1] as u16;
+            let mut data = &payload[3..];
+            while data.len() >= 5 {
+                let mut service = SdtService {
+                    service_id: ((data[0] as u16) << 8) | data[1] as u16,
+                    eit_schedule: data[2] & 0x02 != 0,
+                    eit_present_following: data[2] & 0x01 != 0,
+                    running_status: data[3] >> 5,
+                    free_ca_mode: data[3] & 0x10 != 0,
+                    ..SdtService::default()
+                };
+                let (descriptor_loop, rest) = split_loop(&data[3..])
+                    .ok_or_else(|| "SDT descriptor loop overruns section.".to_string())?;
+                for (tag, content) in descriptors(descriptor_loop) {
+                    match tag {
+                        SERVICE_DESCRIPTOR if !content.is_empty() => {
+                            service.service_type = Some(content[0]);
+                            let provider_length = *content.get(1).unwrap_or(&0) as usize;
+                            if content.len() >= 3 + provider_length {
+                                service.provider_name = content[2..2 + provider_length].to_vec();
+                                let name = &content[2 + provider_length..];
+                                let name_length = name[0] as usize;
+                                if name.len() > name_length {
+                                    service.service_name = name[1..1 + name_length].to_vec();
+                                }
+                            }
+                        }
+                        CA_IDENTIFIER_DESCRIPTOR => {
+                            service.ca_system_ids = content
+                                .chunks(2)
+                                .filter(|c| c.len() == 2)
+                                .map(|c| ((c[0] as u16) << 8) | c[1] as u16)
+                                .collect()
+                        }
+                        _ => {}
+                    }
+                }
+                sdt.services.push(service);
+                data = rest;
+            }
+        }
+        Ok(sdt)
+    }
+}
+
+/// A transport stream described in a NIT. The descriptors are kept unparsed as their
+/// meaning may depend on private data specifiers in the loop.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NitTransport {
+    pub transport_stream_id: u16,
+    pub original_network_id: u16,
+    /// The transport descriptor loop.
+    pub descriptors: Vec<u8>,
+}
+
+/// Where a transport stream described in a NIT is broadcast, from its delivery system
+/// descriptors.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Delivery {
+    /// The frequencies, in kHz.
+    pub frequencies: Vec<u32>,
+    /// The polarization of a satellite transport stream, as libdvbv5 numbers them.
+    pub polarization: Option<u32>,
+    /// The PLP or input stream carrying the transport stream, if it shares the
+    /// frequency with others.
+    pub stream_id: Option<u32>,
+    /// The symbol rate of a cable transport stream, in symbols per second.
+    pub symbol_rate: Option<u32>,
+    /// The modulation of a cable transport stream, as the cable delivery system
+    /// descriptor codes it: 1 for 16-QAM up to 5 for 256-QAM.
+    pub modulation: Option<u8>,
+    /// The inner FEC scheme of a cable transport stream, as the cable delivery system
+    /// descriptor codes it.
+    pub inner_fec: Option<u8>,
+}
+
+/// A Network Information Table.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Nit {
+    pub network_id: u16,
+    pub network_name: String,
+    /// The network descriptor loop, of all sections.
+    pub descriptors: Vec<u8>,
+    pub transports: Vec<NitTransport>,
+}
+
+impl NitTransport {
+    /// The service id and service type of each service in the service list descriptors.
+    pub fn services(&self) -> Vec<(u16, u8)> {
+        descriptors(&self.descriptors)
+            .filter(|(tag, _)| *tag == SERVICE_LIST_DESCRIPTOR)
+            .flat_map(|(_, content)| content.chunks(3).filter(|c| c.len() == 3))
+            .map(|c| (((c[0] as u16) << 8) | c[1] as u16, c[2]))
+            .collect()
+    }
+}
+
+impl NitTransport {
+    /// Where the transport stream is broadcast. Frequencies in the NIT are in units of
+    /// 10 kHz, in binary coded decimal, for satellite and of 10 Hz for terrestrial.
+    pub fn delivery(&self) -> Delivery {
+        let mut delivery = Delivery::default();
+        for (tag, content) in descriptors(&self.descriptors) {
+            match (tag, content) {
+                (SATELLITE_DELIVERY_SYSTEM_DESCRIPTOR, [a, b, c, d, _, _, flags, ..]) => {
+                    if let (Some(a), Some(b), Some(c), Some(d)) =
+                        (bcd(*a), bcd(*b), bcd(*c), bcd(*d))
+                    {
+                        delivery
+                            .frequencies
+                            .push((a * 1_000_000 + b * 10_000 + c * 100 + d) * 10);
+                    }
+                    // Horizontal, vertical, left, right; libdvbv5 counts from 1.
+                    delivery.polarization = Some(u32::from(flags >> 5 & 0x03) + 1);
+                }
+                (CABLE_DELIVERY_SYSTEM_DESCRIPTOR, [a, b, c, d, _, _, modulation, rest @ ..]) => {
+                    // The frequency is in units of 100 Hz, the symbol rate of 100 symbols
+                    // per second, both in binary coded decimal.
+                    if let (Some(a), Some(b), Some(c), Some(d)) =
+                        (bcd(*a), bcd(*b), bcd(*c), bcd(*d))
+                    {
+                        delivery
+                            .frequencies
+                            .push((a * 1_000_000 + b * 10_000 + c * 100 + d) / 10);
+                    }
+                    delivery.modulation = Some(*modulation);
+                    if let [e, f, g, h] = rest {
+                        if let (Some(e), Some(f), Some(g), true) =
+                            (bcd(*e), bcd(*f), bcd(*g), h >> 4 <= 9)
+                        {
+                            let digit = u32::from(h >> 4);
+                            delivery.symbol_rate =
+                                Some((e * 100_000 + f * 1_000 + g * 10 + digit) * 100);
+                        }
+                        delivery.inner_fec = Some(h & 0x0f);
+                    }
+                }
+                (TERRESTRIAL_DELIVERY_SYSTEM_DESCRIPTOR, [a, b, c, d, ..]) => {
+                    delivery
+                        .frequencies
+                        .push(u32::from_be_bytes([*a, *b, *c, *d]) / 100);
+                }
+                (S2_SATELLITE_DELIVERY_SYSTEM_DESCRIPTOR, [flags, rest @ ..]) => {
+                    // An 18 bit scrambling sequence index may precede the input stream id.
+                    let rest = if flags & 0x80 != 0 {
+                        rest.get(3..).unwrap_or_default()
+                    } else {
+                        rest
+                    };
+                    if flags & 0x40 != 0 {
+                        delivery.stream_id = rest.first().map(|id| u32::from(*id));
+                    }
+                }
+                (
+                    EXTENSION_DESCRIPTOR,
+                    [T2_DELIVERY_SYSTEM_DESCRIPTOR, plp_id, _, _, rest @ ..],
+                ) => {
+                    delivery.stream_id = Some(u32::from(*plp_id));
+                    delivery.frequencies.extend(t2_frequencies(rest));
+                }
+                _ => {}
+            }
+        }
+        delivery
+    }
+}
+
+/// The centre frequencies, in kHz, of the cells of a T2 delivery system descriptor, from
+/// its optional part.
+fn t2_frequencies(data: &[u8]) -> Vec<u32> {
+    let mut frequencies = Vec::new();
+    if data.len() < 2 {
+        return frequencies;
+    }
+    let tfs = data[1] & 0x01 != 0;
+    let mut cells = &data[2..];
+    let frequency = |f: &[u8]| u32::from_be_bytes([f[0], f[1], f[2], f[3]]) / 100;
+    while cells.len() >= 2 {
+        cells = &cells[2..];
+        let length = if tfs {
+            match cells.first() {
+                Some(length) => {
+                    cells = &cells[1..];
+                    *length as usize
+                }
+                None => break,
+            }
+        } else {
+            4
+        };
+        if cells.len() < length {
+            break;
+        }
+        frequencies.extend(
+            cells[..length]
+                .chunks(4)
+                .filter(|f| f.len() == 4)
+                .map(frequency),
+        );
+        cells = &cells[length..];
+        // The subcells, each a cell id extension and transposer frequency.
+        match cells.first() {
+            Some(length) if cells.len() > *length as usize => {
+                cells = &cells[1 + *length as usize..];
+            }
+            _ => break,
+        }
+    }
+    frequencies
+}
+
+/// The parts of a NIT or BAT, which share a layout: the table id extension, the decoded
+/// name from a descriptor with the given tag, the first descriptor loop of all sections,
+/// and the transport streams.
+fn parse_network_sections(
+    sections: &[&Section],
+    name_tag: u8,
+    table: &str,
+) -> Result<(u16, String, Vec<u8>, Vec<NitTransport>), String> {
+    let mut id = 0;
+    let mut name = String::new();
+    let mut all_descriptors = Vec::new();
+    let mut transports = Vec::new();
+    for section in sections {
+        id = section.extension;
+        let (first_loop, rest) = split_loop(&section.payload)
+            .ok_or_else(|| format!("{} descriptor loop overruns section.", table))?;
+        for (tag, content) in descriptors(first_loop) {
+            if tag == name_tag {
+                name = text::decode_name(content, None);
+            }
+        }
+        all_descriptors.extend_from_slice(first_loop);
+        let (mut data, _) = split_loop(rest)
+            .ok_or_else(|| format!("{} transport stream loop overruns section.", table))?;
+        while data.len() >= 6 {
+            let (descriptor_loop, rest) = split_loop(&data[4..])
+                .ok_or_else(|| format!("{} transport descriptor loop overruns section.", table))?;
+            transports.push(NitTransport {
+                transport_stream_id: ((data[0] as u16) << 8) | data[1] as u16,
+                original_network_id: ((data[2] as u16) << 8) | data[3] as u16,
+                descriptors: descriptor_loop.to_vec(),
+            });
+            data = rest;
+        }
+    }
+    Ok((id, name, all_descriptors, transports))
+}
+
+impl Nit {
+    /// Parse the sections of a NIT.
+    pub fn parse(sections: &[&Section]) -> Result<Nit, String> {
+        let (network_id, network_name, descriptors, transports) =
+            parse_network_sections(sections, NETWORK_NAME_DESCRIPTOR, "NIT")?;
+        Ok(Nit {
+            network_id,
+            network_name,
+            descriptors,
+            transports,
+        })
+    }
+
+    /// The transport streams broadcast at a frequency in kHz, and polarization for
+    /// satellite.
+    pub fn transports_at(
+        &self,
+        frequency: u32,
+        polarization: Option<u32>,
+    ) -> impl Iterator<Item = (&NitTransport, Delivery)> {
+        self.transports.iter().filter_map(move |transport| {
+            let delivery = transport.delivery();
+            let here = delivery
+                .frequencies
+                .iter()
+                .any(|f| f.abs_diff(frequency) <= FREQUENCY_TOLERANCE)
+                && (delivery.polarization.is_none()
+                    || polarization.is_none()
+                    || delivery.polarization == polarization);
+            if here {
+                Some((transport, delivery))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The stream ids, PLPs or input streams, of the transport streams broadcast at a
+    /// frequency in kHz, and polarization for satellite.
+    pub fn stream_ids(&self, frequency: u32, polarization: Option<u32>) -> Vec<u32> {
+        let mut stream_ids = Vec::new();
+        for (_, delivery) in self.transports_at(frequency, polarization) {
+            if let Some(stream_id) = delivery.stream_id {
+                if !stream_ids.contains(&stream_id) {
+                    stream_ids.push(stream_id);
+                }
+            }
+        }
+        stream_ids
+    }
+
+    /// Whether this NIT describes a given transport stream.
+    pub fn describes(&self, transport_stream_id: u16, original_network_id: u16) -> bool {
+        self.transports.iter().any(|t| {
+            t.transport_stream_id == transport_stream_id
+                && t.original_network_id == original_network_id
+        })
+    }
+}
+
+/// A Bouquet Association Table: a grouping of services, across networks, by an operator.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bat {
+    pub bouquet_id: u16,
+    pub bouquet_name: String,
+    /// The bouquet descriptor loop, of all sections.
+    pub descriptors: Vec<u8>,
+    /// The transport streams with the descriptors of the services of the bouquet.
+    pub transports: Vec<NitTransport>,
+}
+
+impl Bat {
+    /// Add the transport streams of another BAT of the same bouquet that this does not
+    /// describe, as the BATs carried by different transport streams may differ.
+    pub fn merge(&mut self, other: Bat) {
+        for transport in other.transports {
+            if !self.transports.iter().any(|t| {
+                t.transport_stream_id == transport.transport_stream_id
+                    && t.original_network_id == transport.original_network_id
+            }) {
+                self.transports.push(transport);
+            }
+        }
+    }
+
+    /// Parse the sections of a BAT.
+    pub fn parse(sections: &[&Section]) -> Result<Bat, String> {
+        let (bouquet_id, bouquet_name, descriptors, transports) =
+            parse_network_sections(sections, BOUQUET_NAME_DESCRIPTOR, "BAT")?;
+        Ok(Bat {
+            bouquet_id,
+            bouquet_name,
+            descriptors,
+            transports,
+        })
+    }
+}
+
+/// An event described in an EIT.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EitEvent {
+    pub event_id: u16,
+    /// The start time in seconds since the Unix epoch, if defined.
+    pub start_time: Option<i64>,
+    /// The duration in seconds, if defined.
+    pub duration: Option<u32>,
+    pub running_status: u8,
+    pub free_ca_mode: bool,
+    /// The descriptor loop, to be parsed by the user.
+    pub descriptors: Vec<u8>,
+}
+
+/// A section of an Event Information Table. The schedule of a service is spread over many
+/// sections and tables, so sections are parsed individually.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Eit {
+    pub table_id: u8,
+    pub service_id: u16,
+    pub transport_stream_id: u16,
+    pub original_network_id: u16,
+    pub events: Vec<EitEvent>,
+}
+
+impl Eit {
+    /// Parse a section of an EIT.
+    pub fn parse(section: &Section) -> Result<Eit, String> {
+        let payload = &section.payload;
+        if payload.len() < 6 {
+            return Err("EIT section too short.".to_string());
+        }
+        let mut eit = Eit {
+            table_id: section.table_id,
+            service_id: section.extension,
+            transport_stream_id: ((payload[0] as u16) << 8) | payload[1] as u16,
+            original_network_id: ((payload[2] as u16) << 8) | payload[3] as u16,
+            events: Vec::new(),
+        };
+        let mut data = &payload[6..];
+        while data.len() >= 12 {
+            let (descriptor_loop, rest) = split_loop(&data[10..])
+                .ok_or_else(|| "EIT descriptor loop overruns section.".to_string())?;
+            eit.events.push(EitEvent {
+                event_id: ((data[0] as u16) << 8) | data[1] as u16,
+                start_time: parse_utc_time(&data[2..7]),
+                duration: parse_bcd_duration(&data[7..10]),
+                running_status: data[10] >> 5,
+                free_ca_mode: data[10] & 0x10 != 0,
+                descriptors: descriptor_loop.to_vec(),
+            });
+            data = rest;
+        }
+        Ok(eit)
+    }
+}
+
+/// A Time and Date Table, or a Time Offset Table which adds the local time offset
+/// descriptors.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimeTable {
+    pub table_id: u8,
+    /// The UTC time in seconds since the Unix epoch.
+    pub utc_time: i64,
+    /// The descriptor loop of a TOT, empty for a TDT.
+    pub descriptors: Vec<u8>,
+}
+
+impl TimeTable {
+    /// Parse a TDT or TOT, which are short form sections, checking the CRC of a TOT.
+    pub fn parse(data: &[u8]) -> Result<TimeTable, String> {
+        if data.len() < 8 {
+            return Err("time section too short.".to_string());
+        }
+        let section_length = (((data[1] & 0x0f) as usize) << 8) | data[2] as usize;
+        if section_length + 3 > data.len() || section_length < 5 {
+            return Err("section length inconsistent with data.".to_string());
+        }
+        let data = &data[..section_length + 3];
+        let utc_time = parse_utc_time(&data[3..8]).ok_or_else(|| "time undefined.".to_string())?;
+        match data[0] {
+            TDT_TABLE_ID => Ok(TimeTable {
+                table_id: TDT_TABLE_ID,
+                utc_time,
+                descriptors: Vec::new(),
+            }),
+            TOT_TABLE_ID => {
+                if crc32(data) != 0 {
+                    return Err("section CRC incorrect.".to_string());
+                }
+                let (descriptors, _) = split_loop(&data[8..])
+                    .ok_or_else(|| "TOT descriptor loop overruns section.".to_string())?;
+                Ok(TimeTable {
+                    table_id: TOT_TABLE_ID,
+                    utc_time,
+                    descriptors: descriptors.to_vec(),
+                })
+            }
+            _ => Err("not a TDT or TOT.".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// Build a long form section with correct CRC, for tests.
+    pub fn make_section(
+        table_id: u8,
+        extension: u16,
+        section_number: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let length = payload.len() + 9;
+        let mut data = vec![
+            table_id,
+            0xb0 | ((length >> 8) as u8 & 0x0f),
+            length as u8,
+            (extension >> 8) as u8,
+            extension as u8,
+            0xc1,
+            section_number,
+            section_number,
+        ];
+        data.extend_from_slice(payload);
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn collect_a_cycle_of_tables() {
+        let section = |extension| {
+            Section::parse(&make_section(BAT_TABLE_ID, extension, 0, &[0xf0, 0x00])).unwrap()
+        };
+        let mut collector = SectionCollector::new();
+        collector.add(section(1));
+        // The first bouquet is complete, but another may follow.
+        assert!(collector.is_complete());
+        assert!(!collector.is_cycle_complete());
+        collector.add(section(2));
+        assert!(!collector.is_cycle_complete());
+        collector.add(section(1));
+        assert!(collector.is_cycle_complete());
+        assert_eq!(collector.tables().len(), 2);
+    }
+
+    #[test]
+    fn merge_bats_of_a_bouquet() {
+        let transport = |transport_stream_id| NitTransport {
+            transport_stream_id,
+            original_network_id: 59,
+            descriptors: Vec::new(),
+        };
+        let mut bat = Bat {
+            bouquet_id: 0x1000,
+            transports: vec![transport(2000), transport(2001)],
+            ..Bat::default()
+        };
+        bat.merge(Bat {
+            bouquet_id: 0x1000,
+            transports: vec![transport(2001), transport(2002)],
+            ..Bat::default()
+        });
+        assert_eq!(
+            bat.transports,
+            vec![transport(2000), transport(2001), transport(2002)]
+        );
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn parse_sdt() {
+        let mut payload = vec![0x23, 0x3a, 0xff];
+        let descriptor = [
+            0x48, 0x0c, 0x01, 0x03, b'B', b'B', b'C', 0x06, b'B', b'B', b'C', b' ', b'O', b'N',
+        ];
+        payload.extend_from_slice(&[0x10, 0x44, 0xfd, 0x90, descriptor.len() as u8]);
+        payload.extend_from_slice(&descriptor);
+        let section =
+            Section::parse(&make_section(SDT_ACTUAL_TABLE_ID, 4164, 0, &payload)).unwrap();
+        let sdt = Sdt::parse(&[&section]).unwrap();
+        assert_eq!(sdt.original_network_id, 9018);
+        assert_eq!(sdt.transport_stream_id, 4164);
+        assert_eq!(sdt.services.len(), 1);
+        let service = &sdt.services[0];
+        assert_eq!(service.service_id, 4164);
+        assert_eq!(service.service_type, Some(1));
+        assert_eq!(service.provider_name, b"BBC");
+        assert_eq!(service.service_name, b"BBC ON");
+        assert!(service.eit_present_following);
+        assert!(service.is_scrambled());
+        assert_eq!(service.running_status, 4);
+    }
+
+    /// Build the payload of a NIT section with the given transport stream loop entries
+    /// of transport stream id, original network id and descriptors, for tests.
+    pub fn make_nit_payload(network_name: &str, transports: &[(u16, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut network_loop = vec![NETWORK_NAME_DESCRIPTOR, network_name.len() as u8];
+        network_loop.extend_from_slice(network_name.as_bytes());
+        let mut transport_loop = Vec::new();
+        for (transport_stream_id, original_network_id, descriptors) in transports {
+            transport_loop.extend_from_slice(&transport_stream_id.to_be_bytes());
+            transport_loop.extend_from_slice(&original_network_id.to_be_bytes());
+            transport_loop.extend_from_slice(&(0xf000 | descriptors.len() as u16).to_be_bytes());
+            transport_loop.extend_from_slice(descriptors);
+        }
+        let mut payload = (0xf000 | network_loop.len() as u16).to_be_bytes().to_vec();
+        payload.extend_from_slice(&network_loop);
+        payload.extend_from_slice(&(0xf000 | transport_loop.len() as u16).to_be_bytes());
+        payload.extend_from_slice(&transport_loop);
+        payload
+    }
+
+    #[test]
+    fn parse_nit() {
+        let payload = make_nit_payload("Freeview", &[(4164, 9018, vec![0x83, 0x00])]);
+        let section =
+            Section::parse(&make_section(NIT_ACTUAL_TABLE_ID, 12293, 0, &payload)).unwrap();
+        let nit = Nit::parse(&[&section]).unwrap();
+        assert_eq!(nit.network_id, 12293);
+        assert_eq!(nit.network_name, "Freeview");
+        assert_eq!(nit.transports.len(), 1);
+        assert!(nit.describes(4164, 9018));
+        assert_eq!(nit.transports[0].descriptors, vec![0x83, 0x00]);
+    }
+
+    #[test]
+    fn stream_ids_of_nit_delivery_descriptors() {
+        // Two PLPs of a T2 multiplex at 626 MHz, with a single cell.
+        let t2 = |plp_id: u8| {
+            vec![
+                0x7f, 0x0d, 0x04, plp_id, 0x00, 0x01, 0x02, 0x00, 0x30, 0x39, 0x03, 0xbb, 0x33,
+                0x40, 0x00,
+            ]
+        };
+        // An S2 input stream of a transponder at 11.494 GHz horizontal.
+        let mut s2 = vec![
+            0x43, 0x0b, 0x01, 0x14, 0x94, 0x00, 0x00, 0x19, 0x05, 0x02, 0x75, 0x00, 0x03,
+        ];
+        s2.extend_from_slice(&[0x79, 0x02, 0x40, 0x05]);
+        let payload = make_nit_payload(
+            "Multistream",
+            &[(1, 9018, t2(0)), (2, 9018, t2(1)), (3, 1, s2)],
+        );
+        let section =
+            Section::parse(&make_section(NIT_ACTUAL_TABLE_ID, 12293, 0, &payload)).unwrap();
+        let nit = Nit::parse(&[&section]).unwrap();
+        let delivery = nit.transports[0].delivery();
+        assert_eq!(delivery.frequencies, vec![626_000]);
+        assert_eq!(delivery.stream_id, Some(0));
+        assert_eq!(nit.stream_ids(626_000, None), vec![0, 1]);
+        assert!(nit.stream_ids(634_000, None).is_empty());
+        let delivery = nit.transports[2].delivery();
+        assert_eq!(delivery.frequencies, vec![11_494_000]);
+        assert_eq!(delivery.polarization, Some(1));
+        assert_eq!(nit.stream_ids(11_493_000, Some(1)), vec![5]);
+        assert!(nit.stream_ids(11_494_000, Some(2)).is_empty());
+    }
+
+    #[test]
+    fn pmt_pids_without_pcr() {
+        let mut pmt = Pmt {
+            pcr_pid: 0x101,
+            streams: vec![
+                PmtStream {
+                    stream_type: 0x1b,
+                    pid: 0x101,
+                    descriptors: Vec::new(),
+                },
+                PmtStream {
+                    stream_type: 0x06,
+                    pid: 0x102,
+                    descriptors: Vec::new(),
+                },
+            ],
+            ..Pmt::default()
+        };
+        assert_eq!(pmt.pids(), vec![0x101, 0x102]);
+        pmt.pcr_pid = NO_PCR_PID;
+        assert_eq!(pmt.pids(), vec![0x101, 0x102]);
+        pmt.streams.remove(0);
+        assert_eq!(pmt.pids(), vec![0x102]);
+    }
+
+    #[test]
+    fn parse_bat() {
+        let mut payload = make_nit_payload(
+            "",
+            &[(
+                4164,
+                9018,
+                vec![0x41, 0x06, 0x10, 0x44, 0x01, 0x10, 0xbf, 0x02],
+            )],
+        );
+        // Replace the empty network name descriptor with a bouquet name descriptor.
+        payload.splice(2..4, vec![BOUQUET_NAME_DESCRIPTOR, 0x07]);
+        payload.splice(4..4, b"Freesat".to_vec());
+        payload[1] = 9;
+        let section = Section::parse(&make_section(BAT_TABLE_ID, 272, 0, &payload)).unwrap();
+        let bat = Bat::parse(&[&section]).unwrap();
+        assert_eq!(bat.bouquet_id, 272);
+        assert_eq!(bat.bouquet_name, "Freesat");
+        assert_eq!(bat.transports.len(), 1);
+        assert_eq!(
+            bat.transports[0].services(),
+            vec![(4164, 0x01), (4287, 0x02)]
+        );
+    }
+
+    #[test]
+    fn parse_times() {
+        // The example of EN 300 468 annex C: 93/10/13 12:45:00.
+        assert_eq!(
+            parse_utc_time(&[0xc0, 0x79, 0x12, 0x45, 0x00]),
+            Some(750_516_300)
+        );
+        assert_eq!(parse_utc_time(&[0xff; 5]), None);
+        assert_eq!(parse_bcd_duration(&[0x01, 0x45, 0x30]), Some(6330));
+        assert_eq!(parse_bcd_duration(&[0x01, 0x4a, 0x30]), None);
+    }
+}
@@ -0,0 +1,437 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Signal statistics of a frontend that has achieved lock on a transponder.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::channels_file::ChannelEntry;
+use crate::isdbt;
+
+/// libdvbv5's assessment of the quality of a signal.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Quality {
+    Unknown,
+    Poor,
+    Ok,
+    Good,
+}
+
+impl Quality {
+    fn from_name(name: &str) -> Option<Quality> {
+        match name {
+            "Unknown" => Some(Quality::Unknown),
+            "Poor" => Some(Quality::Poor),
+            "Ok" => Some(Quality::Ok),
+            "Good" => Some(Quality::Good),
+            _ => None,
+        }
+    }
+}
+
+/// A statistic as reported by a frontend, either in decibels (dBm for signal strength)
+/// or as a percentage of the driver's range when the driver has no absolute scale.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Measurement {
+    Decibel(f64),
+    Relative(f64),
+}
+
+impl Measurement {
+    /// Read a statistic of layer `layer` of a frontend.
+    ///
+    /// # Safety
+    ///
+    /// `frontend_parameters` must point at an open frontend.
+    unsafe fn read(
+        frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+        command: u32,
+        layer: u32,
+    ) -> Option<Measurement> {
+        let stats = dvbv5_sys::dvb_fe_retrieve_stats_layer(frontend_parameters, command, layer);
+        if stats.is_null() {
+            return None;
+        }
+        let scale = (*stats).scale;
+        if scale == dvbv5_sys::fecap_scale_params::FE_SCALE_DECIBEL as u8 {
+            // Decibel values are reported in units of 0.001 dB.
+            Some(Measurement::Decibel(
+                (*stats).__bindgen_anon_1.svalue as f64 / 1000.0,
+            ))
+        } else if scale == dvbv5_sys::fecap_scale_params::FE_SCALE_RELATIVE as u8 {
+            // Relative values are reported in the range 0 to 65535.
+            Some(Measurement::Relative(
+                (*stats).__bindgen_anon_1.uvalue as f64 * 100.0 / 65535.0,
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn compare(&self, other: &Measurement) -> Ordering {
+        match (self, other) {
+            (Measurement::Decibel(a), Measurement::Decibel(b))
+            | (Measurement::Relative(a), Measurement::Relative(b)) => {
+                a.partial_cmp(b).unwrap_or(Ordering::Equal)
+            }
+            _ => Ordering::Equal,
+        }
+    }
+
+    /// Format as a value with units, `unit` being the decibel unit to use.
+    pub fn format(&self, unit: &str) -> String {
+        match self {
+            Measurement::Decibel(value) => format!("{:.2}{}", value, unit),
+            Measurement::Relative(value) => format!("{:.1}%", value),
+        }
+    }
+
+    pub fn parse(text: &str, unit: &str) -> Option<Measurement> {
+        if let Some(value) = text.strip_suffix('%') {
+            value.parse().ok().map(Measurement::Relative)
+        } else {
+            text.strip_suffix(unit)?
+                .parse()
+                .ok()
+                .map(Measurement::Decibel)
+        }
+    }
+}
+
+/// Read libdvbv5's assessment of the quality of the signal of layer `layer` of a frontend.
+///
+/// # Safety
+///
+/// `frontend_parameters` must point at an open frontend.
+unsafe fn read_quality(
+    frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+    layer: u32,
+) -> Quality {
+    match dvbv5_sys::dvb_fe_retrieve_quality(frontend_parameters, layer) {
+        dvbv5_sys::dvb_quality::DVB_QUAL_POOR => Quality::Poor,
+        dvbv5_sys::dvb_quality::DVB_QUAL_OK => Quality::Ok,
+        dvbv5_sys::dvb_quality::DVB_QUAL_GOOD => Quality::Good,
+        _ => Quality::Unknown,
+    }
+}
+
+/// Read the post Viterbi bit error rate of layer `layer` of a frontend.
+///
+/// # Safety
+///
+/// `frontend_parameters` must point at an open frontend.
+unsafe fn read_ber(
+    frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+    layer: u32,
+) -> Option<f64> {
+    let mut scale = dvbv5_sys::fecap_scale_params::FE_SCALE_NOT_AVAILABLE;
+    let ber = dvbv5_sys::dvb_fe_retrieve_ber(frontend_parameters, layer, &mut scale);
+    if scale == dvbv5_sys::fecap_scale_params::FE_SCALE_NOT_AVAILABLE || ber < 0.0 {
+        None
+    } else {
+        Some(ber as f64)
+    }
+}
+
+/// Read the uncorrected block count of layer `layer` of a frontend.
+///
+/// # Safety
+///
+/// `frontend_parameters` must point at an open frontend.
+unsafe fn read_ucb(
+    frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+    layer: u32,
+) -> Option<u64> {
+    let ucb = dvbv5_sys::dvb_fe_retrieve_stats_layer(
+        frontend_parameters,
+        dvbv5_sys::DTV_STAT_ERROR_BLOCK_COUNT,
+        layer,
+    );
+    if ucb.is_null() || (*ucb).scale != dvbv5_sys::fecap_scale_params::FE_SCALE_COUNTER as u8 {
+        None
+    } else {
+        Some((*ucb).__bindgen_anon_1.uvalue)
+    }
+}
+
+/// Statistics of one layer of an ISDB-T channel, whose layers are modulated and coded
+/// independently.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LayerStatistics {
+    pub quality: Quality,
+    /// Carrier to noise ratio.
+    pub cnr: Option<Measurement>,
+    /// Post Viterbi bit error rate.
+    pub ber: Option<f64>,
+    /// Uncorrected block count.
+    pub ucb: Option<u64>,
+}
+
+impl LayerStatistics {
+    /// Read the statistics of layer `layer`, counting from 1, of a frontend, if the
+    /// frontend reports any for the layer.
+    ///
+    /// # Safety
+    ///
+    /// `frontend_parameters` must point at an open frontend.
+    unsafe fn read(
+        frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+        layer: u32,
+    ) -> Option<LayerStatistics> {
+        let statistics = LayerStatistics {
+            quality: read_quality(frontend_parameters, layer),
+            cnr: Measurement::read(frontend_parameters, dvbv5_sys::DTV_STAT_CNR, layer),
+            ber: read_ber(frontend_parameters, layer),
+            ucb: read_ucb(frontend_parameters, layer),
+        };
+        if statistics.cnr.is_none() && statistics.ber.is_none() && statistics.ucb.is_none() {
+            None
+        } else {
+            Some(statistics)
+        }
+    }
+
+    fn annotate(&self, entry: &mut ChannelEntry, layer: char) {
+        entry.set_annotation(
+            &format!("LAYER_{}_QUALITY", layer),
+            &format!("{:?}", self.quality),
+        );
+        if let Some(cnr) = self.cnr {
+            entry.set_annotation(&format!("LAYER_{}_CNR", layer), &cnr.format("dB"));
+        }
+        if let Some(ber) = self.ber {
+            entry.set_annotation(&format!("LAYER_{}_BER", layer), &format!("{:.2e}", ber));
+        }
+        if let Some(ucb) = self.ucb {
+            entry.set_annotation(&format!("LAYER_{}_UCB", layer), &ucb.to_string());
+        }
+    }
+
+    fn from_entry(entry: &ChannelEntry, layer: char) -> Option<LayerStatistics> {
+        let annotation = |name: &str| entry.get_annotation(&format!("LAYER_{}_{}", layer, name));
+        Some(LayerStatistics {
+            quality: Quality::from_name(annotation("QUALITY")?)?,
+            cnr: annotation("CNR").and_then(|v| Measurement::parse(v, "dB")),
+            ber: annotation("BER").and_then(|v| v.parse().ok()),
+            ucb: annotation("UCB").and_then(|v| v.parse().ok()),
+        })
+    }
+}
+
+impl fmt::Display for LayerStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Quality {:?}", self.quality)?;
+        if let Some(cnr) = self.cnr {
+            write!(f, " C/N {}", cnr.format("dB"))?;
+        }
+        if let Some(ber) = self.ber {
+            write!(f, " BER {:.2e}", ber)?;
+        }
+        if let Some(ucb) = self.ucb {
+            write!(f, " UCB {}", ucb)?;
+        }
+        Ok(())
+    }
+}
+
+/// Statistics read from a frontend at the time lock was achieved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LockStatistics {
+    pub quality: Quality,
+    pub strength: Option<Measurement>,
+    /// Carrier to noise ratio.
+    pub cnr: Option<Measurement>,
+    /// Post Viterbi bit error rate.
+    pub ber: Option<f64>,
+    /// Uncorrected block count.
+    pub ucb: Option<u64>,
+    /// The statistics of layers A, B and C of an ISDB-T channel.
+    pub layers: [Option<LayerStatistics>; 3],
+}
+
+impl LockStatistics {
+    /// Read the statistics of a frontend. The caller must have called `dvb_fe_get_stats`
+    /// on the frontend so that the values are current.
+    ///
+    /// # Safety
+    ///
+    /// `frontend_parameters` must point at an open frontend.
+    pub unsafe fn read(frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms) -> LockStatistics {
+        let mut layers = [None; 3];
+        if (*frontend_parameters).current_sys as u32
+            == dvbv5_sys::fe_delivery_system::SYS_ISDBT as u32
+        {
+            for (index, layer) in layers.iter_mut().enumerate() {
+                *layer = LayerStatistics::read(frontend_parameters, index as u32 + 1);
+            }
+        }
+        LockStatistics {
+            quality: read_quality(frontend_parameters, 0),
+            strength: Measurement::read(
+                frontend_parameters,
+                dvbv5_sys::DTV_STAT_SIGNAL_STRENGTH,
+                0,
+            ),
+            cnr: Measurement::read(frontend_parameters, dvbv5_sys::DTV_STAT_CNR, 0),
+            ber: read_ber(frontend_parameters, 0),
+            ucb: read_ucb(frontend_parameters, 0),
+            layers,
+        }
+    }
+
+    /// Order statistics so that the better signal is the greater. Quality is the primary
+    /// key, carrier to noise ratio and then signal strength break ties; missing values
+    /// compare as worst.
+    pub fn compare(&self, other: &LockStatistics) -> Ordering {
+        fn compare_optional(a: &Option<Measurement>, b: &Option<Measurement>) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => a.compare(b),
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => Ordering::Equal,
+            }
+        }
+        self.quality
+            .cmp(&other.quality)
+            .then_with(|| compare_optional(&self.cnr, &other.cnr))
+            .then_with(|| compare_optional(&self.strength, &other.strength))
+    }
+
+    /// Record these statistics as annotations of a channels file entry.
+    pub fn annotate(&self, entry: &mut ChannelEntry) {
+        entry.set_annotation("SIGNAL_QUALITY", &format!("{:?}", self.quality));
+        if let Some(strength) = self.strength {
+            entry.set_annotation("SIGNAL_STRENGTH", &strength.format("dBm"));
+        }
+        if let Some(cnr) = self.cnr {
+            entry.set_annotation("CNR", &cnr.format("dB"));
+        }
+        if let Some(ber) = self.ber {
+            entry.set_annotation("BER", &format!("{:.2e}", ber));
+        }
+        if let Some(ucb) = self.ucb {
+            entry.set_annotation("UCB", &ucb.to_string());
+        }
+        for (layer, statistics) in isdbt::LAYERS.iter().zip(&self.layers) {
+            if let Some(statistics) = statistics {
+                statistics.annotate(entry, *layer);
+            }
+        }
+    }
+
+    /// Get the statistics recorded as annotations of a channels file entry, if any.
+    pub fn from_entry(entry: &ChannelEntry) -> Option<LockStatistics> {
+        let quality = Quality::from_name(entry.get_annotation("SIGNAL_QUALITY")?)?;
+        Some(LockStatistics {
+            quality,
+            strength: entry
+                .get_annotation("SIGNAL_STRENGTH")
+                .and_then(|v| Measurement::parse(v, "dBm")),
+            cnr: entry
+                .get_annotation("CNR")
+                .and_then(|v| Measurement::parse(v, "dB")),
+            ber: entry.get_annotation("BER").and_then(|v| v.parse().ok()),
+            ucb: entry.get_annotation("UCB").and_then(|v| v.parse().ok()),
+            layers: [
+                LayerStatistics::from_entry(entry, isdbt::LAYERS[0]),
+                LayerStatistics::from_entry(entry, isdbt::LAYERS[1]),
+                LayerStatistics::from_entry(entry, isdbt::LAYERS[2]),
+            ],
+        })
+    }
+}
+
+impl fmt::Display for LockStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Quality {:?}", self.quality)?;
+        if let Some(strength) = self.strength {
+            write!(f, " Signal {}", strength.format("dBm"))?;
+        }
+        if let Some(cnr) = self.cnr {
+            write!(f, " C/N {}", cnr.format("dB"))?;
+        }
+        if let Some(ber) = self.ber {
+            write!(f, " BER {:.2e}", ber)?;
+        }
+        if let Some(ucb) = self.ucb {
+            write!(f, " UCB {}", ucb)?;
+        }
+        for (layer, statistics) in isdbt::LAYERS.iter().zip(&self.layers) {
+            if let Some(statistics) = statistics {
+                write!(f, ", Layer {}: {}", layer, statistics)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn annotations_round_trip() {
+        let statistics = LockStatistics {
+            quality: Quality::Ok,
+            strength: Some(Measurement::Decibel(-52.25)),
+            cnr: Some(Measurement::Relative(62.5)),
+            ber: Some(1.5e-7),
+            ucb: Some(3),
+            layers: [None; 3],
+        };
+        let mut entry = ChannelEntry::new("BBC ONE");
+        statistics.annotate(&mut entry);
+        assert_eq!(entry.get_annotation("SIGNAL_STRENGTH"), Some("-52.25dBm"));
+        assert_eq!(entry.get_annotation("CNR"), Some("62.5%"));
+        assert_eq!(LockStatistics::from_entry(&entry), Some(statistics));
+    }
+
+    #[test]
+    fn layer_annotations_round_trip() {
+        let layer = LayerStatistics {
+            quality: Quality::Good,
+            cnr: Some(Measurement::Decibel(24.5)),
+            ber: None,
+            ucb: Some(0),
+        };
+        let statistics = LockStatistics {
+            quality: Quality::Good,
+            strength: None,
+            cnr: Some(Measurement::Decibel(21.0)),
+            ber: None,
+            ucb: None,
+            layers: [
+                Some(layer),
+                Some(LayerStatistics {
+                    quality: Quality::Ok,
+                    ..layer
+                }),
+                None,
+            ],
+        };
+        let mut entry = ChannelEntry::new("TV Globo");
+        statistics.annotate(&mut entry);
+        assert_eq!(entry.get_annotation("LAYER_A_CNR"), Some("24.50dB"));
+        assert_eq!(entry.get_annotation("LAYER_B_QUALITY"), Some("Ok"));
+        assert_eq!(entry.get_annotation("LAYER_C_QUALITY"), None);
+        assert_eq!(LockStatistics::from_entry(&entry), Some(statistics));
+    }
+}
@@ -0,0 +1,330 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Remuxing a multiple program transport stream to a single program transport stream.
+//!
+//! The PAT is rewritten to list only the selected program, only the PMT section of the
+//! program is kept, and of the other PIDs only those the PMT lists are kept.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::si::{self, Pat, Pmt, Section};
+use crate::ts::{self, Packet, PacketSplitter, SectionAssembler};
+
+/// The amount of a file read at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Extracts one program of a transport stream.
+#[derive(Debug)]
+pub struct SptsRemuxer {
+    program_number: u16,
+    splitter: PacketSplitter,
+    pat_assembler: SectionAssembler,
+    pmt_assembler: SectionAssembler,
+    pmt_pid: Option<u16>,
+    pmt: Option<Pmt>,
+    pat_continuity_counter: u8,
+    pmt_continuity_counter: u8,
+    /// The packets of the PAT and PMT last output.
+    pat_packets: Vec<u8>,
+    pmt_packets: Vec<u8>,
+}
+
+impl SptsRemuxer {
+    /// Create a remuxer for the program, or service, with the given number.
+    pub fn new(program_number: u16) -> SptsRemuxer {
+        SptsRemuxer {
+            program_number,
+            splitter: PacketSplitter::new(),
+            pat_assembler: SectionAssembler::new(),
+            pmt_assembler: SectionAssembler::new(),
+            pmt_pid: None,
+            pmt: None,
+            pat_continuity_counter: 0x0f,
+            pmt_continuity_counter: 0x0f,
+            pat_packets: Vec::new(),
+            pmt_packets: Vec::new(),
+        }
+    }
+
+    /// The packets of the PAT and PMT last output, for starting another file with. They
+    /// repeat the packets output, continuity counters and all, so are duplicates rather
+    /// than a discontinuity.
+    pub fn tables(&self) -> Vec<u8> {
+        [&self.pat_packets[..], &self.pmt_packets[..]].concat()
+    }
+
+    /// Whether the PMT of the program has been found.
+    pub fn has_program(&self) -> bool {
+        self.pmt.is_some()
+    }
+
+    /// Remux some of the transport stream, which need not be in whole packets, appending
+    /// the packets of the program to `output`.
+    pub fn push(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        let mut splitter = std::mem::take(&mut self.splitter);
+        splitter.push(data, |packet| self.push_packet(&packet, output));
+        self.splitter = splitter;
+    }
+
+    /// Remux one packet.
+    fn push_packet(&mut self, packet: &Packet, output: &mut Vec<u8>) {
+        let pid = packet.pid();
+        if pid == si::PAT_PID {
+            for data in self.pat_assembler.push(packet) {
+                self.handle_pat(&data, output);
+            }
+        } else if Some(pid) == self.pmt_pid {
+            for data in self.pmt_assembler.push(packet) {
+                self.handle_pmt(&data, output);
+            }
+        } else if self
+            .pmt
+            .as_ref()
+            .map(|pmt| pmt.pids().contains(&pid))
+            .unwrap_or(false)
+        {
+            output.extend_from_slice(packet.data());
+        }
+    }
+
+    fn handle_pat(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        let section = match Section::parse(data) {
+            Ok(section) if section.table_id == si::PAT_TABLE_ID && section.current_next => section,
+            _ => return,
+        };
+        let pat = Pat::parse(&[&section]);
+        let pmt_pid = match pat.pmt_pid(self.program_number) {
+            Some(pid) => pid,
+            None => return,
+        };
+        if self.pmt_pid != Some(pmt_pid) {
+            self.pmt_pid = Some(pmt_pid);
+            self.pmt = None;
+            self.pmt_packets.clear();
+            self.pmt_assembler = SectionAssembler::new();
+        }
+        let rewritten = Section {
+            section_number: 0,
+            last_section_number: 0,
+            payload: vec![
+                (self.program_number >> 8) as u8,
+                self.program_number as u8,
+                0xe0 | (pmt_pid >> 8) as u8,
+                pmt_pid as u8,
+            ],
+            ..section
+        };
+        self.pat_packets = ts::packetize_section(
+            si::PAT_PID,
+            &rewritten.encode(),
+            &mut self.pat_continuity_counter,
+        );
+        output.extend_from_slice(&self.pat_packets);
+    }
+
+    fn handle_pmt(&mut self, data: &[u8], output: &mut Vec<u8>) {
+        let section = match Section::parse(data) {
+            Ok(section)
+                if section.table_id == si::PMT_TABLE_ID
+                    && section.extension == self.program_number
+                    && section.current_next =>
+            {
+                section
+            }
+            _ => return,
+        };
+        if let Ok(pmt) = Pmt::parse(&section) {
+            self.pmt = Some(pmt);
+            self.pmt_packets = ts::packetize_section(
+                self.pmt_pid.unwrap_or_default(),
+                &section.encode(),
+                &mut self.pmt_continuity_counter,
+            );
+            output.extend_from_slice(&self.pmt_packets);
+        }
+    }
+}
+
+/// Remux a transport stream file to a single program transport stream file, returning the
+/// number of bytes written.
+pub fn remux_file(
+    input_path: &Path,
+    output_path: &Path,
+    program_number: u16,
+) -> Result<u64, String> {
+    let mut input = File::open(input_path)
+        .map_err(|e| format!("could not open {}, {}", input_path.display(), e))?;
+    let mut output = BufWriter::new(
+        File::create(output_path)
+            .map_err(|e| format!("could not create {}, {}", output_path.display(), e))?,
+    );
+    let write_error =
+        |e: std::io::Error| format!("could not write {}, {}", output_path.display(), e);
+    let mut remuxer = SptsRemuxer::new(program_number);
+    let mut buffer = vec![0u8; READ_SIZE];
+    let mut packets = Vec::new();
+    let mut total = 0;
+    loop {
+        let count = input
+            .read(&mut buffer)
+            .map_err(|e| format!("could not read {}, {}", input_path.display(), e))?;
+        if count == 0 {
+            break;
+        }
+        packets.clear();
+        remuxer.push(&buffer[..count], &mut packets);
+        output.write_all(&packets).map_err(write_error)?;
+        total += packets.len() as u64;
+    }
+    output.flush().map_err(write_error)?;
+    if !remuxer.has_program() {
+        return Err(format!(
+            "program {} is not in {}",
+            program_number,
+            input_path.display()
+        ));
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// Build a packet of a PID with a payload, stuffed with 0xff, for tests.
+    pub fn make_packet(pid: u16, continuity_counter: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![
+            ts::SYNC_BYTE,
+            (pid >> 8) as u8,
+            pid as u8,
+            0x10 | continuity_counter,
+        ];
+        data.extend_from_slice(payload);
+        data.resize(ts::PACKET_SIZE, 0xff);
+        data
+    }
+
+    /// Build a multiplex of two programs, 1 with PMT on PID 0x100 and streams on 0x101 and
+    /// 0x102, and 2 with PMT on PID 0x200 and a stream on 0x201, for tests.
+    pub fn make_multiplex() -> Vec<u8> {
+        let section = |table_id, extension, payload: Vec<u8>| {
+            Section {
+                table_id,
+                extension,
+                version: 3,
+                current_next: true,
+                section_number: 0,
+                last_section_number: 0,
+                payload,
+            }
+            .encode()
+        };
+        let pat = section(
+            si::PAT_TABLE_ID,
+            0x1044,
+            vec![0, 0, 0xe0, 0x10, 0, 1, 0xe1, 0x00, 0, 2, 0xe2, 0x00],
+        );
+        let pmt_1 = section(
+            si::PMT_TABLE_ID,
+            1,
+            vec![
+                0xe1, 0x01, 0xf0, 0x00, 0x02, 0xe1, 0x01, 0xf0, 0x00, 0x03, 0xe1, 0x02, 0xf0, 0x00,
+            ],
+        );
+        let pmt_2 = section(
+            si::PMT_TABLE_ID,
+            2,
+            vec![0xe2, 0x01, 0xf0, 0x00, 0x02, 0xe2, 0x01, 0xf0, 0x00],
+        );
+        let mut data = Vec::new();
+        let mut counter = 0x0f;
+        data.extend(ts::packetize_section(0x00, &pat, &mut counter));
+        counter = 0x0f;
+        data.extend(ts::packetize_section(0x100, &pmt_1, &mut counter));
+        counter = 0x0f;
+        data.extend(ts::packetize_section(0x200, &pmt_2, &mut counter));
+        for continuity_counter in 0..3 {
+            for pid in &[0x101, 0x102, 0x201, 0x12] {
+                data.extend(make_packet(*pid, continuity_counter, &[0x00]));
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn remux_selects_one_program() {
+        let input = make_multiplex();
+        let mut remuxer = SptsRemuxer::new(1);
+        let mut output = Vec::new();
+        // Push in odd sized pieces as reads from a device or file may give.
+        for chunk in input.chunks(1000) {
+            remuxer.push(chunk, &mut output);
+        }
+        assert_eq!(output.len() % ts::PACKET_SIZE, 0);
+        let pids = output
+            .chunks(ts::PACKET_SIZE)
+            .map(|p| Packet::new(p).unwrap().pid())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pids,
+            vec![0x00, 0x100, 0x101, 0x102, 0x101, 0x102, 0x101, 0x102]
+        );
+        let section = SectionAssembler::new().push(&Packet::new(&output[..188]).unwrap());
+        let pat = Pat::parse(&[&Section::parse(&section[0]).unwrap()]);
+        assert_eq!(pat.transport_stream_id, 0x1044);
+        assert_eq!(pat.programs, vec![(1, 0x100)]);
+        assert_eq!(remuxer.pmt.as_ref().unwrap().pids(), vec![0x101, 0x102]);
+    }
+
+    #[test]
+    fn remux_a_file() {
+        let directory = std::env::temp_dir().join(format!("dvb-tune-spts-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let input_path = directory.join("mpts.ts");
+        let output_path = directory.join("spts.ts");
+        std::fs::write(&input_path, make_multiplex()).unwrap();
+        assert_eq!(
+            remux_file(&input_path, &output_path, 2),
+            Ok(5 * ts::PACKET_SIZE as u64)
+        );
+        let output = std::fs::read(&output_path).unwrap();
+        let mut splitter = PacketSplitter::new();
+        let mut assembler = SectionAssembler::new();
+        let mut sections = Vec::new();
+        let mut pids = Vec::new();
+        splitter.push(&output, |packet| {
+            pids.push(packet.pid());
+            if packet.pid() == 0x200 {
+                sections.extend(assembler.push(&packet));
+            }
+        });
+        assert_eq!(pids, vec![0x00, 0x200, 0x201, 0x201, 0x201]);
+        // The regenerated PMT has a valid CRC and continuity counter.
+        assert_eq!(output[ts::PACKET_SIZE + 3] & 0x0f, 0);
+        let pmt = Pmt::parse(&Section::parse(&sections[0]).unwrap()).unwrap();
+        assert_eq!(pmt.program_number, 2);
+        assert_eq!(pmt.pids(), vec![0x201]);
+        assert!(remux_file(&input_path, &output_path, 3).is_err());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
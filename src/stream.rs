@@ -0,0 +1,297 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Sending a transport stream over UDP, plain or in RTP packets as specified by RFC 2250.
+
+use std::io::{self, Write};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::record::PacketOutput;
+use crate::ts::{PacketSplitter, PACKET_SIZE};
+
+/// The number of transport stream packets in each datagram, the most that fit an Ethernet
+/// frame.
+pub const PACKETS_PER_DATAGRAM: usize = 7;
+
+/// The size of the RTP header without contributing sources.
+const RTP_HEADER_SIZE: usize = 12;
+
+/// The RTP payload type of MPEG-2 transport streams, RFC 3551.
+const RTP_PAYLOAD_TYPE_MP2T: u8 = 33;
+
+/// How transport stream packets are carried in datagrams.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Udp,
+    Rtp,
+}
+
+impl Protocol {
+    pub fn from_name(name: &str) -> Option<Protocol> {
+        match name {
+            "udp" => Some(Protocol::Udp),
+            "rtp" => Some(Protocol::Rtp),
+            _ => None,
+        }
+    }
+}
+
+/// Where and how to send a stream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamOptions {
+    /// The multicast group, or unicast address, and port.
+    pub destination: SocketAddr,
+    pub protocol: Protocol,
+    /// The time to live, or hop limit for IPv6, of the datagrams.
+    pub ttl: u32,
+    /// The address of the interface to send IPv4 multicast datagrams from, rather than the
+    /// one the routing table gives.
+    pub interface: Option<Ipv4Addr>,
+}
+
+/// Sends the transport stream written to it as datagrams of whole packets.
+#[derive(Debug)]
+pub struct Sender {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    protocol: Protocol,
+    splitter: PacketSplitter,
+    packets: Vec<u8>,
+    sequence_number: u16,
+    ssrc: u32,
+    timestamp_offset: u32,
+    start: Instant,
+}
+
+impl Sender {
+    pub fn new(options: &StreamOptions) -> io::Result<Sender> {
+        if options.destination.is_ipv6() && options.interface.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the interface cannot be given by an IPv4 address for an IPv6 destination",
+            ));
+        }
+        let bind_address: SocketAddr = if options.destination.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind_address)?;
+        let multicast = options.destination.ip().is_multicast();
+        if options.destination.is_ipv6() {
+            let option = if multicast {
+                libc::IPV6_MULTICAST_HOPS
+            } else {
+                libc::IPV6_UNICAST_HOPS
+            };
+            set_option(
+                &socket,
+                libc::IPPROTO_IPV6,
+                option,
+                &(options.ttl as libc::c_int),
+            )?;
+        } else if multicast {
+            socket.set_multicast_ttl_v4(options.ttl)?;
+            if let Some(interface) = options.interface {
+                let address = libc::in_addr {
+                    s_addr: u32::from(interface).to_be(),
+                };
+                set_option(&socket, libc::IPPROTO_IP, libc::IP_MULTICAST_IF, &address)?;
+            }
+        } else {
+            socket.set_ttl(options.ttl)?;
+        }
+        // The synchronisation source and initial timestamp should be random, RFC 3550
+        // section 5.1; the clock is random enough to tell streams apart.
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default()
+            ^ std::process::id().rotate_left(16);
+        Ok(Sender {
+            socket,
+            destination: options.destination,
+            protocol: options.protocol,
+            splitter: PacketSplitter::new(),
+            packets: Vec::with_capacity(PACKETS_PER_DATAGRAM * PACKET_SIZE),
+            sequence_number: seed as u16,
+            ssrc: seed,
+            timestamp_offset: seed.rotate_left(8),
+            start: Instant::now(),
+        })
+    }
+
+    /// Send the packets gathered so far as one datagram.
+    fn send_datagram(&mut self) -> io::Result<()> {
+        if self.packets.is_empty() {
+            return Ok(());
+        }
+        let mut datagram = Vec::with_capacity(RTP_HEADER_SIZE + self.packets.len());
+        if self.protocol == Protocol::Rtp {
+            // The 90 kHz clock of MPEG, RFC 2250 section 2.
+            let timestamp = ((self.start.elapsed().as_micros() * 9 / 100) as u32)
+                .wrapping_add(self.timestamp_offset);
+            datagram.extend_from_slice(&[0x80, RTP_PAYLOAD_TYPE_MP2T]);
+            datagram.extend_from_slice(&self.sequence_number.to_be_bytes());
+            datagram.extend_from_slice(&timestamp.to_be_bytes());
+            datagram.extend_from_slice(&self.ssrc.to_be_bytes());
+            self.sequence_number = self.sequence_number.wrapping_add(1);
+        }
+        datagram.extend_from_slice(&self.packets);
+        self.packets.clear();
+        self.socket.send_to(&datagram, self.destination)?;
+        Ok(())
+    }
+}
+
+impl PacketOutput for Sender {}
+
+impl Write for Sender {
+    /// Send the whole packets of some of a transport stream, which need not be in whole
+    /// packets, holding back packets until there are enough for a datagram.
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut splitter = std::mem::take(&mut self.splitter);
+        let mut result = Ok(());
+        splitter.push(data, |packet| {
+            if result.is_ok() {
+                self.packets.extend_from_slice(packet.data());
+                if self.packets.len() == PACKETS_PER_DATAGRAM * PACKET_SIZE {
+                    result = self.send_datagram();
+                }
+            }
+        });
+        self.splitter = splitter;
+        result.map(|_| data.len())
+    }
+
+    /// Send any packets held back.
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_datagram()
+    }
+}
+
+/// Set a socket option `std::net` does not offer: the interface of IPv4 multicast and the
+/// hop limits of IPv6.
+fn set_option<T>(
+    socket: &UdpSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use super::*;
+    use crate::spts::tests::make_packet;
+
+    /// Send ten packets to a socket on the loopback interface and check the datagrams
+    /// received.
+    fn send_and_receive(protocol: Protocol) -> Vec<Vec<u8>> {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut sender = Sender::new(&StreamOptions {
+            destination: receiver.local_addr().unwrap(),
+            protocol,
+            ttl: 1,
+            interface: None,
+        })
+        .unwrap();
+        let data = (0..10u8)
+            .flat_map(|i| make_packet(0x100, i, &[i]))
+            .collect::<Vec<u8>>();
+        // Split mid packet as reads from a device may give.
+        sender.write_all(&data[..1000]).unwrap();
+        sender.write_all(&data[1000..]).unwrap();
+        sender.flush().unwrap();
+        let mut datagrams = Vec::new();
+        let mut buffer = [0u8; 2048];
+        for _ in 0..2 {
+            let count = receiver.recv(&mut buffer).unwrap();
+            datagrams.push(buffer[..count].to_vec());
+        }
+        datagrams
+    }
+
+    #[test]
+    fn stream_udp() {
+        let datagrams = send_and_receive(Protocol::Udp);
+        assert_eq!(datagrams[0].len(), 7 * PACKET_SIZE);
+        assert_eq!(datagrams[1].len(), 3 * PACKET_SIZE);
+        for (i, packet) in datagrams.concat().chunks(PACKET_SIZE).enumerate() {
+            assert_eq!(packet[0], 0x47);
+            assert_eq!(packet[3] & 0x0f, i as u8);
+        }
+    }
+
+    #[test]
+    fn interface_rejected_for_ipv6() {
+        let result = Sender::new(&StreamOptions {
+            destination: "[ff15::1]:5004".parse().unwrap(),
+            protocol: Protocol::Rtp,
+            ttl: 4,
+            interface: Some(Ipv4Addr::LOCALHOST),
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn stream_rtp() {
+        let datagrams = send_and_receive(Protocol::Rtp);
+        assert_eq!(datagrams[0].len(), RTP_HEADER_SIZE + 7 * PACKET_SIZE);
+        assert_eq!(datagrams[1].len(), RTP_HEADER_SIZE + 3 * PACKET_SIZE);
+        let field = |datagram: &[u8], range: std::ops::Range<usize>| {
+            datagram[range]
+                .iter()
+                .fold(0u32, |value, byte| (value << 8) | *byte as u32)
+        };
+        for datagram in &datagrams {
+            assert_eq!(datagram[0], 0x80);
+            assert_eq!(datagram[1], RTP_PAYLOAD_TYPE_MP2T);
+            assert_eq!(datagram[RTP_HEADER_SIZE], 0x47);
+        }
+        assert_eq!(
+            field(&datagrams[1], 2..4),
+            (field(&datagrams[0], 2..4) + 1) & 0xffff
+        );
+        assert!(field(&datagrams[1], 4..8).wrapping_sub(field(&datagrams[0], 4..8)) < 90_000);
+        assert_eq!(field(&datagrams[0], 8..12), field(&datagrams[1], 8..12));
+    }
+}
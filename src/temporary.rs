@@ -0,0 +1,85 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! A private temporary directory, for the files libdvbv5 can only read or write by path.
+
+use std::env;
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// A directory with a random name, created in the temporary directory readable and
+/// writable only by its owner, so that no one else can put files or links in it. The
+/// directory is removed, with its files, when dropped.
+pub struct TemporaryDirectory {
+    path: PathBuf,
+}
+
+impl TemporaryDirectory {
+    pub fn new() -> io::Result<TemporaryDirectory> {
+        let template = env::temp_dir().join("dvb-tune-XXXXXX");
+        let template = CString::new(template.into_os_string().into_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let template = template.into_raw();
+        // mkdtemp creates the directory with mode 0700, failing rather than using one that
+        // exists.
+        let result = unsafe { libc::mkdtemp(template) };
+        let template = unsafe { CString::from_raw(template) };
+        if result.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TemporaryDirectory {
+            path: PathBuf::from(OsStr::from_bytes(template.as_bytes())),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TemporaryDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn private_and_removed() {
+        let directory = TemporaryDirectory::new().unwrap();
+        let other = TemporaryDirectory::new().unwrap();
+        assert_ne!(directory.path(), other.path());
+        let metadata = fs::metadata(directory.path()).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        fs::write(directory.path().join("channels.conf"), "[BBC ONE]\n").unwrap();
+        let path = directory.path().to_path_buf();
+        drop(directory);
+        assert!(!path.exists());
+    }
+}
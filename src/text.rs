@@ -0,0 +1,275 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Decoding of DVB text strings as specified in EN 300 468 Annex A.
+//!
+//! The first byte of a string may select the character table used for the rest of it;
+//! without a selection the table is ISO/IEC 6937 with the euro sign added. Control codes
+//! mark the start and end of emphasis, which is dropped, and line breaks, which become
+//! `\n`.
+
+use encoding_rs::Encoding;
+use unicode_normalization::UnicodeNormalization;
+
+/// A character table of EN 300 468 Annex A.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charset {
+    /// ISO/IEC 6937 with the euro sign, the default table.
+    Iso6937,
+    /// A part of ISO/IEC 8859.
+    Iso8859(u8),
+    /// The Basic Multilingual Plane of ISO/IEC 10646, two bytes per character. The Big5
+    /// subset selection uses the same coding.
+    Ucs2,
+    /// KS X 1001-2004, Korean.
+    KsX1001,
+    /// GB-2312-1980, simplified Chinese.
+    Gb2312,
+    Utf8,
+}
+
+impl Charset {
+    /// Get a table from a name as used on the command line, for example `iso-8859-2`.
+    pub fn from_name(name: &str) -> Option<Charset> {
+        let name = name.to_lowercase().replace('_', "-");
+        match name.as_str() {
+            "iso6937" | "iso-6937" => Some(Charset::Iso6937),
+            "ucs-2" | "ucs2" | "utf-16" | "utf-16be" | "iso-10646" => Some(Charset::Ucs2),
+            "ks-x-1001" | "ksx1001" | "euc-kr" => Some(Charset::KsX1001),
+            "gb2312" | "gb-2312" => Some(Charset::Gb2312),
+            "utf-8" | "utf8" => Some(Charset::Utf8),
+            _ => name
+                .strip_prefix("iso-8859-")
+                .or_else(|| name.strip_prefix("iso8859-"))
+                .and_then(|part| part.parse().ok())
+                .filter(|part| (1..=16).contains(part) && *part != 12)
+                .map(Charset::Iso8859),
+        }
+    }
+
+    /// Get the table selected at the start of a string, with the rest of the string.
+    /// Reserved selections and the encoding_type_id selection, which needs information
+    /// from outside the string, give the default table.
+    fn select(data: &[u8]) -> (Charset, &[u8]) {
+        match data.first() {
+            Some(0x01..=0x07) => (Charset::Iso8859(data[0] + 4), &data[1..]),
+            Some(0x09..=0x0b) => (Charset::Iso8859(data[0] + 4), &data[1..]),
+            Some(0x10) if data.len() >= 3 => match data[2] {
+                part @ 0x01..=0x0f if data[1] == 0 && part != 0x0c => {
+                    (Charset::Iso8859(part), &data[3..])
+                }
+                _ => (Charset::Iso6937, &data[3..]),
+            },
+            Some(0x11) | Some(0x14) => (Charset::Ucs2, &data[1..]),
+            Some(0x12) => (Charset::KsX1001, &data[1..]),
+            Some(0x13) => (Charset::Gb2312, &data[1..]),
+            Some(0x15) => (Charset::Utf8, &data[1..]),
+            Some(0x1f) if data.len() >= 2 => (Charset::Iso6937, &data[2..]),
+            Some(0x00..=0x1f) => (Charset::Iso6937, &data[1..]),
+            _ => (Charset::Iso6937, data),
+        }
+    }
+
+    /// The `encoding_rs` encoding for a part of ISO/IEC 8859. Where `encoding_rs` has only
+    /// the Windows superset the superset is used, the extra characters being in the
+    /// range of control codes which are removed before decoding.
+    fn iso8859_encoding(part: u8) -> &'static Encoding {
+        match part {
+            2 => encoding_rs::ISO_8859_2,
+            3 => encoding_rs::ISO_8859_3,
+            4 => encoding_rs::ISO_8859_4,
+            5 => encoding_rs::ISO_8859_5,
+            6 => encoding_rs::ISO_8859_6,
+            7 => encoding_rs::ISO_8859_7,
+            8 => encoding_rs::ISO_8859_8,
+            9 => encoding_rs::WINDOWS_1254,
+            10 => encoding_rs::ISO_8859_10,
+            11 => encoding_rs::WINDOWS_874,
+            13 => encoding_rs::ISO_8859_13,
+            14 => encoding_rs::ISO_8859_14,
+            15 => encoding_rs::ISO_8859_15,
+            16 => encoding_rs::ISO_8859_16,
+            _ => encoding_rs::WINDOWS_1252,
+        }
+    }
+}
+
+/// The ISO/IEC 6937 characters for bytes 0xa0 to 0xff, with the euro sign at 0xa4 as
+/// in EN 300 468 figure A.1. Bytes 0xc1 to 0xcf are non-spacing diacritical marks,
+/// given as the combining character; unused bytes are NUL.
+const ISO_6937_UPPER: [char; 96] = [
+    '\u{a0}', '¡', '¢', '£', '€', '¥', '#', '§', '¤', '‘', '“', '«', '←', '↑', '→', '↓', //
+    '°', '±', '²', '³', '×', 'µ', '¶', '·', '÷', '’', '”', '»', '¼', '½', '¾', '¿', //
+    '\0', '\u{300}', '\u{301}', '\u{302}', '\u{303}', '\u{304}', '\u{306}', '\u{307}', //
+    '\u{308}', '\0', '\u{30a}', '\u{327}', '\0', '\u{30b}', '\u{328}', '\u{30c}', //
+    '―', '¹', '®', '©', '™', '♪', '¬', '¦', '\0', '\0', '\0', '\0', '⅛', '⅜', '⅝', '⅞', //
+    'Ω', 'Æ', 'Đ', 'ª', 'Ħ', '\0', 'Ĳ', 'Ŀ', 'Ł', 'Ø', 'Œ', 'º', 'Þ', 'Ŧ', 'Ŋ', 'ŉ', //
+    'ĸ', 'æ', 'đ', 'ð', 'ħ', 'ı', 'ĳ', 'ŀ', 'ł', 'ø', 'œ', 'ß', 'þ', 'ŧ', 'ŋ', '\u{ad}', //
+];
+
+/// The result of a control code.
+fn control(code: u8) -> Option<char> {
+    match code {
+        0x8a => Some('\n'),
+        _ => None,
+    }
+}
+
+/// Decode a run of text in a single byte table containing no control codes.
+fn decode_run(run: &[u8], charset: Charset, text: &mut String) {
+    match charset {
+        Charset::Iso8859(part) => text.push_str(
+            &Charset::iso8859_encoding(part)
+                .decode_without_bom_handling(run)
+                .0,
+        ),
+        _ => {
+            let mut diacritic = None;
+            for byte in run {
+                match byte {
+                    0x20..=0x7e => {
+                        text.push(*byte as char);
+                        if let Some(d) = diacritic.take() {
+                            text.push(d);
+                        }
+                    }
+                    0xc1..=0xcf => diacritic = Some(ISO_6937_UPPER[*byte as usize - 0xa0]),
+                    _ => {
+                        let c = ISO_6937_UPPER[*byte as usize - 0xa0];
+                        if c != '\0' {
+                            text.push(c);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decode text in a single byte table, handling the control codes 0x80 to 0x9f.
+fn decode_single_byte(data: &[u8], charset: Charset) -> String {
+    let mut text = String::new();
+    let mut start = 0;
+    for (index, byte) in data.iter().enumerate() {
+        if *byte < 0x20 || (0x7f..0xa0).contains(byte) {
+            decode_run(&data[start..index], charset, &mut text);
+            if let Some(c) = control(*byte) {
+                text.push(c);
+            }
+            start = index + 1;
+        }
+    }
+    decode_run(&data[start..], charset, &mut text);
+    text.nfc().collect()
+}
+
+/// Handle the control codes of a multi-byte table, 0xe080 to 0xe09f, and remove other
+/// control characters.
+fn filter_multi_byte(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c as u32 {
+            0xe080..=0xe09f => control((c as u32 - 0xe000) as u8),
+            0x00..=0x1f | 0x7f..=0x9f => None,
+            _ => Some(c),
+        })
+        .collect()
+}
+
+/// Decode a DVB text string. If `charset_override` is given that table is used whatever
+/// the string selects, for broadcasters that select the wrong table or none at all.
+pub fn decode(data: &[u8], charset_override: Option<Charset>) -> String {
+    let (selected, data) = Charset::select(data);
+    let charset = charset_override.unwrap_or(selected);
+    match charset {
+        Charset::Iso6937 | Charset::Iso8859(_) => decode_single_byte(data, charset),
+        Charset::Ucs2 => {
+            filter_multi_byte(&encoding_rs::UTF_16BE.decode_without_bom_handling(data).0)
+        }
+        Charset::KsX1001 => {
+            filter_multi_byte(&encoding_rs::EUC_KR.decode_without_bom_handling(data).0)
+        }
+        Charset::Gb2312 => filter_multi_byte(&encoding_rs::GBK.decode_without_bom_handling(data).0),
+        Charset::Utf8 => filter_multi_byte(&String::from_utf8_lossy(data)),
+    }
+}
+
+/// Decode a DVB text string for use as a name on a single line.
+pub fn decode_name(data: &[u8], charset_override: Option<Charset>) -> String {
+    decode(data, charset_override)
+        .split('\n')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn decode_each_table() {
+        let vectors: &[(&[u8], &str)] = &[
+            (b"BBC ONE", "BBC ONE"),
+            (b"M\xc8unchen \xa4 \xc2e\xcfs", "München € éš"),
+            (b"\x01\xb0\xb1", "АБ"),
+            (b"\x02\xc7", "ا"),
+            (b"\x03\xc5\xeb\xeb\xdc\xe4\xe1", "Ελλάδα"),
+            (b"\x04\xe0", "א"),
+            (b"\x05\xde", "Ş"),
+            (b"\x06\xa1", "Ą"),
+            (b"\x07\xa1", "ก"),
+            (b"\x09\xc0", "Ą"),
+            (b"\x0a\xa1", "Ḃ"),
+            (b"\x0b\xa4", "€"),
+            (b"\x10\x00\x01caf\xe9", "café"),
+            (b"\x10\x00\x02Polsat \xb3\xf3d\xbc", "Polsat łódź"),
+            (b"\x11\x00\x41\x04\x10", "AА"),
+            (b"\x12\xb0\xa1", "가"),
+            (b"\x13\xc4\xe3\xba\xc3", "你好"),
+            (b"\x14\x4e\x2d", "中"),
+            (b"\x15\xce\x95\xce\xa1\xce\xa4", "ΕΡΤ"),
+        ];
+        for (data, expected) in vectors {
+            assert_eq!(decode(data, None), *expected, "decoding {:x?}", data);
+        }
+    }
+
+    #[test]
+    fn decode_control_codes() {
+        assert_eq!(decode(b"\x86Das Erste\x87 HD", None), "Das Erste HD");
+        assert_eq!(decode(b"Line 1\x8aLine 2", None), "Line 1\nLine 2");
+        assert_eq!(
+            decode(b"\x11\xe0\x86\x00\x41\xe0\x87\xe0\x8a\x00\x42", None),
+            "A\nB"
+        );
+        assert_eq!(decode(b"\x15\xee\x82\x86A\xee\x82\x8aB", None), "A\nB");
+        assert_eq!(decode_name(b"Line 1\x8aLine 2", None), "Line 1 Line 2");
+    }
+
+    #[test]
+    fn decode_with_override() {
+        let charset = Charset::from_name("ISO-8859-2");
+        assert_eq!(charset, Some(Charset::Iso8859(2)));
+        assert_eq!(decode(b"\xb3\xf3d\xbc", charset), "łódź");
+        assert_eq!(decode(b"\x05\xb3", charset), "ł");
+        assert_eq!(Charset::from_name("iso-8859-12"), None);
+    }
+}
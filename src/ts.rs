@@ -0,0 +1,233 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! MPEG-2 transport stream packets (ISO/IEC 13818-1 section 2.4.3).
+
+/// The size of a transport stream packet.
+pub const PACKET_SIZE: usize = 188;
+
+/// The first byte of every packet.
+pub const SYNC_BYTE: u8 = 0x47;
+
+/// A view of a transport stream packet.
+#[derive(Clone, Copy, Debug)]
+pub struct Packet<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    /// View some data as a packet, if it is one.
+    pub fn new(data: &'a [u8]) -> Option<Packet<'a>> {
+        if data.len() == PACKET_SIZE && data[0] == SYNC_BYTE {
+            Some(Packet { data })
+        } else {
+            None
+        }
+    }
+
+    pub fn pid(&self) -> u16 {
+        (((self.data[1] & 0x1f) as u16) << 8) | self.data[2] as u16
+    }
+
+    /// Whether a PES packet or section starts in this packet.
+    pub fn payload_unit_start(&self) -> bool {
+        self.data[1] & 0x40 != 0
+    }
+
+    pub fn continuity_counter(&self) -> u8 {
+        self.data[3] & 0x0f
+    }
+
+    /// The payload, after any adaptation field; empty if there is none.
+    pub fn payload(&self) -> &'a [u8] {
+        let control = (self.data[3] >> 4) & 0x03;
+        if control & 0x01 == 0 {
+            return &[];
+        }
+        let start = if control & 0x02 != 0 {
+            5 + self.data[4] as usize
+        } else {
+            4
+        };
+        self.data.get(start..).unwrap_or(&[])
+    }
+
+    /// The whole packet.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// Splits a byte stream into packets, regaining sync if data is lost.
+#[derive(Debug, Default)]
+pub struct PacketSplitter {
+    buffer: Vec<u8>,
+}
+
+impl PacketSplitter {
+    pub fn new() -> PacketSplitter {
+        PacketSplitter::default()
+    }
+
+    /// Add data, calling `f` with each complete packet.
+    pub fn push<F: FnMut(Packet)>(&mut self, data: &[u8], mut f: F) {
+        self.buffer.extend_from_slice(data);
+        let mut start = 0;
+        while self.buffer.len() - start >= PACKET_SIZE {
+            let next_in_sync = self
+                .buffer
+                .get(start + PACKET_SIZE)
+                .map(|b| *b == SYNC_BYTE)
+                .unwrap_or(true);
+            match Packet::new(&self.buffer[start..start + PACKET_SIZE]) {
+                Some(packet) if next_in_sync => {
+                    f(packet);
+                    start += PACKET_SIZE;
+                }
+                _ => start += 1,
+            }
+        }
+        self.buffer.drain(..start);
+    }
+}
+
+/// Reassembles the sections carried on a PID.
+#[derive(Debug, Default)]
+pub struct SectionAssembler {
+    buffer: Vec<u8>,
+    started: bool,
+    continuity_counter: Option<u8>,
+}
+
+impl SectionAssembler {
+    pub fn new() -> SectionAssembler {
+        SectionAssembler::default()
+    }
+
+    /// Add a packet of the PID, returning the sections completed by it. A section being
+    /// assembled is abandoned if a packet has been lost.
+    pub fn push(&mut self, packet: &Packet) -> Vec<Vec<u8>> {
+        let mut sections = Vec::new();
+        let payload = packet.payload();
+        if payload.is_empty() {
+            return sections;
+        }
+        let expected = self.continuity_counter.map(|c| (c + 1) & 0x0f);
+        self.continuity_counter = Some(packet.continuity_counter());
+        if expected.is_some() && expected != self.continuity_counter {
+            self.buffer.clear();
+            self.started = false;
+        }
+        if packet.payload_unit_start() {
+            let pointer = payload[0] as usize;
+            if self.started && payload.len() > pointer {
+                self.buffer.extend_from_slice(&payload[1..1 + pointer]);
+                self.take_sections(&mut sections);
+            }
+            self.buffer.clear();
+            self.buffer
+                .extend_from_slice(payload.get(1 + pointer..).unwrap_or(&[]));
+            self.started = true;
+        } else if self.started {
+            self.buffer.extend_from_slice(payload);
+        }
+        self.take_sections(&mut sections);
+        sections
+    }
+
+    fn take_sections(&mut self, sections: &mut Vec<Vec<u8>>) {
+        while self.started && self.buffer.len() >= 3 {
+            if self.buffer[0] == 0xff {
+                // Stuffing: there are no more sections in this packet.
+                self.buffer.clear();
+                self.started = false;
+                break;
+            }
+            let length = 3 + ((((self.buffer[1] & 0x0f) as usize) << 8) | self.buffer[2] as usize);
+            if self.buffer.len() < length {
+                break;
+            }
+            sections.push(self.buffer.drain(..length).collect());
+        }
+    }
+}
+
+/// Split a section into packets of a PID, stuffing the last packet. `continuity_counter` is
+/// the counter of the previous packet of the PID and is updated.
+pub fn packetize_section(pid: u16, section: &[u8], continuity_counter: &mut u8) -> Vec<u8> {
+    let mut data = Vec::with_capacity(PACKET_SIZE);
+    let mut remaining = section;
+    let mut first = true;
+    while first || !remaining.is_empty() {
+        *continuity_counter = (*continuity_counter + 1) & 0x0f;
+        let start = data.len();
+        data.extend_from_slice(&[
+            SYNC_BYTE,
+            if first { 0x40 } else { 0x00 } | (pid >> 8) as u8 & 0x1f,
+            pid as u8,
+            0x10 | *continuity_counter,
+        ]);
+        if first {
+            data.push(0);
+        }
+        let space = PACKET_SIZE - (data.len() - start);
+        let length = space.min(remaining.len());
+        data.extend_from_slice(&remaining[..length]);
+        remaining = &remaining[length..];
+        data.resize(start + PACKET_SIZE, 0xff);
+        first = false;
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn packetize_and_reassemble() {
+        let section = (0..400u32)
+            .map(|i| i as u8)
+            .enumerate()
+            .map(|(i, b)| match i {
+                0 => 0x42,
+                1 => 0xb1,
+                2 => 0x8d,
+                _ => b,
+            })
+            .collect::<Vec<u8>>();
+        assert_eq!(section.len(), 3 + 0x18d);
+        let mut continuity_counter = 15;
+        let data = packetize_section(0x11, &section, &mut continuity_counter);
+        assert_eq!(data.len(), 3 * PACKET_SIZE);
+        assert_eq!(continuity_counter, 2);
+        let mut assembler = SectionAssembler::new();
+        let mut sections = Vec::new();
+        let mut splitter = PacketSplitter::new();
+        // Leading junk and split pushes exercise regaining sync.
+        splitter.push(&[0x00, 0x47, 0x01], |_| panic!("not a packet"));
+        splitter.push(&data[..100], |_| panic!("not a packet"));
+        splitter.push(&data[100..], |p| {
+            assert_eq!(p.pid(), 0x11);
+            sections.extend(assembler.push(&p));
+        });
+        assert_eq!(sections, vec![section]);
+    }
+}
@@ -0,0 +1,286 @@
+/*
+ *  DVBTune — for generating a DVBv5 channels file from a DVBv5 transmitter file.
+ *
+ *  Copyright © 2019, 2020  Russel Winder
+ *
+ *  This program is free software: you can redistribute it and/or modify
+ *  it under the terms of the GNU General Public License as published by
+ *  the Free Software Foundation, either version 3 of the License, or
+ *  (at your option) any later version.
+ *
+ *  This program is distributed in the hope that it will be useful,
+ *  but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ *  GNU General Public License for more details.
+ *
+ *  You should have received a copy of the GNU General Public License
+ *  along with this program. If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Single cable distribution: Unicable I (EN 50494) and JESS, or Unicable II (EN 50607).
+//!
+//! A single cable router gives each receiver on a cable a user band, a slot at a fixed
+//! frequency, and converts whichever transponder the receiver asks for with an
+//! ODU_Channel_change command to that frequency. The receiver then always tunes to its
+//! user band with the supply at 13 V and no tone: polarization and band are chosen by
+//! the command rather than by voltage and tone.
+
+use std::thread::sleep;
+
+use crate::satellite::{Conversion, Lnb, DISEQC_ANY_SWITCH, DISEQC_COMMAND, DISEQC_GAP};
+
+/// EN 50494 commands: ODU_Channel_change and ODU_Channel_change_PIN.
+const EN50494_CHANNEL_CHANGE: u8 = 0x5a;
+const EN50494_CHANNEL_CHANGE_PIN: u8 = 0x5c;
+
+/// EN 50607 framing bytes: ODU_Channel_change and ODU_Channel_change_PIN.
+const EN50607_CHANNEL_CHANGE: u8 = 0x70;
+const EN50607_CHANNEL_CHANGE_PIN: u8 = 0x71;
+
+/// The standard a single cable router follows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Standard {
+    /// EN 50494: 8 user bands, 2 satellite positions, tuning in 4 MHz steps.
+    Unicable,
+    /// EN 50607: 32 user bands, 64 satellite positions, tuning in 1 MHz steps.
+    Jess,
+}
+
+impl Standard {
+    fn user_bands(self) -> u8 {
+        match self {
+            Standard::Unicable => 8,
+            Standard::Jess => 32,
+        }
+    }
+
+    fn positions(self) -> u8 {
+        match self {
+            Standard::Unicable => 2,
+            Standard::Jess => 64,
+        }
+    }
+}
+
+/// The user band of a receiver.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserBand {
+    pub standard: Standard,
+    /// The user band number, 0 to 7 or 0 to 31.
+    pub slot: u8,
+    /// The centre frequency of the user band, in MHz.
+    pub frequency: u32,
+    /// The PIN protecting the user band, if it has one.
+    pub pin: Option<u8>,
+}
+
+impl UserBand {
+    /// Parse a user band given as `SLOT,FREQUENCY[,PIN]`, the slot numbered from 1 and
+    /// the frequency in MHz.
+    pub fn parse(standard: Standard, text: &str) -> Result<UserBand, String> {
+        let numbers = text
+            .split(',')
+            .map(|n| n.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| format!("invalid user band {}", text))?;
+        let (slot, frequency, pin) = match numbers[..] {
+            [slot, frequency] => (slot, frequency, None),
+            [slot, frequency, pin] if pin <= 255 => (slot, frequency, Some(pin as u8)),
+            _ => return Err(format!("a user band is SLOT,FREQUENCY[,PIN], not {}", text)),
+        };
+        if slot < 1 || slot > u32::from(standard.user_bands()) {
+            return Err(format!(
+                "the slot must be from 1 to {}, not {}",
+                standard.user_bands(),
+                slot
+            ));
+        }
+        if !(950..=2150).contains(&frequency) {
+            return Err(format!(
+                "the user band frequency must be from 950 to 2150 MHz, not {}",
+                frequency
+            ));
+        }
+        Ok(UserBand {
+            standard,
+            slot: slot as u8 - 1,
+            frequency,
+            pin,
+        })
+    }
+
+    /// The ODU_Channel_change command converting a transponder to the user band, and
+    /// the frequency, in kHz, the transponder is then received at. The transponder is
+    /// given by its intermediate frequency in kHz, the satellite position (0 to 1 or 0 to
+    /// 63), polarization and band. The transponder is only moved near the user band
+    /// frequency, by the step of the standard, so the frequency to tune to is offset by
+    /// the rounding.
+    pub fn channel_change(
+        &self,
+        intermediate_frequency: u32,
+        position: u8,
+        horizontal: bool,
+        high_band: bool,
+    ) -> Result<(Vec<u8>, u32), String> {
+        if position >= self.standard.positions() {
+            return Err(format!(
+                "a single cable router has at most {} satellite positions",
+                self.standard.positions()
+            ));
+        }
+        let bank = position << 2 | (horizontal as u8) << 1 | high_band as u8;
+        let user_band = self.frequency * 1000;
+        let (mut message, frequency) = match self.standard {
+            Standard::Unicable => {
+                // The router's oscillator is set to (T + 350) × 4 MHz, which should be the
+                // sum of the intermediate and user band frequencies.
+                let t = ((intermediate_frequency + user_band + 2000) / 4000)
+                    .checked_sub(350)
+                    .filter(|t| *t < 0x400)
+                    .ok_or("the transponder cannot be converted to the user band")?;
+                let message = vec![
+                    DISEQC_COMMAND,
+                    DISEQC_ANY_SWITCH,
+                    if self.pin.is_some() {
+                        EN50494_CHANNEL_CHANGE_PIN
+                    } else {
+                        EN50494_CHANNEL_CHANGE
+                    },
+                    self.slot << 5 | bank << 2 | (t >> 8) as u8,
+                    t as u8,
+                ];
+                (message, (t + 350) * 4000 - intermediate_frequency)
+            }
+            Standard::Jess => {
+                // The router converts (T + 100) MHz to the user band frequency.
+                let t = ((intermediate_frequency + 500) / 1000)
+                    .checked_sub(100)
+                    .filter(|t| *t < 0x800)
+                    .ok_or("the transponder cannot be converted to the user band")?;
+                let message = vec![
+                    if self.pin.is_some() {
+                        EN50607_CHANNEL_CHANGE_PIN
+                    } else {
+                        EN50607_CHANNEL_CHANGE
+                    },
+                    self.slot << 3 | (t >> 8) as u8,
+                    t as u8,
+                    bank,
+                ];
+                (
+                    message,
+                    user_band + intermediate_frequency - (t + 100) * 1000,
+                )
+            }
+        };
+        message.extend(self.pin);
+        Ok((message, frequency))
+    }
+
+    /// Send the ODU_Channel_change command for a transponder, at a frequency in kHz, to
+    /// the router of a frontend, returning the frequency, in kHz, to tune to. The command
+    /// is sent with the supply raised to 18 V, as the standards require.
+    ///
+    /// # Safety
+    ///
+    /// `frontend_parameters` must point at an open frontend.
+    pub unsafe fn select(
+        &self,
+        frontend_parameters: *mut dvbv5_sys::dvb_v5_fe_parms,
+        lnb: &Lnb,
+        frequency: u32,
+        position: u8,
+        horizontal: bool,
+    ) -> Result<u32, String> {
+        let (message, frequency) = self.channel_change(
+            lnb.intermediate_frequency(frequency),
+            position,
+            horizontal,
+            lnb.is_high_band(frequency),
+        )?;
+        dvbv5_sys::dvb_fe_sec_tone(
+            frontend_parameters,
+            dvbv5_sys::fe_sec_tone_mode::SEC_TONE_OFF,
+        );
+        dvbv5_sys::dvb_fe_sec_voltage(frontend_parameters, 1, 1);
+        sleep(DISEQC_GAP);
+        let sent = dvbv5_sys::dvb_fe_diseqc_cmd(
+            frontend_parameters,
+            message.len() as u32,
+            message.as_ptr(),
+        );
+        sleep(DISEQC_GAP);
+        dvbv5_sys::dvb_fe_sec_voltage(frontend_parameters, 1, 0);
+        if sent != 0 {
+            return Err("sending the ODU_Channel_change command failed".to_string());
+        }
+        Ok(frequency)
+    }
+
+    /// The conversion taking a transponder, at a frequency in kHz, to the frequency, in
+    /// kHz, the router converts it to. A transponder below that frequency, as given in
+    /// L band or by an LNB with its oscillator above, needs an oscillator above both.
+    pub fn conversion(frequency: u32, tuning_frequency: u32) -> Conversion {
+        if frequency >= tuning_frequency {
+            Conversion {
+                oscillator: frequency - tuning_frequency,
+                inverted: false,
+            }
+        } else {
+            Conversion {
+                oscillator: frequency + tuning_frequency,
+                inverted: true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn unicable_channel_change_commands() {
+        let universal = Lnb::from_name("universal").unwrap();
+        let user_band = UserBand::parse(Standard::Unicable, "1,1210").unwrap();
+        assert_eq!(user_band.slot, 0);
+        let intermediate_frequency = universal.intermediate_frequency(11_494_000);
+        assert_eq!(intermediate_frequency, 1_744_000);
+        assert_eq!(
+            user_band.channel_change(intermediate_frequency, 0, true, false),
+            Ok((vec![0xe0, 0x10, 0x5a, 0x09, 0x85], 1_212_000))
+        );
+        let user_band = UserBand::parse(Standard::Unicable, "3,1420,66").unwrap();
+        assert_eq!(
+            user_band.channel_change(universal.intermediate_frequency(12_188_000), 1, false, true),
+            Ok((vec![0xe0, 0x10, 0x5c, 0x55, 0x92, 0x42], 1_420_000))
+        );
+        assert!(user_band.channel_change(1_588_000, 2, false, true).is_err());
+        assert!(UserBand::parse(Standard::Unicable, "9,1210").is_err());
+        assert!(UserBand::parse(Standard::Unicable, "1,3000").is_err());
+        assert!(UserBand::parse(Standard::Unicable, "1").is_err());
+    }
+
+    #[test]
+    fn jess_channel_change_commands_and_tuning() {
+        let user_band = UserBand::parse(Standard::Jess, "5,1210").unwrap();
+        assert_eq!(
+            user_band.channel_change(1_744_400, 0, true, false),
+            Ok((vec![0x70, 0x26, 0x6c, 0x02], 1_210_400))
+        );
+        let user_band = UserBand::parse(Standard::Jess, "32,2100,1").unwrap();
+        assert_eq!(
+            user_band.channel_change(1_100_000, 63, false, false),
+            Ok((vec![0x71, 0xfb, 0xe8, 0xfc, 0x01], 2_100_000))
+        );
+        assert!(UserBand::parse(Standard::Jess, "33,1210").is_err());
+        let conversion = UserBand::conversion(11_494_400, 1_210_400);
+        assert_eq!(conversion.intermediate_frequency(11_494_400), 1_210_400);
+        assert_eq!(conversion.transponder_frequency(1_210_400), 11_494_400);
+        let conversion = UserBand::conversion(1_100_000, 2_100_000);
+        assert!(conversion.inverted);
+        assert_eq!(conversion.intermediate_frequency(1_100_000), 2_100_000);
+        assert_eq!(conversion.transponder_frequency(2_100_000), 1_100_000);
+    }
+}